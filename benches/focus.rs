@@ -0,0 +1,198 @@
+//! Compiler and VM benchmarks, run with `cargo bench`. Passing a name, as in
+//! `cargo bench -- interpolation`, only runs the cases that contain it.
+
+use std::{
+    collections::HashSet,
+    env,
+    hint::black_box,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use focus_lang::{
+    compiler::Compiler,
+    state::{ModuleLoader, ModuleValue, NativeModuleBuilder, Prototype},
+    stdlib,
+    value::Value,
+    vm::Vm,
+};
+
+struct Bencher {
+    filter: Option<String>,
+}
+
+impl Bencher {
+    fn enabled(&self, name: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| name.contains(filter.as_str()))
+    }
+
+    /// Runs the routine returned by `setup` for at least ten iterations and
+    /// two seconds, then prints the median and fastest iteration.
+    fn run<F: FnMut()>(&self, name: &str, setup: impl FnOnce() -> F) {
+        if !self.enabled(name) {
+            return;
+        }
+        let mut routine = setup();
+        routine();
+        let mut times = Vec::new();
+        let start = Instant::now();
+        while times.len() < 10 || start.elapsed() < Duration::from_secs(2) {
+            let time = Instant::now();
+            routine();
+            times.push(time.elapsed());
+        }
+        times.sort();
+        println!(
+            "{name:<24} median {:>10.3?}  min {:>10.3?}  ({} runs)",
+            times[times.len() / 2],
+            times[0],
+            times.len()
+        );
+    }
+
+    fn report(&self, name: &str, result: impl FnOnce() -> String) {
+        if self.enabled(name) {
+            println!("{name:<24} {}", result());
+        }
+    }
+}
+
+fn compile(source: &str, module_loader: &mut ModuleLoader) -> Rc<Prototype> {
+    let module = Compiler::new(source, module_loader)
+        .compile_module("main")
+        .unwrap();
+    let ModuleValue::Normal(prototype) = module.value else {
+        unreachable!()
+    };
+    prototype
+}
+
+fn execute(source: &str) -> impl FnMut() {
+    let mut vm = Vm::new_with_std();
+    let index = vm.load_from_source("main", source).unwrap();
+    move || vm.execute_module(index, "main").unwrap()
+}
+
+/// Counts the distinct strings held by the constants of a prototype and its
+/// nested prototypes, along with their length in bytes.
+fn string_constants(prototype: &Prototype, seen: &mut HashSet<*const String>) -> (usize, usize) {
+    fn visit(value: &Value, seen: &mut HashSet<*const String>, totals: &mut (usize, usize)) {
+        match value {
+            Value::String(string) if seen.insert(Rc::as_ptr(string)) => {
+                totals.0 += 1;
+                totals.1 += string.len();
+            }
+            Value::Array(array) => {
+                for value in array.borrow().iter() {
+                    visit(value, seen, totals);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut totals = (0, 0);
+    for constant in &prototype.constants {
+        visit(constant, seen, &mut totals);
+    }
+    for prototype in &prototype.prototypes {
+        let (count, bytes) = string_constants(prototype, seen);
+        totals.0 += count;
+        totals.1 += bytes;
+    }
+    totals
+}
+
+fn interpolation_constants(b: &Bencher) {
+    let source: String = (0..200)
+        .map(|i| format!("let f{i} x = \"{i}: {{x}} and {{x}}\"\n"))
+        .collect();
+    b.report("interpolation_constants", || {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.add_modules(stdlib::modules());
+        let prototype = compile(&source, &mut module_loader);
+        let (count, bytes) = string_constants(&prototype, &mut HashSet::new());
+        format!("{count} string constants, {bytes} bytes")
+    });
+}
+
+fn interpolation_loop(b: &Bencher) {
+    b.run("interpolation_loop", || {
+        execute(
+            "let count n =
+    if n == 0 then 0
+    else if String.contains (String.from_format \"{n}: {n} of {n}\") \"x\" then 1
+    else count (n - 1)
+let main () = count 500",
+        )
+    });
+}
+
+fn module_references(b: &Bencher) {
+    let modules = 240;
+    let source: String = (0..200)
+        .map(|i| {
+            let references: Vec<_> = (0..10)
+                .map(|j| format!("M{}.f", (i * 7 + j * 13) % modules))
+                .collect();
+            format!("let f{i} () = [{}]\n", references.join(", "))
+        })
+        .collect();
+    b.run("module_references", || {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.add_modules(stdlib::modules());
+        for i in 0..modules {
+            module_loader.add_module(
+                NativeModuleBuilder::new(&format!("M{i}"))
+                    .with_function("f", |_| Ok(Value::Unit))
+                    .build(),
+            );
+        }
+        move || {
+            black_box(compile(&source, &mut module_loader));
+        }
+    });
+}
+
+fn many_locals(b: &Bencher) {
+    let locals = 250;
+    let mut source = String::from("let main () =\n    let v0 = 0\n");
+    for i in 1..locals {
+        let references: Vec<_> = (1..=8).map(|j| format!("v{}", (i - 1) * j / 8)).collect();
+        source += &format!("    let v{i} = {}\n", references.join(" + "));
+    }
+    source += &format!("    v{}\n", locals - 1);
+    b.run("many_locals", || {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.add_modules(stdlib::modules());
+        move || {
+            black_box(compile(&source, &mut module_loader));
+        }
+    });
+}
+
+fn arithmetic(b: &Bencher) {
+    b.run("arithmetic", || {
+        execute(
+            "let main () =
+    let i = 0
+    let total = 0
+    while i < 100000 do
+        total = total + i * 3 % 7 - 1
+        i = i + 1
+    total",
+        )
+    });
+}
+
+fn main() {
+    let b = Bencher {
+        filter: env::args().skip(1).find(|arg| !arg.starts_with("--")),
+    };
+    interpolation_constants(&b);
+    interpolation_loop(&b);
+    module_references(&b);
+    many_locals(&b);
+    arithmetic(&b);
+}
@@ -3,13 +3,44 @@ pub enum Statement {
     Let {
         line_no: usize,
         ident: String,
+        /// The annotated type in `let x: int = 1`. Annotations aren't checked
+        /// by the compiler.
+        ty: Option<Type>,
         value: Option<Expression>,
+        /// Whether the local is exported with `pub`.
+        public: bool,
     },
     Function {
         line_no: usize,
         ident: String,
         args: Vec<String>,
+        /// The annotated type of each argument, as in `(a: int)`.
+        arg_types: Vec<Option<Type>>,
+        /// The default value of each argument, as in `(a = 1)`. Only the
+        /// last arguments can have one.
+        arg_defaults: Vec<Option<Expression>>,
         expr: Expression,
+        public: bool,
+    },
+    /// `const NAME = value`, a module local whose value is computed by the
+    /// compiler and can't be assigned to.
+    Const {
+        line_no: usize,
+        ident: String,
+        value: Expression,
+        public: bool,
+    },
+    /// `let (a, b) = value`, which unpacks a tuple into locals.
+    LetTuple {
+        line_no: usize,
+        idents: Vec<String>,
+        value: Expression,
+    },
+    /// `type Point = { x, y }`, which declares the fields of a record.
+    Type {
+        line_no: usize,
+        ident: String,
+        fields: Vec<String>,
     },
     Import {
         line_no: usize,
@@ -26,10 +57,70 @@ impl Statement {
     pub fn is_expression(&self) -> bool {
         matches!(self, Statement::Expression { .. })
     }
+
+    pub fn line_no(&self) -> usize {
+        match self {
+            Statement::Let { line_no, .. }
+            | Statement::Function { line_no, .. }
+            | Statement::Const { line_no, .. }
+            | Statement::LetTuple { line_no, .. }
+            | Statement::Type { line_no, .. }
+            | Statement::Import { line_no, .. }
+            | Statement::Expression { line_no, .. } => *line_no,
+        }
+    }
+}
+
+/// Where a node is in the source: its byte range and the 1-based line and
+/// column it starts at. Nodes made up by the compiler have the default span.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// The span from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+/// Expressions are compared by their code, wherever it is in the source.
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl From<ExpressionKind> for Expression {
+    fn from(kind: ExpressionKind) -> Self {
+        Self {
+            kind,
+            span: Span::default(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
-pub enum Expression {
+pub enum ExpressionKind {
     UnaryOperation {
         operand: Box<Expression>,
         operation: UnaryOperation,
@@ -40,6 +131,7 @@ pub enum Expression {
         rhs: Box<Expression>,
     },
     Array(Vec<Expression>),
+    Tuple(Vec<Expression>),
     Table(Vec<TableEntry>),
     Literal(Literal),
     Block(Vec<Statement>),
@@ -51,8 +143,16 @@ pub enum Expression {
         callee: Box<Expression>,
         args: Vec<Expression>,
     },
+    /// `receiver:method`, which is only valid as the callee of a call and
+    /// passes the receiver as the first argument of the method.
+    Method {
+        receiver: Box<Expression>,
+        method: String,
+    },
     Function {
         args: Vec<String>,
+        arg_types: Vec<Option<Type>>,
+        arg_defaults: Vec<Option<Expression>>,
         expr: Box<Expression>,
     },
     If {
@@ -64,6 +164,77 @@ pub enum Expression {
         format: String,
         arguments: Vec<InterpolatedArgument>,
     },
+    Match {
+        value: Box<Expression>,
+        arms: Vec<MatchArm>,
+    },
+    While {
+        condition: Box<Expression>,
+        block: Box<Expression>,
+    },
+    For {
+        ident: String,
+        iterable: Box<Expression>,
+        block: Box<Expression>,
+    },
+    Break,
+    Continue,
+    /// `try block catch e -> catch`, which evaluates to `catch` with the
+    /// error bound to `e` if `block` raises one.
+    Try {
+        block: Box<Expression>,
+        ident: String,
+        catch: Box<Expression>,
+    },
+    /// `assert condition "message"`, which raises an error with the source
+    /// text of the condition if it is false.
+    Assert {
+        condition: Box<Expression>,
+        text: String,
+        message: Option<Box<Expression>>,
+    },
+    /// `yield value`, which makes the function it's in a generator.
+    Yield(Box<Expression>),
+    /// `await value`, which waits for the value if it's a future.
+    Await(Box<Expression>),
+    /// `...array`, which is only valid as an array element or call argument.
+    Spread(Box<Expression>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    /// The condition after `if`, which the arm also needs to be chosen.
+    pub guard: Option<Expression>,
+    pub expression: Expression,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Pattern {
+    /// `_`, which matches any value.
+    Wildcard,
+    /// Matches values equal to the literal.
+    Literal(Literal),
+    /// Matches any value and binds it to the ident in the arm.
+    Binding(String),
+    /// `{status: 200, body}`, which matches tables whose fields match the
+    /// patterns. A field without a pattern is bound to its name, and a
+    /// missing field is unit like when read with a path.
+    Table(Vec<(String, Pattern)>),
+    /// `[head, ...rest]`, which matches arrays with an element for each
+    /// pattern, or with at least that many if there is a rest pattern. The
+    /// rest pattern is a binding or `_`.
+    Array {
+        elements: Vec<Pattern>,
+        rest: Option<Box<Pattern>>,
+    },
+}
+
+impl Pattern {
+    /// Returns whether the pattern matches any value.
+    pub fn is_irrefutable(&self) -> bool {
+        matches!(self, Pattern::Wildcard | Pattern::Binding(_))
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -76,12 +247,19 @@ pub struct TableEntry {
 pub enum PathPart {
     Ident(String),
     Index(Expression),
+    /// `[start..end]` or `[start..=end]`, where either bound can be left out.
+    Slice {
+        start: Option<Expression>,
+        end: Option<Expression>,
+        inclusive: bool,
+    },
 }
 
 #[derive(Debug, PartialEq)]
 pub enum UnaryOperation {
     Not,
     Negate,
+    BitNot,
 }
 
 #[derive(Debug, PartialEq)]
@@ -90,15 +268,27 @@ pub enum Operation {
     Arithmetic(ArithmeticOperator),
     Comparison(ComparisonOperator),
     Boolean(BooleanOperator),
+    Bitwise(BitwiseOperator),
     Concat,
+    Range {
+        inclusive: bool,
+    },
+    /// `lhs ?? rhs`, which only evaluates `rhs` if `lhs` is unit.
+    Coalesce,
 }
 
 impl Operation {
     pub fn precedence(&self) -> i32 {
         match self {
             Operation::Assignment => 10,
+            Operation::Coalesce => 15,
             Operation::Comparison(_) => 20,
             Operation::Boolean(_) => 20,
+            Operation::Range { .. } => 22,
+            Operation::Bitwise(
+                BitwiseOperator::And | BitwiseOperator::Or | BitwiseOperator::Xor,
+            ) => 25,
+            Operation::Bitwise(BitwiseOperator::Shl | BitwiseOperator::Shr) => 27,
             Operation::Arithmetic(ArithmeticOperator::Add | ArithmeticOperator::Subtract) => 30,
             Operation::Arithmetic(
                 ArithmeticOperator::Multiply
@@ -137,6 +327,15 @@ pub enum BooleanOperator {
     Or,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum BitwiseOperator {
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Literal {
     Unit,
@@ -147,10 +346,24 @@ pub enum Literal {
     String(String),
 }
 
+/// A type annotation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    /// `()`
+    Unit,
+    /// A type by name, such as `int` or a record.
+    Named(String),
+    /// `[int]`
+    Array(Box<Type>),
+    /// `(int, string)`
+    Tuple(Vec<Type>),
+}
+
 #[derive(Debug, PartialEq)]
 pub struct InterpolatedArgument {
     pub offset: usize,
     pub expression: Expression,
+    pub spec: Option<String>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -1,13 +1,21 @@
-use std::{fs::File, path::Path};
+use std::{fs::File, io::Write, path::Path};
 
-use focus_lang::{compiler::CompilerError, state::ModuleLoader, stdlib};
+use focus_lang::{
+    binary::{self, BinaryError},
+    compiler::CompilerError,
+    diagnostics::Diagnostic,
+    state::ModuleLoader,
+    stdlib,
+};
 
 #[derive(Debug)]
+#[allow(dead_code)]
 enum CompileCliError {
     MissingInput,
     ReadWriteError(std::io::Error),
     FileError(std::io::Error),
     CompilerError(CompilerError),
+    BinaryError(BinaryError),
 }
 
 impl From<CompilerError> for CompileCliError {
@@ -17,22 +25,40 @@ impl From<CompilerError> for CompileCliError {
 }
 
 fn main() -> Result<(), CompileCliError> {
-    let Some(input_filename) = std::env::args().nth(1) else {
+    // `--peephole` optimizes the code, so that the dumps with and without
+    // it can be compared. `--dump` prints the code instead of writing the
+    // compiled module.
+    let (flags, args): (Vec<String>, Vec<String>) = std::env::args()
+        .skip(1)
+        .partition(|arg| arg.starts_with("--"));
+    let Some(input_filename) = args.into_iter().next() else {
         eprintln!("Please provide a filename as the first argument.");
         return Err(CompileCliError::MissingInput);
     };
 
     let mut module_loader = ModuleLoader::new("");
     module_loader.add_modules(stdlib::modules());
-    module_loader.load_module(&input_filename);
+    module_loader.set_peephole(flags.iter().any(|flag| flag == "--peephole"));
+    let index = module_loader
+        .load_module(&input_filename)
+        .inspect_err(|err| {
+            let source = std::fs::read_to_string(&input_filename).unwrap_or_default();
+            let diagnostic = Diagnostic::from(err).with_file(&input_filename);
+            eprint!("{}", diagnostic.render(&source));
+        })?;
 
+    let module = module_loader.module_at(index).unwrap();
+    if flags.iter().any(|flag| flag == "--dump") {
+        return module
+            .dump(&mut std::io::stdout())
+            .map_err(CompileCliError::ReadWriteError);
+    }
+    let bytes =
+        binary::write_module(&module, &module_loader).map_err(CompileCliError::BinaryError)?;
     let mut out = File::create(Path::new(&input_filename).with_extension("flb"))
         .map_err(CompileCliError::FileError)?;
-    module_loader
-        .module_at(3)
-        .unwrap()
-        .dump(&mut out)
-        .map_err(|e| CompileCliError::ReadWriteError(e))?;
+    out.write_all(&bytes)
+        .map_err(CompileCliError::ReadWriteError)?;
 
     Ok(())
 }
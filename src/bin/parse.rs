@@ -3,6 +3,7 @@ use std::fs::File;
 use focus_lang::parser::{Parser, ParserError};
 
 #[derive(Debug)]
+#[allow(dead_code)]
 enum ParseCliError {
     MissingInput,
     FileError(std::io::Error),
@@ -1,17 +1,23 @@
-use std::{fs::File, path::Path};
+use std::{fs::File, io::Write, path::Path};
 
 use focus_lang::{
+    binary::{self, BinaryError},
     compiler::CompilerError,
+    diagnostics::Diagnostic,
+    state::ModuleLoader,
+    stdlib,
     value::Value,
     vm::{RuntimeError, Vm},
 };
 
 #[derive(Debug)]
+#[allow(dead_code)]
 enum RunCliError {
     MissingInput,
     ReadWriteError(std::io::Error),
     FileError(std::io::Error),
     CompilerError(CompilerError),
+    BinaryError(BinaryError),
     RuntimeError(RuntimeError),
 }
 
@@ -33,30 +39,55 @@ fn main() -> Result<Value, RunCliError> {
         return Err(RunCliError::MissingInput);
     };
 
-    let source = std::fs::read_to_string(&input_filename).map_err(RunCliError::ReadWriteError)?;
+    let bytes = std::fs::read(&input_filename).map_err(RunCliError::ReadWriteError)?;
 
-    let mut out = File::create(Path::new(&input_filename).with_extension("flb"))
-        .map_err(RunCliError::FileError)?;
+    // The modules that the script imports are looked up next to it, not in
+    // the directory it is run from.
+    let root = Path::new(&input_filename)
+        .parent()
+        .and_then(Path::to_str)
+        .unwrap_or_default();
+    let mut module_loader = ModuleLoader::new(root);
+    module_loader.add_modules(stdlib::modules());
+    let mut vm = Vm::new(module_loader);
+    // Compiled modules are run as they are, and have no source to show
+    // errors in.
+    let (result, source) = if binary::is_bytecode(&bytes) {
+        let result = vm
+            .load_from_bytes(&bytes)
+            .inspect_err(|err| eprint!("{}", render(err, &input_filename, "")))?;
+        (result, String::new())
+    } else {
+        let source = String::from_utf8(bytes).map_err(|err| {
+            RunCliError::ReadWriteError(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+        })?;
+        let result = vm
+            .load_from_source("main", &source)
+            .inspect_err(|err| eprint!("{}", render(err, &input_filename, &source)))?;
 
-    let mut vm = Vm::new_with_std();
-    let result = vm.load_from_source("main", &source)?;
-
-    vm.module_loader()
-        .module_at(result)
-        .unwrap()
-        .dump(&mut out)
-        .map_err(RunCliError::ReadWriteError)?;
+        let module = vm.module_loader().module_at(result).unwrap();
+        let bytes =
+            binary::write_module(&module, vm.module_loader()).map_err(RunCliError::BinaryError)?;
+        let mut out = File::create(Path::new(&input_filename).with_extension("flb"))
+            .map_err(RunCliError::FileError)?;
+        out.write_all(&bytes).map_err(RunCliError::ReadWriteError)?;
+        (result, source)
+    };
 
     match vm.execute_module(result, "main") {
         Ok(_) => {}
         Err(err) => {
-            println!("There was an error: {err}");
-            println!("{}", vm.stack_trace(5));
+            eprint!("{}", render(&err, &input_filename, &source));
             return Err(err.into());
         }
     }
 
-    let last_value = vm.stack().last().unwrap();
+    Ok(vm.peek().unwrap())
+}
 
-    Ok(last_value.clone())
+fn render<'a, E>(error: &'a E, filename: &str, source: &str) -> String
+where
+    Diagnostic: From<&'a E>,
+{
+    Diagnostic::from(error).with_file(filename).render(source)
 }
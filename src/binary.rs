@@ -0,0 +1,906 @@
+//! The binary format of compiled modules, which `.flb` files are in.
+//!
+//! A module is written as its locals and its main prototype, with each
+//! prototype followed by the prototypes defined in it. Numbers are little
+//! endian, and strings and lists are prefixed by their length as a `u32`.
+//!
+//! `GetModule` refers to modules by their index in the [`ModuleLoader`],
+//! which depends on the order modules are loaded in. The file lists the
+//! idents of the modules that its code gets instead, and the op codes index
+//! that list. They are looked up again when the module is read.
+
+use std::{collections::HashMap, error::Error, fmt::Display, rc::Rc};
+
+use crate::{
+    compiler::CompilerError,
+    op::OpCode,
+    state::{DebugInfo, Local, Module, ModuleLoader, ModuleValue, Prototype, Upvalue},
+    value::Value,
+};
+
+/// The bytes that compiled modules start with.
+pub const MAGIC: &[u8; 4] = b"FLB\0";
+/// The version of the format, which is written after [`MAGIC`].
+pub const VERSION: u8 = 2;
+
+const UNIT: u8 = 0;
+const BOOL: u8 = 1;
+const INTEGER: u8 = 2;
+const NUMBER: u8 = 3;
+const CHAR: u8 = 4;
+const STRING: u8 = 5;
+const ARRAY: u8 = 6;
+
+#[derive(Debug)]
+pub enum BinaryError {
+    /// The bytes don't start with [`MAGIC`].
+    NotBytecode,
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    InvalidOpCode(u8),
+    InvalidString,
+    /// A constant of a type that can't be written, or whose tag isn't
+    /// known when read.
+    InvalidConstant,
+    /// An op code that refers to a module that isn't in the list of the
+    /// module's imports.
+    InvalidModule(usize),
+    /// The op code at this offset refers to a constant, local, upvalue,
+    /// prototype or op code that doesn't exist, or takes more values than
+    /// are on the stack.
+    InvalidCode(usize),
+    /// Native modules can't be written since they are Rust code.
+    NativeModule(String),
+}
+
+impl Error for BinaryError {}
+
+impl Display for BinaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BinaryError::NotBytecode => write!(f, "Not a compiled module"),
+            BinaryError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "Unsupported bytecode version {version}, expected {VERSION}"
+                )
+            }
+            BinaryError::UnexpectedEnd => write!(f, "Unexpected end of bytecode"),
+            BinaryError::InvalidOpCode(tag) => write!(f, "Invalid op code {tag}"),
+            BinaryError::InvalidString => write!(f, "Invalid UTF-8 string"),
+            BinaryError::InvalidConstant => write!(f, "Invalid constant"),
+            BinaryError::InvalidModule(index) => write!(f, "Invalid module {index}"),
+            BinaryError::InvalidCode(offset) => write!(f, "Invalid op code at {offset}"),
+            BinaryError::NativeModule(ident) => {
+                write!(f, "`{ident}` is a native module and can't be compiled")
+            }
+        }
+    }
+}
+
+/// Returns whether `bytes` look like a compiled module.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Writes a module that is loaded in `module_loader`, which is used to name
+/// the modules that it imports.
+pub fn write_module(module: &Module, module_loader: &ModuleLoader) -> Result<Vec<u8>, BinaryError> {
+    let ModuleValue::Normal(prototype) = &module.value else {
+        return Err(BinaryError::NativeModule(module.ident.clone()));
+    };
+    let mut writer = Writer {
+        bytes: MAGIC.to_vec(),
+        imports: Vec::new(),
+    };
+    writer.bytes.push(VERSION);
+    writer.string(&module.ident);
+    writer.usize(module.locals.len());
+    for local in &module.locals {
+        writer.string(local);
+    }
+    let mut private: Vec<usize> = module.private.iter().copied().collect();
+    private.sort();
+    writer.usize(private.len());
+    for index in private {
+        writer.usize(index);
+    }
+    // The imports are only known once the code has been written, so they
+    // go at the end.
+    writer.prototype(prototype)?;
+    let imports = std::mem::take(&mut writer.imports);
+    writer.usize(imports.len());
+    for index in imports {
+        let module = module_loader
+            .module_at(index)
+            .ok_or(BinaryError::InvalidModule(index))?;
+        writer.string(&module.ident);
+    }
+    Ok(writer.bytes)
+}
+
+/// Reads a module written by [`write_module`]. The modules it imports are
+/// looked up by ident in `module_loader`, or loaded from its search paths if
+/// they aren't loaded yet.
+pub fn read_module(
+    bytes: &[u8],
+    module_loader: &mut ModuleLoader,
+) -> Result<Module, CompilerError> {
+    let mut reader = Reader {
+        bytes,
+        position: 0,
+        module_loader,
+    };
+    let (mut module, mut prototype) = reader.module()?;
+    // The main function of a module has no upvalues.
+    check(&prototype, 0)?;
+    let imports = reader.imports()?;
+    let mut indices = Vec::with_capacity(imports.len());
+    for ident in imports {
+        let index = match module_loader.module(&ident) {
+            Some(index) => index,
+            None => module_loader.load_module(&ident)?,
+        };
+        indices.push(index);
+    }
+    link(&mut prototype, &indices)?;
+    module.value = ModuleValue::Normal(Rc::new(prototype));
+    Ok(module)
+}
+
+/// Replaces the indices in the list of imports of `GetModule` with the
+/// indices of the modules in the loader.
+fn link(prototype: &mut Prototype, indices: &[usize]) -> Result<(), BinaryError> {
+    let mut modules = Vec::new();
+    for (offset, op_code) in prototype.op_codes() {
+        if let OpCode::GetModule(index) = op_code {
+            let module = indices
+                .get(index as usize)
+                .copied()
+                .ok_or(BinaryError::InvalidModule(index as usize))?;
+            let module = u8::try_from(module).map_err(|_| BinaryError::InvalidModule(module))?;
+            modules.push((offset, module));
+        }
+    }
+    // The code was just read too.
+    let code = Rc::get_mut(&mut prototype.code).unwrap();
+    for (offset, module) in modules {
+        // The index is the byte after the tag.
+        code[offset + 1] = module;
+    }
+    for child in &mut prototype.prototypes {
+        // The prototypes were just read, so nothing else refers to them.
+        link(Rc::get_mut(child).unwrap(), indices)?;
+    }
+    Ok(())
+}
+
+/// Checks that the op codes of `prototype` and of the prototypes in it can
+/// run: that their operands refer to constants, upvalues, prototypes and
+/// op codes that exist, and that they don't take more values than are on
+/// the stack or read locals above it. `num_upvalues` is the number of
+/// upvalues that its closures get.
+///
+/// The height of the stack is followed along every path through the code
+/// from its entries. Paths that meet must have the same height, as they do
+/// in compiled code.
+fn check(prototype: &Prototype, num_upvalues: usize) -> Result<(), BinaryError> {
+    let op_codes: Vec<(usize, OpCode)> = prototype.op_codes().collect();
+    // The index of the op code at each offset, with the end of the code
+    // after the last one.
+    let mut indices: HashMap<usize, usize> = op_codes
+        .iter()
+        .enumerate()
+        .map(|(i, &(offset, _))| (offset, i))
+        .collect();
+    indices.insert(prototype.code.len(), op_codes.len());
+    let offset = |i: usize| op_codes.get(i).map_or(prototype.code.len(), |op| op.0);
+
+    // The height of the stack at each op code that was reached, and the op
+    // codes whose successors haven't been followed yet.
+    let mut heights = vec![None; op_codes.len() + 1];
+    let mut pending = Vec::new();
+    let enter = |heights: &mut [Option<usize>],
+                 pending: &mut Vec<usize>,
+                 i: usize,
+                 height: usize,
+                 from: usize| {
+        match heights[i] {
+            Some(known) if known != height => return Err(BinaryError::InvalidCode(offset(from))),
+            Some(_) => {}
+            None => {
+                heights[i] = Some(height);
+                pending.push(i);
+            }
+        }
+        Ok(())
+    };
+    // A call with fewer arguments starts at an earlier entry, which pushes
+    // the defaults of the rest.
+    let entries = match prototype.entries.len() {
+        0 => vec![0],
+        len if len <= prototype.num_args + 1 => prototype.entries.clone(),
+        _ => return Err(BinaryError::InvalidCode(0)),
+    };
+    let first_height = prototype.num_args + 2 - entries.len();
+    for (k, &entry) in entries.iter().enumerate() {
+        let i = *indices.get(&entry).ok_or(BinaryError::InvalidCode(entry))?;
+        enter(&mut heights, &mut pending, i, first_height + k, i)?;
+    }
+
+    while let Some(i) = pending.pop() {
+        if i == op_codes.len() {
+            // A frame that runs past its code has finished.
+            continue;
+        }
+        let h = heights[i].unwrap();
+        let invalid = BinaryError::InvalidCode(op_codes[i].0);
+        let take = |n: usize| {
+            h.checked_sub(n)
+                .ok_or(BinaryError::InvalidCode(op_codes[i].0))
+        };
+        let local = |slot: usize, height: usize| {
+            (slot < height)
+                .then_some(())
+                .ok_or(BinaryError::InvalidCode(op_codes[i].0))
+        };
+        let upvalue = |index: usize| {
+            (index < num_upvalues)
+                .then_some(())
+                .ok_or(BinaryError::InvalidCode(op_codes[i].0))
+        };
+        let constant = |index: usize| {
+            (index < prototype.constants.len())
+                .then_some(())
+                .ok_or(BinaryError::InvalidCode(op_codes[i].0))
+        };
+        let template = |index: usize| match prototype.constants.get(index) {
+            Some(Value::Array(_)) => Ok(()),
+            _ => Err(BinaryError::InvalidCode(op_codes[i].0)),
+        };
+        // The 16-bit argument of a wide op code or a jump, whose high byte
+        // is in the `ExtraArg` after it.
+        let wide = |low: u8| match op_codes.get(i + 1) {
+            Some(&(_, OpCode::ExtraArg(high))) => Ok(u16::from_le_bytes([low, high]) as usize),
+            _ => Err(BinaryError::InvalidCode(op_codes[i].0)),
+        };
+        let after = offset(i + 2);
+        let target = |offset: Option<usize>| {
+            offset
+                .and_then(|offset| indices.get(&offset).copied())
+                .ok_or(BinaryError::InvalidCode(op_codes[i].0))
+        };
+
+        let mut jump = None;
+        let next = match op_codes[i].1 {
+            OpCode::LoadConst(index) => {
+                constant(index as usize)?;
+                Some(h + 1)
+            }
+            OpCode::LoadConstW(low) => {
+                constant(wide(low)?)?;
+                Some(h + 1)
+            }
+            OpCode::LoadUnit
+            | OpCode::LoadTrue
+            | OpCode::LoadFalse
+            | OpCode::LoadInt(_)
+            | OpCode::GetModule(_) => Some(h + 1),
+            OpCode::GetLocal(slot) => {
+                local(slot as usize, h)?;
+                Some(h + 1)
+            }
+            OpCode::GetLocalW(low) => {
+                local(wide(low)?, h)?;
+                Some(h + 1)
+            }
+            OpCode::GetUpvalue(index) => {
+                upvalue(index as usize)?;
+                Some(h + 1)
+            }
+            OpCode::GetUpvalueW(low) => {
+                upvalue(wide(low)?)?;
+                Some(h + 1)
+            }
+            OpCode::SetLocal(slot) => {
+                local(slot as usize, take(1)?)?;
+                Some(h - 1)
+            }
+            OpCode::SetLocalW(low) => {
+                local(wide(low)?, take(1)?)?;
+                Some(h - 1)
+            }
+            OpCode::SetUpvalue(index) => {
+                upvalue(index as usize)?;
+                Some(take(1)?)
+            }
+            OpCode::SetUpvalueW(low) => {
+                upvalue(wide(low)?)?;
+                Some(take(1)?)
+            }
+            OpCode::SetTable => Some(take(3)?),
+            OpCode::CreateList(len) | OpCode::CreateTuple(len) => Some(take(len as usize)? + 1),
+            OpCode::CreateListW(low) => Some(take(wide(low)?)? + 1),
+            OpCode::CreateTable(len) => Some(take(len as usize * 2)? + 1),
+            OpCode::CreateTableW(low) => Some(take(wide(low)? * 2)? + 1),
+            OpCode::Unpack(len) => Some(take(1)? + len as usize),
+            OpCode::Format(index, num_args) => {
+                template(index as usize)?;
+                Some(take(num_args as usize)? + 1)
+            }
+            OpCode::FormatW(low, num_args) => {
+                template(wide(low)?)?;
+                Some(take(num_args as usize)? + 1)
+            }
+            OpCode::Closure(index) => {
+                let child = prototype.prototypes.get(index as usize).ok_or(invalid)?;
+                for captured in &child.upvalues {
+                    if captured.is_local {
+                        local(captured.index, h)?;
+                    } else {
+                        upvalue(captured.index)?;
+                    }
+                }
+                Some(h + 1)
+            }
+            OpCode::GetTable
+            | OpCode::GetField(_)
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Divide
+            | OpCode::IDivide
+            | OpCode::Multiply
+            | OpCode::Modulus
+            | OpCode::Concat
+            | OpCode::Range
+            | OpCode::RangeInclusive
+            | OpCode::Extend
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::Shl
+            | OpCode::Shr
+            | OpCode::CmpEq
+            | OpCode::CmpNEq
+            | OpCode::CmpLess
+            | OpCode::CmpGreater
+            | OpCode::CmpLEq
+            | OpCode::CmpGEq
+            | OpCode::CmpAnd
+            | OpCode::CmpOr
+            | OpCode::CallSpread => Some(take(2)? + 1),
+            OpCode::Slice | OpCode::SliceInclusive => Some(take(3)? + 1),
+            OpCode::Len
+            | OpCode::IsTable
+            | OpCode::HasLen(_)
+            | OpCode::HasMinLen(_)
+            | OpCode::Rest(_)
+            | OpCode::Negate
+            | OpCode::Not
+            | OpCode::BitNot
+            | OpCode::IntoIter
+            | OpCode::Yield
+            | OpCode::Await
+            | OpCode::OpInt(..) => Some(take(1)? + 1),
+            OpCode::OpLocals(_, lhs, rhs) => {
+                local(lhs as usize, h)?;
+                local(rhs as usize, h)?;
+                Some(h + 1)
+            }
+            OpCode::OpLocalInt(_, lhs, _) => {
+                local(lhs as usize, h)?;
+                Some(h + 1)
+            }
+            OpCode::OpLocal(_, rhs) => {
+                local(rhs as usize, take(1)?)?;
+                Some(h)
+            }
+            OpCode::Call(num_args) => Some(take(num_args as usize + 1)? + 1),
+            OpCode::CallMethod(num_args) => Some(take(num_args as usize + 2)? + 1),
+            OpCode::TailCall(num_args) => {
+                take(num_args as usize + 1)?;
+                None
+            }
+            OpCode::Throw | OpCode::Return => {
+                take(1)?;
+                None
+            }
+            OpCode::JumpIfFalse(low) => {
+                jump = Some((target(after.checked_add(wide(low)?))?, take(1)?));
+                Some(h - 1)
+            }
+            OpCode::JumpIfNotUnit(low) => {
+                jump = Some((target(after.checked_add(wide(low)?))?, h));
+                Some(take(1)?)
+            }
+            OpCode::Jump(low) => {
+                jump = Some((target(after.checked_add(wide(low)?))?, h));
+                None
+            }
+            OpCode::Loop(low) => {
+                jump = Some((target(after.checked_sub(wide(low)?))?, h));
+                None
+            }
+            // The error is pushed where the stack was when the `try`
+            // started.
+            OpCode::Try(low) => {
+                jump = Some((target(after.checked_add(wide(low)?))?, h + 1));
+                Some(h)
+            }
+            OpCode::ForIter(low) => {
+                jump = Some((target(after.checked_add(wide(low)?))?, take(1)? + 1));
+                Some(h + 1)
+            }
+            OpCode::EndTry | OpCode::CloseUpvalue(_) => Some(h),
+            OpCode::CloseUpvalueW(low) => {
+                wide(low)?;
+                Some(h)
+            }
+            OpCode::Pop => Some(take(1)?),
+            OpCode::PopScope(len) => Some(take(len as usize + 1)? + 1),
+            OpCode::ExtraArg(_) => return Err(invalid),
+        };
+        // Wide op codes and jumps are followed by their `ExtraArg`.
+        let size = if is_wide(op_codes[i].1) { 2 } else { 1 };
+        if let Some(height) = next {
+            enter(&mut heights, &mut pending, i + size, height, i)?;
+        }
+        if let Some((target, height)) = jump {
+            enter(&mut heights, &mut pending, target, height, i)?;
+        }
+    }
+
+    for child in &prototype.prototypes {
+        check(child, child.upvalues.len())?;
+    }
+    Ok(())
+}
+
+/// Whether `op_code` takes a 16-bit argument, with the high byte in the
+/// `ExtraArg` after it.
+fn is_wide(op_code: OpCode) -> bool {
+    matches!(
+        op_code,
+        OpCode::LoadConstW(_)
+            | OpCode::GetLocalW(_)
+            | OpCode::GetUpvalueW(_)
+            | OpCode::SetLocalW(_)
+            | OpCode::SetUpvalueW(_)
+            | OpCode::CreateListW(_)
+            | OpCode::CreateTableW(_)
+            | OpCode::FormatW(..)
+            | OpCode::CloseUpvalueW(_)
+            | OpCode::JumpIfFalse(_)
+            | OpCode::JumpIfNotUnit(_)
+            | OpCode::Jump(_)
+            | OpCode::Loop(_)
+            | OpCode::Try(_)
+            | OpCode::ForIter(_)
+    )
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    /// The loader indices of the modules that the code gets, in the order
+    /// they are first got.
+    imports: Vec<usize>,
+}
+
+impl Writer {
+    fn usize(&mut self, value: usize) {
+        self.bytes.extend((value as u32).to_le_bytes());
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    fn string(&mut self, string: &str) {
+        self.usize(string.len());
+        self.bytes.extend(string.as_bytes());
+    }
+
+    fn prototype(&mut self, prototype: &Prototype) -> Result<(), BinaryError> {
+        self.string(&prototype.ident);
+        self.bool(prototype.is_anonymous);
+        self.bool(prototype.is_generator);
+        self.usize(prototype.num_args);
+        self.usize(prototype.entries.len());
+        for &entry in &prototype.entries {
+            self.usize(entry);
+        }
+
+        let mut code = prototype.code.to_vec();
+        let mut lines = Vec::new();
+        for (offset, op_code) in prototype.op_codes() {
+            if let OpCode::GetModule(index) = op_code {
+                code[offset + 1] = self.import(index as usize);
+            }
+            lines.push(prototype.line(offset));
+        }
+        self.usize(lines.len());
+        self.usize(code.len());
+        self.bytes.extend(code);
+        for line in lines {
+            self.usize(line);
+        }
+
+        self.usize(prototype.debug_info.locals.len());
+        for local in &prototype.debug_info.locals {
+            self.string(&local.ident);
+            self.usize(local.depth);
+            self.bool(local.is_captured);
+        }
+        self.usize(prototype.upvalues.len());
+        for upvalue in &prototype.upvalues {
+            self.usize(upvalue.index);
+            self.bool(upvalue.is_local);
+        }
+        self.usize(prototype.constants.len());
+        for constant in &prototype.constants {
+            self.constant(constant)?;
+        }
+        self.usize(prototype.prototypes.len());
+        for child in &prototype.prototypes {
+            self.prototype(child)?;
+        }
+        Ok(())
+    }
+
+    /// The index of a module in the list of imports, which it is added to
+    /// the first time.
+    fn import(&mut self, index: usize) -> u8 {
+        let position = match self.imports.iter().position(|&import| import == index) {
+            Some(position) => position,
+            None => {
+                self.imports.push(index);
+                self.imports.len() - 1
+            }
+        };
+        // There are no more imports than modules that `GetModule` can get.
+        position as u8
+    }
+
+    fn constant(&mut self, value: &Value) -> Result<(), BinaryError> {
+        match value {
+            Value::Unit => self.bytes.push(UNIT),
+            Value::Bool(value) => {
+                self.bytes.push(BOOL);
+                self.bool(*value);
+            }
+            Value::Integer(value) => {
+                self.bytes.push(INTEGER);
+                self.bytes.extend(value.to_le_bytes());
+            }
+            Value::Number(value) => {
+                self.bytes.push(NUMBER);
+                self.bytes.extend(value.to_le_bytes());
+            }
+            Value::Char(value) => {
+                self.bytes.push(CHAR);
+                self.bytes.extend((*value as u32).to_le_bytes());
+            }
+            Value::String(value) => {
+                self.bytes.push(STRING);
+                self.string(value);
+            }
+            Value::Array(array) => {
+                self.bytes.push(ARRAY);
+                let array = array.borrow();
+                self.usize(array.len());
+                for value in array.iter() {
+                    self.constant(value)?;
+                }
+            }
+            _ => return Err(BinaryError::InvalidConstant),
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a, 'b> {
+    bytes: &'a [u8],
+    position: usize,
+    /// Interns the string constants, like the compiler does.
+    module_loader: &'b mut ModuleLoader,
+}
+
+impl<'a> Reader<'a, '_> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BinaryError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + len)
+            .ok_or(BinaryError::UnexpectedEnd)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BinaryError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8, BinaryError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, BinaryError> {
+        Ok(self.byte()? != 0)
+    }
+
+    fn usize(&mut self) -> Result<usize, BinaryError> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    /// Reads the length of a list. Every element takes at least a byte, so
+    /// a corrupt length fails here rather than when allocating the list.
+    fn len(&mut self) -> Result<usize, BinaryError> {
+        let len = self.usize()?;
+        if len > self.bytes.len() - self.position {
+            return Err(BinaryError::UnexpectedEnd);
+        }
+        Ok(len)
+    }
+
+    fn string(&mut self) -> Result<String, BinaryError> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| BinaryError::InvalidString)
+    }
+
+    /// Reads the module up to its imports, with its main prototype apart
+    /// since it can only be shared once it is linked.
+    fn module(&mut self) -> Result<(Module, Prototype), BinaryError> {
+        if !is_bytecode(self.bytes) {
+            return Err(BinaryError::NotBytecode);
+        }
+        self.position = MAGIC.len();
+        let version = self.byte()?;
+        if version != VERSION {
+            return Err(BinaryError::UnsupportedVersion(version));
+        }
+        let ident = self.string()?;
+        let locals = (0..self.len()?)
+            .map(|_| self.string())
+            .collect::<Result<_, _>>()?;
+        let private = (0..self.len()?)
+            .map(|_| self.usize())
+            .collect::<Result<_, _>>()?;
+        let prototype = self.prototype()?;
+        let mut module = Module::new(&ident, ModuleValue::Native(Vec::new()), locals);
+        module.private = private;
+        Ok((module, prototype))
+    }
+
+    fn imports(&mut self) -> Result<Vec<String>, BinaryError> {
+        (0..self.len()?).map(|_| self.string()).collect()
+    }
+
+    fn prototype(&mut self) -> Result<Prototype, BinaryError> {
+        let mut prototype = Prototype::new(self.string()?, self.bool()?);
+        prototype.is_generator = self.bool()?;
+        prototype.num_args = self.usize()?;
+        prototype.entries = (0..self.len()?)
+            .map(|_| self.usize())
+            .collect::<Result<_, _>>()?;
+
+        let num_op_codes = self.len()?;
+        let len = self.len()?;
+        let code = self.take(len)?;
+        // The size of each op code, checking that the code decodes.
+        let mut sizes = Vec::with_capacity(num_op_codes);
+        let mut rest = code;
+        while let Some(&tag) = rest.first() {
+            let (_, size) = OpCode::decode(rest).ok_or(BinaryError::InvalidOpCode(tag))?;
+            sizes.push(size);
+            rest = &rest[size..];
+        }
+        if sizes.len() != num_op_codes {
+            return Err(BinaryError::UnexpectedEnd);
+        }
+        prototype.code = code.into();
+        // The file has the line of each op code, and the prototype of each
+        // of their bytes.
+        let mut lines = Vec::with_capacity(len);
+        for size in sizes {
+            let line = self.usize()?;
+            lines.resize(lines.len() + size, line);
+        }
+
+        let locals = (0..self.len()?)
+            .map(|_| {
+                Ok(Local {
+                    ident: self.string()?,
+                    depth: self.usize()?,
+                    is_captured: self.bool()?,
+                })
+            })
+            .collect::<Result<_, BinaryError>>()?;
+        prototype.debug_info = DebugInfo { locals, lines };
+        prototype.init_field_caches();
+        prototype.upvalues = (0..self.len()?)
+            .map(|_| {
+                Ok(Upvalue {
+                    index: self.usize()?,
+                    is_local: self.bool()?,
+                })
+            })
+            .collect::<Result<_, BinaryError>>()?;
+        prototype.constants = (0..self.len()?)
+            .map(|_| self.constant())
+            .collect::<Result<_, _>>()?;
+        prototype.prototypes = (0..self.len()?)
+            .map(|_| self.prototype().map(Rc::new))
+            .collect::<Result<_, _>>()?;
+        Ok(prototype)
+    }
+
+    fn constant(&mut self) -> Result<Value, BinaryError> {
+        let value = match self.byte()? {
+            UNIT => Value::Unit,
+            BOOL => Value::Bool(self.bool()?),
+            INTEGER => Value::Integer(i64::from_le_bytes(self.array()?)),
+            NUMBER => Value::Number(f64::from_le_bytes(self.array()?)),
+            CHAR => {
+                let char = char::from_u32(u32::from_le_bytes(self.array()?));
+                Value::Char(char.ok_or(BinaryError::InvalidConstant)?)
+            }
+            STRING => {
+                let string = self.string()?;
+                Value::String(self.module_loader.intern(&string))
+            }
+            ARRAY => {
+                let array = (0..self.len()?)
+                    .map(|_| self.constant())
+                    .collect::<Result<_, _>>()?;
+                Value::Array(self.module_loader.alloc_array(array))
+            }
+            _ => return Err(BinaryError::InvalidConstant),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        compiler::{CompilerError, CompilerErrorKind},
+        op::OpCode,
+        stdlib,
+        vm::Vm,
+    };
+
+    use super::{read_module, write_module, BinaryError};
+
+    fn compile(source: &str) -> Vec<u8> {
+        let mut vm = Vm::new_with_std();
+        let index = vm.load_from_source("main", source).unwrap();
+        let module = vm.module_loader().module_at(index).unwrap();
+        write_module(&module, vm.module_loader()).unwrap()
+    }
+
+    #[test]
+    fn run_compiled() {
+        let source = "let main () =
+    let offset = 0.5
+    let add x = x + offset
+    let letters = for c in \"ab\" do c
+    let numbers = Iter.collect (Iter.map (1..4) fn x -> add x)
+    let big = 1234567890123
+    \"{numbers} {big} {'c'} {true}\"";
+        let bytes = compile(source);
+        let mut vm = Vm::new_with_std();
+        let index = vm.load_from_bytes(&bytes).unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(
+            vm.peek().unwrap().to_string(),
+            "[1.5,2.5,3.5,] 1234567890123 c true"
+        );
+    }
+
+    #[test]
+    fn imports_are_looked_up_by_ident() {
+        let bytes = compile("let main () = String.trim_start \"  a\"");
+        // The module was compiled with all of the standard library, so
+        // `Math` was at another index.
+        let mut vm = Vm::new_with_stdlib(stdlib::StdlibSet::STRING);
+        let index = vm.load_from_bytes(&bytes).unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek().unwrap().to_string(), "a");
+        let mut vm = Vm::new_with_stdlib(stdlib::StdlibSet::IO);
+        assert!(matches!(
+            vm.load_from_bytes(&bytes),
+            Err(CompilerError {
+                kind: CompilerErrorKind::ModuleError(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn strings_are_interned() {
+        let bytes = compile("let main () = \"shared\"");
+        let mut vm = Vm::new_with_std();
+        let source = vm
+            .load_from_source("source", "let main () = \"shared\"")
+            .unwrap();
+        let compiled = vm.load_from_bytes(&bytes).unwrap();
+        let mut result = |index: usize| {
+            vm.execute_module(index, "main").unwrap();
+            vm.peek().unwrap().as_string().unwrap()
+        };
+        let (source, compiled) = (result(source), result(compiled));
+        assert_eq!(compiled.as_str(), "shared");
+        assert!(Rc::ptr_eq(&source, &compiled));
+        assert!(Rc::ptr_eq(&compiled, &vm.intern("shared")));
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        let read = |bytes: &[u8]| {
+            let mut module_loader = crate::state::ModuleLoader::new("");
+            module_loader.add_modules(stdlib::modules());
+            match read_module(bytes, &mut module_loader) {
+                Err(CompilerError {
+                    kind: CompilerErrorKind::BinaryError(error),
+                    ..
+                }) => error,
+                result => panic!("expected a binary error, got {result:?}"),
+            }
+        };
+        assert!(matches!(read(b"let main () = 1"), BinaryError::NotBytecode));
+        let bytes = compile("let main () = \"a\"");
+        assert!(matches!(
+            read(&[&bytes[..4], &[0]].concat()),
+            BinaryError::UnsupportedVersion(0)
+        ));
+        // Every prefix of a module is missing something.
+        for len in 5..bytes.len() {
+            assert!(matches!(read(&bytes[..len]), BinaryError::UnexpectedEnd));
+        }
+        // Swap the code of `main` for code of the same length that uses
+        // something that isn't there.
+        let code = |op_codes: &[OpCode]| {
+            let mut code = Vec::new();
+            for &op_code in op_codes {
+                op_code.encode(&mut code);
+            }
+            code
+        };
+        let main = code(&[OpCode::LoadConst(0), OpCode::Return]);
+        let at = bytes.windows(main.len()).position(|w| w == main).unwrap();
+        let with_main = |op_codes: &[OpCode]| {
+            let mut bytes = bytes.clone();
+            bytes[at..at + main.len()].copy_from_slice(&code(op_codes));
+            read(&bytes)
+        };
+        for op_codes in [
+            [OpCode::LoadConst(1), OpCode::Return],
+            [OpCode::GetLocal(1), OpCode::Return],
+            [OpCode::GetUpvalue(0), OpCode::Return],
+            [OpCode::Closure(0), OpCode::Return],
+            [OpCode::PopScope(1), OpCode::Return],
+            [OpCode::Jump(0), OpCode::Return],
+        ] {
+            assert!(matches!(with_main(&op_codes), BinaryError::InvalidCode(_)));
+        }
+        // Any change to a byte is read or rejected, but never panics.
+        for i in 5..bytes.len() {
+            for byte in [0, 1, 2, 0x7f, 0xff] {
+                let mut bytes = bytes.clone();
+                bytes[i] = byte;
+                let mut module_loader = crate::state::ModuleLoader::new("");
+                module_loader.add_modules(stdlib::modules());
+                let _ = read_module(&bytes, &mut module_loader);
+            }
+        }
+
+        let module = stdlib::modules().remove(0);
+        let module_loader = crate::state::ModuleLoader::new("");
+        assert!(matches!(
+            write_module(&module, &module_loader),
+            Err(BinaryError::NativeModule(_))
+        ));
+    }
+}
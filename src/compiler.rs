@@ -1,5 +1,6 @@
 use std::{
     cell::{RefCell, RefMut},
+    collections::{HashMap, HashSet},
     error::Error,
     fmt::Display,
     io::{BufWriter, Write},
@@ -8,18 +9,25 @@ use std::{
 
 use crate::{
     ast::{
-        ArithmeticOperator, BooleanOperator, ComparisonOperator, Expression, Import, ImportSource,
-        Literal, Operation, PathPart, Statement, UnaryOperation,
+        ArithmeticOperator, BitwiseOperator, BooleanOperator, ComparisonOperator, Expression,
+        ExpressionKind, Import, ImportSource, Literal, Operation, PathPart, Pattern, Span,
+        Statement, UnaryOperation,
     },
-    op::{FunctionIdx, InitLen, LocalIdx, OpCode},
+    binary::BinaryError,
+    op::{ConstIdx, FunctionIdx, OpCode, Operator},
     parser::{Parser, ParserError},
-    state::{Local, Module, ModuleAlias, ModuleLoader, ModuleValue, Prototype, Upvalue},
+    state::{
+        Local, Module, ModuleAlias, ModuleError, ModuleLoader, ModuleValue, Prototype, Upvalue,
+    },
+    typecheck::{self, TypeError},
     value::Value,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ScopeResolver {
     locals: Vec<Local>,
+    /// The slots of the locals with each ident, the innermost last.
+    slots: HashMap<String, Vec<usize>>,
     depth: usize,
     base_depth: usize,
 }
@@ -28,6 +36,7 @@ impl ScopeResolver {
     pub fn new() -> Self {
         Self {
             locals: Vec::new(),
+            slots: HashMap::new(),
             depth: 0,
             base_depth: 0,
         }
@@ -48,7 +57,14 @@ impl ScopeResolver {
         if self.depth > self.base_depth {
             self.depth -= 1;
         }
-        self.locals.truncate(self.locals.len() - n);
+        for local in self.locals.drain(self.locals.len() - n..) {
+            if let Some(slots) = self.slots.get_mut(&local.ident) {
+                slots.pop();
+                if slots.is_empty() {
+                    self.slots.remove(&local.ident);
+                }
+            }
+        }
         n
     }
 
@@ -69,8 +85,8 @@ impl ScopeResolver {
     }
 
     pub fn add_local(&mut self, ident: String) -> Result<usize, CompilerError> {
-        if self.locals.len() > u8::MAX as usize {
-            return Err(CompilerError::MaxNumberOfLocalsExceeded);
+        if self.locals.len() > u16::MAX as usize {
+            return Err(CompilerErrorKind::MaxNumberOfLocalsExceeded.into());
         }
 
         let local = Local {
@@ -80,9 +96,32 @@ impl ScopeResolver {
         };
         let index = self.locals.len();
         self.locals.push(local);
+        self.slots.entry(ident).or_default().push(index);
         Ok(index)
     }
 
+    /// Reserves the slot of a value that stays on the stack while the
+    /// expressions after it are compiled, so that the locals they declare go
+    /// where the VM pushes them.
+    pub fn add_temporary(&mut self) -> Result<(), CompilerError> {
+        if self.locals.len() > u16::MAX as usize {
+            return Err(CompilerErrorKind::MaxNumberOfLocalsExceeded.into());
+        }
+        self.locals.push(Local {
+            ident: String::new(),
+            depth: self.depth,
+            is_captured: false,
+        });
+        Ok(())
+    }
+
+    /// Releases the slots of the last `n` temporaries once they are popped.
+    pub fn remove_temporaries(&mut self, n: usize) {
+        let len = self.locals.len() - n;
+        debug_assert!(self.locals[len..].iter().all(|l| l.ident.is_empty()));
+        self.locals.truncate(len);
+    }
+
     pub fn local(&self, index: usize) -> &Local {
         &self.locals[index]
     }
@@ -92,12 +131,10 @@ impl ScopeResolver {
     }
 
     pub fn resolve_local(&self, ident: &str) -> Option<usize> {
-        for (i, local) in self.locals.iter().enumerate().rev() {
-            if local.ident == ident {
-                return Some(i);
-            }
-        }
-        None
+        self.slots
+            .get(ident)
+            .and_then(|slots| slots.last())
+            .copied()
     }
 
     fn mark_captured(&mut self, index: usize) {
@@ -105,12 +142,81 @@ impl ScopeResolver {
     }
 }
 
+/// Where the value of a name is stored while a function runs.
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    Local(usize),
+    Upvalue(usize),
+}
+
+/// An operand that a fused op code reads in place.
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Local(u8),
+    Int(u8),
+}
+
+/// A step from a matched value to one of its parts.
+#[derive(Debug, Clone)]
+enum PatternStep {
+    Field(String),
+    Element(usize),
+}
+
+#[derive(Debug)]
+pub struct LoopState {
+    /// Where `continue` jumps to.
+    pub start: usize,
+    /// The number of locals outside of the loop's body.
+    pub num_locals: usize,
+    /// The jumps of the `break`s, patched at the end of the loop.
+    pub breaks: Vec<usize>,
+    /// The number of `try` blocks outside of the loop's body.
+    pub num_tries: usize,
+}
+
+/// The code of a function while it is compiled, one op code at a time,
+/// with jumps and entries counting op codes so that the optimizer can move
+/// them around. [`CompilerState::build_prototype`] encodes it into the
+/// bytes of the [`Prototype`].
+#[derive(Debug, Clone, Default)]
+pub struct Code {
+    pub op_codes: Vec<OpCode>,
+    /// The line of each op code.
+    pub lines: Vec<usize>,
+    /// The entries of the prototype, as indices of op codes.
+    pub entries: Vec<usize>,
+}
+
+impl Code {
+    pub fn len(&self) -> usize {
+        self.op_codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.op_codes.is_empty()
+    }
+
+    fn push(&mut self, op_code: OpCode, line: usize) {
+        self.op_codes.push(op_code);
+        self.lines.push(line);
+    }
+}
+
 #[derive(Debug)]
 pub struct CompilerState {
     pub parent: Option<Rc<RefCell<CompilerState>>>,
     pub prototype: Prototype,
+    pub code: Code,
     pub resolver: ScopeResolver,
     pub defined_states: Vec<Rc<RefCell<CompilerState>>>,
+    pub loops: Vec<LoopState>,
+    /// The number of `try` blocks being compiled.
+    pub num_tries: usize,
+    /// The record type of the locals initialized with a record, by slot.
+    pub records: HashMap<usize, String>,
+    /// The number of `GetField` caches given out.
+    pub num_field_caches: usize,
 }
 
 impl CompilerState {
@@ -119,7 +225,12 @@ impl CompilerState {
             parent: None,
             resolver: ScopeResolver::new(),
             prototype: Prototype::new(ident, is_anonymous),
+            code: Code::default(),
             defined_states: Vec::new(),
+            loops: Vec::new(),
+            num_tries: 0,
+            records: HashMap::new(),
+            num_field_caches: 0,
         }
     }
 
@@ -133,23 +244,27 @@ impl CompilerState {
         self
     }
 
-    fn build_prototype(&self) -> Rc<Prototype> {
+    fn build_prototype(&self, peephole: bool) -> Result<Rc<Prototype>, CompilerError> {
         let mut proto = self.prototype.clone();
+        let mut code = self.code.clone();
+        if peephole {
+            optimize(&mut code);
+        }
+        assemble(&code, &mut proto)?;
         for state in &self.defined_states {
-            let child_proto = state.borrow().build_prototype();
+            let child_proto = state.borrow().build_prototype(peephole)?;
             proto.prototypes.push(child_proto);
         }
-        Rc::new(proto)
+        Ok(Rc::new(proto))
     }
 
     fn dump(&self, w: &mut impl Write) {
         writeln!(w, "fn {}", self.prototype.ident()).unwrap();
 
         let mut last_line = 0;
-        for (i, op) in self.prototype.op_codes().iter().enumerate() {
-            let line = self.prototype.line(i);
-            if last_line < line + 1 {
-                last_line = line + 1;
+        for (op, &line) in self.code.op_codes.iter().zip(&self.code.lines) {
+            if last_line < line {
+                last_line = line;
                 writeln!(w, "{last_line}:").unwrap();
             }
             writeln!(w, " {op}").unwrap();
@@ -179,7 +294,16 @@ pub struct Compiler<'a> {
     pub module_locals: Vec<String>,
     pub module_provider: &'a mut ModuleLoader,
     pub module_aliases: Vec<ModuleAlias>,
+    /// Modules imported with `import ... as`, by their alias.
+    module_renames: HashMap<String, usize>,
+    /// The fields of each record type.
+    records: HashMap<String, Vec<String>>,
+    /// The ident of the module being compiled.
+    module: String,
     line_no: usize,
+    /// The values of the module's consts, which are loaded directly wherever
+    /// they are used.
+    consts: HashMap<String, Value>,
 }
 
 impl<'a> Compiler<'a> {
@@ -190,7 +314,11 @@ impl<'a> Compiler<'a> {
             module_locals: Vec::new(),
             module_provider,
             module_aliases: Vec::new(),
+            module_renames: HashMap::new(),
+            records: HashMap::new(),
+            module: "<main>".to_string(),
             line_no: 1,
+            consts: HashMap::new(),
         }
     }
 
@@ -203,37 +331,98 @@ impl<'a> Compiler<'a> {
     }
 
     pub fn compile_module(mut self, ident: &str) -> Result<Module, CompilerError> {
+        self.module = ident.to_string();
         let mut statements = Vec::new();
+        let mut public = HashSet::new();
         self.add_local("<main>".to_string())?;
         self.module_locals.push("<main>".to_string());
         loop {
             let statement = self.parser.parse();
             match statement {
                 Ok(Statement::Import { .. }) => statements.push(statement.unwrap()),
-                Ok(Statement::Let { ref ident, .. }) => {
+                Ok(Statement::Let {
+                    ref ident,
+                    public: is_public,
+                    ..
+                })
+                | Ok(Statement::Function {
+                    ref ident,
+                    public: is_public,
+                    ..
+                }) => {
+                    if self.consts.contains_key(ident) {
+                        return Err(CompilerErrorKind::AssignmentToConst(ident.to_string()).into());
+                    }
                     self.add_local(ident.to_string())?;
+                    if is_public {
+                        public.insert(ident.to_string());
+                    }
                     statements.push(statement.unwrap());
                 }
-                Ok(Statement::Function { ref ident, .. }) => {
+                // Consts are folded right away so that the functions before
+                // them can load them as well.
+                Ok(Statement::Const {
+                    ref ident,
+                    ref value,
+                    public: is_public,
+                    ..
+                }) => {
+                    if self.state().resolver.resolve_local(ident).is_some() {
+                        return Err(CompilerErrorKind::AssignmentToConst(ident.to_string()).into());
+                    }
+                    let value = self.fold(value).map_err(|error| error.at(value.span))?;
+                    self.consts.insert(ident.to_string(), value);
                     self.add_local(ident.to_string())?;
+                    if is_public {
+                        public.insert(ident.to_string());
+                    }
+                    statements.push(statement.unwrap());
+                }
+                Ok(Statement::Type { ident, fields, .. }) => {
+                    self.records.insert(ident, fields);
+                }
+                Ok(Statement::LetTuple { ref idents, .. }) => {
+                    for ident in idents {
+                        if self.consts.contains_key(ident) {
+                            return Err(
+                                CompilerErrorKind::AssignmentToConst(ident.to_string()).into()
+                            );
+                        }
+                        self.add_local(ident.to_string())?;
+                    }
                     statements.push(statement.unwrap());
                 }
+                Ok(Statement::Expression { line_no, .. }) => {
+                    return Err(
+                        CompilerError::from(ParserError::TopLevelExpressionNotAllowed)
+                            .at_line(line_no),
+                    );
+                }
                 Err(ParserError::EndOfSource) => break,
-                Err(e) => return Err(CompilerError::ParserError(e)),
-                _ => unreachable!(),
+                Err(e) => return Err(CompilerError::from(e).at(self.parser.span())),
             }
         }
 
+        if self.module_provider.typecheck() {
+            typecheck::check(&statements).map_err(CompilerErrorKind::TypeErrors)?;
+        }
         for statement in statements {
-            self.module_statement(statement)?;
+            let line_no = statement.line_no();
+            self.module_statement(statement)
+                .map_err(|error| error.at_line(line_no))?;
         }
 
-        let prototype = self.state().build_prototype();
-        Ok(Module::new(
-            ident,
-            ModuleValue::Normal(prototype),
-            self.module_locals,
-        ))
+        let prototype = self
+            .state()
+            .build_prototype(self.module_provider.peephole())?;
+        let mut module = Module::new(ident, ModuleValue::Normal(prototype), self.module_locals);
+        // Modules without any `pub` local export all of them.
+        if !public.is_empty() {
+            module.private = (0..module.locals.len())
+                .filter(|&i| !public.contains(&module.locals[i]))
+                .collect();
+        }
+        Ok(module)
     }
 
     pub fn compile(&mut self) -> Result<(), CompilerError> {
@@ -248,13 +437,15 @@ impl<'a> Compiler<'a> {
                     break;
                 }
                 Err(err) => {
-                    return Err(CompilerError::ParserError(err));
+                    return Err(CompilerError::from(err).at(self.parser.span()));
                 }
             }
         }
         self.add_local("<main>".to_string())?;
         for statement in expressions {
-            self.module_statement(statement)?;
+            let line_no = statement.line_no();
+            self.module_statement(statement)
+                .map_err(|error| error.at_line(line_no))?;
         }
         Ok(())
     }
@@ -265,13 +456,19 @@ impl<'a> Compiler<'a> {
                 line_no,
                 ident,
                 value,
+                ..
             } => {
                 self.line_no = line_no;
+                let record = value.as_ref().and_then(|value| self.record_of(value));
                 if let Some(expression) = value {
                     self.expression(expression)?;
                 } else {
                     self.emit_code(OpCode::LoadUnit);
                 }
+                if let Some(record) = record {
+                    let local = self.state().resolver.resolve_local(&ident).unwrap();
+                    self.state_mut().records.insert(local, record);
+                }
                 self.module_locals.push(ident);
                 Ok(())
             }
@@ -279,41 +476,54 @@ impl<'a> Compiler<'a> {
                 line_no,
                 ident,
                 args,
+                arg_defaults,
                 expr,
+                ..
+            } => {
+                self.line_no = line_no;
+                self.function(ident.clone(), args, arg_defaults, expr, false)?;
+                self.module_locals.push(ident);
+                Ok(())
+            }
+            Statement::Const {
+                line_no,
+                ident,
+                value,
+                ..
             } => {
                 self.line_no = line_no;
-                self.function(ident.clone(), args, expr, false)?;
+                let value = match self.consts.get(&ident) {
+                    Some(value) => value.clone(),
+                    None => {
+                        let value = self.fold(&value)?;
+                        self.consts.insert(ident.clone(), value.clone());
+                        value
+                    }
+                };
+                self.constant(value)?;
                 self.module_locals.push(ident);
                 Ok(())
             }
+            Statement::LetTuple {
+                line_no,
+                idents,
+                value,
+            } => {
+                self.line_no = line_no;
+                self.unpack(value, idents.len())?;
+                self.module_locals.extend(idents);
+                Ok(())
+            }
+            Statement::Type { .. } => Ok(()),
             Statement::Import {
                 line_no,
                 source,
                 imports,
             } => {
                 self.line_no = line_no;
-                let module_index = match source {
-                    ImportSource::Module(_) => todo!(),
-                    ImportSource::File(filename) => self.module_provider.load_module(filename),
-                };
-
-                let module = self.module_provider.module_at(module_index).unwrap();
-
-                match imports.as_slice() {
-                    [Import::All { alias: None }] => {
-                        for (i, local) in module.locals.iter().enumerate() {
-                            self.module_aliases.push(ModuleAlias {
-                                ident: local.to_string(),
-                                module_index,
-                                local_index: i,
-                            });
-                        }
-                    }
-                    _ => {}
-                }
-                Ok(())
+                self.import(source, imports)
             }
-            _ => unreachable!(),
+            Statement::Expression { .. } => Err(ParserError::TopLevelExpressionNotAllowed.into()),
         }
     }
 
@@ -323,53 +533,69 @@ impl<'a> Compiler<'a> {
                 line_no,
                 ident,
                 value,
+                ..
             } => {
                 self.line_no = line_no;
-                if let Some(expression) = value {
-                    self.expression(expression)?;
-                } else {
-                    self.emit_code(OpCode::LoadUnit);
+                let record = value.as_ref().and_then(|value| self.record_of(value));
+                match value {
+                    // A lambda bound to a local is compiled as a function
+                    // with the local's name, so that it can call itself.
+                    Some(Expression {
+                        kind:
+                            ExpressionKind::Function {
+                                args,
+                                arg_defaults,
+                                expr,
+                                ..
+                            },
+                        ..
+                    }) => self.function(ident.clone(), args, arg_defaults, *expr, false)?,
+                    Some(expression) => self.expression(expression)?,
+                    None => self.emit_code(OpCode::LoadUnit),
+                }
+                let local = self.add_local(ident)?;
+                if let Some(record) = record {
+                    self.state_mut().records.insert(local, record);
                 }
-                self.add_local(ident)?;
                 Ok(())
             }
             Statement::Function {
                 line_no,
                 ident,
                 args,
+                arg_defaults,
                 expr,
+                ..
             } => {
                 self.line_no = line_no;
-                self.function(ident.clone(), args, expr, false)?;
+                self.function(ident.clone(), args, arg_defaults, expr, false)?;
                 self.add_local(ident)?;
                 Ok(())
             }
+            Statement::LetTuple {
+                line_no,
+                idents,
+                value,
+            } => {
+                self.line_no = line_no;
+                self.unpack(value, idents.len())?;
+                for ident in idents {
+                    self.add_local(ident)?;
+                }
+                Ok(())
+            }
+            Statement::Type { ident, fields, .. } => {
+                self.records.insert(ident, fields);
+                Ok(())
+            }
+            Statement::Const { .. } => Err(CompilerErrorKind::ConstOutsideModule.into()),
             Statement::Import {
                 line_no,
                 source,
                 imports,
             } => {
                 self.line_no = line_no;
-                let module_index = match source {
-                    ImportSource::Module(_) => todo!(),
-                    ImportSource::File(filename) => self.module_provider.load_module(filename),
-                };
-
-                let module = self.module_provider.module_at(module_index).unwrap();
-
-                match imports.as_slice() {
-                    [Import::All { alias: None }] => {
-                        for (i, local) in module.locals.iter().enumerate() {
-                            self.module_aliases.push(ModuleAlias {
-                                ident: local.to_string(),
-                                module_index,
-                                local_index: i,
-                            });
-                        }
-                    }
-                    _ => {}
-                }
-                Ok(())
+                self.import(source, imports)
             }
             Statement::Expression {
                 expression,
@@ -382,169 +608,386 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    /// Compiles `value` and unpacks it into `len` values on the stack.
+    fn unpack(&mut self, value: Expression, len: usize) -> Result<(), CompilerError> {
+        if len > u8::MAX as usize {
+            return Err(CompilerErrorKind::MaxNumberOfLocalsExceeded.into());
+        }
+        self.expression(value)?;
+        self.emit_code(OpCode::Unpack(len as u8));
+        Ok(())
+    }
+
+    fn import(&mut self, source: ImportSource, imports: Vec<Import>) -> Result<(), CompilerError> {
+        let module_index = match source {
+            ImportSource::Module(ident) => self
+                .module_provider
+                .module(&ident)
+                .ok_or(CompilerErrorKind::NameNotFound(ident))?,
+            ImportSource::File(filename) => self.module_provider.load_module(filename)?,
+        };
+
+        let module = self.module_provider.module_at(module_index).unwrap();
+
+        for import in imports {
+            match import {
+                Import::All { alias: None } => {
+                    for (i, local) in module.exports() {
+                        self.module_aliases.push(ModuleAlias {
+                            ident: local.to_string(),
+                            module_index,
+                            local_index: i,
+                        });
+                    }
+                }
+                Import::All { alias: Some(alias) } => {
+                    self.module_renames.insert(alias, module_index);
+                }
+                Import::Local { ident, alias } => {
+                    let local_index = module
+                        .export(&ident)
+                        .ok_or(CompilerErrorKind::NameNotFound(ident))?;
+                    self.module_aliases.push(ModuleAlias {
+                        ident: alias,
+                        module_index,
+                        local_index,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn expression(&mut self, expression: Expression) -> Result<(), CompilerError> {
-        match expression {
-            Expression::UnaryOperation { operand, operation } => {
+        if self.module_provider.fold_constants() && is_foldable(&expression) {
+            if let Ok(value) = self.fold(&expression) {
+                return self.constant(value);
+            }
+        }
+        let span = expression.span;
+        self.expression_kind(expression.kind)
+            .map_err(|error| error.at(span))
+    }
+
+    fn expression_kind(&mut self, kind: ExpressionKind) -> Result<(), CompilerError> {
+        match kind {
+            ExpressionKind::UnaryOperation { operand, operation } => {
                 self.expression(*operand)?;
                 match operation {
                     UnaryOperation::Not => self.emit_code(OpCode::Not),
                     UnaryOperation::Negate => self.emit_code(OpCode::Negate),
+                    UnaryOperation::BitNot => self.emit_code(OpCode::BitNot),
                 }
                 Ok(())
             }
-            Expression::Operation {
+            ExpressionKind::Operation {
                 lhs,
                 operation,
                 rhs,
             } => match operation {
                 Operation::Assignment => self.assignment(*lhs, *rhs),
                 Operation::Arithmetic(operator) => {
-                    self.expression(*lhs)?;
-                    self.expression(*rhs)?;
-                    self.compile_arithmetic_operator(operator);
-                    Ok(())
+                    let operator = match operator {
+                        ArithmeticOperator::Add => Operator::Add,
+                        ArithmeticOperator::Subtract => Operator::Subtract,
+                        ArithmeticOperator::Divide => Operator::Divide,
+                        ArithmeticOperator::IDivide => Operator::IDivide,
+                        ArithmeticOperator::Multiply => Operator::Multiply,
+                        ArithmeticOperator::Modulus => Operator::Modulus,
+                    };
+                    self.operation(operator, *lhs, *rhs)
                 }
                 Operation::Comparison(comparison) => {
-                    self.expression(*lhs)?;
-                    self.expression(*rhs)?;
-                    match comparison {
-                        ComparisonOperator::Less => self.emit_code(OpCode::CmpLess),
-                        ComparisonOperator::LessEqual => self.emit_code(OpCode::CmpLEq),
-                        ComparisonOperator::Equal => self.emit_code(OpCode::CmpEq),
-                        ComparisonOperator::NotEqual => self.emit_code(OpCode::CmpNEq),
-                        ComparisonOperator::GreaterEqual => self.emit_code(OpCode::CmpGEq),
-                        ComparisonOperator::Greater => self.emit_code(OpCode::CmpGreater),
-                    }
-                    Ok(())
+                    let operator = match comparison {
+                        ComparisonOperator::Less => Operator::Less,
+                        ComparisonOperator::LessEqual => Operator::LessEqual,
+                        ComparisonOperator::Equal => Operator::Equal,
+                        ComparisonOperator::NotEqual => Operator::NotEqual,
+                        ComparisonOperator::GreaterEqual => Operator::GreaterEqual,
+                        ComparisonOperator::Greater => Operator::Greater,
+                    };
+                    self.operation(operator, *lhs, *rhs)
                 }
                 Operation::Boolean(boolean) => {
-                    self.expression(*lhs)?;
+                    self.temporary(*lhs)?;
                     self.expression(*rhs)?;
+                    self.pop_temporaries(1);
                     match boolean {
                         BooleanOperator::And => self.emit_code(OpCode::CmpAnd),
                         BooleanOperator::Or => self.emit_code(OpCode::CmpOr),
                     }
                     Ok(())
                 }
+                Operation::Bitwise(bitwise) => {
+                    self.temporary(*lhs)?;
+                    self.expression(*rhs)?;
+                    self.pop_temporaries(1);
+                    match bitwise {
+                        BitwiseOperator::And => self.emit_code(OpCode::BitAnd),
+                        BitwiseOperator::Or => self.emit_code(OpCode::BitOr),
+                        BitwiseOperator::Xor => self.emit_code(OpCode::BitXor),
+                        BitwiseOperator::Shl => self.emit_code(OpCode::Shl),
+                        BitwiseOperator::Shr => self.emit_code(OpCode::Shr),
+                    }
+                    Ok(())
+                }
                 Operation::Concat => {
-                    self.expression(*lhs)?;
+                    self.temporary(*lhs)?;
                     self.expression(*rhs)?;
+                    self.pop_temporaries(1);
                     self.emit_code(OpCode::Concat);
                     Ok(())
                 }
+                Operation::Coalesce => {
+                    self.expression(*lhs)?;
+                    let jump = self.emit_jump(OpCode::JumpIfNotUnit(0));
+                    self.expression(*rhs)?;
+                    self.patch_jump(jump)?;
+                    Ok(())
+                }
+                Operation::Range { inclusive } => {
+                    self.temporary(*lhs)?;
+                    self.expression(*rhs)?;
+                    self.pop_temporaries(1);
+                    if inclusive {
+                        self.emit_code(OpCode::RangeInclusive);
+                    } else {
+                        self.emit_code(OpCode::Range);
+                    }
+                    Ok(())
+                }
             },
-            Expression::Array(array) => {
+            ExpressionKind::Array(array)
+                if array
+                    .iter()
+                    .any(|e| matches!(e.kind, ExpressionKind::Spread(_))) =>
+            {
+                self.spread_list(array)
+            }
+            ExpressionKind::Array(array) => {
                 let len = array.len();
-                if len > InitLen::MAX as usize {
-                    return Err(CompilerError::ListInitializerTooLong);
+                if len > u16::MAX as usize {
+                    return Err(CompilerErrorKind::ListInitializerTooLong.into());
                 }
                 for expression in array {
-                    self.expression(expression)?;
+                    self.temporary(expression)?;
                 }
-                self.emit_code(OpCode::CreateList(len as InitLen));
+                self.pop_temporaries(len);
+                self.emit_wide(OpCode::CreateList, OpCode::CreateListW, len);
                 Ok(())
             }
-            Expression::Table(table) => {
+            ExpressionKind::Tuple(tuple) => {
+                let len = tuple.len();
+                if len > u8::MAX as usize {
+                    return Err(CompilerErrorKind::ListInitializerTooLong.into());
+                }
+                for expression in tuple {
+                    self.temporary(expression)?;
+                }
+                self.pop_temporaries(len);
+                self.emit_code(OpCode::CreateTuple(len as u8));
+                Ok(())
+            }
+            ExpressionKind::Table(table) => {
                 let len = table.len();
-                if len > InitLen::MAX as usize {
-                    return Err(CompilerError::MapInitializerTooLong);
+                if len > u16::MAX as usize {
+                    return Err(CompilerErrorKind::MapInitializerTooLong.into());
                 }
                 for entry in table {
-                    self.expression(entry.key)?;
-                    self.expression(entry.value)?;
+                    self.temporary(entry.key)?;
+                    self.temporary(entry.value)?;
                 }
-                self.emit_code(OpCode::CreateTable(len as InitLen));
+                self.pop_temporaries(len * 2);
+                self.emit_wide(OpCode::CreateTable, OpCode::CreateTableW, len);
                 Ok(())
             }
-            Expression::Literal(literal) => self.literal(literal),
-            Expression::Block(block) => {
+            ExpressionKind::Literal(literal) => self.literal(literal),
+            ExpressionKind::Block(block) => {
                 self.begin_scope();
                 let block_len = block.len() - 1;
+                let functions: Vec<Option<String>> = block
+                    .iter()
+                    .map(|statement| match statement {
+                        Statement::Function { ident, .. } => Some(ident.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                // The functions of a run of function statements are all
+                // declared before any of them is compiled so that they can
+                // call each other.
+                let mut declared = HashMap::new();
                 for (i, statement) in block.into_iter().enumerate() {
-                    let is_expression = statement.is_expression();
-                    let pop = !matches!(
-                        statement,
-                        Statement::Expression {
-                            expression: Expression::Call { .. },
-                            ..
+                    let starts_run = i == 0 || functions[i - 1].is_none();
+                    if starts_run && functions[i].is_some() {
+                        let run: Vec<_> = functions[i..].iter().map_while(Option::as_ref).collect();
+                        if run.len() > 1 {
+                            for ident in run {
+                                if !declared.contains_key(ident) {
+                                    self.emit_code(OpCode::LoadUnit);
+                                    declared.insert(ident.clone(), self.add_local(ident.clone())?);
+                                }
+                            }
                         }
-                    );
+                    }
+                    let is_expression = statement.is_expression();
                     let is_assignment = matches!(
                         statement,
                         Statement::Expression {
-                            expression: Expression::Operation {
-                                operation: Operation::Assignment,
+                            expression: Expression {
+                                kind: ExpressionKind::Operation {
+                                    operation: Operation::Assignment,
+                                    ..
+                                },
                                 ..
                             },
                             ..
                         }
                     );
-                    self.statement(statement)?;
-                    if i < block_len && is_expression && pop && !is_assignment {
+                    let statement_line_no = statement.line_no();
+                    match statement {
+                        Statement::Function {
+                            line_no,
+                            ident,
+                            args,
+                            arg_defaults,
+                            expr,
+                            ..
+                        } if declared.contains_key(&ident) => {
+                            self.line_no = line_no;
+                            let local = declared.remove(&ident).unwrap();
+                            self.function(ident, args, arg_defaults, expr, false)?;
+                            self.emit_set(Variable::Local(local));
+                        }
+                        statement => self
+                            .statement(statement)
+                            .map_err(|error| error.at_line(statement_line_no))?,
+                    }
+                    if i < block_len && is_expression && !is_assignment {
                         self.emit_code(OpCode::Pop);
                     }
                     if i == block_len && is_assignment {
                         self.emit_code(OpCode::LoadUnit);
                     }
                 }
-                self.end_scope();
+                let num_locals = self.end_scope();
+                if num_locals > 0 {
+                    self.pop_scope(num_locals);
+                }
                 Ok(())
             }
-            Expression::Path { ident, parts } => {
+            ExpressionKind::Path { ident, parts } => {
+                self.check_field(&ident, &parts)?;
                 let mut getter = None;
-                if let Some((g, _)) = self.resolve_name(&ident) {
-                    self.emit_code(g);
-                    getter = Some(g);
+                if let Some(value) = self.resolve_const(&ident) {
+                    self.constant(value)?;
+                } else if let Some(variable) = self.resolve_name(&ident) {
+                    self.emit_get(variable);
                 } else if let Some(alias) = self.resolve_module_alias(&ident) {
                     self.emit_code(OpCode::GetModule(alias.module_index as u8));
                     self.constant(Value::Integer(alias.local_index as i64))?;
-                    self.emit_code(OpCode::GetTable);
+                    self.emit_get_field();
                 } else if let Some(module) = self.resolve_module(&ident) {
                     let opcode = OpCode::GetModule(module as u8);
                     self.emit_code(opcode);
                     getter = Some(opcode);
                 } else {
-                    return Err(CompilerError::NameNotFound(ident));
+                    return Err(CompilerErrorKind::NameNotFound(ident).into());
                 }
+                // The value being indexed stays under the index.
+                self.state_mut().resolver.add_temporary()?;
                 for part in parts {
                     match part {
-                        PathPart::Ident(ident) => match getter {
-                            Some(OpCode::GetModule(i)) => self.constant(Value::Integer(
-                                self.module_provider
-                                    .module_at(i as usize)
-                                    .unwrap()
-                                    .local(&ident)
-                                    .ok_or(CompilerError::NameNotFound(ident))?
-                                    as i64,
-                            ))?,
-                            _ => self.constant(Value::String(Rc::new(ident)))?,
-                        },
+                        PathPart::Ident(ident) => {
+                            match getter {
+                                Some(OpCode::GetModule(i)) => self.constant(Value::Integer(
+                                    self.module_provider
+                                        .module_at(i as usize)
+                                        .unwrap()
+                                        .export(&ident)
+                                        .ok_or(CompilerErrorKind::NameNotFound(ident))?
+                                        as i64,
+                                ))?,
+                                _ => self.constant(Value::String(Rc::new(ident)))?,
+                            }
+                            self.emit_get_field();
+                            continue;
+                        }
                         PathPart::Index(expression) => {
                             self.expression(expression)?;
                         }
+                        PathPart::Slice {
+                            start,
+                            end,
+                            inclusive,
+                        } => {
+                            for bound in [start, end] {
+                                match bound {
+                                    Some(bound) => self.expression(bound)?,
+                                    None => self.emit_code(OpCode::LoadUnit),
+                                }
+                                self.state_mut().resolver.add_temporary()?;
+                            }
+                            self.pop_temporaries(2);
+                            self.emit_code(if inclusive {
+                                OpCode::SliceInclusive
+                            } else {
+                                OpCode::Slice
+                            });
+                            continue;
+                        }
                     }
                     self.emit_code(OpCode::GetTable);
                 }
+                self.pop_temporaries(1);
                 Ok(())
             }
-            Expression::Call { callee, args } => {
-                self.expression(*callee)?;
+            ExpressionKind::Call { callee, mut args } => {
+                if args.len() == 1 && self.is_intrinsic(&callee, "len") {
+                    return self.len(args.pop().unwrap());
+                }
+                if args.len() == 1 && self.is_intrinsic(&callee, "throw") {
+                    self.expression(args.pop().unwrap())?;
+                    self.emit_code(OpCode::Throw);
+                    return Ok(());
+                }
+                if let Some(record) = self.record_constructor(&callee) {
+                    return self.record(record, args);
+                }
+                if let ExpressionKind::Method { receiver, method } = callee.kind {
+                    return self.method_call(*receiver, method, args);
+                }
+                self.temporary(*callee)?;
+                if args
+                    .iter()
+                    .any(|arg| matches!(arg.kind, ExpressionKind::Spread(_)))
+                {
+                    self.spread_list(args)?;
+                    self.pop_temporaries(1);
+                    self.emit_code(OpCode::CallSpread);
+                    return Ok(());
+                }
                 let num_args = args.len();
                 if num_args > u8::MAX as usize {
-                    return Err(CompilerError::MaxNumberOfArgsExceeded);
+                    return Err(CompilerErrorKind::MaxNumberOfArgsExceeded.into());
                 }
                 for arg in args {
-                    self.expression(arg)?;
+                    self.temporary(arg)?;
                 }
+                self.pop_temporaries(num_args + 1);
                 self.emit_code(OpCode::Call(num_args as u8));
                 Ok(())
             }
-            Expression::Function { args, expr } => {
-                let func_name = format!("<anonymous>");
-                self.function(func_name, args, *expr, true)?;
+            ExpressionKind::Function {
+                args,
+                arg_defaults,
+                expr,
+                ..
+            } => {
+                let func_name = "<anonymous>".to_string();
+                self.function(func_name, args, arg_defaults, *expr, true)?;
                 Ok(())
             }
-            Expression::If {
+            ExpressionKind::If {
                 condition,
                 block,
                 r#else,
@@ -553,83 +996,533 @@ impl<'a> Compiler<'a> {
                 let then_location = self.emit_jump(OpCode::JumpIfFalse(0));
                 self.expression(*block)?;
                 let else_location = self.emit_jump(OpCode::Jump(0));
-                self.patch_jump(then_location);
+                self.patch_jump(then_location)?;
                 if let Some(r#else) = r#else {
                     self.expression(*r#else)?;
                 } else {
                     self.emit_code(OpCode::LoadUnit);
                 }
-                self.patch_jump(else_location);
+                self.patch_jump(else_location)?;
                 Ok(())
             }
-            Expression::InterpolatedString { format, arguments } => {
-                self.constant(Value::String(Rc::new("format".to_string())))?;
-                self.constant(Value::String(Rc::new(format)))?;
-                let instruction = OpCode::CreateList(arguments.len() as u8);
-                self.constant(Value::String(Rc::new("args".to_string())))?;
-                for arg in arguments {
-                    self.constant(Value::String(Rc::new("arg".to_string())))?;
-                    self.expression(arg.expression)?;
-                    self.constant(Value::String(Rc::new("offset".to_string())))?;
-                    self.constant(Value::Integer(arg.offset as i64))?;
-                    self.emit_code(OpCode::CreateTable(2));
-                }
-                self.emit_code(instruction);
-                self.emit_code(OpCode::CreateTable(2));
+            ExpressionKind::While { condition, block } => {
+                let loop_start = self.state().code.len();
+                self.begin_loop(loop_start);
+                self.expression(*condition)?;
+                let exit_location = self.emit_jump(OpCode::JumpIfFalse(0));
+                self.expression(*block)?;
+                self.emit_code(OpCode::Pop);
+                self.emit_loop(loop_start)?;
+                self.patch_jump(exit_location)?;
+                self.end_loop()?;
+                self.emit_code(OpCode::LoadUnit);
                 Ok(())
             }
-        }
-    }
-
-    fn literal(&mut self, literal: Literal) -> Result<(), CompilerError> {
-        match literal {
-            Literal::Unit => self.constant(Value::Unit),
-            Literal::Bool(b) => self.constant(Value::Bool(b)),
-            Literal::Char(c) => self.constant(Value::Char(c)),
-            Literal::Integer(i) => self.constant(Value::Integer(i)),
-            Literal::Number(n) => self.constant(Value::Number(n)),
-            Literal::String(s) => self.constant(Value::String(Rc::new(s))),
-        }
-    }
-
-    fn constant(&mut self, value: Value) -> Result<(), CompilerError> {
-        let instruction = match value {
-            Value::Unit => OpCode::LoadUnit,
-            Value::Bool(b) => {
-                if b {
-                    OpCode::LoadTrue
-                } else {
-                    OpCode::LoadFalse
+            ExpressionKind::For {
+                ident,
+                iterable,
+                block,
+            } => {
+                // The iterator is kept in a hidden local below the loop
+                // variable.
+                self.begin_scope();
+                self.expression(*iterable)?;
+                self.emit_code(OpCode::IntoIter);
+                self.add_local("<iterator>".to_string())?;
+                let loop_start = self.state().code.len();
+                self.begin_loop(loop_start);
+                let exit_location = self.emit_jump(OpCode::ForIter(0));
+                self.begin_scope();
+                self.add_local(ident)?;
+                self.expression(*block)?;
+                self.emit_code(OpCode::Pop);
+                self.end_scope();
+                self.emit_code(OpCode::Pop);
+                self.emit_loop(loop_start)?;
+                self.patch_jump(exit_location)?;
+                self.end_loop()?;
+                self.emit_code(OpCode::LoadUnit);
+                let num_locals = self.end_scope();
+                self.pop_scope(num_locals);
+                Ok(())
+            }
+            ExpressionKind::Break => {
+                let (num_locals, num_tries) = match self.state().loops.last() {
+                    Some(loop_state) => (loop_state.num_locals, loop_state.num_tries),
+                    None => return Err(CompilerErrorKind::BreakOutsideLoop.into()),
+                };
+                self.end_tries(num_tries);
+                self.pop_locals(num_locals);
+                let location = self.emit_jump(OpCode::Jump(0));
+                self.state_mut()
+                    .loops
+                    .last_mut()
+                    .unwrap()
+                    .breaks
+                    .push(location);
+                Ok(())
+            }
+            ExpressionKind::Yield(value) => {
+                // Suspending a generator would leave its handlers behind.
+                if self.state().num_tries > 0 {
+                    return Err(CompilerErrorKind::YieldInsideTry.into());
                 }
+                self.state_mut().prototype.is_generator = true;
+                self.expression(*value)?;
+                self.emit_code(OpCode::Yield);
+                Ok(())
             }
-            Value::Integer(i) => {
-                if i <= u8::MAX as i64 {
-                    OpCode::LoadInt(i as u8)
-                } else {
-                    let index = self.add_constant(Value::Integer(i))?;
-                    OpCode::LoadConst(index)
+            ExpressionKind::Await(value) => {
+                self.expression(*value)?;
+                self.emit_code(OpCode::Await);
+                Ok(())
+            }
+            ExpressionKind::Spread(_) | ExpressionKind::Method { .. } => {
+                Err(CompilerErrorKind::UnexpectedExpression.into())
+            }
+            ExpressionKind::Continue => {
+                let (start, num_locals, num_tries) = match self.state().loops.last() {
+                    Some(loop_state) => (
+                        loop_state.start,
+                        loop_state.num_locals,
+                        loop_state.num_tries,
+                    ),
+                    None => return Err(CompilerErrorKind::ContinueOutsideLoop.into()),
+                };
+                self.end_tries(num_tries);
+                self.pop_locals(num_locals);
+                self.emit_loop(start)?;
+                Ok(())
+            }
+            ExpressionKind::Try {
+                block,
+                ident,
+                catch,
+            } => {
+                let catch_location = self.emit_jump(OpCode::Try(0));
+                self.state_mut().num_tries += 1;
+                self.expression(*block)?;
+                self.state_mut().num_tries -= 1;
+                self.emit_code(OpCode::EndTry);
+                let end_location = self.emit_jump(OpCode::Jump(0));
+                // The error is pushed where the stack was when the `try`
+                // started, which is where the local of the error goes.
+                self.patch_jump(catch_location)?;
+                self.begin_scope();
+                self.add_local(ident)?;
+                self.expression(*catch)?;
+                let num_locals = self.end_scope();
+                self.pop_scope(num_locals);
+                self.patch_jump(end_location)?;
+                Ok(())
+            }
+            ExpressionKind::Assert {
+                condition,
+                text,
+                message,
+            } => {
+                self.expression(*condition)?;
+                let fail_location = self.emit_jump(OpCode::JumpIfFalse(0));
+                self.emit_code(OpCode::LoadUnit);
+                let end_location = self.emit_jump(OpCode::Jump(0));
+                self.patch_jump(fail_location)?;
+                let mut error = format!("Assertion failed in `{}`: {text}", self.module);
+                if message.is_some() {
+                    error.push_str(": ");
+                }
+                let error = Value::String(self.module_provider.intern(&error));
+                self.constant(error)?;
+                if let Some(message) = message {
+                    self.state_mut().resolver.add_temporary()?;
+                    self.expression(*message)?;
+                    self.pop_temporaries(1);
+                    self.emit_code(OpCode::Concat);
                 }
+                self.emit_code(OpCode::Throw);
+                self.patch_jump(end_location)?;
+                Ok(())
             }
-            Value::Number(n) => {
-                let index = self.add_constant(Value::Number(n))?;
-                OpCode::LoadConst(index)
+            ExpressionKind::Match { value, arms } => {
+                // The value is kept in a hidden local that the arms compare
+                // against.
+                self.begin_scope();
+                self.expression(*value)?;
+                let value = self.add_local("<match>".to_string())?;
+                let mut end_locations = Vec::with_capacity(arms.len());
+                for arm in arms {
+                    // The pattern is tested before anything is bound, which
+                    // leaves nothing to pop when a test fails.
+                    let mut next_locations = Vec::new();
+                    let mut bindings = Vec::new();
+                    self.pattern(
+                        arm.pattern,
+                        value,
+                        &mut Vec::new(),
+                        &mut next_locations,
+                        &mut bindings,
+                    )?;
+                    let first_binding = self.state().resolver.num_locals();
+                    let num_bindings = bindings.len();
+                    if num_bindings > 0 {
+                        self.begin_scope();
+                        for (ident, path, rest) in bindings {
+                            self.load_pattern_value(value, &path)?;
+                            if let Some(rest) = rest {
+                                self.emit_code(OpCode::Rest(rest));
+                            }
+                            self.add_local(ident)?;
+                        }
+                    }
+                    let guard_location = match arm.guard {
+                        Some(guard) => {
+                            self.expression(guard)?;
+                            Some(self.emit_jump(OpCode::JumpIfFalse(0)))
+                        }
+                        None => None,
+                    };
+                    self.expression(arm.expression)?;
+                    let captured = (first_binding..first_binding + num_bindings)
+                        .find(|&i| self.state().resolver.local(i).is_captured);
+                    if num_bindings > 0 {
+                        let num_locals = self.end_scope();
+                        self.pop_scope(num_locals);
+                    }
+                    end_locations.push(self.emit_jump(OpCode::Jump(0)));
+                    // A failed guard still has the bindings to pop.
+                    if let Some(guard_location) = guard_location {
+                        self.patch_jump(guard_location)?;
+                        if let Some(captured) = captured {
+                            self.emit_wide(OpCode::CloseUpvalue, OpCode::CloseUpvalueW, captured);
+                        }
+                        for _ in 0..num_bindings {
+                            self.emit_code(OpCode::Pop);
+                        }
+                    }
+                    for location in next_locations {
+                        self.patch_jump(location)?;
+                    }
+                }
+                self.emit_code(OpCode::LoadUnit);
+                for location in end_locations {
+                    self.patch_jump(location)?;
+                }
+                let num_locals = self.end_scope();
+                self.pop_scope(num_locals);
+                Ok(())
             }
-            Value::String(s) => {
-                let index = self.add_constant(Value::String(s))?;
-                OpCode::LoadConst(index)
+            ExpressionKind::InterpolatedString { format, arguments } => {
+                let num_args = arguments.len();
+                if num_args > u8::MAX as usize {
+                    return Err(CompilerErrorKind::MaxNumberOfArgsExceeded.into());
+                }
+                let mut template = Vec::with_capacity(num_args * 2 + 1);
+                let mut position = 0;
+                for arg in arguments {
+                    let piece = format[position..position + arg.offset].to_string();
+                    position += arg.offset;
+                    template.push(Value::String(self.module_provider.intern(&piece)));
+                    template.push(match arg.spec {
+                        Some(spec) => Value::String(self.module_provider.intern(&spec)),
+                        None => Value::Unit,
+                    });
+                    self.temporary(arg.expression)?;
+                }
+                self.pop_temporaries(num_args);
+                let piece = format[position..].to_string();
+                template.push(Value::String(self.module_provider.intern(&piece)));
+                let template = self.module_provider.alloc_array(template);
+                let index = self.add_constant(Value::Array(template))?;
+                if index > ConstIdx::MAX as usize {
+                    self.emit_code(OpCode::FormatW(index as ConstIdx, num_args as u8));
+                    self.emit_code(OpCode::ExtraArg((index >> 8) as u8));
+                } else {
+                    self.emit_code(OpCode::Format(index as ConstIdx, num_args as u8));
+                }
+                Ok(())
             }
-            _ => return Err(CompilerError::NotAValidConstant),
-        };
-        self.emit_code(instruction);
-        Ok(())
+        }
     }
 
-    fn add_constant(&mut self, value: Value) -> Result<u8, CompilerError> {
+    /// Emits the tests of `pattern` on the part at `path` of the matched
+    /// value in the local `value`. The jumps taken when a test fails are
+    /// added to `fails`, and the bindings to `bindings` with their path and,
+    /// for rest patterns, the number of elements before the rest.
+    fn pattern(
+        &mut self,
+        pattern: Pattern,
+        value: usize,
+        path: &mut Vec<PatternStep>,
+        fails: &mut Vec<usize>,
+        bindings: &mut Vec<(String, Vec<PatternStep>, Option<u8>)>,
+    ) -> Result<(), CompilerError> {
+        match pattern {
+            Pattern::Wildcard => {}
+            Pattern::Binding(ident) => bindings.push((ident, path.clone(), None)),
+            Pattern::Literal(literal) => {
+                self.load_pattern_value(value, path)?;
+                self.literal(literal)?;
+                self.emit_code(OpCode::CmpEq);
+                fails.push(self.emit_jump(OpCode::JumpIfFalse(0)));
+            }
+            Pattern::Table(fields) => {
+                self.load_pattern_value(value, path)?;
+                self.emit_code(OpCode::IsTable);
+                fails.push(self.emit_jump(OpCode::JumpIfFalse(0)));
+                for (field, pattern) in fields {
+                    path.push(PatternStep::Field(field));
+                    self.pattern(pattern, value, path, fails, bindings)?;
+                    path.pop();
+                }
+            }
+            Pattern::Array { elements, rest } => {
+                if elements.len() > u8::MAX as usize {
+                    return Err(CompilerErrorKind::ListInitializerTooLong.into());
+                }
+                let len = elements.len() as u8;
+                self.load_pattern_value(value, path)?;
+                self.emit_code(if rest.is_some() {
+                    OpCode::HasMinLen(len)
+                } else {
+                    OpCode::HasLen(len)
+                });
+                fails.push(self.emit_jump(OpCode::JumpIfFalse(0)));
+                for (i, pattern) in elements.into_iter().enumerate() {
+                    path.push(PatternStep::Element(i));
+                    self.pattern(pattern, value, path, fails, bindings)?;
+                    path.pop();
+                }
+                if let Some(Pattern::Binding(ident)) = rest.map(|rest| *rest) {
+                    bindings.push((ident, path.clone(), Some(len)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the part at `path` of the matched value in the local `value`.
+    fn load_pattern_value(
+        &mut self,
+        value: usize,
+        path: &[PatternStep],
+    ) -> Result<(), CompilerError> {
+        self.emit_get(Variable::Local(value));
+        for step in path {
+            match step {
+                PatternStep::Field(field) => {
+                    let field = Value::String(self.module_provider.intern(field));
+                    self.constant(field)?;
+                }
+                PatternStep::Element(i) => self.constant(Value::Integer(*i as i64))?,
+            }
+            self.emit_code(OpCode::GetTable);
+        }
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: Literal) -> Result<(), CompilerError> {
+        match literal {
+            Literal::Unit => self.constant(Value::Unit),
+            Literal::Bool(b) => self.constant(Value::Bool(b)),
+            Literal::Char(c) => self.constant(Value::Char(c)),
+            Literal::Integer(i) => self.constant(Value::Integer(i)),
+            Literal::Number(n) => self.constant(Value::Number(n)),
+            Literal::String(s) => self.constant(Value::String(Rc::new(s))),
+        }
+    }
+
+    /// Returns the record type that `callee` constructs, if it names one
+    /// that isn't shadowed.
+    fn record_constructor(&mut self, callee: &Expression) -> Option<String> {
+        match &callee.kind {
+            ExpressionKind::Path { ident, parts }
+                if parts.is_empty() && self.records.contains_key(ident) =>
+            {
+                self.is_intrinsic(callee, ident).then(|| ident.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the record type that `value` constructs, if it does.
+    fn record_of(&mut self, value: &Expression) -> Option<String> {
+        match &value.kind {
+            ExpressionKind::Call { callee, .. } => self.record_constructor(callee),
+            _ => None,
+        }
+    }
+
+    /// Compiles `receiver:method args`, leaving the receiver and the method
+    /// name under the arguments for `CallMethod` to look the method up.
+    fn method_call(
+        &mut self,
+        receiver: Expression,
+        method: String,
+        args: Vec<Expression>,
+    ) -> Result<(), CompilerError> {
+        if args
+            .iter()
+            .any(|arg| matches!(arg.kind, ExpressionKind::Spread(_)))
+        {
+            return Err(CompilerErrorKind::UnexpectedExpression.into());
+        }
+        let num_args = args.len();
+        if num_args >= u8::MAX as usize {
+            return Err(CompilerErrorKind::MaxNumberOfArgsExceeded.into());
+        }
+        self.temporary(receiver)?;
+        let method = Value::String(self.module_provider.intern(&method));
+        self.constant(method)?;
+        self.state_mut().resolver.add_temporary()?;
+        for arg in args {
+            self.temporary(arg)?;
+        }
+        self.pop_temporaries(num_args + 2);
+        self.emit_code(OpCode::CallMethod(num_args as u8));
+        Ok(())
+    }
+
+    /// Compiles `Record { field: value }` as a table, after checking that
+    /// the table has exactly the fields of the record.
+    fn record(&mut self, record: String, mut args: Vec<Expression>) -> Result<(), CompilerError> {
+        let (
+            Some(Expression {
+                kind: ExpressionKind::Table(entries),
+                span,
+            }),
+            true,
+        ) = (args.pop(), args.is_empty())
+        else {
+            return Err(CompilerErrorKind::UnexpectedExpression.into());
+        };
+        let fields = &self.records[&record];
+        let mut found = HashSet::new();
+        for entry in &entries {
+            let field = match &entry.key.kind {
+                ExpressionKind::Literal(Literal::String(field)) => field,
+                _ => return Err(CompilerErrorKind::UnexpectedExpression.into()),
+            };
+            if !fields.contains(field) || !found.insert(field.as_str()) {
+                return Err(CompilerErrorKind::UnknownField {
+                    record,
+                    field: field.clone(),
+                }
+                .into());
+            }
+        }
+        if let Some(field) = fields.iter().find(|f| !found.contains(f.as_str())) {
+            return Err(CompilerErrorKind::MissingField {
+                record,
+                field: field.clone(),
+            }
+            .into());
+        }
+        self.expression(Expression::new(ExpressionKind::Table(entries), span))
+    }
+
+    /// Checks that the first field accessed on a local holding a record is
+    /// one of the record's fields.
+    fn check_field(&self, ident: &str, parts: &[PathPart]) -> Result<(), CompilerError> {
+        let Some(PathPart::Ident(field)) = parts.first() else {
+            return Ok(());
+        };
+        let mut state = Some(self.state.clone());
+        while let Some(current) = state {
+            let current = current.borrow();
+            if let Some(local) = current.resolver.resolve_local(ident) {
+                let Some(record) = current.records.get(&local) else {
+                    return Ok(());
+                };
+                if !self.records[record].contains(field) {
+                    return Err(CompilerErrorKind::UnknownField {
+                        record: record.clone(),
+                        field: field.clone(),
+                    }
+                    .into());
+                }
+                return Ok(());
+            }
+            state = current.parent.clone();
+        }
+        Ok(())
+    }
+
+    /// Returns whether `callee` names the intrinsic `ident`, which is the
+    /// case unless a local or a module with the same name shadows it.
+    fn is_intrinsic(&mut self, callee: &Expression, ident: &str) -> bool {
+        matches!(&callee.kind, ExpressionKind::Path { ident: name, parts } if name == ident && parts.is_empty())
+            && self.resolve_name(ident).is_none()
+            && self.resolve_module_alias(ident).is_none()
+            && self.resolve_module(ident).is_none()
+    }
+
+    /// Compiles `len value`. The length of a literal whose elements are all
+    /// literals is known at compile time and is loaded as a constant.
+    fn len(&mut self, value: Expression) -> Result<(), CompilerError> {
+        let is_literal = |e: &Expression| matches!(e.kind, ExpressionKind::Literal(_));
+        let len = match &value.kind {
+            ExpressionKind::Literal(Literal::String(string)) => Some(string.chars().count()),
+            ExpressionKind::Array(array) if array.iter().all(is_literal) => Some(array.len()),
+            ExpressionKind::Table(table)
+                if table
+                    .iter()
+                    .all(|entry| is_literal(&entry.key) && is_literal(&entry.value)) =>
+            {
+                // Later entries replace earlier ones with the same key.
+                let keys: Vec<_> = table.iter().map(|entry| &entry.key).collect();
+                Some(
+                    keys.iter()
+                        .enumerate()
+                        .filter(|(i, key)| !keys[..*i].contains(key))
+                        .count(),
+                )
+            }
+            _ => None,
+        };
+        match len {
+            Some(len) => self.constant(Value::Integer(len as i64)),
+            None => {
+                self.expression(value)?;
+                self.emit_code(OpCode::Len);
+                Ok(())
+            }
+        }
+    }
+
+    fn constant(&mut self, value: Value) -> Result<(), CompilerError> {
+        let index = match value {
+            Value::Unit => {
+                self.emit_code(OpCode::LoadUnit);
+                return Ok(());
+            }
+            Value::Bool(b) => {
+                self.emit_code(if b {
+                    OpCode::LoadTrue
+                } else {
+                    OpCode::LoadFalse
+                });
+                return Ok(());
+            }
+            Value::Integer(i) if (0..=u8::MAX as i64).contains(&i) => {
+                self.emit_code(OpCode::LoadInt(i as u8));
+                return Ok(());
+            }
+            Value::Integer(_) | Value::Number(_) | Value::Char(_) => self.add_constant(value)?,
+            Value::String(s) => {
+                let s = self.module_provider.intern(&s);
+                self.add_constant(Value::String(s))?
+            }
+            _ => return Err(CompilerErrorKind::NotAValidConstant.into()),
+        };
+        self.emit_wide(OpCode::LoadConst, OpCode::LoadConstW, index);
+        Ok(())
+    }
+
+    fn add_constant(&mut self, value: Value) -> Result<usize, CompilerError> {
         let index = self
             .state_mut()
             .prototype
             .add_constant(value)
-            .ok_or(CompilerError::MaxNumberOfConstsExceeded)?;
+            .ok_or(CompilerErrorKind::MaxNumberOfConstsExceeded)?;
         Ok(index)
     }
 
@@ -637,9 +1530,11 @@ impl<'a> Compiler<'a> {
         &mut self,
         ident: String,
         args: Vec<String>,
+        arg_defaults: Vec<Option<Expression>>,
         expression: Expression,
         is_anonymous: bool,
     ) -> Result<(), CompilerError> {
+        let line_no = self.line_no;
         let index = self.state().defined_states.len();
         let new_state = Rc::new(RefCell::new(
             CompilerState::new(ident.clone(), is_anonymous)
@@ -651,24 +1546,34 @@ impl<'a> Compiler<'a> {
         self.begin_scope();
         self.add_local(ident.clone())?;
 
-        if args.is_empty() {
-            self.add_local("".to_string())?;
+        // Each default value is pushed in place of its argument, in order,
+        // so a call that leaves some of them out starts at the first one
+        // missing.
+        let has_defaults = arg_defaults.iter().any(Option::is_some);
+        for (arg, default) in args.into_iter().zip(arg_defaults) {
+            if let Some(default) = default {
+                let entry = self.state().code.len();
+                self.state_mut().code.entries.push(entry);
+                self.expression(default)?;
+            }
+            self.add_local(arg)?;
             self.state_mut().prototype.num_args += 1;
-        } else {
-            for arg in args {
-                self.add_local(arg)?;
-                self.state_mut().prototype.num_args += 1;
-                if self.state_mut().prototype.num_args > u8::MAX as usize {
-                    return Err(CompilerError::MaxNumberOfArgsExceeded);
-                }
+            if self.state_mut().prototype.num_args > u8::MAX as usize {
+                return Err(CompilerErrorKind::MaxNumberOfArgsExceeded.into());
             }
         }
+        if has_defaults {
+            let entry = self.state().code.len();
+            self.state_mut().code.entries.push(entry);
+        }
 
         self.expression(expression)?;
         self.end_scope();
         self.emit_code(OpCode::Return);
+        mark_tail_calls(&mut self.state_mut().code.op_codes);
         let old_state = self.state().parent.clone().unwrap();
         self.state = old_state;
+        self.line_no = line_no;
         self.emit_code(OpCode::Closure(index as FunctionIdx));
 
         Ok(())
@@ -676,31 +1581,50 @@ impl<'a> Compiler<'a> {
 
     fn add_local(&mut self, ident: String) -> Result<usize, CompilerError> {
         let index = self.state_mut().resolver.add_local(ident)?;
+        self.state_mut().records.remove(&index);
         let local = self.state().resolver.local(index).clone();
         self.state_mut().prototype.add_local(local);
         Ok(index)
     }
 
+    /// Compiles `expression`, whose value stays on the stack under the
+    /// expressions compiled after it until `pop_temporaries` is called.
+    fn temporary(&mut self, expression: Expression) -> Result<(), CompilerError> {
+        self.expression(expression)?;
+        self.state_mut().resolver.add_temporary()
+    }
+
+    fn pop_temporaries(&mut self, n: usize) {
+        self.state_mut().resolver.remove_temporaries(n);
+    }
+
     fn assignment(&mut self, lhs: Expression, rhs: Expression) -> Result<(), CompilerError> {
-        match lhs {
-            Expression::Path { ident, parts } => {
-                let (getter, setter) = if let Some((g, s)) = self.resolve_name(&ident) {
-                    (g, s)
-                } else if let Some(module) = self.resolve_module(&ident) {
-                    (OpCode::GetModule(module as u8), None)
-                } else {
-                    return Err(CompilerError::NameNotFound(ident.to_string()));
-                };
+        let span = lhs.span;
+        match lhs.kind {
+            ExpressionKind::Path { ident, parts } => {
+                if self.resolve_const(&ident).is_some() {
+                    return Err(CompilerErrorKind::AssignmentToConst(ident).into());
+                }
+                self.check_field(&ident, &parts)?;
+                let variable = self.resolve_name(&ident);
+                let module = self.resolve_module(&ident);
+                if variable.is_none() && module.is_none() {
+                    return Err(CompilerErrorKind::NameNotFound(ident.to_string()).into());
+                }
 
                 if parts.is_empty() {
                     self.expression(rhs)?;
-                    if let Some(setter) = setter {
-                        self.emit_code(setter);
-                    } else {
-                        return Err(CompilerError::CannotSetTheValueOfAModule);
-                    }
+                    let Some(variable) = variable else {
+                        return Err(CompilerErrorKind::CannotSetTheValueOfAModule.into());
+                    };
+                    self.emit_set(variable);
                 } else {
-                    self.emit_code(getter);
+                    match (variable, module) {
+                        (Some(variable), _) => self.emit_get(variable),
+                        (None, Some(module)) => self.emit_code(OpCode::GetModule(module as u8)),
+                        (None, None) => unreachable!(),
+                    }
+                    self.state_mut().resolver.add_temporary()?;
                     let num_parts = parts.len();
                     for (i, part) in parts.into_iter().enumerate() {
                         match part {
@@ -710,38 +1634,68 @@ impl<'a> Compiler<'a> {
                             PathPart::Index(expression) => {
                                 self.expression(expression)?;
                             }
+                            // A slice is a new array, so setting its
+                            // elements would have no effect.
+                            PathPart::Slice { .. } => {
+                                return Err(CompilerErrorKind::UnexpectedExpression.into())
+                            }
                         }
                         if i < num_parts - 1 {
                             self.emit_code(OpCode::GetTable);
                         }
                     }
+                    self.state_mut().resolver.add_temporary()?;
                     self.expression(rhs)?;
+                    self.pop_temporaries(2);
                     self.emit_code(OpCode::SetTable);
                 }
             }
-            _ => unreachable!(),
+            _ => {
+                return Err(
+                    CompilerError::from(CompilerErrorKind::InvalidAssignmentTarget).at(span),
+                )
+            }
         }
         Ok(())
     }
 
-    fn resolve_name(&mut self, ident: &str) -> Option<(OpCode, Option<OpCode>)> {
-        let local = self.state().resolver.resolve_local(&ident);
+    fn resolve_name(&mut self, ident: &str) -> Option<Variable> {
+        let local = self.state().resolver.resolve_local(ident);
         if let Some(local) = local {
-            return Some((
-                OpCode::GetLocal(local as LocalIdx),
-                Some(OpCode::SetLocal(local as LocalIdx)),
-            ));
+            return Some(Variable::Local(local));
         }
 
-        let upvalue = self.resolve_upvalue(&ident, self.state.clone());
-        if let Some(index) = upvalue {
-            return Some((
-                OpCode::GetUpvalue(index as LocalIdx),
-                Some(OpCode::SetUpvalue(index as LocalIdx)),
-            ));
+        let upvalue = self.resolve_upvalue(ident, self.state.clone());
+        upvalue.map(Variable::Upvalue)
+    }
+
+    /// Pushes the value of a variable.
+    fn emit_get(&mut self, variable: Variable) {
+        match variable {
+            Variable::Local(slot) => self.emit_wide(OpCode::GetLocal, OpCode::GetLocalW, slot),
+            Variable::Upvalue(index) => {
+                self.emit_wide(OpCode::GetUpvalue, OpCode::GetUpvalueW, index)
+            }
         }
+    }
 
-        return None;
+    /// Pops the value on top into a variable.
+    fn emit_set(&mut self, variable: Variable) {
+        match variable {
+            Variable::Local(slot) => self.emit_wide(OpCode::SetLocal, OpCode::SetLocalW, slot),
+            Variable::Upvalue(index) => {
+                self.emit_wide(OpCode::SetUpvalue, OpCode::SetUpvalueW, index)
+            }
+        }
+    }
+
+    /// Pops `num_locals` locals from below the value on top.
+    fn pop_scope(&mut self, num_locals: usize) {
+        for chunk in (0..num_locals).step_by(u8::MAX as usize) {
+            self.emit_code(OpCode::PopScope(
+                (num_locals - chunk).min(u8::MAX as usize) as u8
+            ));
+        }
     }
 
     fn resolve_module_alias(&self, ident: &str) -> Option<ModuleAlias> {
@@ -752,7 +1706,88 @@ impl<'a> Compiler<'a> {
     }
 
     fn resolve_module(&self, ident: &str) -> Option<usize> {
-        self.module_provider.module(ident)
+        self.module_renames
+            .get(ident)
+            .copied()
+            .or_else(|| self.module_provider.module(ident))
+    }
+
+    /// The value of the const `ident`, unless a local of the function being
+    /// compiled, or of one around it, has the same name.
+    fn resolve_const(&self, ident: &str) -> Option<Value> {
+        let value = self.consts.get(ident)?;
+        let mut state = self.state.clone();
+        loop {
+            let parent = state.borrow().parent.clone();
+            // The outermost state is the module's, whose local is the const.
+            let Some(parent) = parent else {
+                return Some(value.clone());
+            };
+            if state.borrow().resolver.resolve_local(ident).is_some() {
+                return None;
+            }
+            state = parent;
+        }
+    }
+
+    /// Computes the value of a const, which can only be made of literals,
+    /// other consts and arithmetic, comparisons, `++`, `-` and `not` on them.
+    fn fold(&self, expression: &Expression) -> Result<Value, CompilerError> {
+        let value = match &expression.kind {
+            ExpressionKind::Literal(literal) => match literal {
+                Literal::Unit => Value::Unit,
+                Literal::Bool(bool) => Value::Bool(*bool),
+                Literal::Char(char) => Value::Char(*char),
+                Literal::Integer(int) => Value::Integer(*int),
+                Literal::Number(num) => Value::Number(*num),
+                Literal::String(string) => Value::String(Rc::new(string.clone())),
+            },
+            ExpressionKind::Path { ident, parts } if parts.is_empty() => self
+                .resolve_const(ident)
+                .ok_or(CompilerErrorKind::NotAValidConstant)?,
+            ExpressionKind::UnaryOperation { operand, operation } => {
+                match (operation, self.fold(operand)?) {
+                    (UnaryOperation::Negate, Value::Integer(int)) => int
+                        .checked_neg()
+                        .map(Value::Integer)
+                        .ok_or(CompilerErrorKind::NotAValidConstant)?,
+                    (UnaryOperation::Negate, Value::Number(num)) => Value::Number(-num),
+                    (UnaryOperation::Not, value) => Value::Bool(value.is_false()),
+                    _ => return Err(CompilerErrorKind::NotAValidConstant.into()),
+                }
+            }
+            ExpressionKind::Operation {
+                lhs,
+                operation: Operation::Arithmetic(operator),
+                rhs,
+            } => fold_arithmetic(operator, self.fold(lhs)?, self.fold(rhs)?)
+                .ok_or(CompilerErrorKind::NotAValidConstant)?,
+            ExpressionKind::Operation {
+                lhs,
+                operation: Operation::Comparison(comparison),
+                rhs,
+            } => {
+                let (lhs, rhs) = (self.fold(lhs)?, self.fold(rhs)?);
+                Value::Bool(match comparison {
+                    ComparisonOperator::Less => lhs < rhs,
+                    ComparisonOperator::LessEqual => lhs <= rhs,
+                    ComparisonOperator::Equal => lhs.numeric_eq(&rhs),
+                    ComparisonOperator::NotEqual => !lhs.numeric_eq(&rhs),
+                    ComparisonOperator::GreaterEqual => lhs >= rhs,
+                    ComparisonOperator::Greater => lhs > rhs,
+                })
+            }
+            ExpressionKind::Operation {
+                lhs,
+                operation: Operation::Concat,
+                rhs,
+            } => match (self.fold(lhs)?, self.fold(rhs)?) {
+                (Value::String(lhs), rhs) => Value::String(Rc::new(format!("{lhs}{rhs}"))),
+                _ => return Err(CompilerErrorKind::NotAValidConstant.into()),
+            },
+            _ => return Err(CompilerErrorKind::NotAValidConstant.into()),
+        };
+        Ok(value)
     }
 
     fn resolve_upvalue(&self, ident: &str, state: Rc<RefCell<CompilerState>>) -> Option<usize> {
@@ -791,14 +1826,66 @@ impl<'a> Compiler<'a> {
         }
     }
 
-    fn compile_arithmetic_operator(&mut self, operator: ArithmeticOperator) {
-        match operator {
-            ArithmeticOperator::Add => self.emit_code(OpCode::Add),
-            ArithmeticOperator::Subtract => self.emit_code(OpCode::Subtract),
-            ArithmeticOperator::Divide => self.emit_code(OpCode::Divide),
-            ArithmeticOperator::IDivide => self.emit_code(OpCode::IDivide),
-            ArithmeticOperator::Multiply => self.emit_code(OpCode::Multiply),
-            ArithmeticOperator::Modulus => self.emit_code(OpCode::Modulus),
+    /// Compiles an arithmetic operation or a comparison. Operands that are
+    /// locals or small integers are read in place by the fused op codes
+    /// rather than pushed, unless the `stack-vm` feature is enabled. A local
+    /// on the left is only read in place if the right side is too, since
+    /// evaluating the right side could change it.
+    fn operation(
+        &mut self,
+        operator: Operator,
+        lhs: Expression,
+        rhs: Expression,
+    ) -> Result<(), CompilerError> {
+        let operands = if cfg!(feature = "stack-vm") {
+            None
+        } else {
+            self.operand(&rhs).map(|rhs| (self.operand(&lhs), rhs))
+        };
+        let op_code = match operands {
+            Some((Some(Operand::Local(lhs)), Operand::Local(rhs))) => {
+                OpCode::OpLocals(operator, lhs, rhs)
+            }
+            Some((Some(Operand::Local(lhs)), Operand::Int(rhs))) => {
+                OpCode::OpLocalInt(operator, lhs, rhs)
+            }
+            Some((_, Operand::Local(rhs))) => {
+                self.expression(lhs)?;
+                OpCode::OpLocal(operator, rhs)
+            }
+            Some((_, Operand::Int(rhs))) => {
+                self.expression(lhs)?;
+                OpCode::OpInt(operator, rhs)
+            }
+            None => {
+                self.temporary(lhs)?;
+                self.expression(rhs)?;
+                self.pop_temporaries(1);
+                operator.op_code()
+            }
+        };
+        self.emit_code(op_code);
+        Ok(())
+    }
+
+    /// How a fused op code can read an expression in place, if it can.
+    fn operand(&self, expression: &Expression) -> Option<Operand> {
+        let value = match &expression.kind {
+            ExpressionKind::Literal(Literal::Integer(i)) => Value::Integer(*i),
+            ExpressionKind::Path { ident, parts } if parts.is_empty() => {
+                match self.resolve_const(ident) {
+                    Some(value) => value,
+                    None => {
+                        let slot = self.state().resolver.resolve_local(ident)?;
+                        return Some(Operand::Local(u8::try_from(slot).ok()?));
+                    }
+                }
+            }
+            _ => return None,
+        };
+        match value {
+            Value::Integer(i) => u8::try_from(i).ok().map(Operand::Int),
+            _ => None,
         }
     }
 
@@ -811,46 +1898,174 @@ impl<'a> Compiler<'a> {
         let num_locals = self.state().resolver.num_locals();
         for i in 0..size {
             if self.state().resolver.locals[num_locals - 1 - i].is_captured {
-                self.emit_code(OpCode::CloseUpvalue((num_locals - 1 - i) as u8));
+                let slot = num_locals - 1 - i;
+                self.emit_wide(OpCode::CloseUpvalue, OpCode::CloseUpvalueW, slot);
             }
         }
         self.state_mut().resolver.end_scope();
         size
     }
 
+    /// Emits `op_code`, or `wide` with the high byte of `arg` as an extra
+    /// argument when `arg` doesn't fit in a byte.
+    fn emit_wide(&mut self, op_code: fn(u8) -> OpCode, wide: fn(u8) -> OpCode, arg: usize) {
+        if arg > u8::MAX as usize {
+            self.emit_code(wide(arg as u8));
+            self.emit_code(OpCode::ExtraArg((arg >> 8) as u8));
+        } else {
+            self.emit_code(op_code(arg as u8));
+        }
+    }
+
+    /// Collects `elements` into a new array. Runs of plain elements are
+    /// created as lists and spread elements extend the array with their
+    /// own elements.
+    fn spread_list(&mut self, elements: Vec<Expression>) -> Result<(), CompilerError> {
+        let mut pending = 0;
+        let mut started = false;
+        for element in elements {
+            if let Expression {
+                kind: ExpressionKind::Spread(array),
+                ..
+            } = element
+            {
+                if pending > 0 || !started {
+                    self.pop_temporaries(pending);
+                    self.flush_list(pending, started);
+                    if !started {
+                        self.state_mut().resolver.add_temporary()?;
+                    }
+                    started = true;
+                    pending = 0;
+                }
+                self.expression(*array)?;
+                self.emit_code(OpCode::Extend);
+            } else {
+                if pending == u16::MAX as usize {
+                    return Err(CompilerErrorKind::ListInitializerTooLong.into());
+                }
+                self.temporary(element)?;
+                pending += 1;
+            }
+        }
+        self.pop_temporaries(pending + started as usize);
+        if pending > 0 {
+            self.flush_list(pending, started);
+        }
+        Ok(())
+    }
+
+    fn flush_list(&mut self, len: usize, started: bool) {
+        self.emit_wide(OpCode::CreateList, OpCode::CreateListW, len);
+        if started {
+            self.emit_code(OpCode::Extend);
+        }
+    }
+
+    /// Emits a `GetField` with a cache of its own, or a `GetTable` once the
+    /// function has given out all the caches that a `GetField` can refer to.
+    fn emit_get_field(&mut self) {
+        let cache = self.state().num_field_caches;
+        if cache > u8::MAX as usize {
+            self.emit_code(OpCode::GetTable);
+            return;
+        }
+        self.state_mut().num_field_caches += 1;
+        self.emit_code(OpCode::GetField(cache as u8));
+    }
+
     fn emit_jump(&mut self, op_code: OpCode) -> usize {
-        let index = self.state().prototype.code.len();
+        let index = self.state().code.len();
         self.emit_code(op_code);
         self.emit_code(OpCode::ExtraArg(0));
         index
     }
 
-    fn patch_jump(&mut self, index: usize) {
-        let len = self.state().prototype.code.len() - 2 - index;
+    fn begin_loop(&mut self, start: usize) {
+        let num_locals = self.state().resolver.num_locals();
+        let num_tries = self.state().num_tries;
+        self.state_mut().loops.push(LoopState {
+            start,
+            num_locals,
+            breaks: Vec::new(),
+            num_tries,
+        });
+    }
+
+    /// Patches the `break`s of the innermost loop to jump here.
+    fn end_loop(&mut self) -> Result<(), CompilerError> {
+        let loop_state = self.state_mut().loops.pop().unwrap();
+        for location in loop_state.breaks {
+            self.patch_jump(location)?;
+        }
+        Ok(())
+    }
+
+    /// Emits the code popping the locals above the first `num_locals`,
+    /// without removing them from the scope.
+    fn pop_locals(&mut self, num_locals: usize) {
+        let total = self.state().resolver.num_locals();
+        let captured = (num_locals..total).find(|i| self.state().resolver.local(*i).is_captured);
+        if let Some(captured) = captured {
+            self.emit_wide(OpCode::CloseUpvalue, OpCode::CloseUpvalueW, captured);
+        }
+        for _ in num_locals..total {
+            self.emit_code(OpCode::Pop);
+        }
+    }
+
+    /// Ends the `try` blocks that a `break` or `continue` jumps out of.
+    fn end_tries(&mut self, num_tries: usize) {
+        let total = self.state().num_tries;
+        for _ in num_tries..total {
+            self.emit_code(OpCode::EndTry);
+        }
+    }
+
+    /// Emits a jump back to `loop_start`.
+    fn emit_loop(&mut self, loop_start: usize) -> Result<(), CompilerError> {
+        let offset = self.state().code.len() + 2 - loop_start;
+        if offset > u16::MAX as usize {
+            return Err(CompilerErrorKind::JumpTooLong.into());
+        }
+        self.emit_code(OpCode::Loop(offset as u8));
+        self.emit_code(OpCode::ExtraArg((offset >> 8) as u8));
+        Ok(())
+    }
+
+    fn patch_jump(&mut self, index: usize) -> Result<(), CompilerError> {
+        let len = self.state().code.len() - 2 - index;
+        if len > u16::MAX as usize {
+            return Err(CompilerErrorKind::JumpTooLong.into());
+        }
         {
-            let code = &mut self.state_mut().prototype.code[index];
+            let code = &mut self.state_mut().code.op_codes[index];
             match code {
                 OpCode::Jump(ref mut index) => {
                     *index = len as u8;
                 }
-                OpCode::JumpIfFalse(ref mut index) => {
+                OpCode::JumpIfFalse(ref mut index)
+                | OpCode::JumpIfNotUnit(ref mut index)
+                | OpCode::ForIter(ref mut index)
+                | OpCode::Try(ref mut index) => {
                     *index = len as u8;
                 }
                 _ => unreachable!(),
             }
         }
-        let arg = &mut self.state_mut().prototype.code[index + 1];
+        let arg = &mut self.state_mut().code.op_codes[index + 1];
         match arg {
             OpCode::ExtraArg(ref mut arg) => {
                 *arg = (len >> 8) as u8;
             }
             _ => unreachable!(),
         }
+        Ok(())
     }
 
     fn emit_code(&mut self, op_code: OpCode) {
         let line = self.line_no;
-        self.state_mut().prototype.push_op_code(op_code, line);
+        self.state_mut().code.push(op_code, line);
     }
 
     pub fn dump(&self) -> String {
@@ -863,8 +2078,343 @@ impl<'a> Compiler<'a> {
     }
 }
 
+/// Turns the calls after which the function returns, possibly after
+/// leaving some scopes or jumping to the end of an `if` or `match`, into tail
+/// calls.
+fn mark_tail_calls(code: &mut [OpCode]) {
+    for i in 0..code.len() {
+        let OpCode::Call(num_args) = code[i] else {
+            continue;
+        };
+        let mut next = i + 1;
+        let is_tail_call = loop {
+            match code.get(next) {
+                Some(OpCode::Return) => break true,
+                Some(OpCode::PopScope(_) | OpCode::CloseUpvalue(_)) => next += 1,
+                Some(OpCode::CloseUpvalueW(_)) => next += 2,
+                Some(OpCode::Jump(low)) => {
+                    let Some(OpCode::ExtraArg(high)) = code.get(next + 1) else {
+                        break false;
+                    };
+                    next += 2 + ((*high as usize) << 8 | *low as usize);
+                }
+                _ => break false,
+            }
+        };
+        if is_tail_call {
+            code[i] = OpCode::TailCall(num_args);
+        }
+    }
+}
+
+/// Where the op code at `index` jumps to, if it is a jump.
+fn jump_target(code: &[OpCode], index: usize) -> Option<usize> {
+    let offset = |low: u8| match code.get(index + 1) {
+        Some(OpCode::ExtraArg(high)) => (*high as usize) << 8 | low as usize,
+        _ => unreachable!(),
+    };
+    match code[index] {
+        OpCode::Jump(low)
+        | OpCode::JumpIfFalse(low)
+        | OpCode::JumpIfNotUnit(low)
+        | OpCode::ForIter(low)
+        | OpCode::Try(low) => Some(index + 2 + offset(low)),
+        OpCode::Loop(low) => Some(index + 2 - offset(low)),
+        _ => None,
+    }
+}
+
+/// Removes the code that can't be reached and sequences of op codes that
+/// have no effect, until there is nothing left to remove.
+fn optimize(code: &mut Code) {
+    loop {
+        let unreachable = unreachable_op_codes(code);
+        let removed_unreachable = remove_op_codes(code, &unreachable);
+        let redundant = redundant_op_codes(code);
+        if !remove_op_codes(code, &redundant) && !removed_unreachable {
+            break;
+        }
+    }
+}
+
+/// Whether each op code, and the end of the code, is jumped to or is an
+/// entry of the prototype.
+fn jump_targets(code: &Code) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for index in 0..code.len() {
+        if let Some(target) = jump_target(&code.op_codes, index) {
+            targets[target] = true;
+        }
+    }
+    for &entry in &code.entries {
+        targets[entry] = true;
+    }
+    targets
+}
+
+/// Finds the op codes that can't be reached from any entry of the
+/// prototype, like a branch after a jump or anything after a `Return`.
+fn unreachable_op_codes(code: &Code) -> Vec<bool> {
+    let entries = &code.entries;
+    let code = &code.op_codes;
+    let mut unreachable = vec![true; code.len()];
+    let mut pending = vec![0];
+    pending.extend_from_slice(entries);
+    while let Some(index) = pending.pop() {
+        if index >= code.len() || !unreachable[index] {
+            continue;
+        }
+        unreachable[index] = false;
+        let falls_through = !matches!(
+            code[index],
+            OpCode::Return | OpCode::Throw | OpCode::Jump(_) | OpCode::Loop(_)
+        );
+        if let Some(target) = jump_target(code, index) {
+            unreachable[index + 1] = false;
+            pending.push(target);
+            if falls_through {
+                pending.push(index + 2);
+            }
+        } else if falls_through {
+            pending.push(index + 1);
+        }
+    }
+    unreachable
+}
+
+/// Finds sequences of op codes that have no effect: a `LoadTrue` followed
+/// by a `JumpIfFalse`, a `GetLocal` followed by a `SetLocal` of the same
+/// slot, a `LoadUnit` followed by a `Pop` and a jump to the next op code. A
+/// `LoadFalse` followed by a `JumpIfFalse` is turned into a `Jump`. A
+/// sequence is left alone if anything jumps into the middle of it.
+fn redundant_op_codes(code: &mut Code) -> Vec<bool> {
+    let targets = jump_targets(code);
+    let code = &mut code.op_codes;
+    let mut redundant = vec![false; code.len()];
+    let mut index = 0;
+    while index < code.len() {
+        let len = match (code[index], code.get(index + 1)) {
+            (OpCode::LoadTrue, Some(OpCode::JumpIfFalse(_))) => 3,
+            (OpCode::LoadFalse, Some(OpCode::JumpIfFalse(low))) if !targets[index + 1] => {
+                code[index + 1] = OpCode::Jump(*low);
+                1
+            }
+            (OpCode::GetLocal(get), Some(OpCode::SetLocal(set))) if get == *set => 2,
+            (OpCode::LoadUnit, Some(OpCode::Pop)) => 2,
+            (OpCode::Jump(0), Some(OpCode::ExtraArg(0))) => 2,
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+        if targets[index + 1..index + len].iter().any(|&target| target) {
+            index += 1;
+            continue;
+        }
+        redundant[index..index + len].fill(true);
+        index += len;
+    }
+    redundant
+}
+
+/// Removes the op codes marked in `removed`, moving the jumps, entries and
+/// lines to match the code that's left. Anything that jumped to a removed
+/// op code jumps to the next one that is left instead. Returns whether any
+/// op code was removed.
+fn remove_op_codes(code: &mut Code, removed: &[bool]) -> bool {
+    if !removed.contains(&true) {
+        return false;
+    }
+    let lines = &code.lines;
+    let op_codes = &code.op_codes;
+
+    // The index of each op code in the new code, or of the one after it if
+    // it is removed.
+    let mut new_indices = Vec::with_capacity(op_codes.len() + 1);
+    let mut new_index = 0;
+    for &is_removed in removed {
+        new_indices.push(new_index);
+        if !is_removed {
+            new_index += 1;
+        }
+    }
+    new_indices.push(new_index);
+
+    let mut new_code = Vec::with_capacity(new_index);
+    let mut new_lines = Vec::with_capacity(new_index);
+    let mut index = 0;
+    while index < op_codes.len() {
+        if removed[index] {
+            index += 1;
+            continue;
+        }
+        let Some(target) = jump_target(op_codes, index) else {
+            new_code.push(op_codes[index]);
+            new_lines.push(lines[index]);
+            index += 1;
+            continue;
+        };
+        let (from, to) = (new_indices[index] + 2, new_indices[target]);
+        let mut op_code = op_codes[index];
+        let offset = match &mut op_code {
+            OpCode::Loop(low) => {
+                *low = (from - to) as u8;
+                from - to
+            }
+            OpCode::Jump(low)
+            | OpCode::JumpIfFalse(low)
+            | OpCode::JumpIfNotUnit(low)
+            | OpCode::ForIter(low)
+            | OpCode::Try(low) => {
+                *low = (to - from) as u8;
+                to - from
+            }
+            _ => unreachable!(),
+        };
+        new_code.push(op_code);
+        new_code.push(OpCode::ExtraArg((offset >> 8) as u8));
+        new_lines.extend_from_slice(&lines[index..index + 2]);
+        index += 2;
+    }
+
+    for entry in &mut code.entries {
+        *entry = new_indices[*entry];
+    }
+    code.op_codes = new_code;
+    code.lines = new_lines;
+    true
+}
+
+/// Encodes the code into the bytes of the prototype, turning the jumps and
+/// entries into byte offsets. A jump that is within range when counting op
+/// codes might not be when counting bytes.
+fn assemble(code: &Code, proto: &mut Prototype) -> Result<(), CompilerError> {
+    let mut positions = Vec::with_capacity(code.len() + 1);
+    let mut position = 0;
+    for op_code in &code.op_codes {
+        positions.push(position);
+        position += op_code.size();
+    }
+    positions.push(position);
+
+    let mut bytes = Vec::with_capacity(position);
+    let mut lines = Vec::with_capacity(position);
+    let mut emit = |op_code: OpCode, line: usize| {
+        op_code.encode(&mut bytes);
+        lines.resize(bytes.len(), line);
+    };
+    let mut index = 0;
+    while index < code.len() {
+        let mut op_code = code.op_codes[index];
+        let line = code.lines[index];
+        let Some(target) = jump_target(&code.op_codes, index) else {
+            emit(op_code, line);
+            index += 1;
+            continue;
+        };
+        let offset = positions[index + 2].abs_diff(positions[target]);
+        if offset > u16::MAX as usize {
+            return Err(CompilerError::from(CompilerErrorKind::JumpTooLong).at_line(line));
+        }
+        match &mut op_code {
+            OpCode::Jump(low)
+            | OpCode::JumpIfFalse(low)
+            | OpCode::JumpIfNotUnit(low)
+            | OpCode::ForIter(low)
+            | OpCode::Try(low)
+            | OpCode::Loop(low) => *low = offset as u8,
+            _ => unreachable!(),
+        }
+        emit(op_code, line);
+        emit(OpCode::ExtraArg((offset >> 8) as u8), code.lines[index + 1]);
+        index += 2;
+    }
+
+    proto.entries = code.entries.iter().map(|&entry| positions[entry]).collect();
+    proto.code = bytes.into();
+    proto.debug_info.lines = lines;
+    proto.init_field_caches();
+    Ok(())
+}
+
+/// Whether an expression is an operation that [`Compiler::fold`] might turn
+/// into a single constant.
+fn is_foldable(expression: &Expression) -> bool {
+    matches!(
+        expression.kind,
+        ExpressionKind::UnaryOperation {
+            operation: UnaryOperation::Negate | UnaryOperation::Not,
+            ..
+        } | ExpressionKind::Operation {
+            operation: Operation::Arithmetic(_) | Operation::Comparison(_) | Operation::Concat,
+            ..
+        }
+    )
+}
+
+/// Applies an arithmetic operator to two const values the way the VM would,
+/// or `None` if the VM would fail on them.
+fn fold_arithmetic(operator: &ArithmeticOperator, lhs: Value, rhs: Value) -> Option<Value> {
+    let value = match (lhs, rhs) {
+        (Value::Integer(l), Value::Integer(r)) => Value::Integer(match operator {
+            ArithmeticOperator::Add => l.checked_add(r)?,
+            ArithmeticOperator::Subtract => l.checked_sub(r)?,
+            ArithmeticOperator::Multiply => l.checked_mul(r)?,
+            ArithmeticOperator::Divide | ArithmeticOperator::IDivide => l.checked_div(r)?,
+            ArithmeticOperator::Modulus => l.checked_rem(r)?,
+        }),
+        (
+            lhs @ (Value::Integer(_) | Value::Number(_)),
+            rhs @ (Value::Integer(_) | Value::Number(_)),
+        ) => {
+            let as_number = |value| match value {
+                Value::Integer(int) => int as f64,
+                Value::Number(num) => num,
+                _ => unreachable!(),
+            };
+            let (l, r) = (as_number(lhs), as_number(rhs));
+            match operator {
+                ArithmeticOperator::Add => Value::Number(l + r),
+                ArithmeticOperator::Subtract => Value::Number(l - r),
+                ArithmeticOperator::Multiply => Value::Number(l * r),
+                ArithmeticOperator::Divide => Value::Number(l / r),
+                ArithmeticOperator::IDivide => Value::Integer((l as i64).checked_div(r as i64)?),
+                ArithmeticOperator::Modulus => Value::Number(l % r),
+            }
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// An error in a module, with where in its source it is if that's known.
 #[derive(Debug)]
-pub enum CompilerError {
+pub struct CompilerError {
+    pub kind: CompilerErrorKind,
+    pub span: Option<Span>,
+}
+
+impl CompilerError {
+    /// Locates the error at `span`, unless it already points at a more
+    /// precise place. Spans of nodes made up by the compiler are ignored.
+    pub fn at(mut self, span: Span) -> Self {
+        if self.span.is_none() && span.line > 0 {
+            self.span = Some(span);
+        }
+        self
+    }
+
+    /// Locates the error at a whole line, like [`CompilerError::at`].
+    pub fn at_line(self, line: usize) -> Self {
+        self.at(Span {
+            line,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum CompilerErrorKind {
     ParserError(ParserError),
     MaxNumberOfConstsExceeded,
     NotImplemented,
@@ -878,36 +2428,129 @@ pub enum CompilerError {
     MaxNumberOfArgsExceeded,
     NotAValidConstant,
     CannotSetTheValueOfAModule,
+    CannotReloadModule(usize),
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    ModuleError(ModuleError),
+    BinaryError(BinaryError),
+    /// The paths of the modules that import each other, starting and
+    /// ending with the same module.
+    CircularImport(Vec<String>),
+    UnknownField {
+        record: String,
+        field: String,
+    },
+    MissingField {
+        record: String,
+        field: String,
+    },
+    TypeErrors(Vec<TypeError>),
+    YieldInsideTry,
+    AssignmentToConst(String),
+    /// The left side of an `=` that isn't a name or a path into one.
+    InvalidAssignmentTarget,
+    /// A `const` statement given to [`Compiler::statement`], since consts
+    /// only exist in modules.
+    ConstOutsideModule,
+    /// A jump over more code than fits in its 16-bit offset.
+    JumpTooLong,
+}
+
+impl From<CompilerErrorKind> for CompilerError {
+    fn from(kind: CompilerErrorKind) -> Self {
+        Self { kind, span: None }
+    }
 }
 
 impl From<ParserError> for CompilerError {
     fn from(value: ParserError) -> Self {
-        Self::ParserError(value)
+        CompilerErrorKind::ParserError(value).into()
+    }
+}
+
+impl From<ModuleError> for CompilerError {
+    fn from(value: ModuleError) -> Self {
+        CompilerErrorKind::ModuleError(value).into()
+    }
+}
+
+impl From<BinaryError> for CompilerError {
+    fn from(value: BinaryError) -> Self {
+        CompilerErrorKind::BinaryError(value).into()
     }
 }
 
 impl Error for CompilerError {}
 
 impl Display for CompilerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.span {
+            Some(span) if span.column > 0 => write!(f, "line {}:{}: ", span.line, span.column)?,
+            Some(span) => write!(f, "line {}: ", span.line)?,
+            None => {}
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for CompilerErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            CompilerError::ParserError(e) => write!(f, "Parser error: {e}"),
-            CompilerError::MaxNumberOfConstsExceeded => {
+            CompilerErrorKind::ParserError(e) => write!(f, "Parser error: {e}"),
+            CompilerErrorKind::MaxNumberOfConstsExceeded => {
                 write!(f, "Max number of constants exceeded")
             }
-            CompilerError::NotImplemented => write!(f, "Not implemented yet"),
-            CompilerError::EndOfSource => write!(f, "End of source"),
-            CompilerError::UnexpectedLocalAssignment => write!(f, "Unexpected local assignment"),
-            CompilerError::UnexpectedExpression => write!(f, "Unexpected expression"),
-            CompilerError::ListInitializerTooLong => write!(f, "List initializer too long"),
-            CompilerError::NameNotFound(name) => write!(f, "Name `{name}` not found"),
-            CompilerError::MapInitializerTooLong => write!(f, "Map initializer too long"),
-            CompilerError::MaxNumberOfLocalsExceeded => write!(f, "Max number of locals exceeded"),
-            CompilerError::MaxNumberOfArgsExceeded => write!(f, "Max number of args exceeded"),
-            CompilerError::NotAValidConstant => write!(f, "Not a valid constant"),
-            CompilerError::CannotSetTheValueOfAModule => {
+            CompilerErrorKind::NotImplemented => write!(f, "Not implemented yet"),
+            CompilerErrorKind::EndOfSource => write!(f, "End of source"),
+            CompilerErrorKind::UnexpectedLocalAssignment => {
+                write!(f, "Unexpected local assignment")
+            }
+            CompilerErrorKind::UnexpectedExpression => write!(f, "Unexpected expression"),
+            CompilerErrorKind::ListInitializerTooLong => write!(f, "List initializer too long"),
+            CompilerErrorKind::NameNotFound(name) => write!(f, "Name `{name}` not found"),
+            CompilerErrorKind::MapInitializerTooLong => write!(f, "Map initializer too long"),
+            CompilerErrorKind::MaxNumberOfLocalsExceeded => {
+                write!(f, "Max number of locals exceeded")
+            }
+            CompilerErrorKind::MaxNumberOfArgsExceeded => write!(f, "Max number of args exceeded"),
+            CompilerErrorKind::NotAValidConstant => write!(f, "Not a valid constant"),
+            CompilerErrorKind::CannotSetTheValueOfAModule => {
                 write!(f, "Cannot set the value in another module")
             }
+            CompilerErrorKind::CannotReloadModule(index) => {
+                write!(f, "Module at index {index} cannot be reloaded")
+            }
+            CompilerErrorKind::BreakOutsideLoop => write!(f, "`break` outside of a loop"),
+            CompilerErrorKind::ContinueOutsideLoop => write!(f, "`continue` outside of a loop"),
+            CompilerErrorKind::YieldInsideTry => write!(f, "`yield` inside a `try` block"),
+            CompilerErrorKind::JumpTooLong => write!(f, "Too much code to jump over"),
+            CompilerErrorKind::AssignmentToConst(ident) => {
+                write!(f, "Cannot assign to the const `{ident}`")
+            }
+            CompilerErrorKind::InvalidAssignmentTarget => {
+                write!(f, "Cannot assign to this expression")
+            }
+            CompilerErrorKind::ConstOutsideModule => {
+                write!(f, "`const` is only allowed at the top level of a module")
+            }
+            CompilerErrorKind::ModuleError(e) => write!(f, "{e}"),
+            CompilerErrorKind::BinaryError(e) => write!(f, "{e}"),
+            CompilerErrorKind::CircularImport(chain) => {
+                write!(f, "Circular import: {}", chain.join(" -> "))
+            }
+            CompilerErrorKind::UnknownField { record, field } => {
+                write!(f, "`{record}` has no field `{field}`")
+            }
+            CompilerErrorKind::MissingField { record, field } => {
+                write!(f, "Missing field `{field}` of `{record}`")
+            }
+            CompilerErrorKind::TypeErrors(errors) => {
+                write!(f, "Type errors:")?;
+                for error in errors {
+                    write!(f, "\n    {error}")?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -918,3 +2561,374 @@ impl Into<wasm_bindgen::JsValue> for CompilerError {
         wasm_bindgen::JsValue::from_str(&self.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        ast::Span,
+        op::{OpCode, Operator},
+        state::{ModuleLoader, ModuleValue, Prototype},
+        stdlib,
+        value::Value,
+    };
+
+    use super::{Compiler, CompilerErrorKind};
+
+    fn op_codes(prototype: &Prototype) -> Vec<OpCode> {
+        prototype.op_codes().map(|(_, op)| op).collect()
+    }
+
+    #[test]
+    fn line_mapping() {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.add_modules(stdlib::modules());
+        let source = "let a = 1\n\nlet f () =\n    Io.print a\n    a";
+        let module = Compiler::new(source, &mut module_loader)
+            .compile_module("main")
+            .unwrap();
+        let ModuleValue::Normal(main) = module.value else {
+            unreachable!()
+        };
+        let f = &main.prototypes[0];
+        for (offset, op) in f.op_codes() {
+            let expected = match op {
+                OpCode::GetModule(_) | OpCode::Call(_) => 4,
+                OpCode::Return => 5,
+                _ => continue,
+            };
+            assert_eq!(f.line(offset), expected, "{op}");
+            assert_eq!(f.line(offset + op.size() - 1), expected, "{op}");
+        }
+        let (closure, _) = main
+            .op_codes()
+            .find(|(_, op)| matches!(op, OpCode::Closure(_)))
+            .unwrap();
+        assert_eq!(main.line(closure), 3);
+    }
+
+    #[test]
+    fn resolve_many_modules() {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.add_modules(stdlib::modules());
+        for i in 0..200 {
+            module_loader
+                .load_module_from_source(&format!("m{i}"), "let value = 1")
+                .unwrap();
+        }
+        let references: Vec<String> = (0..200).map(|i| format!("m{i}.value")).collect();
+        let source = format!("let main () = [{}]", references.join(", "));
+        let module = Compiler::new(&source, &mut module_loader)
+            .compile_module("main")
+            .unwrap();
+        let ModuleValue::Normal(main) = module.value else {
+            unreachable!()
+        };
+        let first = module_loader.module("m0").unwrap();
+        assert_eq!(module_loader.module("m199"), Some(first + 199));
+        let modules: Vec<usize> = main.prototypes[0]
+            .op_codes()
+            .filter_map(|(_, op)| match op {
+                OpCode::GetModule(index) => Some(index as usize),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(modules, (first..first + 200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn resolve_many_locals() {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.add_modules(stdlib::modules());
+        let mut source = String::from("let main () =\n    let x0 = 0\n");
+        for i in 1..250 {
+            source.push_str(&format!("    let x{i} = x{} + x0\n", i - 1));
+        }
+        source.push_str("    let x0 = x249\n    x0");
+        let module = Compiler::new(&source, &mut module_loader)
+            .compile_module("main")
+            .unwrap();
+        let ModuleValue::Normal(main) = module.value else {
+            unreachable!()
+        };
+        let code = op_codes(&main.prototypes[0]);
+        // Slot 0 holds the function, so `x249` is in slot 250 and the
+        // shadowing `x0` in slot 251.
+        assert!(matches!(code[code.len() - 3], OpCode::GetLocal(251)));
+        assert!(matches!(code[code.len() - 4], OpCode::GetLocal(250)));
+    }
+
+    #[test]
+    fn fold_literal_len() {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.add_modules(stdlib::modules());
+        let source = "let main () = len [1, 2, 3]\nlet table () = len { a: 1, a: 2 }";
+        let module = Compiler::new(source, &mut module_loader)
+            .compile_module("main")
+            .unwrap();
+        let ModuleValue::Normal(main) = module.value else {
+            unreachable!()
+        };
+        assert!(matches!(
+            op_codes(&main.prototypes[0])[..],
+            [OpCode::LoadInt(3), OpCode::Return]
+        ));
+        assert!(matches!(
+            op_codes(&main.prototypes[1])[..],
+            [OpCode::LoadInt(1), OpCode::Return]
+        ));
+    }
+
+    #[test]
+    fn fold_constants() {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.set_fold_constants(true);
+        let source =
+            "let day () = 60 * 60 * 24\nlet f x = \"a\" ++ 1 < \"b\" and x\nlet g x = x * (1 / 0)";
+        let module = Compiler::new(source, &mut module_loader)
+            .compile_module("main")
+            .unwrap();
+        let ModuleValue::Normal(main) = module.value else {
+            unreachable!()
+        };
+        assert!(matches!(
+            op_codes(&main.prototypes[0])[..],
+            [OpCode::LoadConst(_), OpCode::Return]
+        ));
+        assert_eq!(main.prototypes[0].constants(), [Value::Integer(86400)]);
+        assert!(matches!(
+            op_codes(&main.prototypes[1])[..],
+            [
+                OpCode::LoadTrue,
+                OpCode::GetLocal(1),
+                OpCode::CmpAnd,
+                OpCode::Return
+            ]
+        ));
+        // Division by zero is left to fail at runtime.
+        assert!(main.prototypes[2]
+            .op_codes()
+            .any(|(_, op)| matches!(op, OpCode::Divide | OpCode::OpInt(Operator::Divide, 0))));
+    }
+
+    /// Compiles a module, with or without the peephole optimizer, and
+    /// returns the code of each of its functions as text.
+    fn compile_optimized(source: &str, peephole: bool) -> Vec<Vec<String>> {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.set_peephole(peephole);
+        let module = Compiler::new(source, &mut module_loader)
+            .compile_module("main")
+            .unwrap();
+        let ModuleValue::Normal(main) = module.value else {
+            unreachable!()
+        };
+        main.prototypes
+            .iter()
+            .map(|f| {
+                assert_eq!(f.code.len(), f.debug_info.lines.len());
+                f.op_codes().map(|(_, op)| op.to_string()).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn peephole() {
+        let source =
+            "let f x =\n    x = x\n    if x then x\n    while x do\n        x = x + 1\n    x";
+        let plain = &compile_optimized(source, false)[0];
+        let optimized = &compile_optimized(source, true)[0];
+        let has =
+            |code: &[String], pattern: &[&str]| code.windows(pattern.len()).any(|w| w == pattern);
+        for code in [plain, optimized] {
+            // The `Pop` after the `if` is where its `then` branch jumps to.
+            assert!(has(code, &["LoadUnit", "Pop"]));
+        }
+        assert!(has(plain, &["GetLocal 1", "SetLocal 1"]));
+        assert!(!has(optimized, &["GetLocal 1", "SetLocal 1"]));
+        let body_loop = if cfg!(feature = "stack-vm") {
+            "Loop 17"
+        } else {
+            "Loop 16"
+        };
+        assert!(has(optimized, &["SetLocal 1", body_loop]));
+        assert_eq!(optimized.len(), plain.len() - 6);
+    }
+
+    #[test]
+    fn dead_code() {
+        let source = "let f x = if true then x else x + 1
+let g x = if false then x else 2
+let h x =
+    while true do
+        x = x + 1
+    x";
+        let optimized = compile_optimized(source, true);
+        assert_eq!(optimized[0], ["GetLocal 1", "Return"]);
+        assert_eq!(optimized[1], ["LoadInt 2", "Return"]);
+        if cfg!(feature = "stack-vm") {
+            assert_eq!(
+                optimized[2],
+                [
+                    "GetLocal 1",
+                    "LoadInt 1",
+                    "Add",
+                    "SetLocal 1",
+                    "Loop 11",
+                    "ExtraArg 0"
+                ]
+            );
+        } else {
+            assert_eq!(
+                optimized[2],
+                ["OpLocalInt Add 1 1", "SetLocal 1", "Loop 10", "ExtraArg 0"]
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "stack-vm"))]
+    fn register_op_codes() {
+        let source = "const step = 2
+let f x y = x < y
+let g x = x * step
+let h x y = (x - 1) / y
+let i x = 300 + x
+let j x = x + j x";
+        let optimized = compile_optimized(source, true);
+        assert_eq!(optimized[0], ["OpLocals Less 1 2", "Return"]);
+        assert_eq!(optimized[1], ["OpLocalInt Multiply 1 2", "Return"]);
+        assert_eq!(
+            optimized[2],
+            ["OpLocalInt Subtract 1 1", "OpLocal Divide 2", "Return"]
+        );
+        assert_eq!(optimized[3][1..], ["OpLocal Add 1", "Return"]);
+        // The right side could change a local on the left, so it is pushed
+        // before the right side is evaluated.
+        assert_eq!(
+            optimized[4],
+            [
+                "GetLocal 1",
+                "GetLocal 0",
+                "GetLocal 1",
+                "Call 1",
+                "Add",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn tail_calls() {
+        let mut module_loader = ModuleLoader::new("");
+        let source = "let f x = if x then f x else 1 + f x\nlet g x =\n    let y = x\n    g y";
+        let module = Compiler::new(source, &mut module_loader)
+            .compile_module("main")
+            .unwrap();
+        let ModuleValue::Normal(main) = module.value else {
+            unreachable!()
+        };
+        let calls = |prototype: &crate::state::Prototype| -> Vec<bool> {
+            prototype
+                .op_codes()
+                .filter_map(|(_, op)| match op {
+                    OpCode::Call(_) => Some(false),
+                    OpCode::TailCall(_) => Some(true),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(calls(&main.prototypes[0]), [true, false]);
+        assert_eq!(calls(&main.prototypes[1]), [true]);
+    }
+
+    #[test]
+    fn shared_string_constants() {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.add_modules(stdlib::modules());
+        let source = "let f x = \"value: {x}\"\nlet g x = \"value: {x}!\"";
+        let module = Compiler::new(source, &mut module_loader)
+            .compile_module("main")
+            .unwrap();
+        let ModuleValue::Normal(main) = module.value else {
+            unreachable!()
+        };
+        let first_piece = |index: usize| {
+            main.prototypes[index]
+                .constants()
+                .iter()
+                .find_map(|c| match c {
+                    Value::Array(template) => template.borrow()[0].clone().as_string(),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert_eq!(first_piece(0).as_str(), "value: ");
+        assert!(Rc::ptr_eq(&first_piece(0), &first_piece(1)));
+    }
+
+    #[test]
+    fn interpolation_uses_format() {
+        let mut module_loader = ModuleLoader::new("");
+        let source = "let f x = \"{x} and {x:?}\"";
+        let module = Compiler::new(source, &mut module_loader)
+            .compile_module("main")
+            .unwrap();
+        let ModuleValue::Normal(main) = module.value else {
+            unreachable!()
+        };
+        let code = op_codes(&main.prototypes[0]);
+        assert!(code.iter().any(|op| matches!(op, OpCode::Format(_, 2))));
+        assert!(!code
+            .iter()
+            .any(|op| matches!(op, OpCode::CreateTable(_) | OpCode::CreateList(_))));
+    }
+
+    #[test]
+    fn error_locations() {
+        let compile = |source| {
+            let mut module_loader = ModuleLoader::new("");
+            Compiler::new(source, &mut module_loader)
+                .compile_module("main")
+                .unwrap_err()
+        };
+        let error = compile("let main () =\n    let a = 1\n    a + missing");
+        assert!(matches!(&error.kind, CompilerErrorKind::NameNotFound(name) if name == "missing"));
+        assert_eq!(
+            error.span,
+            Some(Span {
+                start: 36,
+                end: 43,
+                line: 3,
+                column: 9
+            })
+        );
+        assert_eq!(error.to_string(), "line 3:9: Name `missing` not found");
+        // Errors outside of any expression point at their statement.
+        let error = compile("let f () =\n    break");
+        assert!(matches!(error.kind, CompilerErrorKind::BreakOutsideLoop));
+        assert_eq!(error.span.map(|span| span.line), Some(2));
+        let error = compile("let a = 1\nlet b = )");
+        assert!(matches!(error.kind, CompilerErrorKind::ParserError(_)));
+        assert_eq!(
+            error.span.map(|span| (span.line, span.column)),
+            Some((2, 9))
+        );
+    }
+
+    #[test]
+    fn invalid_assignment_targets() {
+        for target in ["1", "f 1", "-a", "(a, a)", "f:g"] {
+            let source = format!("let f x = x\nlet main () =\n    let a = 1\n    {target} = 2");
+            let mut module_loader = ModuleLoader::new("");
+            let error = Compiler::new(&source, &mut module_loader)
+                .compile_module("main")
+                .unwrap_err();
+            assert!(
+                matches!(error.kind, CompilerErrorKind::InvalidAssignmentTarget),
+                "{target}: {error}"
+            );
+            assert_eq!(error.span.map(|span| span.line), Some(4));
+        }
+    }
+}
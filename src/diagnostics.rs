@@ -0,0 +1,217 @@
+use std::fmt::Write;
+
+use crate::{ast::Span, compiler::CompilerError, state::StackTraceInfo, vm::RuntimeError};
+
+/// An error ready to be shown to the user, with the place in the source it
+/// points at and any notes that explain it.
+///
+/// ```text
+/// error: Unknown local `x`
+///  --> main.fl:3:9
+///   |
+/// 3 | let y = x + 1
+///   |         ^
+///   = note: locals must be declared before they are used
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    message: String,
+    file: Option<String>,
+    span: Option<Span>,
+    notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            file: None,
+            span: None,
+            notes: Vec::new(),
+        }
+    }
+
+    /// Sets the name of the file that the source was read from.
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    /// Points the diagnostic at a span, whose part on its first line is
+    /// underlined.
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// Points the diagnostic at a whole line, for errors that don't know
+    /// their column, such as runtime errors.
+    pub fn with_line(self, line: usize) -> Self {
+        self.with_span(Span {
+            line,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    /// Renders the diagnostic with the line of `source` it points at.
+    pub fn render(&self, source: &str) -> String {
+        let mut out = String::new();
+        // Writing to a string can't fail.
+        let _ = self.write(&mut out, source);
+        out
+    }
+
+    fn write(&self, out: &mut String, source: &str) -> std::fmt::Result {
+        writeln!(out, "error: {}", self.message)?;
+        let line = self
+            .span
+            .filter(|span| span.line > 0)
+            .and_then(|span| Some((span, source.lines().nth(span.line - 1)?)));
+        let gutter = line.map_or(0, |(span, _)| span.line.to_string().len());
+        match (&self.file, line) {
+            (Some(file), Some((span, _))) if span.column > 0 => writeln!(
+                out,
+                "{:gutter$}--> {file}:{}:{}",
+                "", span.line, span.column
+            )?,
+            (Some(file), Some((span, _))) => {
+                writeln!(out, "{:gutter$}--> {file}:{}", "", span.line)?
+            }
+            (Some(file), None) => writeln!(out, "--> {file}")?,
+            (None, _) => {}
+        }
+        if let Some((span, text)) = line {
+            writeln!(out, "{:gutter$} |", "")?;
+            writeln!(out, "{} | {text}", span.line)?;
+            let (offset, len) = underline(span, text);
+            writeln!(out, "{:gutter$} | {:offset$}{}", "", "", "^".repeat(len))?;
+        }
+        for note in &self.notes {
+            writeln!(out, "{:gutter$} = note: {note}", "")?;
+        }
+        Ok(())
+    }
+}
+
+/// The column, in chars, and the length of the underline of a span on its
+/// first line. A span without a column underlines the whole line.
+fn underline(span: Span, text: &str) -> (usize, usize) {
+    if span.column == 0 {
+        let trimmed = text.trim_start();
+        let offset = text.chars().count() - trimmed.chars().count();
+        return (offset, trimmed.trim_end().chars().count().max(1));
+    }
+    let offset = span.column - 1;
+    let rest: String = text.chars().skip(offset).collect();
+    let len = rest
+        .char_indices()
+        .take_while(|(i, _)| *i < span.end.saturating_sub(span.start))
+        .count();
+    (offset, len.max(1))
+}
+
+impl From<&CompilerError> for Diagnostic {
+    fn from(error: &CompilerError) -> Self {
+        let diagnostic = Diagnostic::new(error.kind.to_string());
+        match error.span {
+            Some(span) => diagnostic.with_span(span),
+            None => diagnostic,
+        }
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(error: &RuntimeError) -> Self {
+        match error {
+            RuntimeError::Thrown { value, line, .. } => {
+                Diagnostic::new(value.to_string()).with_line(*line)
+            }
+            // The error is where the innermost script function is, and each
+            // call gets a note.
+            RuntimeError::Traced { error, trace } => {
+                let mut diagnostic = Diagnostic::from(error.as_ref());
+                for info in trace.info() {
+                    diagnostic = match info {
+                        StackTraceInfo::Prototype { ident, line } => {
+                            if diagnostic.span.is_none() {
+                                diagnostic = diagnostic.with_line(*line);
+                            }
+                            diagnostic.with_note(format!("in `{ident}` at line {line}"))
+                        }
+                        StackTraceInfo::NativeFunction { ident } => {
+                            diagnostic.with_note(format!("in `{ident}`"))
+                        }
+                    };
+                }
+                diagnostic
+            }
+            error => Diagnostic::new(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::Span;
+
+    use super::Diagnostic;
+
+    const SOURCE: &str = "let x = 1\nlet y = x + zed\n    throw \"oops\"\n";
+
+    #[test]
+    fn span() {
+        let span = Span {
+            start: 22,
+            end: 25,
+            line: 2,
+            column: 13,
+        };
+        let diagnostic = Diagnostic::new("Unknown local `zed`")
+            .with_file("main.fl")
+            .with_span(span)
+            .with_note("locals must be declared before they are used");
+        assert_eq!(
+            diagnostic.render(SOURCE),
+            "error: Unknown local `zed`\n \
+             --> main.fl:2:13\n  \
+               |\n\
+             2 | let y = x + zed\n  \
+               |             ^^^\n  \
+               = note: locals must be declared before they are used\n"
+        );
+    }
+
+    #[test]
+    fn line() {
+        let diagnostic = Diagnostic::new("oops").with_line(3);
+        assert_eq!(
+            diagnostic.render(SOURCE),
+            "error: oops\n  |\n3 |     throw \"oops\"\n  |     ^^^^^^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn no_location() {
+        let diagnostic = Diagnostic::new("Stack Overflow").with_file("main.fl");
+        assert_eq!(
+            diagnostic.render(SOURCE),
+            "error: Stack Overflow\n--> main.fl\n"
+        );
+        // A line past the end of the source isn't shown.
+        let diagnostic = Diagnostic::new("oops").with_line(10);
+        assert_eq!(diagnostic.render(SOURCE), "error: oops\n");
+    }
+}
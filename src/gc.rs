@@ -1,76 +1,292 @@
-use std::{any::Any, hash::Hash, marker::PhantomData};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
 
+use crate::value::{Closure, Function, Table, Upvalue, Value};
+
+/// The fewest objects that are tracked before the first collection.
+const MIN_THRESHOLD: usize = 4096;
+
+/// An object on the heap that can refer to other objects, and so be part of
+/// a reference cycle.
 pub trait GcObject {
-    fn mark(&self, gc: &mut Gc);
-    fn as_any(&self) -> &dyn Any;
-    fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Calls `mark` with the address of each object this one refers to.
+    fn mark(&self, mark: &mut dyn FnMut(*const ()));
+
+    /// Drops the references to other objects, once nothing can reach this
+    /// one anymore.
+    fn clear(&self);
 }
 
-pub struct GcRef<T: GcObject> {
-    index: usize,
-    _marker: PhantomData<T>,
+/// A cycle collector for the tables, arrays, tuples, closures and upvalues
+/// that the VM allocates, and for the array constants of loaded modules.
+///
+/// Objects are still reference counted, and freed as soon as nothing refers
+/// to them. The collector frees the ones that are only kept alive by
+/// reference cycles, which it keeps track of with weak references.
+///
+/// There is no explicit root set. Instead, the references between tracked
+/// objects are subtracted from their reference counts, and any object with
+/// references left is a root: one held by the stack, call frames, open
+/// upvalues or module values, or by anything natives or the host hold on
+/// to. Everything reachable from a root is kept, and the rest has its
+/// references cleared, which breaks the cycles so reference counting frees
+/// them. Objects held in places that can't be traced are never freed.
+pub struct Gc {
+    objects: Vec<Weak<dyn GcObject>>,
+    /// The number of tracked objects that starts the next collection.
+    threshold: usize,
 }
 
-impl<T: GcObject> Hash for GcRef<T> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.index.hash(state);
+impl Gc {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            threshold: MIN_THRESHOLD,
+        }
+    }
+
+    /// Tracks a newly allocated object, collecting garbage first if enough
+    /// objects were allocated since the last collection.
+    pub fn alloc<T: GcObject + 'static>(&mut self, object: Rc<T>) -> Rc<T> {
+        if self.objects.len() >= self.threshold {
+            self.collect();
+        }
+        let weak: Weak<dyn GcObject> = Rc::downgrade(&object) as Weak<dyn GcObject>;
+        self.objects.push(weak);
+        object
+    }
+
+    /// Frees the objects that can't be reached from a root, returning how
+    /// many there were.
+    pub fn collect(&mut self) -> usize {
+        let objects: Vec<Rc<dyn GcObject>> =
+            self.objects.iter().filter_map(Weak::upgrade).collect();
+        let indices: HashMap<*const (), usize> = objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (Rc::as_ptr(object).cast(), i))
+            .collect();
+
+        // The references from outside, not counting the ones just upgraded.
+        let mut outside: Vec<usize> = objects
+            .iter()
+            .map(|object| Rc::strong_count(object) - 1)
+            .collect();
+        for object in &objects {
+            object.mark(&mut |address| {
+                if let Some(&i) = indices.get(&address) {
+                    outside[i] -= 1;
+                }
+            });
+        }
+
+        let mut marked: Vec<bool> = outside.iter().map(|&count| count > 0).collect();
+        let mut pending: Vec<usize> = (0..objects.len()).filter(|&i| marked[i]).collect();
+        while let Some(i) = pending.pop() {
+            objects[i].mark(&mut |address| {
+                if let Some(&i) = indices.get(&address) {
+                    if !marked[i] {
+                        marked[i] = true;
+                        pending.push(i);
+                    }
+                }
+            });
+        }
+
+        // Garbage only refers to other garbage, so clearing all of it breaks
+        // every cycle, and it's freed when `objects` is dropped.
+        let mut freed = 0;
+        for (object, marked) in objects.iter().zip(marked) {
+            if !marked {
+                object.clear();
+                freed += 1;
+            }
+        }
+        drop(objects);
+        self.objects.retain(|object| object.strong_count() > 0);
+        self.threshold = MIN_THRESHOLD.max(self.objects.len() * 2);
+        freed
     }
 }
 
-pub struct GcObjectHeader {
-    is_marked: bool,
-    object: Box<dyn GcObject>,
+impl Default for Gc {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-pub struct Gc {
-    objects: Vec<Option<GcObjectHeader>>,
-    free_slots: Vec<usize>,
+fn mark_value(value: &Value, mark: &mut dyn FnMut(*const ())) {
+    match value {
+        Value::Table(table) => mark(Rc::as_ptr(table).cast()),
+        Value::Array(array) => mark(Rc::as_ptr(array).cast()),
+        Value::Tuple(tuple) => mark(Rc::as_ptr(tuple).cast()),
+        Value::Closure(closure) | Value::Iterator(closure) => mark(Rc::as_ptr(closure).cast()),
+        _ => {}
+    }
 }
 
-impl Gc {
-    pub fn alloc<T: GcObject + 'static>(&mut self, object: T) -> GcRef<T> {
-        let header = GcObjectHeader {
-            is_marked: false,
-            object: Box::new(object),
+// An object that is borrowed can't be looked into, but whoever borrowed it
+// holds it, so what it refers to is kept as a root.
+
+impl GcObject for RefCell<Table> {
+    fn mark(&self, mark: &mut dyn FnMut(*const ())) {
+        let Ok(table) = self.try_borrow() else {
+            return;
         };
-        let index = if let Some(index) = self.free_slots.pop() {
-            self.objects[index] = Some(header);
-            index
-        } else {
-            self.objects.push(Some(header));
-            self.objects.len() - 1
+        for (key, value) in table.iter() {
+            mark_value(key, mark);
+            mark_value(value, mark);
+        }
+    }
+
+    fn clear(&self) {
+        drop(std::mem::take(&mut *self.borrow_mut()));
+    }
+}
+
+impl GcObject for RefCell<Vec<Value>> {
+    fn mark(&self, mark: &mut dyn FnMut(*const ())) {
+        let Ok(array) = self.try_borrow() else {
+            return;
         };
-        GcRef {
-            index,
-            _marker: Default::default(),
+        for value in array.iter() {
+            mark_value(value, mark);
         }
     }
 
-    pub fn free<T: GcObject + 'static>(&mut self, gc_ref: GcRef<T>) {
-        if let Some(_object) = self.objects[gc_ref.index].take() {
-            self.free_slots.push(gc_ref.index);
-        } else {
-            panic!("Free called on freed object {}", gc_ref.index);
+    fn clear(&self) {
+        drop(std::mem::take(&mut *self.borrow_mut()));
+    }
+}
+
+/// Like closures, tuples can't be changed, so the cycles they are in go
+/// through a table, array or upvalue that is cleared.
+impl GcObject for Box<[Value]> {
+    fn mark(&self, mark: &mut dyn FnMut(*const ())) {
+        for value in self.iter() {
+            mark_value(value, mark);
+        }
+    }
+
+    fn clear(&self) {}
+}
+
+impl GcObject for RefCell<Upvalue> {
+    fn mark(&self, mark: &mut dyn FnMut(*const ())) {
+        if let Ok(Upvalue::Closed { value }) = self.try_borrow().as_deref() {
+            mark_value(value, mark);
+        }
+    }
+
+    fn clear(&self) {
+        if let Upvalue::Closed { value } = &mut *self.borrow_mut() {
+            drop(std::mem::replace(value, Value::Unit));
+        }
+    }
+}
+
+/// Closures can't be changed, so the cycles they are in are broken by
+/// clearing their upvalues and the tables and arrays they refer to.
+impl GcObject for Closure {
+    fn mark(&self, mark: &mut dyn FnMut(*const ())) {
+        for upvalue in &self.upvalues {
+            mark(Rc::as_ptr(upvalue).cast());
         }
+        if let Function::Partial(partial) = &self.function {
+            mark(Rc::as_ptr(&partial.closure).cast());
+            for arg in &partial.args {
+                mark_value(arg, mark);
+            }
+        }
+    }
+
+    fn clear(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        rc::{Rc, Weak},
+    };
+
+    use crate::{
+        value::{Table, Value},
+        vm::Vm,
+    };
+
+    use super::Gc;
+
+    fn table(gc: &mut Gc) -> Rc<RefCell<Table>> {
+        gc.alloc(Rc::new(RefCell::new(Table::new())))
+    }
+
+    #[test]
+    fn cycles() {
+        let mut gc = Gc::new();
+        let a = table(&mut gc);
+        let b = table(&mut gc);
+        a.borrow_mut()
+            .insert(Value::Integer(0), Value::Table(b.clone()));
+        b.borrow_mut()
+            .insert(Value::Integer(0), Value::Table(a.clone()));
+        let array = gc.alloc(Rc::new(RefCell::new(vec![Value::Table(a.clone())])));
+        let (weak_a, weak_b) = (Rc::downgrade(&a), Rc::downgrade(&b));
+        drop((a, b));
+
+        // The array outside of the cycle keeps it alive.
+        assert_eq!(gc.collect(), 0);
+        assert_eq!(weak_b.upgrade().unwrap().borrow().len(), 1);
+        drop(array);
+        assert_eq!(gc.collect(), 2);
+        assert!(weak_a.upgrade().is_none() && weak_b.upgrade().is_none());
+        assert!(gc.objects.is_empty());
     }
 
-    pub fn get_ref<T: GcObject + 'static>(&self, gc_ref: GcRef<T>) -> &T {
-        self.objects[gc_ref.index]
-            .as_ref()
-            .unwrap()
-            .object
-            .as_any()
-            .downcast_ref()
-            .expect(&format!("Reference {} not found", gc_ref.index))
-    }
-
-    pub fn get_mut<T: GcObject + 'static>(&mut self, gc_ref: GcRef<T>) -> &mut T {
-        self.objects[gc_ref.index]
-            .as_mut()
-            .unwrap()
-            .object
-            .as_any_mut()
-            .downcast_mut()
-            .expect(&format!("Reference {} not found", gc_ref.index))
+    #[test]
+    fn borrowed_objects_are_roots() {
+        let mut gc = Gc::new();
+        let a = table(&mut gc);
+        let b = table(&mut gc);
+        a.borrow_mut()
+            .insert(Value::Integer(0), Value::Table(b.clone()));
+        b.borrow_mut()
+            .insert(Value::Integer(0), Value::Table(a.clone()));
+        let weak_b = Rc::downgrade(&b);
+        drop(b);
+        let borrowed = a.borrow();
+        assert_eq!(gc.collect(), 0);
+        drop(borrowed);
+        assert_eq!(weak_b.upgrade().unwrap().borrow().len(), 1);
+    }
+
+    #[test]
+    fn script_cycles() {
+        let mut vm = Vm::new_with_std();
+        // A table that holds a closure that holds the table, and arrays that
+        // hold each other.
+        let source = "let main () =
+    let t = { name: \"t\" }
+    t.get = fn () -> t.name
+    let a = [1]
+    let b = [a]
+    a[0] = b
+    t";
+        let index = vm.load_from_source("main", source).unwrap();
+        vm.execute_module(index, "main").unwrap();
+        let t = vm.peek().unwrap().as_table().unwrap();
+        let weak: Weak<RefCell<Table>> = Rc::downgrade(&t);
+        drop(t);
+        // The table is still on the stack.
+        assert_eq!(vm.collect_garbage(), 2);
+        assert_eq!(weak.upgrade().unwrap().borrow().len(), 2);
+
+        vm.reload_module(index, source).unwrap();
+        // The table, the closure and its upvalue.
+        assert_eq!(vm.collect_garbage(), 3);
+        assert!(weak.upgrade().is_none());
     }
 }
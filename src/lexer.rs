@@ -83,7 +83,7 @@ impl<'a> Lexer<'a> {
         let mut l = self.clone();
         l.skip_new_lines();
         if l.next().token_type == TokenType::Eos {
-            return 0;
+            0
         } else {
             l.indentation
         }
@@ -231,6 +231,7 @@ impl<'a> Lexer<'a> {
             ':' => TokenType::Colon,
             ',' => TokenType::Comma,
             '!' if self.next_char_checked('=') => TokenType::NotEqual,
+            '+' if self.next_char_checked('+') => TokenType::Concat,
             '+' => TokenType::Plus,
             '=' if self.next_char_checked('=') => TokenType::Equal,
             '=' => TokenType::Assign,
@@ -240,11 +241,23 @@ impl<'a> Lexer<'a> {
             '%' => TokenType::Mod,
             '/' if self.next_char_checked('/') => TokenType::IDiv,
             '/' => TokenType::Div,
+            '"' if self.rest().starts_with("\"\"") => {
+                self.position += 2;
+                let len = self
+                    .rest()
+                    .find("\"\"\"")
+                    .map_or(self.rest().len(), |i| i + 3);
+                self.line += self.rest()[..len].matches('\n').count();
+                self.position += len;
+                TokenType::RawString
+            }
             '"' => TokenType::DoubleQuote,
             '\'' => TokenType::SingleQuote,
             '.' if self.next_char_checked('.') => {
                 if self.next_char_checked('.') {
                     TokenType::Spread
+                } else if self.next_char_checked('=') {
+                    TokenType::DotsEq
                 } else {
                     TokenType::Dots
                 }
@@ -265,26 +278,23 @@ impl<'a> Lexer<'a> {
             ')' => TokenType::RParen,
             '&' => TokenType::BinAnd,
             '|' if self.next_char_checked('>') => TokenType::Pipe,
-            '|' => TokenType::BinAnd,
+            '|' => TokenType::BinOr,
             '^' => TokenType::BinXor,
+            '?' if self.next_char_checked('?') => TokenType::Coalesce,
             '~' => TokenType::BinNot,
             c if c.is_numeric() => {
-                let len = self
-                    .source
-                    .chars()
-                    .skip(self.position)
-                    .take_while(|c| c.is_numeric() || *c == '_' || *c == '.')
-                    .count();
-                self.position += len;
+                self.position += self.count_bytes_while(|c| c.is_numeric() || c == '_');
+                // A dot is only part of the number if a digit follows, so
+                // that `0..10` is a range.
+                let mut rest = self.rest().chars();
+                if rest.next() == Some('.') && rest.next().is_some_and(|c| c.is_numeric()) {
+                    self.position += 1;
+                    self.position += self.count_bytes_while(|c| c.is_numeric() || c == '_');
+                }
                 TokenType::Number
             }
             c if c.is_alphabetic() || c == '_' => {
-                let len = self
-                    .source
-                    .chars()
-                    .skip(self.position)
-                    .take_while(|c| c.is_alphanumeric() || *c == '_')
-                    .count();
+                let len = self.count_bytes_while(|c| c.is_alphanumeric() || c == '_');
                 self.position += len;
                 let str = &self.source[start_position..self.position];
                 if let Some(tok) = get_reserved(str) {
@@ -296,7 +306,11 @@ impl<'a> Lexer<'a> {
             _ => TokenType::Unknown,
         };
         if !self.is_new_line {
-            self.column += self.position - start_position;
+            let text = &self.source[start_position..self.position];
+            match text.rfind('\n') {
+                Some(i) => self.column = text[i + 1..].chars().count(),
+                None => self.column += text.chars().count(),
+            }
         }
         let token = Token {
             position: start_position,
@@ -309,6 +323,15 @@ impl<'a> Lexer<'a> {
         token
     }
 
+    /// Consumes a single char of the source without tokenizing it, as in
+    /// the body of a char literal. New lines are not consumed.
+    pub fn next_raw_char(&mut self) -> Option<char> {
+        let ch = self.rest().chars().next().filter(|c| *c != '\n')?;
+        self.position += ch.len_utf8();
+        self.column += 1;
+        Some(ch)
+    }
+
     pub fn skip_new_lines(&mut self) {
         while self.peek() == TokenType::NewLine {
             self.next();
@@ -335,25 +358,34 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn count_whitespace(&self) -> usize {
-        self.source
+    /// Positions are byte offsets into the source, so lengths are counted
+    /// in bytes to keep spans valid for slicing.
+    fn count_bytes_while(&self, predicate: impl Fn(char) -> bool) -> usize {
+        self.rest()
             .chars()
-            .skip(self.position)
-            .enumerate()
-            .take_while(|(_, c)| c.is_whitespace() && *c != '\n')
-            .count()
+            .take_while(|c| predicate(*c))
+            .map(char::len_utf8)
+            .sum()
+    }
+
+    fn rest(&self) -> &'a str {
+        self.source.get(self.position..).unwrap_or_default()
+    }
+
+    fn count_whitespace(&self) -> usize {
+        self.count_bytes_while(|c| c.is_whitespace() && c != '\n')
     }
 
     fn next_char(&mut self) -> Option<char> {
-        let ch = self.source.chars().nth(self.position);
-        self.position += 1;
+        let ch = self.rest().chars().next();
+        self.position += ch.map_or(1, char::len_utf8);
         ch
     }
 
     fn next_char_checked(&mut self, ch: char) -> bool {
-        let next = self.source.chars().nth(self.position);
+        let next = self.rest().chars().next();
         if next.is_some_and(|c| c == ch) {
-            self.position += 1;
+            self.position += ch.len_utf8();
             true
         } else {
             false
@@ -369,10 +401,29 @@ mod tests {
 
     #[test]
     fn dots() {
-        let mut lexer = Lexer::new(". .. ...");
+        let mut lexer = Lexer::new(". .. ... ..=");
         assert_eq!(lexer.next().token_type, TokenType::Dot);
         assert_eq!(lexer.next().token_type, TokenType::Dots);
         assert_eq!(lexer.next().token_type, TokenType::Spread);
+        assert_eq!(lexer.next().token_type, TokenType::DotsEq);
+    }
+
+    #[test]
+    fn numbers_before_dots() {
+        let mut lexer = Lexer::new("1.5 0..10");
+        assert_eq!(lexer.next().span, 0..3);
+        assert_eq!(lexer.next().token_type, TokenType::Number);
+        assert_eq!(lexer.next().token_type, TokenType::Dots);
+        assert_eq!(lexer.next().token_type, TokenType::Number);
+    }
+
+    #[test]
+    fn concat() {
+        let mut lexer = Lexer::new("a ++ b + c");
+        lexer.next();
+        assert_eq!(lexer.next().token_type, TokenType::Concat);
+        lexer.next();
+        assert_eq!(lexer.next().token_type, TokenType::Plus);
     }
 
     #[test]
@@ -449,4 +500,15 @@ mod tests {
             }
         ));
     }
+
+    #[test]
+    fn multibyte_spans() {
+        let mut lexer = Lexer::new("héllo wörld");
+        let token = lexer.next();
+        assert_eq!(lexer.slice(token.span), "héllo");
+        let token = lexer.next();
+        assert_eq!(token.column, 6);
+        assert_eq!(lexer.slice(token.span), "wörld");
+        assert_eq!(lexer.next().token_type, TokenType::Eos);
+    }
 }
@@ -1,12 +1,16 @@
 pub mod ast;
+pub mod binary;
 pub mod compiler;
+pub mod diagnostics;
 mod gc;
 mod lexer;
-mod object;
-mod op;
+pub mod nanbox;
+pub mod op;
 pub mod parser;
+pub mod plugin;
 pub mod state;
 pub mod stdlib;
 mod token;
+pub mod typecheck;
 pub mod value;
 pub mod vm;
@@ -0,0 +1,294 @@
+use std::{
+    any::Any, cell::RefCell, fmt::Debug, marker::PhantomData, mem::ManuallyDrop, ops::Deref, rc::Rc,
+};
+
+use crate::{
+    state::Module,
+    value::{Closure, Table, Value},
+};
+
+/// A [`Value`] packed in the 8 bytes of an `f64`, which is what the stack
+/// holds with the `nan-boxing` feature.
+///
+/// Numbers are stored as themselves, with every NaN made the same quiet
+/// NaN. Everything else is stored in the payload of the quiet NaNs that
+/// have the bits of `QNAN` set, which no number has then:
+///
+/// | tag | payload                                           |
+/// |-----|---------------------------------------------------|
+/// | 0   | unit, a bool or a char                            |
+/// | 1   | an integer that fits in 48 bits                   |
+/// | 2-3 | a pointer, with the kind in its low 2 bits        |
+/// | 4   | a pointer to a larger integer                     |
+///
+/// Pointers take the 48 bits that addresses use on 64 bit platforms, and
+/// are aligned to at least 4 bytes, so their low 2 bits are free.
+pub struct NanBox {
+    bits: u64,
+    /// Holds reference counts, so it can't be sent to another thread.
+    _marker: PhantomData<*const ()>,
+}
+
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const PAYLOAD: u64 = (1 << 48) - 1;
+const TAG: u64 = !PAYLOAD;
+
+/// The tag bits are bits 48 and 49 and the sign bit.
+const fn tag(tag: u64) -> u64 {
+    QNAN | (tag & 3) << 48 | (tag >> 2) << 63
+}
+
+const IMMEDIATE: u64 = tag(0);
+const INTEGER: u64 = tag(1);
+const POINTER: u64 = tag(2);
+const POINTER_HIGH: u64 = tag(3);
+const BIG_INTEGER: u64 = tag(4);
+
+const UNIT: u64 = 0;
+const BOOL: u64 = 1;
+const CHAR: u64 = 2;
+
+/// The kinds of pointers, the first four with the tag `POINTER` and the
+/// rest with `POINTER_HIGH`.
+const STRING: u64 = 0;
+const TABLE: u64 = 1;
+const CLOSURE: u64 = 2;
+const ARRAY: u64 = 3;
+const MODULE: u64 = 4;
+const USER_DATA: u64 = 5;
+const ITERATOR: u64 = 6;
+const TUPLE: u64 = 7;
+
+impl NanBox {
+    fn from_bits(bits: u64) -> Self {
+        Self {
+            bits,
+            _marker: PhantomData,
+        }
+    }
+
+    fn pointer<T>(kind: u64, pointer: *const T) -> Self {
+        let address = pointer as usize as u64;
+        debug_assert!(address & !PAYLOAD == 0 && address & 3 == 0);
+        let tag = if kind < 4 { POINTER } else { POINTER_HIGH };
+        Self::from_bits(tag | address | (kind & 3))
+    }
+
+    /// Whether the box holds no pointer, so that it can be copied and
+    /// dropped as it is.
+    #[inline(always)]
+    fn is_inline(&self) -> bool {
+        self.bits & QNAN != QNAN || matches!(self.bits & TAG, IMMEDIATE | INTEGER)
+    }
+
+    /// Gives the value without changing any reference counts. Dropping the
+    /// value gives up the reference of the box.
+    ///
+    /// # Safety
+    ///
+    /// The value must be dropped at most once, and the box not at all then.
+    unsafe fn decode(&self) -> Value {
+        let bits = self.bits;
+        if bits & QNAN != QNAN {
+            return Value::Number(f64::from_bits(bits));
+        }
+        let payload = bits & PAYLOAD;
+        match bits & TAG {
+            IMMEDIATE => match payload & 3 {
+                UNIT => Value::Unit,
+                BOOL => Value::Bool(payload >> 2 != 0),
+                _ => Value::Char(char::from_u32_unchecked((payload >> 2) as u32)),
+            },
+            INTEGER => Value::Integer(((payload << 16) as i64) >> 16),
+            BIG_INTEGER => Value::Integer(*(payload as usize as *const i64)),
+            tag => {
+                let kind = if tag == POINTER { 0 } else { 4 } | payload & 3;
+                let address = (payload & !3) as usize;
+                match kind {
+                    STRING => Value::String(Rc::from_raw(address as *const String)),
+                    TABLE => Value::Table(Rc::from_raw(address as *const RefCell<Table>)),
+                    CLOSURE => Value::Closure(Rc::from_raw(address as *const Closure)),
+                    ARRAY => Value::Array(Rc::from_raw(address as *const RefCell<Vec<Value>>)),
+                    MODULE => Value::Module(Rc::from_raw(address as *const Module)),
+                    USER_DATA => Value::UserData(Box::from_raw(address as *mut Rc<dyn Any>)),
+                    ITERATOR => Value::Iterator(Rc::from_raw(address as *const Closure)),
+                    _ => Value::Tuple(Rc::from_raw(address as *const Box<[Value]>)),
+                }
+            }
+        }
+    }
+
+    /// Takes the value out of the box, freeing a boxed integer.
+    ///
+    /// # Safety
+    ///
+    /// The box must not be used or dropped afterwards.
+    unsafe fn take(&self) -> Value {
+        if self.bits & TAG == BIG_INTEGER {
+            let int = Box::from_raw((self.bits & PAYLOAD) as usize as *mut i64);
+            return Value::Integer(*int);
+        }
+        self.decode()
+    }
+
+    /// Borrows the value in the box.
+    pub fn value(&self) -> ValueRef<'_> {
+        ValueRef {
+            // The value is never dropped.
+            value: ManuallyDrop::new(unsafe { self.decode() }),
+            _box: PhantomData,
+        }
+    }
+
+    pub fn is_unit(&self) -> bool {
+        self.bits == IMMEDIATE | UNIT
+    }
+
+    /// The integer in the box, without looking at anything else.
+    pub fn as_integer(&self) -> Option<i64> {
+        match self.bits & TAG {
+            INTEGER => Some((((self.bits & PAYLOAD) << 16) as i64) >> 16),
+            _ => None,
+        }
+    }
+}
+
+impl From<Value> for NanBox {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Number(num) if num.is_nan() => Self::from_bits(f64::NAN.to_bits()),
+            Value::Number(num) => Self::from_bits(num.to_bits()),
+            Value::Unit => Self::from_bits(IMMEDIATE | UNIT),
+            Value::Bool(bool) => Self::from_bits(IMMEDIATE | BOOL | (bool as u64) << 2),
+            Value::Char(char) => Self::from_bits(IMMEDIATE | CHAR | (char as u64) << 2),
+            Value::Integer(int) if (int << 16) >> 16 == int => {
+                Self::from_bits(INTEGER | int as u64 & PAYLOAD)
+            }
+            Value::Integer(int) => {
+                let address = Box::into_raw(Box::new(int)) as usize as u64;
+                Self::from_bits(BIG_INTEGER | address)
+            }
+            Value::String(string) => Self::pointer(STRING, Rc::into_raw(string)),
+            Value::Table(table) => Self::pointer(TABLE, Rc::into_raw(table)),
+            Value::Closure(closure) => Self::pointer(CLOSURE, Rc::into_raw(closure)),
+            Value::Array(array) => Self::pointer(ARRAY, Rc::into_raw(array)),
+            Value::Module(module) => Self::pointer(MODULE, Rc::into_raw(module)),
+            Value::UserData(user_data) => Self::pointer(USER_DATA, Box::into_raw(user_data)),
+            Value::Iterator(iterator) => Self::pointer(ITERATOR, Rc::into_raw(iterator)),
+            Value::Tuple(tuple) => Self::pointer(TUPLE, Rc::into_raw(tuple)),
+        }
+    }
+}
+
+impl From<NanBox> for Value {
+    fn from(nan_box: NanBox) -> Self {
+        // The box is forgotten, so its reference moves to the value.
+        let nan_box = ManuallyDrop::new(nan_box);
+        unsafe { nan_box.take() }
+    }
+}
+
+impl Clone for NanBox {
+    fn clone(&self) -> Self {
+        if self.is_inline() {
+            return Self::from_bits(self.bits);
+        }
+        Self::from(self.value().clone())
+    }
+}
+
+impl Drop for NanBox {
+    fn drop(&mut self) {
+        if !self.is_inline() {
+            drop(unsafe { self.take() });
+        }
+    }
+}
+
+impl Default for NanBox {
+    fn default() -> Self {
+        Self::from(Value::Unit)
+    }
+}
+
+impl PartialEq for NanBox {
+    fn eq(&self, other: &Self) -> bool {
+        *self.value() == *other.value()
+    }
+}
+
+impl Debug for NanBox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.value().fmt(f)
+    }
+}
+
+/// A value borrowed from a [`NanBox`].
+pub struct ValueRef<'a> {
+    value: ManuallyDrop<Value>,
+    _box: PhantomData<&'a NanBox>,
+}
+
+impl Deref for ValueRef<'_> {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        &self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::value::{Table, Value};
+
+    use super::NanBox;
+
+    #[test]
+    fn round_trip() {
+        let table = Rc::new(RefCell::new(Table::new()));
+        let values = [
+            Value::Unit,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Char('é'),
+            Value::Integer(0),
+            Value::Integer(-1),
+            Value::Integer((1 << 47) - 1),
+            Value::Integer(-(1 << 47)),
+            Value::Integer(1 << 47),
+            Value::Integer(i64::MIN),
+            Value::Number(1.5),
+            Value::Number(-0.0),
+            Value::Number(f64::INFINITY),
+            Value::Number(f64::NEG_INFINITY),
+            Value::String(Rc::new("string".to_string())),
+            Value::Table(table.clone()),
+            Value::Array(Rc::new(RefCell::new(vec![Value::Integer(1)]))),
+            Value::Tuple(Rc::new(vec![Value::Unit, Value::Integer(2)].into())),
+            Value::UserData(Box::new(Rc::new(5_u8))),
+        ];
+        for value in values {
+            let nan_box = NanBox::from(value.clone());
+            assert_eq!(*nan_box.value(), value);
+            assert_eq!(nan_box.clone(), nan_box);
+            assert_eq!(Value::from(nan_box.clone()), value);
+            assert_eq!(Value::from(nan_box), value);
+        }
+        // Every box of the table was dropped.
+        assert_eq!(Rc::strong_count(&table), 1);
+        let nan = NanBox::from(Value::Number(-f64::NAN));
+        assert!(matches!(*nan.value(), Value::Number(num) if num.is_nan()));
+        assert_eq!(std::mem::size_of::<NanBox>(), 8);
+        assert_eq!(std::mem::size_of::<Value>(), 16);
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(NanBox::from(Value::Integer(-7)).as_integer(), Some(-7));
+        assert_eq!(NanBox::from(Value::Integer(i64::MAX)).as_integer(), None);
+        assert_eq!(NanBox::from(Value::Number(1.0)).as_integer(), None);
+        assert!(NanBox::default().is_unit());
+    }
+}
@@ -8,6 +8,8 @@ pub type InitLen = u8;
 #[derive(Debug, Clone, Copy)]
 pub enum OpCode {
     LoadConst(ConstIdx),
+    /// Like `LoadConst`, with the high byte of the index in an `ExtraArg`.
+    LoadConstW(ConstIdx),
     LoadUnit,
     LoadTrue,
     LoadFalse,
@@ -15,15 +17,38 @@ pub enum OpCode {
 
     GetLocal(LocalIdx),
     GetUpvalue(LocalIdx),
+    /// Like `GetLocal`, with the high byte of the slot in an `ExtraArg`.
+    GetLocalW(LocalIdx),
+    /// Like `GetUpvalue`, with the high byte of the index in an `ExtraArg`.
+    GetUpvalueW(LocalIdx),
     GetModule(LocalIdx),
     GetTable,
+    /// Like `GetTable`, for a field named in the code, with the index of a
+    /// cache in the function that remembers what it got.
+    GetField(u8),
 
     SetLocal(LocalIdx),
     SetUpvalue(LocalIdx),
+    /// Like `SetLocal`, with the high byte of the slot in an `ExtraArg`.
+    SetLocalW(LocalIdx),
+    /// Like `SetUpvalue`, with the high byte of the index in an `ExtraArg`.
+    SetUpvalueW(LocalIdx),
     SetTable,
 
     CreateList(InitLen),
     CreateTable(InitLen),
+    /// Like `CreateList`, with the high byte of the length in an `ExtraArg`.
+    CreateListW(InitLen),
+    /// Like `CreateTable`, with the high byte of the length in an `ExtraArg`.
+    CreateTableW(InitLen),
+    Extend,
+    CreateTuple(u8),
+    /// Replaces the tuple or array on top with its elements, which must be
+    /// exactly as many as the argument.
+    Unpack(u8),
+    Format(ConstIdx, u8),
+    /// Like `Format`, with the high byte of the index in an `ExtraArg`.
+    FormatW(ConstIdx, u8),
 
     Closure(FunctionIdx),
 
@@ -34,9 +59,43 @@ pub enum OpCode {
     Multiply,
     Modulus,
     Concat,
+    Range,
+    RangeInclusive,
+    /// Replaces the array or string under the two bounds on top with the
+    /// elements between them, where a unit bound is left out.
+    Slice,
+    SliceInclusive,
+    Len,
+    /// Calls the method named by the key under the arguments, looked up in
+    /// the table under it, with the table as its first argument.
+    CallMethod(u8),
+    /// Suspends the generator with the value on top as its next value.
+    Yield,
+    /// Replaces the future on top with its result once it is ready. Other
+    /// values are left as they are.
+    Await,
+    /// Raises the value on top as an error.
+    Throw,
+    /// Replaces the value on top with whether it is a table.
+    IsTable,
+    /// Replaces the value on top with whether it is an array of the length.
+    HasLen(u8),
+    /// Replaces the value on top with whether it is an array of at least
+    /// the length.
+    HasMinLen(u8),
+    /// Replaces the array on top with a new array of its elements after the
+    /// given number of them.
+    Rest(u8),
     Negate,
     Not,
 
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    BitNot,
+
     CmpEq,
     CmpNEq,
     CmpLess,
@@ -46,12 +105,48 @@ pub enum OpCode {
     CmpAnd,
     CmpOr,
 
+    /// Pushes the result of an operator on two locals, which are read in
+    /// their slots instead of being pushed first.
+    OpLocals(Operator, LocalIdx, LocalIdx),
+    /// Like `OpLocals`, with an integer like `LoadInt` on the right.
+    OpLocalInt(Operator, LocalIdx, u8),
+    /// Replaces the value on top with the result of an operator on it and a
+    /// local.
+    OpLocal(Operator, LocalIdx),
+    /// Like `OpLocal`, with an integer like `LoadInt` on the right.
+    OpInt(Operator, u8),
+
     JumpIfFalse(u8),
+    /// Jumps forwards like `Jump` if the value on top isn't unit, leaving it
+    /// there, and pops it otherwise.
+    JumpIfNotUnit(u8),
+    /// Jumps forwards by a number of bytes, counted from the end of the
+    /// `ExtraArg` after it that holds the high byte of the offset.
     Jump(u8),
+    /// Jumps backwards, with the high byte of the offset in an `ExtraArg`.
+    Loop(u8),
+    /// Turns the value on top into an iterator.
+    IntoIter,
+    /// Advances the iterator on top and pushes the next value, or jumps
+    /// forwards like `Jump` once the iterator is exhausted.
+    ForIter(u8),
+    /// Starts catching errors until the matching `EndTry`. A caught error
+    /// unwinds the stack, is pushed and execution continues at the jump
+    /// target, which is given like in `Jump`.
+    Try(u8),
+    EndTry,
 
     Call(u8),
+    /// A call whose result is returned right away, which reuses the frame
+    /// of the caller.
+    TailCall(u8),
+    CallSpread,
     CloseUpvalue(u8),
+    /// Like `CloseUpvalue`, with the high byte of the slot in an `ExtraArg`.
+    CloseUpvalueW(u8),
     Pop,
+    /// Pops the given number of locals from below the value on top.
+    PopScope(u8),
     Return,
 
     ExtraArg(u8),
@@ -61,19 +156,32 @@ impl Display for OpCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             OpCode::LoadConst(idx) => write!(f, "LoadConst {idx}"),
+            OpCode::LoadConstW(idx) => write!(f, "LoadConstW {idx}"),
             OpCode::LoadUnit => write!(f, "LoadUnit"),
             OpCode::LoadTrue => write!(f, "LoadTrue"),
             OpCode::LoadFalse => write!(f, "LoadFalse"),
             OpCode::LoadInt(int) => write!(f, "LoadInt {int}"),
             OpCode::GetLocal(idx) => write!(f, "GetLocal {idx}"),
             OpCode::GetUpvalue(idx) => write!(f, "GetUpvalue {idx}"),
+            OpCode::GetLocalW(idx) => write!(f, "GetLocalW {idx}"),
+            OpCode::GetUpvalueW(idx) => write!(f, "GetUpvalueW {idx}"),
             OpCode::GetModule(idx) => write!(f, "GetModule {idx}"),
             OpCode::GetTable => write!(f, "GetTable"),
+            OpCode::GetField(cache) => write!(f, "GetField {cache}"),
             OpCode::SetLocal(idx) => write!(f, "SetLocal {idx}"),
             OpCode::SetUpvalue(idx) => write!(f, "SetUpvalue {idx}"),
+            OpCode::SetLocalW(idx) => write!(f, "SetLocalW {idx}"),
+            OpCode::SetUpvalueW(idx) => write!(f, "SetUpvalueW {idx}"),
             OpCode::SetTable => write!(f, "SetTable"),
             OpCode::CreateList(len) => write!(f, "CreateList {len}"),
             OpCode::CreateTable(len) => write!(f, "CreateTable {len}"),
+            OpCode::CreateListW(len) => write!(f, "CreateListW {len}"),
+            OpCode::CreateTableW(len) => write!(f, "CreateTableW {len}"),
+            OpCode::Extend => write!(f, "Extend"),
+            OpCode::CreateTuple(len) => write!(f, "CreateTuple {len}"),
+            OpCode::Unpack(len) => write!(f, "Unpack {len}"),
+            OpCode::Format(idx, num_args) => write!(f, "Format {idx} {num_args}"),
+            OpCode::FormatW(idx, num_args) => write!(f, "FormatW {idx} {num_args}"),
             OpCode::Closure(idx) => write!(f, "Closure {idx}"),
             OpCode::Add => write!(f, "Add"),
             OpCode::Subtract => write!(f, "Subtract"),
@@ -82,6 +190,25 @@ impl Display for OpCode {
             OpCode::Multiply => write!(f, "Multiply"),
             OpCode::Modulus => write!(f, "Modulus"),
             OpCode::Concat => write!(f, "Concat"),
+            OpCode::Range => write!(f, "Range"),
+            OpCode::RangeInclusive => write!(f, "RangeInclusive"),
+            OpCode::Slice => write!(f, "Slice"),
+            OpCode::SliceInclusive => write!(f, "SliceInclusive"),
+            OpCode::Len => write!(f, "Len"),
+            OpCode::CallMethod(num_args) => write!(f, "CallMethod {num_args}"),
+            OpCode::Yield => write!(f, "Yield"),
+            OpCode::Await => write!(f, "Await"),
+            OpCode::Throw => write!(f, "Throw"),
+            OpCode::IsTable => write!(f, "IsTable"),
+            OpCode::HasLen(len) => write!(f, "HasLen {len}"),
+            OpCode::HasMinLen(len) => write!(f, "HasMinLen {len}"),
+            OpCode::Rest(len) => write!(f, "Rest {len}"),
+            OpCode::BitAnd => write!(f, "BitAnd"),
+            OpCode::BitOr => write!(f, "BitOr"),
+            OpCode::BitXor => write!(f, "BitXor"),
+            OpCode::Shl => write!(f, "Shl"),
+            OpCode::Shr => write!(f, "Shr"),
+            OpCode::BitNot => write!(f, "BitNot"),
             OpCode::Negate => write!(f, "Negate"),
             OpCode::Not => write!(f, "Not"),
             OpCode::CmpEq => write!(f, "CmpEq"),
@@ -92,13 +219,253 @@ impl Display for OpCode {
             OpCode::CmpGEq => write!(f, "CmpGEq"),
             OpCode::CmpAnd => write!(f, "CmpAnd"),
             OpCode::CmpOr => write!(f, "CmpOr"),
+            OpCode::OpLocals(op, lhs, rhs) => write!(f, "OpLocals {op} {lhs} {rhs}"),
+            OpCode::OpLocalInt(op, lhs, rhs) => write!(f, "OpLocalInt {op} {lhs} {rhs}"),
+            OpCode::OpLocal(op, rhs) => write!(f, "OpLocal {op} {rhs}"),
+            OpCode::OpInt(op, rhs) => write!(f, "OpInt {op} {rhs}"),
             OpCode::JumpIfFalse(location) => write!(f, "JumpIfFalse {location}"),
+            OpCode::JumpIfNotUnit(location) => write!(f, "JumpIfNotUnit {location}"),
             OpCode::Jump(location) => write!(f, "Jump {location}"),
+            OpCode::Loop(location) => write!(f, "Loop {location}"),
+            OpCode::IntoIter => write!(f, "IntoIter"),
+            OpCode::ForIter(location) => write!(f, "ForIter {location}"),
+            OpCode::Try(location) => write!(f, "Try {location}"),
+            OpCode::EndTry => write!(f, "EndTry"),
             OpCode::Call(args) => write!(f, "Call {args}"),
+            OpCode::TailCall(args) => write!(f, "TailCall {args}"),
+            OpCode::CallSpread => write!(f, "CallSpread"),
             OpCode::CloseUpvalue(index) => write!(f, "CloseUpvalue {index}"),
+            OpCode::CloseUpvalueW(index) => write!(f, "CloseUpvalueW {index}"),
             OpCode::Pop => write!(f, "Pop"),
+            OpCode::PopScope(num) => write!(f, "PopScope {num}"),
             OpCode::Return => write!(f, "Return"),
             OpCode::ExtraArg(arg) => write!(f, "ExtraArg {arg}"),
         }
     }
 }
+
+/// The operators of the op codes that read their operands in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    IDivide,
+    Modulus,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Operator {
+    const ALL: [Operator; 12] = [
+        Operator::Add,
+        Operator::Subtract,
+        Operator::Multiply,
+        Operator::Divide,
+        Operator::IDivide,
+        Operator::Modulus,
+        Operator::Less,
+        Operator::LessEqual,
+        Operator::Greater,
+        Operator::GreaterEqual,
+        Operator::Equal,
+        Operator::NotEqual,
+    ];
+
+    /// The op code that applies the operator to the two values on top.
+    pub fn op_code(self) -> OpCode {
+        match self {
+            Operator::Add => OpCode::Add,
+            Operator::Subtract => OpCode::Subtract,
+            Operator::Multiply => OpCode::Multiply,
+            Operator::Divide => OpCode::Divide,
+            Operator::IDivide => OpCode::IDivide,
+            Operator::Modulus => OpCode::Modulus,
+            Operator::Less => OpCode::CmpLess,
+            Operator::LessEqual => OpCode::CmpLEq,
+            Operator::Greater => OpCode::CmpGreater,
+            Operator::GreaterEqual => OpCode::CmpGEq,
+            Operator::Equal => OpCode::CmpEq,
+            Operator::NotEqual => OpCode::CmpNEq,
+        }
+    }
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The same names as the op codes that push the result.
+        match self.op_code() {
+            OpCode::CmpLess => write!(f, "Less"),
+            OpCode::CmpLEq => write!(f, "LEq"),
+            OpCode::CmpGreater => write!(f, "Greater"),
+            OpCode::CmpGEq => write!(f, "GEq"),
+            OpCode::CmpEq => write!(f, "Eq"),
+            OpCode::CmpNEq => write!(f, "NEq"),
+            op_code => write!(f, "{op_code}"),
+        }
+    }
+}
+
+/// An argument of an op code, which is encoded as a byte.
+trait Arg: Sized {
+    fn to_byte(self) -> u8;
+    fn from_byte(byte: u8) -> Option<Self>;
+}
+
+impl Arg for u8 {
+    fn to_byte(self) -> u8 {
+        self
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        Some(byte)
+    }
+}
+
+impl Arg for Operator {
+    fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        Operator::ALL.get(byte as usize).copied()
+    }
+}
+
+/// Implements the encoding of op codes as bytes: a tag for the op code
+/// followed by its arguments. The tags are part of the format of compiled
+/// modules, so they must not change.
+macro_rules! op_code_tags {
+    ($($tag:literal $variant:ident $(($($arg:ident),+))?,)*) => {
+        impl OpCode {
+            /// Appends the bytes of the op code to `bytes`.
+            pub fn encode(self, bytes: &mut Vec<u8>) {
+                match self {
+                    $(OpCode::$variant $(($($arg),+))? => {
+                        bytes.push($tag);
+                        $($(bytes.push(Arg::to_byte($arg));)+)?
+                    })*
+                }
+            }
+
+            /// The number of bytes that the op code is encoded in.
+            pub fn size(self) -> usize {
+                match self {
+                    $(OpCode::$variant $(($($arg),+))? => {
+                        1 $($(+ std::mem::size_of_val(&$arg))+)?
+                    })*
+                }
+            }
+
+            /// Reads an op code from the start of `bytes`, and returns it
+            /// with the number of bytes it takes. Returns `None` if the bytes
+            /// don't start with an op code.
+            #[inline(always)]
+            pub fn decode(bytes: &[u8]) -> Option<(OpCode, usize)> {
+                let (&tag, rest) = bytes.split_first()?;
+                let mut args = rest.iter().copied();
+                let op_code = match tag {
+                    $($tag => OpCode::$variant $(($({
+                        let $arg = args.next()?;
+                        Arg::from_byte($arg)?
+                    }),+))?,)*
+                    _ => return None,
+                };
+                Some((op_code, bytes.len() - args.len()))
+            }
+        }
+    };
+}
+
+op_code_tags! {
+    0 LoadConst(a),
+    1 LoadConstW(a),
+    2 LoadUnit,
+    3 LoadTrue,
+    4 LoadFalse,
+    5 LoadInt(a),
+    6 GetLocal(a),
+    7 GetUpvalue(a),
+    8 GetLocalW(a),
+    9 GetUpvalueW(a),
+    10 GetModule(a),
+    11 GetTable,
+    12 SetLocal(a),
+    13 SetUpvalue(a),
+    14 SetLocalW(a),
+    15 SetUpvalueW(a),
+    16 SetTable,
+    17 CreateList(a),
+    18 CreateTable(a),
+    19 CreateListW(a),
+    20 CreateTableW(a),
+    21 Extend,
+    22 CreateTuple(a),
+    23 Unpack(a),
+    24 Format(a, b),
+    25 FormatW(a, b),
+    26 Closure(a),
+    27 Add,
+    28 Subtract,
+    29 Divide,
+    30 IDivide,
+    31 Multiply,
+    32 Modulus,
+    33 Concat,
+    34 Range,
+    35 RangeInclusive,
+    36 Slice,
+    37 SliceInclusive,
+    38 Len,
+    39 CallMethod(a),
+    40 Yield,
+    41 Await,
+    42 Throw,
+    43 IsTable,
+    44 HasLen(a),
+    45 HasMinLen(a),
+    46 Rest(a),
+    47 Negate,
+    48 Not,
+    49 BitAnd,
+    50 BitOr,
+    51 BitXor,
+    52 Shl,
+    53 Shr,
+    54 BitNot,
+    55 CmpEq,
+    56 CmpNEq,
+    57 CmpLess,
+    58 CmpGreater,
+    59 CmpLEq,
+    60 CmpGEq,
+    61 CmpAnd,
+    62 CmpOr,
+    63 JumpIfFalse(a),
+    64 JumpIfNotUnit(a),
+    65 Jump(a),
+    66 Loop(a),
+    67 IntoIter,
+    68 ForIter(a),
+    69 Try(a),
+    70 EndTry,
+    71 Call(a),
+    72 TailCall(a),
+    73 CallSpread,
+    74 CloseUpvalue(a),
+    75 CloseUpvalueW(a),
+    76 Pop,
+    77 PopScope(a),
+    78 Return,
+    79 ExtraArg(a),
+    80 OpLocals(a, b, c),
+    81 OpLocalInt(a, b, c),
+    82 OpLocal(a, b),
+    83 OpInt(a, b),
+    84 GetField(a),
+}
@@ -2,13 +2,18 @@ use std::{error::Error, fmt::Display};
 
 use crate::{
     ast::{
-        ArithmeticOperator, BooleanOperator, ComparisonOperator, Expression, Import, ImportSource,
-        InterpolatedArgument, Literal, Operation, PathPart, Statement, TableEntry, UnaryOperation,
+        ArithmeticOperator, BitwiseOperator, BooleanOperator, ComparisonOperator, Expression,
+        ExpressionKind, Import, ImportSource, InterpolatedArgument, Literal, MatchArm, Operation,
+        PathPart, Pattern, Span, Statement, TableEntry, Type, UnaryOperation,
     },
     lexer::Lexer,
+    stdlib::fmt::FormatSpec,
     token::{Token, TokenType},
 };
 
+/// The names, annotated types and default values of a function's arguments.
+type FunctionArgs = (Vec<String>, Vec<Option<Type>>, Vec<Option<Expression>>);
+
 #[derive(Clone)]
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
@@ -16,6 +21,12 @@ pub struct Parser<'a> {
     last_expr_line: usize,
     depth: usize,
     call_depth: usize,
+    /// Set while parsing a `match` outside of parentheses, where `|`
+    /// starts the next arm instead of being an operator.
+    in_match: bool,
+    /// Set while parsing an interpolated argument outside of parentheses,
+    /// where `:` starts its format spec instead of a method call.
+    in_interpolation: bool,
 }
 
 impl<'a> Parser<'a> {
@@ -26,6 +37,8 @@ impl<'a> Parser<'a> {
             last_expr_line: 0,
             depth: 0,
             call_depth: 0,
+            in_match: false,
+            in_interpolation: false,
         }
     }
 
@@ -52,17 +65,39 @@ impl<'a> Parser<'a> {
 
     pub fn get_last_expr_line(&self) -> &str {
         let source = self.lexer.source();
-        let position = &source
-            .chars()
-            .skip(self.last_expr_start_position)
-            .position(|c| c == '\n');
+        let position = source[self.last_expr_start_position..].find('\n');
         if let Some(position) = position {
-            &source[self.last_expr_start_position..self.last_expr_start_position + *position]
+            &source[self.last_expr_start_position..self.last_expr_start_position + position]
         } else {
             &source[self.last_expr_start_position..]
         }
     }
 
+    /// The span of the token the parser stopped at, which is where the last
+    /// parse error is.
+    pub fn span(&self) -> Span {
+        span(&self.lexer.clone().next())
+    }
+
+    /// The 1-based source line of the next token, as stored in statements.
+    fn line_no(&self) -> usize {
+        self.lexer.line() + 1
+    }
+
+    /// The span of the next token, where the expression about to be parsed
+    /// starts.
+    fn start(&self) -> Span {
+        let mut lexer = self.lexer.clone();
+        lexer.skip_comments_and_new_lines();
+        span(&lexer.next())
+    }
+
+    /// Makes an expression that goes from `start` to the last token parsed.
+    fn finish(&self, start: Span, kind: ExpressionKind) -> Expression {
+        let end = span(&self.lexer.last_token());
+        Expression::new(kind, start.to(end))
+    }
+
     fn expect(&mut self, token_type: TokenType) -> Result<Token, ParserError> {
         self.lexer
             .next_checked(token_type.clone())
@@ -96,31 +131,85 @@ impl<'a> Parser<'a> {
         let token = self.lexer.peek();
         self.last_expr_start_position = self.lexer.position();
         self.last_expr_line = self.lexer.line();
-        let line_no = self.lexer.line();
+        let line_no = self.line_no();
         let statement = match token {
             TokenType::Let => self.r#let()?,
-            TokenType::From => return Err(ParserError::NotImplemented),
-            TokenType::Import => {
+            TokenType::Pub if self.depth == 0 => {
                 self.lexer.next();
-
-                let source = if self.lexer.peek() == TokenType::DoubleQuote {
-                    match self.string()? {
-                        Expression::Literal(Literal::String(string)) => ImportSource::File(string),
-                        Expression::InterpolatedString { .. } => {
-                            return Err(ParserError::UnexpectedExpression(
-                                "interpolated string".to_string(),
-                            ));
-                        }
-                        _ => unreachable!(),
+                let mut statement = if self.lexer.peek() == TokenType::Const {
+                    self.r#const()?
+                } else {
+                    self.r#let()?
+                };
+                match &mut statement {
+                    Statement::Let { public, .. }
+                    | Statement::Function { public, .. }
+                    | Statement::Const { public, .. } => *public = true,
+                    // Destructured locals can't be exported one by one.
+                    _ => return Err(ParserError::InvalidPattern),
+                }
+                statement
+            }
+            TokenType::From => {
+                self.lexer.next();
+                let source = self.import_source()?;
+                self.expect(TokenType::Import)?;
+                let mut imports = Vec::new();
+                loop {
+                    let token = self.expect(TokenType::Ident)?;
+                    let ident = self.lexer.slice(token.span).to_string();
+                    let alias = if self.lexer.next_checked(TokenType::As).is_some() {
+                        let token = self.expect(TokenType::Ident)?;
+                        self.lexer.slice(token.span).to_string()
+                    } else {
+                        ident.clone()
+                    };
+                    imports.push(Import::Local { ident, alias });
+                    if self.lexer.next_checked(TokenType::Comma).is_none() {
+                        break;
                     }
+                }
+                Statement::Import {
+                    line_no,
+                    source,
+                    imports,
+                }
+            }
+            TokenType::Const if self.depth == 0 => self.r#const()?,
+            TokenType::Type if self.depth == 0 => {
+                self.lexer.next();
+                let token = self.expect(TokenType::Ident)?;
+                let ident = self.lexer.slice(token.span).to_string();
+                self.expect(TokenType::Assign)?;
+                self.expect(TokenType::LCurly)?;
+                self.lexer.skip_comments_and_new_lines();
+                let mut fields = Vec::new();
+                while let Some(token) = self.lexer.next_checked(TokenType::Ident) {
+                    fields.push(self.lexer.slice(token.span).to_string());
+                    self.lexer.skip_comments_and_new_lines();
+                    self.lexer.next_checked(TokenType::Comma);
+                    self.lexer.skip_comments_and_new_lines();
+                }
+                self.expect(TokenType::RCurly)?;
+                Statement::Type {
+                    line_no,
+                    ident,
+                    fields,
+                }
+            }
+            TokenType::Import => {
+                self.lexer.next();
+                let source = self.import_source()?;
+                let alias = if self.lexer.next_checked(TokenType::As).is_some() {
+                    let token = self.expect(TokenType::Ident)?;
+                    Some(self.lexer.slice(token.span).to_string())
                 } else {
-                    return Err(ParserError::NotImplemented);
+                    None
                 };
-
                 Statement::Import {
                     line_no,
                     source,
-                    imports: vec![Import::All { alias: None }],
+                    imports: vec![Import::All { alias }],
                 }
             }
             TokenType::Eos => return Err(ParserError::EndOfSource),
@@ -135,6 +224,48 @@ impl<'a> Parser<'a> {
         Ok(statement)
     }
 
+    /// Parses the module of an import, either a file path as a string or
+    /// the ident of a module already known to the loader.
+    /// Parses the rest of a parenthesized expression, or of a tuple if the
+    /// expression is followed by a comma.
+    fn parenthesized(&mut self) -> Result<Expression, ParserError> {
+        let start = self.start();
+        let expr = self.expression()?;
+        if self.lexer.peek() != TokenType::Comma {
+            self.expect(TokenType::RParen)?;
+            return Ok(expr);
+        }
+        let mut elements = vec![expr];
+        while self.lexer.next_checked(TokenType::Comma).is_some() {
+            self.lexer.skip_comments_and_new_lines();
+            elements.push(self.expression()?);
+        }
+        self.lexer.skip_comments_and_new_lines();
+        self.expect(TokenType::RParen)?;
+        Ok(self.finish(start, ExpressionKind::Tuple(elements)))
+    }
+
+    fn import_source(&mut self) -> Result<ImportSource, ParserError> {
+        if let Some(token) = self.lexer.next_checked(TokenType::Ident) {
+            return Ok(ImportSource::Module(
+                self.lexer.slice(token.span).to_string(),
+            ));
+        }
+        if self.lexer.peek() != TokenType::DoubleQuote {
+            return Err(ParserError::UnexpectedTokenOneOf(
+                vec![TokenType::DoubleQuote, TokenType::Ident],
+                self.lexer.peek(),
+            ));
+        }
+        match self.string()?.kind {
+            ExpressionKind::Literal(Literal::String(string)) => Ok(ImportSource::File(string)),
+            ExpressionKind::InterpolatedString { .. } => Err(ParserError::UnexpectedExpression(
+                "interpolated string".to_string(),
+            )),
+            _ => unreachable!(),
+        }
+    }
+
     fn expression(&mut self) -> Result<Expression, ParserError> {
         let mut lhs = self.primary()?;
         let mut previous_precedence = 0;
@@ -169,42 +300,53 @@ impl<'a> Parser<'a> {
                 self.primary()?
             };
 
-            lhs = Expression::Operation {
+            let span = lhs.span.to(rhs.span);
+            let kind = ExpressionKind::Operation {
                 lhs: lhs.into(),
                 operation,
                 rhs: rhs.into(),
             };
+            lhs = Expression::new(kind, span);
         }
     }
 
     fn primary(&mut self) -> Result<Expression, ParserError> {
-        match self.lexer.peek() {
+        let start = self.start();
+        let kind = match self.lexer.peek() {
             TokenType::Unit => {
                 self.lexer.next();
-                Ok(Expression::Literal(Literal::Unit))
+                ExpressionKind::Literal(Literal::Unit)
             }
             TokenType::SingleQuote => {
                 self.lexer.next();
-                let token = self.lexer.next_empty();
-                let str = self.lexer.slice(token.span);
-                let char = str
-                    .parse::<char>()
-                    .map_err(|e| ParserError::UnableToParseChar(e))?;
-                Ok(Expression::Literal(Literal::Char(char)))
+                let char = match self.lexer.next_raw_char() {
+                    Some('\\') => self.escape_sequence()?,
+                    Some('\'') | None => return Err(ParserError::InvalidCharLiteral),
+                    Some(char) => char,
+                };
+                self.expect(TokenType::SingleQuote)?;
+                ExpressionKind::Literal(Literal::Char(char))
             }
             TokenType::Minus => {
                 self.lexer.next();
-                Ok(Expression::UnaryOperation {
+                ExpressionKind::UnaryOperation {
                     operand: self.primary()?.into(),
                     operation: UnaryOperation::Negate,
-                })
+                }
             }
             TokenType::Not => {
                 self.lexer.next();
-                Ok(Expression::UnaryOperation {
+                ExpressionKind::UnaryOperation {
                     operand: self.primary()?.into(),
                     operation: UnaryOperation::Not,
-                })
+                }
+            }
+            TokenType::BinNot => {
+                self.lexer.next();
+                ExpressionKind::UnaryOperation {
+                    operand: self.primary()?.into(),
+                    operation: UnaryOperation::BitNot,
+                }
             }
             TokenType::Number => {
                 let token = self.lexer.next();
@@ -213,22 +355,20 @@ impl<'a> Parser<'a> {
                     if num.ends_with('.') {
                         num.push('0');
                     }
-                    num.parse::<f64>()
-                        .map(|n| Expression::Literal(Literal::Number(n)))
-                        .map_err(|e| ParserError::UnableToParseNumber(e))
+                    let n = num.parse().map_err(ParserError::UnableToParseNumber)?;
+                    ExpressionKind::Literal(Literal::Number(n))
                 } else {
-                    num.parse::<i64>()
-                        .map(|n| Expression::Literal(Literal::Integer(n)))
-                        .map_err(|e| ParserError::UnableToParseInt(e))
+                    let n = num.parse().map_err(ParserError::UnableToParseInt)?;
+                    ExpressionKind::Literal(Literal::Integer(n))
                 }
             }
-            TokenType::Function => self.function_expression(),
+            TokenType::Function => return self.function_expression(),
             TokenType::Ident => {
-                if self.call_depth == 0 && self.is_call()? {
+                return if self.call_depth == 0 && self.is_call()? {
                     self.call()
                 } else {
                     self.path()
-                }
+                };
             }
             TokenType::LParen => {
                 let mut dec = false;
@@ -238,12 +378,16 @@ impl<'a> Parser<'a> {
                 }
                 self.lexer.next();
                 self.lexer.skip_comments_and_new_lines();
-                let expr = self.expression()?;
+                let in_match = std::mem::take(&mut self.in_match);
+                let in_interpolation = std::mem::take(&mut self.in_interpolation);
+                let expr = self.parenthesized();
+                self.in_match = in_match;
+                self.in_interpolation = in_interpolation;
+                let expr = expr?;
                 if dec {
                     self.call_depth += 1;
                 }
-                self.expect(TokenType::RParen)?;
-                Ok(expr)
+                return Ok(expr);
             }
             TokenType::LBracket => {
                 self.lexer.next();
@@ -252,32 +396,73 @@ impl<'a> Parser<'a> {
                 while self.lexer.peek() != TokenType::RBracket
                     && self.lexer.peek() != TokenType::Eos
                 {
-                    let expr = self.primary()?;
+                    let expr = self.element()?;
                     arr.push(expr);
+                    self.lexer.skip_comments_and_new_lines();
                     self.lexer.next_checked(TokenType::Comma);
+                    self.lexer.skip_comments_and_new_lines();
                 }
                 self.expect(TokenType::RBracket)?;
-                Ok(Expression::Array(arr))
+                ExpressionKind::Array(arr)
             }
-            TokenType::LCurly => self.table(),
-            TokenType::DoubleQuote => self.string(),
+            TokenType::LCurly => return self.table(),
+            TokenType::DoubleQuote => return self.string(),
+            TokenType::RawString => return self.raw_string(),
             TokenType::True => {
                 self.lexer.next();
-                Ok(Expression::Literal(Literal::Bool(true)))
+                ExpressionKind::Literal(Literal::Bool(true))
             }
             TokenType::False => {
                 self.lexer.next();
-                Ok(Expression::Literal(Literal::Bool(false)))
+                ExpressionKind::Literal(Literal::Bool(false))
             }
             TokenType::If => {
                 self.lexer.next();
-                self.r#if()
+                self.r#if()?
             }
-            _ => Err(ParserError::NotAPrimaryExpression),
-        }
+            TokenType::Match => {
+                self.lexer.next();
+                self.r#match()?
+            }
+            TokenType::While => {
+                self.lexer.next();
+                self.r#while()?
+            }
+            TokenType::For => {
+                self.lexer.next();
+                self.r#for()?
+            }
+            TokenType::Try => {
+                self.lexer.next();
+                self.r#try()?
+            }
+            TokenType::Assert => {
+                self.lexer.next();
+                self.assert()?
+            }
+            TokenType::Yield => {
+                self.lexer.next();
+                ExpressionKind::Yield(self.expression()?.into())
+            }
+            TokenType::Await => {
+                self.lexer.next();
+                ExpressionKind::Await(self.expression()?.into())
+            }
+            TokenType::Break => {
+                self.lexer.next();
+                ExpressionKind::Break
+            }
+            TokenType::Continue => {
+                self.lexer.next();
+                ExpressionKind::Continue
+            }
+            _ => return Err(ParserError::NotAPrimaryExpression),
+        };
+        Ok(self.finish(start, kind))
     }
 
     fn table(&mut self) -> Result<Expression, ParserError> {
+        let start = self.start();
         self.expect(TokenType::LCurly)?;
         let mut table = Vec::new();
         self.lexer.skip_comments_and_new_lines();
@@ -291,9 +476,11 @@ impl<'a> Parser<'a> {
                 }
                 TokenType::Ident => {
                     let token = self.lexer.next();
-                    Expression::Literal(Literal::String(self.lexer.slice(token.span).to_string()))
+                    let key = self.lexer.slice(token.span.clone()).to_string();
+                    Expression::new(ExpressionKind::Literal(Literal::String(key)), span(&token))
                 }
                 TokenType::DoubleQuote => self.string()?,
+                TokenType::RawString => self.raw_string()?,
                 token => {
                     return Err(ParserError::UnexpectedTokenOneOf(
                         vec![
@@ -316,17 +503,92 @@ impl<'a> Parser<'a> {
             self.lexer.skip_comments_and_new_lines();
         }
         self.expect(TokenType::RCurly)?;
-        Ok(Expression::Table(table))
+        Ok(self.finish(start, ExpressionKind::Table(table)))
+    }
+
+    /// Parses what follows a `\` in a char literal: `n`, `r`, `t`, `0`,
+    /// `\`, `'`, `"` or a unicode escape like `u{1F600}`.
+    fn escape_sequence(&mut self) -> Result<char, ParserError> {
+        let char = match self.lexer.next_raw_char() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('\'') => '\'',
+            Some('"') => '"',
+            Some('u') => {
+                if self.lexer.next_raw_char() != Some('{') {
+                    return Err(ParserError::InvalidEscapeSequence);
+                }
+                let mut code = String::new();
+                loop {
+                    match self.lexer.next_raw_char() {
+                        Some('}') => break,
+                        Some(digit) if digit.is_ascii_hexdigit() && code.len() < 6 => {
+                            code.push(digit)
+                        }
+                        _ => return Err(ParserError::InvalidEscapeSequence),
+                    }
+                }
+                u32::from_str_radix(&code, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(ParserError::InvalidEscapeSequence)?
+            }
+            _ => return Err(ParserError::InvalidEscapeSequence),
+        };
+        Ok(char)
+    }
+
+    fn r#const(&mut self) -> Result<Statement, ParserError> {
+        let line_no = self.line_no();
+        self.expect(TokenType::Const)?;
+        let token = self.expect(TokenType::Ident)?;
+        let ident = self.lexer.slice(token.span).to_string();
+        self.expect(TokenType::Assign)?;
+        Ok(Statement::Const {
+            line_no,
+            ident,
+            value: self.expression()?,
+            public: false,
+        })
     }
 
     fn r#let(&mut self) -> Result<Statement, ParserError> {
-        let line_no = self.lexer.line();
+        let line_no = self.line_no();
         self.expect(TokenType::Let)?;
-        if self.lexer.peek_nth(1) == TokenType::Ident || self.lexer.peek_nth(1) == TokenType::Unit {
+        if self.lexer.next_checked(TokenType::LParen).is_some() {
+            let mut idents = Vec::new();
+            loop {
+                let token = self.expect(TokenType::Ident)?;
+                idents.push(self.lexer.slice(token.span).to_string());
+                if self.lexer.next_checked(TokenType::Comma).is_none() {
+                    break;
+                }
+            }
+            self.expect(TokenType::RParen)?;
+            self.expect(TokenType::Assign)?;
+            self.lexer.skip_comments_and_new_lines();
+            return Ok(Statement::LetTuple {
+                line_no,
+                idents,
+                value: self.expression()?,
+            });
+        }
+        if matches!(
+            self.lexer.peek_nth(1),
+            TokenType::Ident | TokenType::Unit | TokenType::LParen
+        ) {
             self.function_statement()
         } else {
             let token = self.expect(TokenType::Ident)?;
             let ident = self.lexer.slice(token.span).to_string();
+            let ty = if self.lexer.next_checked(TokenType::Colon).is_some() {
+                Some(self.r#type()?)
+            } else {
+                None
+            };
             let value = if self.lexer.next_checked(TokenType::Assign).is_none() {
                 None
             } else {
@@ -335,8 +597,10 @@ impl<'a> Parser<'a> {
             };
             Ok(Statement::Let {
                 ident,
+                ty,
                 value,
                 line_no,
+                public: false,
             })
         }
     }
@@ -348,6 +612,7 @@ impl<'a> Parser<'a> {
             self.call_depth -= 1;
         }
         self.depth += 1;
+        let start = self.start();
         let statements = if self.lexer.peek() == TokenType::NewLine {
             let start_indentation = self.lexer.indentation();
             self.lexer.next(); // Skip new line
@@ -361,10 +626,22 @@ impl<'a> Parser<'a> {
                     || matches!(
                         stm,
                         Statement::Expression {
-                            expression: Expression::Operation {
-                                operation: Operation::Assignment,
+                            expression: Expression {
+                                kind: ExpressionKind::Operation {
+                                    operation: Operation::Assignment,
+                                    ..
+                                } | ExpressionKind::Call { .. }
+                                    | ExpressionKind::While { .. }
+                                    | ExpressionKind::For { .. }
+                                    | ExpressionKind::If { .. }
+                                    | ExpressionKind::Try { .. }
+                                    | ExpressionKind::Assert { .. }
+                                    | ExpressionKind::Yield(_)
+                                    | ExpressionKind::Await(_)
+                                    | ExpressionKind::Break
+                                    | ExpressionKind::Continue,
                                 ..
-                            } | Expression::Call { .. },
+                            },
                             ..
                         }
                     )
@@ -381,7 +658,7 @@ impl<'a> Parser<'a> {
             self.call_depth += 1;
         }
         self.depth -= 1;
-        Ok(Expression::Block(statements))
+        Ok(self.finish(start, ExpressionKind::Block(statements)))
     }
 
     fn block_with_indentation(
@@ -412,16 +689,23 @@ impl<'a> Parser<'a> {
         match cloned.lexer.peek() {
             TokenType::Ident => {
                 cloned.path()?;
+                if !self.in_interpolation && cloned.lexer.peek_empty() == TokenType::Colon {
+                    return Ok(true);
+                }
                 Ok(cloned
                     .lexer
                     .next_indented()
-                    .is_some_and(|t| t.token_type.is_primary() && t.token_type != TokenType::Minus))
+                    .is_some_and(|t| match t.token_type {
+                        TokenType::Minus => false,
+                        TokenType::Spread => true,
+                        token => token.is_primary(),
+                    }))
             }
             _ => Ok(false),
         }
     }
 
-    fn r#if(&mut self) -> Result<Expression, ParserError> {
+    fn r#if(&mut self) -> Result<ExpressionKind, ParserError> {
         let if_indentation = self.lexer.indentation();
         let condition = self.expression()?.into();
         self.expect(TokenType::Then)?;
@@ -432,8 +716,10 @@ impl<'a> Parser<'a> {
             .next_checked_continued(TokenType::Else, if_indentation)
             .is_some()
         {
+            let start = self.start();
             if self.lexer.next_checked(TokenType::If).is_some() {
-                Some(self.r#if()?.into())
+                let kind = self.r#if()?;
+                Some(self.finish(start, kind).into())
             } else {
                 Some(self.block()?.into())
             }
@@ -441,48 +727,330 @@ impl<'a> Parser<'a> {
             None
         };
 
-        Ok(Expression::If {
+        Ok(ExpressionKind::If {
             condition,
             block,
             r#else,
         })
     }
 
+    fn r#try(&mut self) -> Result<ExpressionKind, ParserError> {
+        let try_indentation = self.lexer.indentation();
+        let block = self.block()?.into();
+        if self
+            .lexer
+            .next_checked_continued(TokenType::Catch, try_indentation)
+            .is_none()
+        {
+            return Err(ParserError::UnexpectedToken(
+                TokenType::Catch,
+                self.lexer.peek(),
+            ));
+        }
+        let token = self.expect(TokenType::Ident)?;
+        let ident = self.lexer.slice(token.span).to_string();
+        self.expect(TokenType::ThinArrow)?;
+        let catch = self.block()?.into();
+        Ok(ExpressionKind::Try {
+            block,
+            ident,
+            catch,
+        })
+    }
+
+    /// Parses the rest of `assert condition "message"`. A condition that
+    /// ends with a call takes the message as an argument of the call, so
+    /// such conditions need parentheses.
+    fn assert(&mut self) -> Result<ExpressionKind, ParserError> {
+        let start = self.lexer.clone().next().span.start;
+        let condition = self.expression()?.into();
+        let text = self
+            .lexer
+            .slice(start..self.lexer.last_token().span.end)
+            .to_string();
+        let message = match self.lexer.peek_indented() {
+            Some(TokenType::DoubleQuote | TokenType::RawString) => Some(self.primary()?.into()),
+            _ => None,
+        };
+        Ok(ExpressionKind::Assert {
+            condition,
+            text,
+            message,
+        })
+    }
+
+    fn r#while(&mut self) -> Result<ExpressionKind, ParserError> {
+        let condition = self.expression()?.into();
+        self.expect(TokenType::Do)?;
+        let block = self.block()?.into();
+        Ok(ExpressionKind::While { condition, block })
+    }
+
+    fn r#for(&mut self) -> Result<ExpressionKind, ParserError> {
+        let token = self.expect(TokenType::Ident)?;
+        let ident = self.lexer.slice(token.span).to_string();
+        self.expect(TokenType::In)?;
+        let iterable = self.expression()?.into();
+        self.expect(TokenType::Do)?;
+        let block = self.block()?.into();
+        Ok(ExpressionKind::For {
+            ident,
+            iterable,
+            block,
+        })
+    }
+
+    fn r#match(&mut self) -> Result<ExpressionKind, ParserError> {
+        let in_match = std::mem::replace(&mut self.in_match, true);
+        let result = self.match_arms();
+        self.in_match = in_match;
+        result
+    }
+
+    fn match_arms(&mut self) -> Result<ExpressionKind, ParserError> {
+        let match_indentation = self.lexer.indentation();
+        let value = self.expression()?.into();
+        let mut arms = Vec::new();
+        while self.next_match_arm(match_indentation) {
+            let pattern = self.pattern()?;
+            let guard = if self.lexer.next_checked(TokenType::If).is_some() {
+                Some(self.expression()?)
+            } else {
+                None
+            };
+            self.expect(TokenType::ThinArrow)?;
+            let expression = self.block()?;
+            arms.push(MatchArm {
+                pattern,
+                guard,
+                expression,
+            });
+        }
+        if arms.is_empty() {
+            return Err(ParserError::ExpectedMatchArm);
+        }
+        Ok(ExpressionKind::Match { value, arms })
+    }
+
+    /// Consumes the `|` starting the next arm, which is either on the same
+    /// line or on a following line indented at least as much as the `match`.
+    fn next_match_arm(&mut self, indentation: usize) -> bool {
+        let mut lexer = self.lexer.clone();
+        if lexer.peek() == TokenType::NewLine {
+            if lexer.peek_indentation() < indentation {
+                return false;
+            }
+            lexer.skip_new_lines();
+        }
+        if lexer.next().token_type == TokenType::BinOr {
+            self.lexer = lexer;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, ParserError> {
+        match self.lexer.peek() {
+            TokenType::Ident => {
+                let token = self.lexer.next();
+                let ident = self.lexer.slice(token.span);
+                if ident == "_" {
+                    Ok(Pattern::Wildcard)
+                } else {
+                    Ok(Pattern::Binding(ident.to_string()))
+                }
+            }
+            TokenType::Unit
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Number
+            | TokenType::Minus
+            | TokenType::DoubleQuote
+            | TokenType::RawString
+            | TokenType::SingleQuote => match self.primary()?.kind {
+                ExpressionKind::Literal(literal) => Ok(Pattern::Literal(literal)),
+                ExpressionKind::UnaryOperation {
+                    operand,
+                    operation: UnaryOperation::Negate,
+                } => match operand.kind {
+                    ExpressionKind::Literal(Literal::Integer(i)) => {
+                        Ok(Pattern::Literal(Literal::Integer(-i)))
+                    }
+                    ExpressionKind::Literal(Literal::Number(n)) => {
+                        Ok(Pattern::Literal(Literal::Number(-n)))
+                    }
+                    _ => Err(ParserError::InvalidPattern),
+                },
+                _ => Err(ParserError::InvalidPattern),
+            },
+            TokenType::LCurly => {
+                self.lexer.next();
+                let mut fields = Vec::new();
+                self.lexer.skip_comments_and_new_lines();
+                while let Some(token) = self.lexer.next_checked(TokenType::Ident) {
+                    let field = self.lexer.slice(token.span).to_string();
+                    let pattern = if self.lexer.next_checked(TokenType::Colon).is_some() {
+                        self.pattern()?
+                    } else {
+                        Pattern::Binding(field.clone())
+                    };
+                    fields.push((field, pattern));
+                    self.lexer.skip_comments_and_new_lines();
+                    if self.lexer.next_checked(TokenType::Comma).is_none() {
+                        break;
+                    }
+                    self.lexer.skip_comments_and_new_lines();
+                }
+                self.expect(TokenType::RCurly)?;
+                Ok(Pattern::Table(fields))
+            }
+            TokenType::LBracket => {
+                self.lexer.next();
+                let mut elements = Vec::new();
+                let mut rest = None;
+                while self.lexer.peek() != TokenType::RBracket {
+                    if self.lexer.next_checked(TokenType::Spread).is_some() {
+                        let pattern = self.pattern()?;
+                        if !pattern.is_irrefutable() {
+                            return Err(ParserError::InvalidPattern);
+                        }
+                        rest = Some(pattern.into());
+                        break;
+                    }
+                    elements.push(self.pattern()?);
+                    if self.lexer.next_checked(TokenType::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.expect(TokenType::RBracket)?;
+                Ok(Pattern::Array { elements, rest })
+            }
+            _ => Err(ParserError::InvalidPattern),
+        }
+    }
+
     fn function_statement(&mut self) -> Result<Statement, ParserError> {
-        let line_no = self.lexer.line();
+        let line_no = self.line_no();
         let token = self.expect(TokenType::Ident)?;
         let ident = self.lexer().slice(token.span).to_string();
-        let args = if self.lexer.next_checked(TokenType::Unit).is_none() {
+        let (args, arg_types, arg_defaults) = if self.lexer.next_checked(TokenType::Unit).is_none()
+        {
             self.function_args(TokenType::Assign)?
         } else {
-            Vec::new()
+            (Vec::new(), Vec::new(), Vec::new())
         };
         self.expect(TokenType::Assign)?;
-        let expr = self.block()?.into();
+        let expr = self.block()?;
         Ok(Statement::Function {
             ident,
             args,
+            arg_types,
+            arg_defaults,
             expr,
             line_no,
+            public: false,
         })
     }
 
     fn function_expression(&mut self) -> Result<Expression, ParserError> {
+        let start = self.start();
         self.expect(TokenType::Function)?;
-        let args = self.function_args(TokenType::ThinArrow)?;
+        let (args, arg_types, arg_defaults) = if self.lexer.next_checked(TokenType::Unit).is_none()
+        {
+            self.function_args(TokenType::ThinArrow)?
+        } else {
+            (Vec::new(), Vec::new(), Vec::new())
+        };
         self.expect(TokenType::ThinArrow)?;
         let expr = self.block()?.into();
-        Ok(Expression::Function { args, expr })
+        let kind = ExpressionKind::Function {
+            args,
+            arg_types,
+            arg_defaults,
+            expr,
+        };
+        Ok(self.finish(start, kind))
     }
 
-    fn function_args(&mut self, func_token: TokenType) -> Result<Vec<String>, ParserError> {
+    /// Parses the arguments of a function, each either a plain identifier or
+    /// a parenthesized one with a type annotation, a default value or both,
+    /// such as `(a: int)`, `(a = 1)` or `(a: int = 1)`.
+    fn function_args(&mut self, func_token: TokenType) -> Result<FunctionArgs, ParserError> {
         let mut args = Vec::new();
+        let mut arg_types = Vec::new();
+        let mut arg_defaults = Vec::new();
         while self.lexer.peek_indented().is_some_and(|t| t != func_token) {
+            let parenthesized = self.lexer.next_checked(TokenType::LParen).is_some();
             let token = self.expect_indented(TokenType::Ident)?;
             let ident = self.lexer.slice(token.span).to_string();
             args.push(ident);
+            let (mut ty, mut default) = (None, None);
+            if parenthesized {
+                if self.lexer.next_checked(TokenType::Colon).is_some() {
+                    ty = Some(self.r#type()?);
+                }
+                if self.lexer.next_checked(TokenType::Assign).is_some() {
+                    default = Some(self.expression()?);
+                }
+                if ty.is_none() && default.is_none() {
+                    return Err(ParserError::UnexpectedTokenOneOf(
+                        [TokenType::Colon, TokenType::Assign].to_vec(),
+                        self.lexer.peek(),
+                    ));
+                }
+                self.expect(TokenType::RParen)?;
+            }
+            if default.is_none() && arg_defaults.iter().any(Option::is_some) {
+                return Err(ParserError::ArgWithoutDefaultAfterDefault);
+            }
+            arg_types.push(ty);
+            arg_defaults.push(default);
+        }
+        Ok((args, arg_types, arg_defaults))
+    }
+
+    /// Parses a type annotation.
+    fn r#type(&mut self) -> Result<Type, ParserError> {
+        match self.lexer.peek() {
+            TokenType::Unit => {
+                self.lexer.next();
+                Ok(Type::Unit)
+            }
+            TokenType::Ident => {
+                let token = self.expect(TokenType::Ident)?;
+                Ok(Type::Named(self.lexer.slice(token.span).to_string()))
+            }
+            TokenType::LBracket => {
+                self.lexer.next();
+                let ty = self.r#type()?;
+                self.expect(TokenType::RBracket)?;
+                Ok(Type::Array(ty.into()))
+            }
+            TokenType::LParen => {
+                self.lexer.next();
+                let mut types = vec![self.r#type()?];
+                while self.lexer.next_checked(TokenType::Comma).is_some() {
+                    types.push(self.r#type()?);
+                }
+                self.expect(TokenType::RParen)?;
+                Ok(if types.len() == 1 {
+                    types.pop().unwrap()
+                } else {
+                    Type::Tuple(types)
+                })
+            }
+            t => Err(ParserError::UnexpectedTokenOneOf(
+                [
+                    TokenType::Ident,
+                    TokenType::Unit,
+                    TokenType::LBracket,
+                    TokenType::LParen,
+                ]
+                .to_vec(),
+                t,
+            )),
         }
-        Ok(args)
     }
 
     fn callee(&mut self) -> Result<Expression, ParserError> {
@@ -505,10 +1073,21 @@ impl<'a> Parser<'a> {
             .next_checked_continued(TokenType::Pipe, indentation)
             .is_some()
         {
-            match self.call_simple()? {
-                Expression::Call { callee, mut args } => {
-                    args.insert(0, call);
-                    call = Expression::Call { callee, args }
+            let piped = self.call_simple()?;
+            let span = call.span.to(piped.span);
+            match piped.kind {
+                ExpressionKind::Call { callee, mut args } => {
+                    // A `_` argument marks where the piped value goes,
+                    // which is otherwise the first argument.
+                    let mut placeholders = args.iter_mut().filter(|arg| {
+                        matches!(&arg.kind, ExpressionKind::Path { ident, parts } if ident == "_" && parts.is_empty())
+                    });
+                    match (placeholders.next(), placeholders.next()) {
+                        (Some(_), Some(_)) => return Err(ParserError::MultiplePipePlaceholders),
+                        (Some(placeholder), None) => *placeholder = call,
+                        _ => args.insert(0, call),
+                    }
+                    call = Expression::new(ExpressionKind::Call { callee, args }, span)
                 }
                 _ => unreachable!(),
             }
@@ -519,17 +1098,55 @@ impl<'a> Parser<'a> {
 
     fn call_simple(&mut self) -> Result<Expression, ParserError> {
         self.call_depth += 1;
-        let callee = self.callee()?.into();
+        let start = self.start();
+        let mut callee = self.callee()?;
+        if !self.in_interpolation && self.lexer.peek_empty() == TokenType::Colon {
+            self.lexer.next_empty();
+            let token = self.expect(TokenType::Ident)?;
+            let kind = ExpressionKind::Method {
+                receiver: callee.into(),
+                method: self.lexer.slice(token.span).to_string(),
+            };
+            callee = self.finish(start, kind);
+        }
+        let callee = callee.into();
         let mut args = Vec::new();
 
-        while self.lexer.peek_indented().is_some_and(|t| t.is_primary()) {
+        // A lone `()` after the callee is a call without arguments rather
+        // than a call with a single unit argument.
+        let unit_call = self.lexer.peek_indented() == Some(TokenType::Unit);
+        if unit_call {
+            self.lexer.next_indented();
+        }
+
+        while self
+            .lexer
+            .peek_indented()
+            .is_some_and(|t| t.is_primary() || t == TokenType::Spread)
+        {
             self.lexer.skip_comments_and_new_lines();
-            let arg = self.primary()?;
+            let arg = self.element()?;
             args.push(arg);
         }
 
+        if unit_call && !args.is_empty() {
+            args.insert(0, ExpressionKind::Literal(Literal::Unit).into());
+        }
+
         self.call_depth -= 1;
-        Ok(Expression::Call { callee, args })
+        Ok(self.finish(start, ExpressionKind::Call { callee, args }))
+    }
+
+    /// Parses an array element or a call argument, either of which can be
+    /// spread.
+    fn element(&mut self) -> Result<Expression, ParserError> {
+        let start = self.start();
+        if self.lexer.next_checked(TokenType::Spread).is_some() {
+            let kind = ExpressionKind::Spread(self.primary()?.into());
+            Ok(self.finish(start, kind))
+        } else {
+            self.primary()
+        }
     }
 
     fn operator(&mut self) -> Option<Operation> {
@@ -552,12 +1169,21 @@ impl<'a> Parser<'a> {
             TokenType::Less => Some(Operation::Comparison(ComparisonOperator::Less)),
             TokenType::LessEqual => Some(Operation::Comparison(ComparisonOperator::LessEqual)),
             TokenType::Assign => Some(Operation::Assignment),
-            TokenType::Dots => Some(Operation::Concat),
+            TokenType::Concat => Some(Operation::Concat),
+            TokenType::Coalesce => Some(Operation::Coalesce),
+            TokenType::Dots => Some(Operation::Range { inclusive: false }),
+            TokenType::DotsEq => Some(Operation::Range { inclusive: true }),
+            TokenType::BinAnd => Some(Operation::Bitwise(BitwiseOperator::And)),
+            TokenType::BinOr if !self.in_match => Some(Operation::Bitwise(BitwiseOperator::Or)),
+            TokenType::BinXor => Some(Operation::Bitwise(BitwiseOperator::Xor)),
+            TokenType::Lsh => Some(Operation::Bitwise(BitwiseOperator::Shl)),
+            TokenType::Rsh => Some(Operation::Bitwise(BitwiseOperator::Shr)),
             _ => None,
         }
     }
 
     fn string(&mut self) -> Result<Expression, ParserError> {
+        let start = self.start();
         self.expect(TokenType::DoubleQuote)?;
         let mut args = Vec::new();
         let mut offset = 0;
@@ -571,24 +1197,34 @@ impl<'a> Parser<'a> {
                 && self.lexer.peek_nth(1) != TokenType::LCurly
             {
                 self.lexer.next();
-                if self.lexer.peek() == TokenType::Ident {
-                    let arg = self.path()?;
-                    args.push(InterpolatedArgument {
-                        offset,
-                        expression: arg,
-                    });
-                } else if self.lexer.peek() == TokenType::LParen {
-                    let arg = self.primary()?;
-                    args.push(InterpolatedArgument {
-                        offset,
-                        expression: arg,
-                    });
+                // The expression starts afresh, as in parentheses, even
+                // when the string is an argument or in a match arm.
+                let call_depth = std::mem::take(&mut self.call_depth);
+                let in_match = std::mem::take(&mut self.in_match);
+                let in_interpolation = std::mem::replace(&mut self.in_interpolation, true);
+                let expression = self.expression();
+                self.call_depth = call_depth;
+                self.in_match = in_match;
+                self.in_interpolation = in_interpolation;
+                let expression = expression?;
+                let spec = if self.lexer.next_checked(TokenType::Colon).is_some() {
+                    let mut spec = String::new();
+                    while !matches!(self.lexer.peek_empty(), TokenType::RCurly | TokenType::Eos) {
+                        let token = self.lexer.next_empty();
+                        spec.push_str(self.lexer.slice(token.span));
+                    }
+                    if FormatSpec::parse(&spec).is_none() {
+                        return Err(ParserError::InvalidFormatSpec(spec));
+                    }
+                    Some(spec)
                 } else {
-                    return Err(ParserError::UnexpectedTokenOneOf(
-                        vec![TokenType::LParen, TokenType::Ident],
-                        self.lexer.peek(),
-                    ));
-                }
+                    None
+                };
+                args.push(InterpolatedArgument {
+                    offset,
+                    expression,
+                    spec,
+                });
                 self.expect(TokenType::RCurly)?;
                 offset = 0;
             } else {
@@ -600,17 +1236,36 @@ impl<'a> Parser<'a> {
             }
         }
         self.expect(TokenType::DoubleQuote)?;
-        if args.is_empty() {
-            Ok(Expression::Literal(Literal::String(string)))
+        let kind = if args.is_empty() {
+            ExpressionKind::Literal(Literal::String(string))
         } else {
-            Ok(Expression::InterpolatedString {
+            ExpressionKind::InterpolatedString {
                 format: string.to_string(),
                 arguments: args,
-            })
+            }
+        };
+        Ok(self.finish(start, kind))
+    }
+
+    /// Parses a triple-quoted string. Its contents are taken verbatim, without
+    /// interpolation, except for a new line directly after the opening quotes.
+    fn raw_string(&mut self) -> Result<Expression, ParserError> {
+        let token = self.expect(TokenType::RawString)?;
+        let string = self.lexer.slice(token.span.clone());
+        if string.len() < 6 || !string.ends_with("\"\"\"") {
+            return Err(ParserError::EarlyEos);
         }
+        let string = &string[3..string.len() - 3];
+        let string = string
+            .strip_prefix("\r\n")
+            .or_else(|| string.strip_prefix('\n'))
+            .unwrap_or(string);
+        let kind = ExpressionKind::Literal(Literal::String(string.to_string()));
+        Ok(Expression::new(kind, span(&token)))
     }
 
     fn path(&mut self) -> Result<Expression, ParserError> {
+        let start = self.start();
         let token = self.expect(TokenType::Ident)?;
         let ident = self.lexer.slice(token.span).to_string();
         let mut path_parts = Vec::new();
@@ -618,26 +1273,61 @@ impl<'a> Parser<'a> {
             match self.lexer.peek_empty() {
                 TokenType::LBracket => {
                     self.lexer.next_empty();
-                    let expr = self.primary()?;
+                    let start = match self.lexer.peek() {
+                        TokenType::Dots | TokenType::DotsEq => None,
+                        _ => Some(self.primary()?),
+                    };
+                    let part = match (start, self.lexer.peek()) {
+                        (start, range @ (TokenType::Dots | TokenType::DotsEq)) => {
+                            self.lexer.next();
+                            let end = match self.lexer.peek() {
+                                TokenType::RBracket => None,
+                                _ => Some(self.primary()?),
+                            };
+                            PathPart::Slice {
+                                start,
+                                end,
+                                inclusive: range == TokenType::DotsEq,
+                            }
+                        }
+                        (Some(index), _) => PathPart::Index(index),
+                        (None, _) => unreachable!(),
+                    };
                     self.expect(TokenType::RBracket)?;
-                    path_parts.push(PathPart::Index(expr));
+                    path_parts.push(part);
                 }
                 TokenType::Dot => {
                     self.lexer.next_empty();
                     if self.lexer.peek_empty() == TokenType::Empty {
                         return Err(ParserError::InvalidEmptySpace);
                     }
-                    let token = self.expect(TokenType::Ident)?;
+                    // `yield` is a keyword, but also a function of the
+                    // `Coroutine` module.
+                    let token = match self.lexer.peek() {
+                        TokenType::Yield => self.lexer.next(),
+                        _ => self.expect(TokenType::Ident)?,
+                    };
                     let ident = self.lexer.slice(token.span);
                     path_parts.push(PathPart::Ident(ident.to_string()));
                 }
                 _ => break,
             }
         }
-        Ok(Expression::Path {
+        let kind = ExpressionKind::Path {
             ident,
             parts: path_parts,
-        })
+        };
+        Ok(self.finish(start, kind))
+    }
+}
+
+/// The span of a single token.
+fn span(token: &Token) -> Span {
+    Span {
+        start: token.span.start,
+        end: token.span.end,
+        line: token.line + 1,
+        column: token.column + 1,
     }
 }
 
@@ -660,7 +1350,13 @@ pub enum ParserError {
     FoundExpressionWhenStatementWasExpected,
     TopLevelExpressionNotAllowed,
     NotImplemented,
-    UnableToParseChar(std::char::ParseCharError),
+    ExpectedMatchArm,
+    InvalidPattern,
+    InvalidCharLiteral,
+    InvalidEscapeSequence,
+    MultiplePipePlaceholders,
+    ArgWithoutDefaultAfterDefault,
+    InvalidFormatSpec(String),
 }
 
 impl Error for ParserError {}
@@ -701,7 +1397,396 @@ impl Display for ParserError {
                 write!(f, "Top level expresion not allowed")
             }
             ParserError::NotImplemented => write!(f, "Not implemented"),
-            ParserError::UnableToParseChar(err) => write!(f, "Unable to parse number: `{err}`"),
+            ParserError::ExpectedMatchArm => write!(f, "Expected a match arm"),
+            ParserError::InvalidPattern => write!(f, "Invalid pattern"),
+            ParserError::InvalidCharLiteral => write!(f, "Invalid char literal"),
+            ParserError::InvalidEscapeSequence => write!(f, "Invalid escape sequence"),
+            ParserError::MultiplePipePlaceholders => {
+                write!(f, "More than one `_` placeholder in a piped call")
+            }
+            ParserError::ArgWithoutDefaultAfterDefault => {
+                write!(
+                    f,
+                    "Argument without a default value after one with a default"
+                )
+            }
+            ParserError::InvalidFormatSpec(spec) => write!(f, "Invalid format spec: `{spec}`"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{Expression, ExpressionKind, Import, Literal, Pattern, Span, Statement, Type};
+
+    use super::{Parser, ParserError};
+
+    #[test]
+    fn line_numbers() {
+        let mut parser = Parser::new("let a = 1\n\n# comment\nlet f () =\n    Io.print a\n    a");
+        assert!(matches!(
+            parser.parse(),
+            Ok(Statement::Let { line_no: 1, .. })
+        ));
+        let Ok(Statement::Function { line_no, expr, .. }) = parser.parse() else {
+            panic!("expected a function statement");
+        };
+        assert_eq!(line_no, 4);
+        let ExpressionKind::Block(block) = expr.kind else {
+            panic!("expected a block");
+        };
+        assert!(matches!(
+            block.as_slice(),
+            [
+                Statement::Expression { line_no: 5, .. },
+                Statement::Expression { line_no: 6, .. }
+            ]
+        ));
+    }
+
+    #[test]
+    fn spans() {
+        let mut parser = Parser::new("let a = 1\nlet b = f (x + 2) y");
+        parser.parse().unwrap();
+        let Ok(Statement::Let {
+            value: Some(call), ..
+        }) = parser.parse()
+        else {
+            panic!("expected a let statement");
+        };
+        let span = |start, end, column| Span {
+            start,
+            end,
+            line: 2,
+            column,
+        };
+        assert_eq!(call.span, span(18, 29, 9));
+        let ExpressionKind::Call { args, .. } = call.kind else {
+            panic!("expected a call");
+        };
+        assert_eq!(args[0].span, span(21, 26, 12));
+        assert_eq!(args[1].span, span(28, 29, 19));
+    }
+
+    #[test]
+    fn multi_line_array() {
+        let mut parser = Parser::new("let a = [\n    1,\n    # the second\n\n    2 # last\n]");
+        let Ok(Statement::Let {
+            value:
+                Some(Expression {
+                    kind: ExpressionKind::Array(array),
+                    ..
+                }),
+            ..
+        }) = parser.parse()
+        else {
+            panic!("expected an array");
+        };
+        assert_eq!(array.len(), 2);
+    }
+
+    #[test]
+    fn multi_line_table() {
+        let mut parser = Parser::new("let t = {\n    a: 1, # first\n\n    # second\n    b: 2\n}");
+        let Ok(Statement::Let {
+            value:
+                Some(Expression {
+                    kind: ExpressionKind::Table(table),
+                    ..
+                }),
+            ..
+        }) = parser.parse()
+        else {
+            panic!("expected a table");
+        };
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn match_arms() {
+        let mut parser =
+            Parser::new("let f x =\n    match x\n    | 1 -> 2\n    | -1 -> 3\n    | _ -> x");
+        let Ok(Statement::Function {
+            expr:
+                Expression {
+                    kind: ExpressionKind::Block(block),
+                    ..
+                },
+            ..
+        }) = parser.parse()
+        else {
+            panic!("expected a function");
+        };
+        let [Statement::Expression {
+            expression:
+                Expression {
+                    kind: ExpressionKind::Match { arms, .. },
+                    ..
+                },
+            ..
+        }] = block.as_slice()
+        else {
+            panic!("expected a match expression");
+        };
+        let patterns: Vec<_> = arms.iter().map(|arm| &arm.pattern).collect();
+        assert_eq!(
+            patterns,
+            [
+                &Pattern::Literal(Literal::Integer(1)),
+                &Pattern::Literal(Literal::Integer(-1)),
+                &Pattern::Wildcard
+            ]
+        );
+    }
+
+    #[test]
+    fn char_literals() {
+        let source = r"let a = ['a', 'é', '\n', '\t', '\\', '\'', '\0', '\u{1F600}']";
+        let mut parser = Parser::new(source);
+        let Ok(Statement::Let {
+            value:
+                Some(Expression {
+                    kind: ExpressionKind::Array(array),
+                    ..
+                }),
+            ..
+        }) = parser.parse()
+        else {
+            panic!("expected an array");
+        };
+        let chars: Vec<_> = array
+            .iter()
+            .map(|e| match e.kind {
+                ExpressionKind::Literal(Literal::Char(c)) => c,
+                _ => panic!("expected a char"),
+            })
+            .collect();
+        assert_eq!(chars, ['a', 'é', '\n', '\t', '\\', '\'', '\0', '😀']);
+    }
+
+    #[test]
+    fn invalid_char_literals() {
+        for source in [
+            r"let a = '\q'",
+            "let a = ''",
+            "let a = 'ab'",
+            r"let a = '\u{110000}'",
+        ] {
+            assert!(Parser::new(source).parse().is_err(), "{source}");
         }
     }
+
+    #[test]
+    fn raw_string() {
+        let source = "let a = \"\"\"\n{ \"key\": [1, 2] }\n  {done}\"\"\"\nlet b = 1";
+        let mut parser = Parser::new(source);
+        let Ok(Statement::Let {
+            value:
+                Some(Expression {
+                    kind: ExpressionKind::Literal(Literal::String(string)),
+                    ..
+                }),
+            ..
+        }) = parser.parse()
+        else {
+            panic!("expected a string");
+        };
+        assert_eq!(string, "{ \"key\": [1, 2] }\n  {done}");
+        assert!(matches!(
+            parser.parse(),
+            Ok(Statement::Let { line_no: 4, .. })
+        ));
+        assert!(Parser::new("let a = \"\"\"abc\"\"").parse().is_err());
+    }
+
+    #[test]
+    fn selective_import() {
+        let mut parser = Parser::new("from \"utils\" import a, b as c");
+        let Ok(Statement::Import { imports, .. }) = parser.parse() else {
+            panic!("expected an import");
+        };
+        assert_eq!(
+            imports,
+            [
+                Import::Local {
+                    ident: "a".to_string(),
+                    alias: "a".to_string()
+                },
+                Import::Local {
+                    ident: "b".to_string(),
+                    alias: "c".to_string()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn public_statements() {
+        let mut parser = Parser::new("pub let a = 1\npub let f x = x\nlet b = 2");
+        assert!(matches!(
+            parser.parse(),
+            Ok(Statement::Let { public: true, .. })
+        ));
+        assert!(matches!(
+            parser.parse(),
+            Ok(Statement::Function { public: true, .. })
+        ));
+        assert!(matches!(
+            parser.parse(),
+            Ok(Statement::Let { public: false, .. })
+        ));
+        assert!(Parser::new("let f () =\n    pub let a = 1\n    a")
+            .parse()
+            .is_err());
+    }
+
+    #[test]
+    fn type_annotations() {
+        let mut parser = Parser::new(
+            "let x: int = 1\nlet add (a: int) b (c: [(int, string)]) = a\nlet f = fn (a: ()) -> a",
+        );
+        assert_eq!(
+            parser.parse(),
+            Ok(Statement::Let {
+                line_no: 1,
+                ident: "x".to_string(),
+                ty: Some(Type::Named("int".to_string())),
+                value: Some(ExpressionKind::Literal(Literal::Integer(1)).into()),
+                public: false,
+            })
+        );
+        let Ok(Statement::Function {
+            args, arg_types, ..
+        }) = parser.parse()
+        else {
+            panic!("expected a function");
+        };
+        assert_eq!(args, ["a", "b", "c"]);
+        assert_eq!(
+            arg_types,
+            [
+                Some(Type::Named("int".to_string())),
+                None,
+                Some(Type::Array(
+                    Type::Tuple(vec![
+                        Type::Named("int".to_string()),
+                        Type::Named("string".to_string())
+                    ])
+                    .into()
+                )),
+            ]
+        );
+        let Ok(Statement::Let {
+            value:
+                Some(Expression {
+                    kind: ExpressionKind::Function { arg_types, .. },
+                    ..
+                }),
+            ..
+        }) = parser.parse()
+        else {
+            panic!("expected a lambda");
+        };
+        assert_eq!(arg_types, [Some(Type::Unit)]);
+    }
+
+    #[test]
+    fn structural_patterns() {
+        let mut parser = Parser::new("{status: 200, body}");
+        assert_eq!(
+            parser.pattern(),
+            Ok(Pattern::Table(vec![
+                (
+                    "status".to_string(),
+                    Pattern::Literal(Literal::Integer(200))
+                ),
+                ("body".to_string(), Pattern::Binding("body".to_string())),
+            ]))
+        );
+        let mut parser = Parser::new("[head, _, ...rest]");
+        assert_eq!(
+            parser.pattern(),
+            Ok(Pattern::Array {
+                elements: vec![Pattern::Binding("head".to_string()), Pattern::Wildcard],
+                rest: Some(Pattern::Binding("rest".to_string()).into()),
+            })
+        );
+        assert_eq!(
+            Parser::new("[a, ...[b]]").pattern(),
+            Err(ParserError::InvalidPattern)
+        );
+    }
+
+    #[test]
+    fn pipe_placeholder() {
+        let path = |ident: &str| {
+            Expression::from(ExpressionKind::Path {
+                ident: ident.to_string(),
+                parts: Vec::new(),
+            })
+        };
+        let piped = || {
+            Expression::from(ExpressionKind::Call {
+                callee: path("g").into(),
+                args: vec![path("x")],
+            })
+        };
+        assert_eq!(
+            Parser::new("g x |> f a _ b").expression(),
+            Ok(ExpressionKind::Call {
+                callee: path("f").into(),
+                args: vec![path("a"), piped(), path("b")],
+            }
+            .into())
+        );
+        assert_eq!(
+            Parser::new("g x |> f a").expression(),
+            Ok(ExpressionKind::Call {
+                callee: path("f").into(),
+                args: vec![piped(), path("a")],
+            }
+            .into())
+        );
+        assert_eq!(
+            Parser::new("g x |> f _ _").expression(),
+            Err(ParserError::MultiplePipePlaceholders)
+        );
+    }
+
+    #[test]
+    fn default_arguments() {
+        let Ok(Statement::Function {
+            arg_types,
+            arg_defaults,
+            ..
+        }) = Parser::new("let f a (b = 1) (c: int = 2) = a").parse()
+        else {
+            panic!("expected a function");
+        };
+        assert_eq!(
+            arg_types,
+            [None, None, Some(Type::Named("int".to_string()))]
+        );
+        assert_eq!(
+            arg_defaults,
+            [
+                None,
+                Some(ExpressionKind::Literal(Literal::Integer(1)).into()),
+                Some(ExpressionKind::Literal(Literal::Integer(2)).into())
+            ]
+        );
+        assert_eq!(
+            Parser::new("let f (a = 1) b = a").parse(),
+            Err(ParserError::ArgWithoutDefaultAfterDefault)
+        );
+    }
+
+    #[test]
+    fn import_line_number() {
+        let mut parser = Parser::new("\n\nimport \"other\"");
+        assert!(matches!(
+            parser.parse(),
+            Ok(Statement::Import { line_no: 3, .. })
+        ));
+    }
 }
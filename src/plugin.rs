@@ -0,0 +1,34 @@
+use std::{error::Error, fmt::Display};
+
+use crate::state::Module;
+
+/// An entry point for crates that provide native modules.
+///
+/// Module idents share one namespace with the standard library and with
+/// each other, so plugins should give their modules a distinctive name,
+/// typically prefixed with the plugin's name (e.g. `SqliteDb` rather than
+/// `Db`). Registering a module whose ident is already taken fails.
+pub trait Plugin {
+    fn name(&self) -> &str;
+    fn modules(&self) -> Vec<Module>;
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    ModuleAlreadyExists { plugin: String, module: String },
+}
+
+impl Error for PluginError {}
+
+impl Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PluginError::ModuleAlreadyExists { plugin, module } => {
+                write!(
+                    f,
+                    "Plugin `{plugin}` defines module `{module}` which already exists"
+                )
+            }
+        }
+    }
+}
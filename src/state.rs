@@ -1,10 +1,18 @@
-use std::{cell::RefCell, fmt::Display, io::Write, path::Path, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Display,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use crate::{
-    compiler::{Compiler, CompilerError},
-    op::{ConstIdx, OpCode},
-    stdlib,
-    value::{Closure, NativeFunction, Value},
+    binary,
+    compiler::{Compiler, CompilerError, CompilerErrorKind},
+    op::OpCode,
+    value::{ArrayRef, Closure, NativeFn, NativeFunction, Value},
     vm::{RuntimeError, Vm},
 };
 
@@ -18,17 +26,94 @@ pub struct ModuleAlias {
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
 pub struct ModuleLoader {
     modules: Vec<Rc<Module>>,
-    #[cfg(not(target_arch = "wasm32"))]
-    root: String,
+    /// The index of the first module with each ident.
+    indices: HashMap<String, usize>,
+    /// The directories that module files are looked up in, in order.
+    search_paths: Vec<PathBuf>,
+    /// The index of each module loaded from a file, by canonical path.
+    paths: HashMap<PathBuf, usize>,
+    /// The files being compiled, outermost first.
+    loading: Vec<PathBuf>,
+    /// Whether modules are type checked before they are compiled.
+    typecheck: bool,
+    /// Whether operations on constants are computed by the compiler.
+    fold_constants: bool,
+    /// Whether the code of compiled prototypes is run through a peephole
+    /// optimizer that also removes unreachable code.
+    peephole: bool,
+    /// The strings of every loaded module and of the natives that ask for
+    /// them.
+    strings: Interner,
+    /// The array constants allocated since a VM last took them, see
+    /// [`ModuleLoader::alloc_array`].
+    arrays: Vec<ArrayRef>,
+    /// Whether module files can be loaded, which imports of modules that
+    /// aren't loaded yet do.
+    file_imports: bool,
+    /// The modules that were compiled against the locals of an imported
+    /// module that has since been reloaded with different ones, so they
+    /// have to be reloaded themselves before they can run.
+    stale: HashSet<usize>,
+}
+
+/// A set of strings that hands out one allocation for each distinct string,
+/// so that the string constants of all modules share their allocations and
+/// equal strings are equal by pointer, which [`Value`] equality checks first.
+///
+/// Interned strings are never freed, so only strings known when compiling,
+/// and keys that natives look up, are interned, never ones built at runtime.
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Interned>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, string: &str) -> Rc<String> {
+        if let Some(Interned(interned)) = self.strings.get(string) {
+            return interned.clone();
+        }
+        let interned = Rc::new(string.to_string());
+        self.strings.insert(Interned(interned.clone()));
+        interned
+    }
+}
+
+/// An interned string, which can be looked up by `&str` because it hashes
+/// like one.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Interned(Rc<String>);
+
+impl std::borrow::Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
 }
 
+/// The environment variable with extra directories to look up modules in,
+/// separated like the `PATH` of the platform. They are searched before the
+/// root so that they can override its modules.
+pub const SEARCH_PATH_VAR: &str = "FOCUS_PATH";
+
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
 impl ModuleLoader {
-    pub fn new(_root: &str) -> Self {
+    pub fn new(root: &str) -> Self {
+        let mut search_paths: Vec<PathBuf> = std::env::var_os(SEARCH_PATH_VAR)
+            .map(|paths| std::env::split_paths(&paths).collect())
+            .unwrap_or_default();
+        search_paths.push(PathBuf::from(root));
         Self {
-            modules: vec![Rc::new(stdlib::io::module())],
-            #[cfg(not(target_arch = "wasm32"))]
-            root: _root.to_string(),
+            modules: Vec::new(),
+            indices: HashMap::new(),
+            search_paths,
+            paths: HashMap::new(),
+            loading: Vec::new(),
+            typecheck: false,
+            fold_constants: false,
+            peephole: false,
+            strings: Interner::default(),
+            arrays: Vec::new(),
+            file_imports: true,
+            stale: HashSet::new(),
         }
     }
 
@@ -38,16 +123,28 @@ impl ModuleLoader {
         source: &str,
     ) -> Result<usize, CompilerError> {
         let compiler = Compiler::new(source, self);
-        let module = Rc::new(compiler.compile_module(ident)?);
-        let index = self.modules.len();
-        self.modules.push(module.clone());
-        Ok(index)
+        let module = compiler.compile_module(ident)?;
+        Ok(self.push_module(module))
+    }
+
+    /// Loads a module compiled with [`binary::write_module`], such as the
+    /// contents of a `.flb` file.
+    pub fn load_module_from_bytes(&mut self, bytes: &[u8]) -> Result<usize, CompilerError> {
+        let module = binary::read_module(bytes, self)?;
+        Ok(self.push_module(module))
     }
 }
 
 impl ModuleLoader {
     pub fn add_module(&mut self, module: Module) {
+        self.push_module(module);
+    }
+
+    fn push_module(&mut self, module: Module) -> usize {
+        let index = self.modules.len();
+        self.indices.entry(module.ident.clone()).or_insert(index);
         self.modules.push(Rc::new(module));
+        index
     }
 
     pub fn add_modules(&mut self, modules: Vec<Module>) {
@@ -56,30 +153,272 @@ impl ModuleLoader {
         }
     }
 
+    /// Adds a native function to the native module `module`, creating the
+    /// module if it doesn't exist yet. A function with the same ident is
+    /// replaced.
+    pub fn add_native_function(
+        &mut self,
+        module: &str,
+        ident: &str,
+        function: Rc<RefCell<NativeFn>>,
+    ) -> Result<(), RuntimeError> {
+        let value = Value::Closure(Rc::new(Closure::from_native(Rc::new(NativeFunction {
+            ident: ident.to_string(),
+            function,
+        }))));
+        let Some(index) = self.module(module) else {
+            self.add_module(Module::new(
+                module,
+                ModuleValue::Native(vec![value]),
+                vec![ident.to_string()],
+            ));
+            return Ok(());
+        };
+        let module = Rc::make_mut(&mut self.modules[index]);
+        let position = module.local(ident);
+        let ModuleValue::Native(values) = &mut module.value else {
+            return Err(RuntimeError::Custom(format!(
+                "`{}` is not a native module",
+                module.ident
+            )));
+        };
+        match position {
+            Some(position) => values[position] = value,
+            None => {
+                values.push(value);
+                module.locals.push(ident.to_string());
+            }
+        }
+        Ok(())
+    }
+
     pub fn module(&self, ident: &str) -> Option<usize> {
-        self.modules.iter().position(|m| m.ident == ident)
+        self.indices.get(ident).copied()
     }
 
     pub fn module_at(&self, index: usize) -> Option<Rc<Module>> {
         self.modules.get(index).cloned()
     }
 
-    pub fn load_module(&mut self, path: impl AsRef<Path>) -> usize {
-        let path = if path.as_ref().extension().is_none() {
-            let mut buf = path.as_ref().to_path_buf();
-            buf.set_extension("fl");
-            buf.canonicalize().unwrap()
+    pub fn modules(&self) -> &[Rc<Module>] {
+        &self.modules
+    }
+
+    pub fn module_names(&self) -> impl Iterator<Item = &str> {
+        self.modules.iter().map(|m| m.ident.as_str())
+    }
+
+    /// Recompiles the module at `index` from `source`, keeping its index so
+    /// that modules importing it pick up the new code.
+    ///
+    /// Importing modules refer to its locals by index, so if the new source
+    /// declares different locals they are marked stale, and fail to run
+    /// until they are reloaded too. Values that were already produced by the
+    /// old module still refer to the old code. Native modules cannot be
+    /// reloaded.
+    pub fn reload(&mut self, index: usize, source: &str) -> Result<(), CompilerError> {
+        let old = match self.modules.get(index) {
+            Some(module) if matches!(module.value, ModuleValue::Normal(_)) => module.clone(),
+            _ => return Err(CompilerErrorKind::CannotReloadModule(index).into()),
+        };
+        let compiler = Compiler::new(source, self);
+        let module = compiler.compile_module(&old.ident)?;
+        if module.locals != old.locals {
+            for (i, other) in self.modules.iter().enumerate() {
+                if i != index && other.imports().contains(&index) {
+                    self.stale.insert(i);
+                }
+            }
+        }
+        self.stale.remove(&index);
+        self.modules[index] = Rc::new(module);
+        Ok(())
+    }
+
+    /// Whether the module at `index` has to be reloaded before it can run,
+    /// see [`ModuleLoader::reload`].
+    pub fn is_stale(&self, index: usize) -> bool {
+        self.stale.contains(&index)
+    }
+
+    /// Enables the type checking of the modules compiled from now on. See
+    /// [`crate::typecheck`].
+    pub fn set_typecheck(&mut self, typecheck: bool) {
+        self.typecheck = typecheck;
+    }
+
+    pub fn typecheck(&self) -> bool {
+        self.typecheck
+    }
+
+    /// Enables the folding of arithmetic, comparisons and concatenation of
+    /// literals into single constants in the modules compiled from now on.
+    pub fn set_fold_constants(&mut self, fold_constants: bool) {
+        self.fold_constants = fold_constants;
+    }
+
+    pub fn fold_constants(&self) -> bool {
+        self.fold_constants
+    }
+
+    /// Enables the removal of unreachable code and of op code sequences
+    /// without any effect from the modules compiled from now on.
+    pub fn set_peephole(&mut self, peephole: bool) {
+        self.peephole = peephole;
+    }
+
+    pub fn peephole(&self) -> bool {
+        self.peephole
+    }
+
+    /// Allows or forbids loading module files, which is allowed by default.
+    /// Without it, scripts can only import the modules that the host loaded
+    /// itself, which along with leaving out [`crate::stdlib::StdlibSet::IO`]
+    /// keeps them away from the file system.
+    pub fn set_file_imports(&mut self, file_imports: bool) {
+        self.file_imports = file_imports;
+    }
+
+    pub fn file_imports(&self) -> bool {
+        self.file_imports
+    }
+
+    /// Returns the shared allocation of `string`, see [`Interner`].
+    pub fn intern(&mut self, string: &str) -> Rc<String> {
+        self.strings.intern(string)
+    }
+
+    /// Allocates an array constant. The compiler and the binary reader
+    /// allocate them without a VM, so the VM takes them with
+    /// [`ModuleLoader::take_arrays`] to track them before it runs anything.
+    pub fn alloc_array(&mut self, array: Vec<Value>) -> ArrayRef {
+        let array = Rc::new(RefCell::new(array));
+        self.arrays.push(array.clone());
+        array
+    }
+
+    pub fn take_arrays(&mut self) -> Vec<ArrayRef> {
+        std::mem::take(&mut self.arrays)
+    }
+
+    /// Adds a directory to look up module files in, after the ones that
+    /// are already searched.
+    pub fn add_search_path(&mut self, path: impl Into<PathBuf>) {
+        self.search_paths.push(path.into());
+    }
+
+    pub fn search_paths(&self) -> &[PathBuf] {
+        &self.search_paths
+    }
+
+    /// Finds the file of the module at `path` in the search paths. If
+    /// `path` has no extension, the source with the `fl` extension is looked
+    /// for, and then the compiled module with the `flb` extension.
+    pub fn resolve_path(&self, path: impl AsRef<Path>) -> Result<PathBuf, ModuleError> {
+        let mut path = path.as_ref().to_path_buf();
+        let candidates = if path.extension().is_none() {
+            path.set_extension("fl");
+            vec![path.clone(), path.with_extension("flb")]
         } else {
-            path.as_ref().canonicalize().unwrap()
+            vec![path.clone()]
         };
+        let Some(found) = self
+            .search_paths
+            .iter()
+            .flat_map(|dir| candidates.iter().map(|path| dir.join(path)))
+            .find(|candidate| candidate.is_file())
+        else {
+            return Err(ModuleError::NotFound {
+                path,
+                search_paths: self.search_paths.clone(),
+            });
+        };
+        found
+            .canonicalize()
+            .map_err(|error| ModuleError::Io { path: found, error })
+    }
+
+    /// Compiles the module file at `path`, unless the same file has been
+    /// loaded before, in which case the index of that module is returned.
+    /// Files that start with [`binary::MAGIC`] are loaded as compiled
+    /// modules.
+    pub fn load_module(&mut self, path: impl AsRef<Path>) -> Result<usize, CompilerError> {
+        if !self.file_imports {
+            return Err(ModuleError::FileImportsDisabled(path.as_ref().to_path_buf()).into());
+        }
+        let path = self.resolve_path(path)?;
+        if let Some(&index) = self.paths.get(&path) {
+            return Ok(index);
+        }
+        if let Some(start) = self.loading.iter().position(|p| p == &path) {
+            let chain = self.loading[start..]
+                .iter()
+                .chain([&path])
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(CompilerErrorKind::CircularImport(chain).into());
+        }
         let name = path.with_extension("");
-        let name = name.file_name().unwrap().to_str().unwrap();
-        let source = std::fs::read_to_string(path).unwrap();
-        let compiler = Compiler::new(&source, self);
-        let module = Rc::new(compiler.compile_module(name).unwrap());
-        let index = self.modules.len();
-        self.modules.push(module.clone());
-        index
+        let name = name.file_name().unwrap().to_string_lossy().into_owned();
+        let io_error = |error| ModuleError::Io {
+            path: path.clone(),
+            error,
+        };
+        let bytes = std::fs::read(&path).map_err(io_error)?;
+        self.loading.push(path.clone());
+        let module = if binary::is_bytecode(&bytes) {
+            binary::read_module(&bytes, self)
+        } else {
+            String::from_utf8(bytes)
+                .map_err(|error| {
+                    io_error(std::io::Error::new(ErrorKind::InvalidData, error)).into()
+                })
+                .and_then(|source| Compiler::new(&source, self).compile_module(&name))
+        };
+        self.loading.pop();
+        let index = self.push_module(module?);
+        self.paths.insert(path, index);
+        Ok(index)
+    }
+}
+
+#[derive(Debug)]
+pub enum ModuleError {
+    NotFound {
+        path: PathBuf,
+        search_paths: Vec<PathBuf>,
+    },
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// A module file that can't be loaded, see [`ModuleLoader::set_file_imports`].
+    FileImportsDisabled(PathBuf),
+}
+
+impl Error for ModuleError {}
+
+impl Display for ModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModuleError::NotFound { path, search_paths } => {
+                write!(f, "Module `{}` not found in:", path.display())?;
+                for search_path in search_paths {
+                    write!(f, " `{}`", search_path.display())?;
+                }
+                Ok(())
+            }
+            ModuleError::Io { path, error } => {
+                write!(f, "Cannot read module `{}`: {error}", path.display())
+            }
+            ModuleError::FileImportsDisabled(path) => {
+                write!(
+                    f,
+                    "Cannot import `{}`: file imports are disabled",
+                    path.display()
+                )
+            }
+        }
     }
 }
 
@@ -107,6 +446,8 @@ pub struct Module {
     pub ident: String,
     pub locals: Vec<String>,
     pub value: ModuleValue,
+    /// The indices of the locals that other modules cannot import.
+    pub private: HashSet<usize>,
 }
 
 impl Module {
@@ -115,6 +456,7 @@ impl Module {
             ident: name.to_string(),
             locals,
             value,
+            private: HashSet::new(),
         }
     }
 
@@ -126,6 +468,29 @@ impl Module {
         self.locals.iter().position(|l| l == ident)
     }
 
+    /// Returns the index of the local `ident` if other modules can import
+    /// it.
+    pub fn export(&self, ident: &str) -> Option<usize> {
+        self.local(ident).filter(|i| !self.private.contains(i))
+    }
+
+    pub fn exports(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.locals
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !self.private.contains(i))
+            .map(|(i, local)| (i, local.as_str()))
+    }
+
+    /// The indices of the modules that the code of this module gets.
+    pub fn imports(&self) -> Vec<usize> {
+        let mut imports = Vec::new();
+        if let ModuleValue::Normal(prototype) = &self.value {
+            imported_modules(prototype, &mut imports);
+        }
+        imports
+    }
+
     pub fn dump(&self, buf: &mut impl Write) -> Result<(), std::io::Error> {
         match &self.value {
             ModuleValue::Native(native) => {
@@ -139,6 +504,17 @@ impl Module {
     }
 }
 
+fn imported_modules(prototype: &Prototype, imports: &mut Vec<usize>) {
+    for (_, op) in prototype.op_codes() {
+        if let OpCode::GetModule(index) = op {
+            imports.push(index as usize);
+        }
+    }
+    for prototype in &prototype.prototypes {
+        imported_modules(prototype, imports);
+    }
+}
+
 pub struct NativeModuleBuilder {
     pub ident: String,
     pub locals: Vec<String>,
@@ -171,11 +547,7 @@ impl NativeModuleBuilder {
     }
 
     pub fn build(self) -> Module {
-        Module {
-            ident: self.ident,
-            locals: self.locals,
-            value: ModuleValue::Native(self.values),
-        }
+        Module::new(&self.ident, ModuleValue::Native(self.values), self.locals)
     }
 }
 
@@ -185,9 +557,11 @@ pub struct Name {
     pub start_pc: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct DebugInfo {
     pub locals: Vec<Local>,
+    /// The line of each byte of the code, so that any byte of an op code
+    /// finds its line.
     pub lines: Vec<usize>,
 }
 
@@ -202,55 +576,172 @@ impl DebugInfo {
 
 #[derive(Debug, Clone)]
 pub struct Prototype {
-    pub code: Vec<OpCode>,
+    /// The op codes, each encoded as a tag byte followed by its arguments
+    /// (see [`OpCode::encode`]). Jumps count bytes, and frames keep the code
+    /// of their function so that they don't need to get it from the
+    /// prototype for every op code.
+    pub code: Rc<[u8]>,
     pub constants: Vec<Value>,
     pub ident: String,
     pub num_args: usize,
+    /// Where to start in the code when called without some of the
+    /// arguments that have default values: the first entry for all of them
+    /// left out, and the last, where the body starts, for none.
+    pub entries: Vec<usize>,
     pub debug_info: DebugInfo,
     pub upvalues: Vec<Upvalue>,
     pub prototypes: Vec<Rc<Prototype>>,
     pub is_anonymous: bool,
+    /// Whether the function yields, which makes calling it create an
+    /// iterator over the values it yields.
+    pub is_generator: bool,
+    /// The cache of each `GetField` in the code, by the index it is given.
+    pub field_caches: Box<[RefCell<FieldCache>]>,
+}
+
+/// What a `GetField` got last time, so that getting the same field of the
+/// same table or module again skips looking it up.
+#[derive(Debug, Clone, Default)]
+pub enum FieldCache {
+    #[default]
+    Empty,
+    /// A field of a table, which is still there while the table has the
+    /// same version.
+    Table {
+        version: u64,
+        key: Value,
+        value: Value,
+    },
+    /// An export of a module, whose values stay the same while the VM's
+    /// module values have the same version.
+    Module {
+        module: *const Module,
+        version: u64,
+        key: Value,
+        value: Value,
+    },
+}
+
+impl FieldCache {
+    /// Remembers the value of a field, for the tables and modules whose
+    /// fields can be cached.
+    pub fn new(target: &Value, key: Value, value: Value, module_version: u64) -> Self {
+        match target {
+            Value::Table(table) => FieldCache::Table {
+                version: table.borrow().version(),
+                key,
+                value,
+            },
+            Value::Module(module) => FieldCache::Module {
+                module: Rc::as_ptr(module),
+                version: module_version,
+                key,
+                value,
+            },
+            _ => FieldCache::Empty,
+        }
+    }
+
+    /// The value of the field if it is the one that was cached.
+    pub fn get(&self, target: &Value, key: &Value, module_version: u64) -> Option<Value> {
+        match (self, target) {
+            (
+                FieldCache::Table {
+                    version,
+                    key: cached,
+                    value,
+                },
+                Value::Table(table),
+            ) if table.borrow().version() == *version && cached == key => Some(value.clone()),
+            (
+                FieldCache::Module {
+                    module,
+                    version,
+                    key: cached,
+                    value,
+                },
+                Value::Module(target),
+            ) if Rc::as_ptr(target) == *module && *version == module_version && cached == key => {
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
 }
 
 impl Prototype {
     pub fn new(ident: String, is_anonymous: bool) -> Self {
         Self {
-            code: Vec::new(),
+            code: Rc::default(),
             constants: Vec::new(),
             ident,
             num_args: 0,
+            entries: Vec::new(),
             upvalues: Vec::new(),
             debug_info: DebugInfo::new(),
             prototypes: Vec::new(),
             is_anonymous,
+            is_generator: false,
+            field_caches: Box::default(),
         }
     }
 
+    /// Makes an empty cache for each `GetField` in the code.
+    pub fn init_field_caches(&mut self) {
+        let len = self
+            .op_codes()
+            .filter_map(|(_, op)| match op {
+                OpCode::GetField(cache) => Some(cache as usize + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        self.field_caches = (0..len).map(|_| RefCell::default()).collect();
+    }
+
     pub fn ident(&self) -> &str {
         &self.ident
     }
-    pub fn line(&self, index: usize) -> usize {
-        self.debug_info.lines[index]
+    /// The line of the op code that the byte at `offset` is part of.
+    pub fn line(&self, offset: usize) -> usize {
+        self.debug_info.lines[offset]
     }
 
-    pub fn push_op_code(&mut self, op_code: OpCode, line: usize) {
-        self.code.push(op_code);
-        self.debug_info.lines.push(line);
+    /// The number of arguments without a default value.
+    pub fn num_required_args(&self) -> usize {
+        self.num_args - self.entries.len().saturating_sub(1)
     }
 
-    pub fn op_codes(&self) -> &[OpCode] {
-        &self.code
+    /// Where to start when called with `num_args` arguments.
+    pub fn entry(&self, num_args: usize) -> usize {
+        self.entries
+            .get(num_args - self.num_required_args())
+            .copied()
+            .unwrap_or(0)
     }
 
-    pub fn add_constant(&mut self, value: Value) -> Option<ConstIdx> {
-        if self.constants.len() > u8::MAX as usize {
+    /// Decodes the code, with the offset of each op code.
+    pub fn op_codes(&self) -> impl Iterator<Item = (usize, OpCode)> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let (op_code, size) = OpCode::decode(&self.code[offset..])?;
+            offset += size;
+            Some((offset - size, op_code))
+        })
+    }
+
+    /// Adds a constant, unless there is an equal one already, and returns
+    /// its index. Indices that don't fit in a byte are loaded with the wide
+    /// op codes, which take 16-bit indices.
+    pub fn add_constant(&mut self, value: Value) -> Option<usize> {
+        if let Some(idx) = self.constants.iter().position(|v| v == &value) {
+            Some(idx)
+        } else if self.constants.len() > u16::MAX as usize {
             None
-        } else if let Some(idx) = self.constants.iter().position(|v| v == &value) {
-            Some(idx as ConstIdx)
         } else {
             let idx = self.constants.len();
             self.constants.push(value);
-            Some(idx as ConstIdx)
+            Some(idx)
         }
     }
 
@@ -270,10 +761,10 @@ impl Prototype {
         writeln!(buf, "fn {}", self.ident())?;
 
         let mut last_line = 0;
-        for (i, op) in self.op_codes().iter().enumerate() {
-            let line = self.line(i);
-            if last_line < line + 1 {
-                last_line = line + 1;
+        for (offset, op) in self.op_codes() {
+            let line = self.line(offset);
+            if last_line < line {
+                last_line = line;
                 writeln!(buf, "{last_line}:")?;
             }
             writeln!(buf, " {op}")?;
@@ -312,12 +803,14 @@ impl PartialEq for Prototype {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum StackTraceInfo {
     NativeFunction { ident: String },
     Prototype { ident: String, line: usize },
 }
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct StackTrace {
     info: Vec<StackTraceInfo>,
 }
@@ -326,10 +819,16 @@ impl StackTrace {
     pub fn new(trace_info: Vec<StackTraceInfo>) -> Self {
         Self { info: trace_info }
     }
+
+    /// The calls in the trace, innermost first.
+    pub fn info(&self) -> &[StackTraceInfo] {
+        &self.info
+    }
 }
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
 impl StackTrace {
+    #[allow(clippy::inherent_to_string_shadow_display)]
     pub fn to_string(&self) -> String {
         ToString::to_string(self)
     }
@@ -0,0 +1,93 @@
+use std::cmp::Ordering;
+
+use crate::{
+    state::{Module, NativeModuleBuilder},
+    value::{Table, Value},
+    vm::{RuntimeError, Vm},
+};
+
+/// Builds a table out of an array of `[key, value]` pairs.
+fn to_table(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let array = vm.pop()?.as_array().ok_or(RuntimeError::UnexpectedType)?;
+    #[allow(clippy::mutable_key_type)]
+    let mut table = Table::new();
+    for pair in array.borrow().iter() {
+        let pair = pair
+            .clone()
+            .as_array()
+            .ok_or(RuntimeError::UnexpectedType)?;
+        let pair = pair.borrow();
+        let [key, value] = pair.as_slice() else {
+            return Err(RuntimeError::Custom(
+                "to_table expects an array of [key, value] pairs".to_string(),
+            ));
+        };
+        table.insert(key.clone(), value.clone());
+    }
+    Ok(Value::Table(vm.alloc_table(table)))
+}
+
+/// Returns a sorted copy of an array. Arrays are ordered by their elements.
+fn sort(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let array = vm.pop()?.as_array().ok_or(RuntimeError::UnexpectedType)?;
+    let mut sorted = array.borrow().clone();
+    let mut is_comparable = true;
+    sorted.sort_by(|a, b| {
+        a.partial_cmp(b).unwrap_or_else(|| {
+            is_comparable = false;
+            Ordering::Equal
+        })
+    });
+    if !is_comparable {
+        return Err(RuntimeError::Custom(
+            "sort expects an array of comparable values".to_string(),
+        ));
+    }
+    Ok(Value::Array(vm.alloc_array(sorted)))
+}
+
+pub fn module() -> Module {
+    NativeModuleBuilder::new("Array")
+        .with_function("to_table", to_table)
+        .with_function("sort", sort)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{tests::run, Vm};
+
+    #[test]
+    fn sort_nested() {
+        let result = run("let main () = Array.sort [[2, 1], [1, 2, 3], [1, 2], [1]]");
+        let sorted: Vec<String> = result
+            .as_array()
+            .unwrap()
+            .borrow()
+            .iter()
+            .map(|array| array.to_string())
+            .collect();
+        assert_eq!(sorted, ["[1,]", "[1,2,]", "[1,2,3,]", "[2,1,]"]);
+    }
+
+    #[test]
+    fn sort_strings() {
+        let result = run("let main () = Array.sort [\"pear\", \"apple\", \"app\"]");
+        assert_eq!(result.to_string(), "[app,apple,pear,]");
+    }
+
+    #[test]
+    fn sort_incomparable() {
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let main () = Array.sort [1, \"a\"]")
+            .unwrap();
+        assert!(vm.execute_module(index, "main").is_err());
+    }
+}
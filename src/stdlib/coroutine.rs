@@ -0,0 +1,140 @@
+use std::{any::Any, cell::RefCell, rc::Rc};
+
+use crate::{
+    state::{Module, NativeModuleBuilder},
+    value::Value,
+    vm::{Coroutine, RuntimeError, Vm},
+};
+
+fn as_coroutine(value: Value) -> Result<Rc<dyn Any>, RuntimeError> {
+    let user_data = value.as_user_data().ok_or(RuntimeError::UnexpectedType)?;
+    if user_data.is::<RefCell<Coroutine>>() {
+        Ok(*user_data)
+    } else {
+        Err(RuntimeError::UnexpectedType)
+    }
+}
+
+fn borrow_coroutine(coroutine: &Rc<dyn Any>) -> &RefCell<Coroutine> {
+    coroutine.downcast_ref::<RefCell<Coroutine>>().unwrap()
+}
+
+/// Creates a suspended coroutine that runs a function once resumed.
+fn create(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let function = vm.pop()?.as_closure().ok_or(RuntimeError::UnexpectedType)?;
+    let coroutine = RefCell::new(Coroutine::new(function));
+    Ok(Value::UserData(Box::new(Rc::new(coroutine))))
+}
+
+/// Runs a coroutine until it yields or returns, optionally passing it a
+/// value: the argument of its function the first time, and the result of
+/// `yield` afterwards.
+fn resume(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    let value = match vm.top() - 1 {
+        1 => Value::Unit,
+        2 => vm.pop()?,
+        _ => return Err(RuntimeError::IncorrectNumberOfArguments),
+    };
+    let coroutine = as_coroutine(vm.pop()?)?;
+    vm.resume_coroutine(borrow_coroutine(&coroutine), value)
+}
+
+/// Suspends the running coroutine, making its `resume` result in the value.
+fn r#yield(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let value = vm.pop()?;
+    Err(vm.yield_coroutine(value))
+}
+
+/// Returns whether a coroutine is `"suspended"`, `"running"` or `"dead"`.
+fn status(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let coroutine = as_coroutine(vm.pop()?)?;
+    let status = borrow_coroutine(&coroutine).borrow().status();
+    Ok(Value::String(vm.intern(&status.to_string())))
+}
+
+pub fn module() -> Module {
+    NativeModuleBuilder::new("Coroutine")
+        .with_function("create", create)
+        .with_function("resume", resume)
+        .with_function("yield", r#yield)
+        .with_function("status", status)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::tests::run;
+
+    #[test]
+    fn resume_and_yield() {
+        let source = "let producer start =
+    let n = start
+    let received = []
+    let step = fn () ->
+        n = n + 1
+        Coroutine.yield n
+    while n < start + 3 do
+        received = [...received, step ()]
+    \"done {received}\"
+
+let main () =
+    let co = Coroutine.create producer
+    let results = [Coroutine.status co]
+    let first = Coroutine.resume co 10
+    let second = Coroutine.resume co \"a\"
+    let third = Coroutine.resume co
+    let last = Coroutine.resume co
+    [...results, first, second, third, last, Coroutine.status co]";
+        assert_eq!(
+            run(source).to_string(),
+            "[suspended,11,12,13,done [a,(),(),],dead,]"
+        );
+    }
+
+    #[test]
+    fn scheduler() {
+        let source = "let task spec =
+    for i in 1..=spec.count do
+        Coroutine.yield \"{spec.name}{i}\"
+
+let main () =
+    let tasks = [Coroutine.create task, Coroutine.create task]
+    let specs = [{ name: \"a\", count: 2 }, { name: \"b\", count: 3 }]
+    let order = []
+    let running = true
+    while running do
+        running = false
+        for i in 0..2 do
+            let co = tasks[i]
+            if Coroutine.status co == \"suspended\" then
+                let value = Coroutine.resume co specs[i]
+                if value != () then
+                    order = [...order, value]
+                    running = true
+    order";
+        assert_eq!(run(source).to_string(), "[a1,b1,a2,b2,b3,]");
+    }
+
+    #[test]
+    fn errors() {
+        let source = "let main () =
+    let co = Coroutine.create fn () -> Iter.map [1] fn x -> Coroutine.yield x
+    let crossed = try Coroutine.resume co catch e -> e
+    let outside = try Coroutine.yield 1 catch e -> e
+    let dead = try Coroutine.resume co catch e -> e
+    [crossed, outside, dead]";
+        assert_eq!(
+            run(source).to_string(),
+            "[Error: Cannot yield across a native function,Error: Cannot yield outside of a coroutine,Error: Cannot resume a dead coroutine,]"
+        );
+    }
+}
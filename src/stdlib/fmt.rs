@@ -1,40 +1,452 @@
 use std::rc::Rc;
 
-use crate::value::{TableRef, Value};
-
-pub fn format_to_string(args: TableRef) -> String {
-    let args = args.borrow();
-    let mut format = args
-        .get(&Value::String(Rc::new("format".to_string())))
-        .unwrap()
-        .clone()
-        .as_string()
-        .unwrap()
-        .as_ref()
-        .to_owned();
-    let mut offset = 0;
-    let args = args
-        .get(&Value::String(Rc::new("args".to_string())))
-        .unwrap()
-        .clone()
-        .as_array()
-        .unwrap();
-    let args = args.borrow();
-    for i in 0..args.len() {
-        let arg = args[i].clone().as_table().unwrap();
-        let arg = arg.borrow();
-        let value = arg
-            .get(&Value::String(Rc::new("arg".to_string())))
-            .unwrap()
-            .to_string();
-        let position = arg
-            .get(&Value::String(Rc::new("offset".to_string())))
-            .unwrap()
-            .clone()
-            .as_int()
-            .unwrap() as usize;
-        format.insert_str(offset + position, &value);
-        offset += value.len() + position;
-    }
-    format
+use crate::{
+    state::{Module, NativeModuleBuilder},
+    value::{Table, Value},
+    vm::{RuntimeError, Vm},
+};
+
+/// Formats a value readably for debugging: strings and chars are quoted and
+/// collections are written out with their elements inspected. A collection
+/// that contains itself is written as `<cycle>` where it repeats.
+pub fn inspect(value: &Value) -> String {
+    inspect_in(value, &mut Vec::new())
+}
+
+/// Inspects `value` inside the arrays and tables in `parents`.
+fn inspect_in(value: &Value, parents: &mut Vec<*const ()>) -> String {
+    let address: *const () = match value {
+        Value::Array(array) => Rc::as_ptr(array).cast(),
+        Value::Table(table) => Rc::as_ptr(table).cast(),
+        _ => std::ptr::null(),
+    };
+    if !address.is_null() {
+        if parents.contains(&address) {
+            return "<cycle>".to_string();
+        }
+        parents.push(address);
+    }
+    let string = match value {
+        Value::String(str) => format!("{:?}", str.as_str()),
+        Value::Char(char) => format!("{char:?}"),
+        Value::Array(array) => {
+            let elements: Vec<String> = array
+                .borrow()
+                .iter()
+                .map(|value| inspect_in(value, parents))
+                .collect();
+            format!("[{}]", elements.join(", "))
+        }
+        Value::Tuple(tuple) => {
+            let elements: Vec<String> = tuple
+                .iter()
+                .map(|value| inspect_in(value, parents))
+                .collect();
+            format!("({})", elements.join(", "))
+        }
+        Value::Table(table) => {
+            let entries: Vec<String> = table
+                .borrow()
+                .iter()
+                .map(|(key, value)| {
+                    format!(
+                        "{}: {}",
+                        inspect_in(key, parents),
+                        inspect_in(value, parents)
+                    )
+                })
+                .collect();
+            if entries.is_empty() {
+                "{}".to_string()
+            } else {
+                format!("{{ {} }}", entries.join(", "))
+            }
+        }
+        value => value.to_string(),
+    };
+    if !address.is_null() {
+        parents.pop();
+    }
+    string
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+impl Align {
+    fn from_char(char: char) -> Option<Self> {
+        match char {
+            '<' => Some(Align::Left),
+            '^' => Some(Align::Center),
+            '>' => Some(Align::Right),
+            _ => None,
+        }
+    }
+}
+
+/// The spec after the `:` of an interpolation argument, written like
+/// Rust's: `[[fill]align][+][0][width][.precision][type]`.
+///
+/// `align` is `<`, `^` or `>`. The `type` is `?` to format the value with
+/// [`inspect`], `x`, `X`, `o` or `b` for an integer in another base, or `e`
+/// for a number in scientific notation. The precision is the number of
+/// decimals of a number, or the maximum length of a string. Like in Rust, the
+/// width and precision can't be larger than `u16::MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatSpec {
+    fill: char,
+    align: Option<Align>,
+    sign: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    kind: Option<char>,
+}
+
+impl FormatSpec {
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut chars = spec.chars();
+        let (fill, align, mut rest) = match (chars.next(), chars.next()) {
+            (Some(fill), Some(align)) if Align::from_char(align).is_some() => {
+                (fill, Align::from_char(align), &spec[fill.len_utf8() + 1..])
+            }
+            (Some(align), _) if Align::from_char(align).is_some() => {
+                (' ', Align::from_char(align), &spec[1..])
+            }
+            _ => (' ', None, spec),
+        };
+        let sign = strip(&mut rest, '+');
+        let zero = strip(&mut rest, '0');
+        let width = digits(&mut rest).map_or(Some(0), |width| width.parse::<u16>().ok())?;
+        let precision = if strip(&mut rest, '.') {
+            Some(digits(&mut rest)?.parse::<u16>().ok()?.into())
+        } else {
+            None
+        };
+        let kind = match rest {
+            "" => None,
+            "?" | "x" | "X" | "o" | "b" | "e" => rest.chars().next(),
+            _ => return None,
+        };
+        Some(Self {
+            fill,
+            align,
+            sign,
+            zero,
+            width: width.into(),
+            precision,
+            kind,
+        })
+    }
+
+    pub fn format(&self, value: &Value) -> Result<String, RuntimeError> {
+        let body = match (self.kind, value) {
+            (Some('?'), value) => inspect(value),
+            (Some('x'), Value::Integer(int)) => format!("{int:x}"),
+            (Some('X'), Value::Integer(int)) => format!("{int:X}"),
+            (Some('o'), Value::Integer(int)) => format!("{int:o}"),
+            (Some('b'), Value::Integer(int)) => format!("{int:b}"),
+            (Some('e'), Value::Integer(int)) => self.scientific(*int as f64),
+            (Some('e'), Value::Number(num)) => self.scientific(*num),
+            (Some(_), _) => return Err(RuntimeError::UnexpectedType),
+            (None, Value::Integer(int)) => match self.precision {
+                Some(precision) => format!("{:.*}", precision, *int as f64),
+                None => int.to_string(),
+            },
+            (None, Value::Number(num)) => match self.precision {
+                Some(precision) => format!("{num:.precision$}"),
+                None => num.to_string(),
+            },
+            (None, Value::String(str)) => match self.precision {
+                Some(precision) => str.chars().take(precision).collect(),
+                None => str.to_string(),
+            },
+            (None, value) => value.to_string(),
+        };
+        let is_number =
+            matches!(value, Value::Integer(_) | Value::Number(_)) && self.kind != Some('?');
+        let body = if self.sign && is_number && !body.starts_with('-') {
+            format!("+{body}")
+        } else {
+            body
+        };
+
+        let Some(padding) = self.width.checked_sub(body.chars().count()) else {
+            return Ok(body);
+        };
+        if self.zero && is_number && self.align.is_none() {
+            // The zeros go between the sign and the digits.
+            let digits = body.trim_start_matches(['+', '-']);
+            let sign = &body[..body.len() - digits.len()];
+            return Ok(format!("{sign}{}{digits}", "0".repeat(padding)));
+        }
+        let fill = |len: usize| self.fill.to_string().repeat(len);
+        let align = self
+            .align
+            .unwrap_or(if is_number { Align::Right } else { Align::Left });
+        Ok(match align {
+            Align::Left => format!("{body}{}", fill(padding)),
+            Align::Center => format!("{}{body}{}", fill(padding / 2), fill(padding - padding / 2)),
+            Align::Right => format!("{}{body}", fill(padding)),
+        })
+    }
+
+    fn scientific(&self, num: f64) -> String {
+        match self.precision {
+            Some(precision) => format!("{num:.precision$e}"),
+            None => format!("{num:e}"),
+        }
+    }
+}
+
+/// Removes `char` from the start of `spec`, returning whether it was there.
+fn strip(spec: &mut &str, char: char) -> bool {
+    match spec.strip_prefix(char) {
+        Some(rest) => {
+            *spec = rest;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Removes the digits at the start of `spec` and returns them, if any.
+fn digits<'a>(spec: &mut &'a str) -> Option<&'a str> {
+    let len = spec
+        .find(|char: char| !char.is_ascii_digit())
+        .unwrap_or(spec.len());
+    let (digits, rest) = spec.split_at(len);
+    *spec = rest;
+    (len > 0).then_some(digits)
+}
+
+/// Formats an interpolation argument according to its [`FormatSpec`], or
+/// with its `Display` form if it has none.
+pub fn format_value(value: &Value, spec: Option<&str>) -> Result<String, RuntimeError> {
+    match spec {
+        Some(spec) => FormatSpec::parse(spec)
+            .ok_or_else(|| RuntimeError::Custom(format!("Invalid format spec `{spec}`")))?
+            .format(value),
+        None => Ok(value.to_string()),
+    }
+}
+
+/// Builds the string of an interpolated string literal.
+///
+/// `template` alternates the literal pieces of the string with the specs of
+/// the arguments placed between them, as emitted by the compiler for
+/// `OpCode::Format`: `[piece, spec, piece, ..., piece]`, where a spec is
+/// either a string or unit.
+pub fn format(template: &[Value], args: &[Value]) -> Result<String, RuntimeError> {
+    let mut string = String::new();
+    let mut parts = template.iter();
+    for arg in args {
+        if let Some(Value::String(piece)) = parts.next() {
+            string.push_str(piece);
+        }
+        let spec = match parts.next() {
+            Some(Value::String(spec)) => Some(spec.as_str()),
+            _ => None,
+        };
+        string.push_str(&format_value(arg, spec)?);
+    }
+    if let Some(Value::String(piece)) = parts.next() {
+        string.push_str(piece);
+    }
+    Ok(string)
+}
+
+#[allow(clippy::mutable_key_type)]
+fn string_option(options: &Table, key: Rc<String>, default: &str) -> Result<String, RuntimeError> {
+    match options.get(&Value::String(key)) {
+        None | Some(Value::Unit) => Ok(default.to_string()),
+        Some(Value::String(str)) => Ok(str.to_string()),
+        Some(_) => Err(RuntimeError::UnexpectedType),
+    }
+}
+
+fn group_digits(digits: &str, separator: &str) -> String {
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push_str(separator);
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// Formats a number for display.
+///
+/// The options table accepts `thousands` (the grouping separator, `","` by
+/// default), `decimal` (the decimal separator, `"."` by default) and
+/// `precision` (the number of decimals, all of them by default, and at most
+/// `u16::MAX` like in format specs).
+fn number(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let options = vm.pop()?.as_table().ok_or(RuntimeError::UnexpectedType)?;
+    let options = options.borrow();
+    let thousands = string_option(&options, vm.intern("thousands"), ",")?;
+    let decimal = string_option(&options, vm.intern("decimal"), ".")?;
+    let precision = match options.get(&Value::String(vm.intern("precision"))) {
+        None | Some(Value::Unit) => None,
+        Some(Value::Integer(precision)) => Some(
+            u16::try_from(*precision)
+                .map_err(|_| RuntimeError::UnexpectedType)?
+                .into(),
+        ),
+        Some(_) => return Err(RuntimeError::UnexpectedType),
+    };
+
+    let formatted = match (vm.pop()?, precision) {
+        (Value::Integer(int), None) => int.to_string(),
+        (Value::Integer(int), Some(precision)) => format!("{:.*}", precision, int as f64),
+        (Value::Number(num), None) => num.to_string(),
+        (Value::Number(num), Some(precision)) => format!("{num:.precision$}"),
+        _ => return Err(RuntimeError::UnexpectedType),
+    };
+    let (sign, formatted) = match formatted.strip_prefix('-') {
+        Some(formatted) => ("-", formatted),
+        None => ("", formatted.as_str()),
+    };
+    let (integer, fraction) = match formatted.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (formatted, None),
+    };
+
+    let mut result = format!("{sign}{}", group_digits(integer, &thousands));
+    if let Some(fraction) = fraction {
+        result.push_str(&decimal);
+        result.push_str(fraction);
+    }
+    Ok(Value::String(Rc::new(result)))
+}
+
+pub fn module() -> Module {
+    NativeModuleBuilder::new("Fmt")
+        .with_function("number", number)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        compiler::{CompilerError, CompilerErrorKind},
+        parser::ParserError,
+        value::Value,
+        vm::{tests::run, RuntimeError, Vm},
+    };
+
+    #[test]
+    fn debug_spec() {
+        let result = run(
+            "let main () =\n    let data = { a: [1, \"b\"] }\n    String.from_format \"data: {data:?}\"",
+        );
+        assert_eq!(
+            result,
+            Value::String(Rc::new("data: { \"a\": [1, \"b\"] }".to_string()))
+        );
+    }
+
+    #[test]
+    fn debug_spec_cycles() {
+        let result = run("let main () =
+    let t = {}
+    t.t = t
+    let shared = [1]
+    let a = [shared, shared]
+    a[0] = a
+    \"{t:?} {a:?}\"");
+        assert_eq!(
+            result,
+            Value::String(Rc::new("{ \"t\": <cycle> } [<cycle>, [1]]".to_string()))
+        );
+    }
+
+    #[test]
+    fn format_specs() {
+        let result = run("let main () =
+    let x = 3.14159
+    let n = 7
+    let name = \"focus\"
+    \"{x:.2}|{n:04}|{n:+}|{name:>7}|{name:*^9}|{name:.3}|{n:<3}|{(255):x}|{(-5):05}|{(1234.5):.1e}\"");
+        assert_eq!(
+            result,
+            Value::String(Rc::new(
+                "3.14|0007|+7|  focus|**focus**|foc|7  |ff|-0005|1.2e3".to_string()
+            ))
+        );
+
+        let mut vm = Vm::new_with_std();
+        for spec in [".", ".99999999999", "99999999999", "65536", ".65536"] {
+            let source = format!("let main () = \"{{(1.5):{spec}}}\"");
+            assert!(matches!(
+                vm.load_from_source("main", &source),
+                Err(CompilerError {
+                    kind: CompilerErrorKind::ParserError(ParserError::InvalidFormatSpec(s)),
+                    ..
+                }) if s == spec
+            ));
+        }
+        let index = vm
+            .load_from_source("main", "let main () = \"{(1.5):x}\"")
+            .unwrap();
+        assert!(matches!(
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace),
+            Err(RuntimeError::UnexpectedType)
+        ));
+    }
+
+    #[test]
+    fn interpolated_expressions() {
+        let result = run("let f x = x * 2
+let id x = x
+let main () =
+    let a = 1
+    let b = 2
+    let t = { get: fn self n -> n + 1 }
+    let c = id \"{f 4}\"
+    let d = match a | 1 -> \"{a | b}\" | _ -> \"\"
+    \"{a + b}|{f b}|{f b:03}|{a + b:?}|{(t:get 5)}|{if a > 0 then \"pos\" else \"neg\"}|{c}|{d}\"");
+        assert_eq!(
+            result,
+            Value::String(Rc::new("3|4|004|3|6|pos|8|3".to_string()))
+        );
+    }
+
+    #[test]
+    fn group_thousands() {
+        assert_eq!(
+            run("let main () = Fmt.number 1234567 {}"),
+            Value::String(Rc::new("1,234,567".to_string()))
+        );
+        assert_eq!(
+            run("let main () = Fmt.number 1234567.89 { thousands: \".\", decimal: \",\" }"),
+            Value::String(Rc::new("1.234.567,89".to_string()))
+        );
+        assert_eq!(
+            run("let main () = Fmt.number (-1234.5) { precision: 2 }"),
+            Value::String(Rc::new("-1,234.50".to_string()))
+        );
+
+        let mut vm = Vm::new_with_std();
+        for precision in ["-1", "65536", "99999999999"] {
+            let source = format!("let main () = Fmt.number 1.5 {{ precision: {precision} }}");
+            let index = vm.load_from_source("main", &source).unwrap();
+            assert!(matches!(
+                vm.execute_module(index, "main")
+                    .map_err(RuntimeError::without_trace),
+                Err(RuntimeError::UnexpectedType)
+            ));
+        }
+    }
 }
@@ -0,0 +1,127 @@
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak as RcWeak},
+};
+
+use crate::{
+    state::{Module, NativeModuleBuilder},
+    value::{Closure, Table, Value},
+    vm::{RuntimeError, Vm},
+};
+
+/// A reference to a table, array or closure that doesn't keep it alive.
+enum Weak {
+    Table(RcWeak<RefCell<Table>>),
+    Array(RcWeak<RefCell<Vec<Value>>>),
+    Closure(RcWeak<Closure>),
+}
+
+impl Weak {
+    fn upgrade(&self) -> Value {
+        let value = match self {
+            Weak::Table(table) => table.upgrade().map(Value::Table),
+            Weak::Array(array) => array.upgrade().map(Value::Array),
+            Weak::Closure(closure) => closure.upgrade().map(Value::Closure),
+        };
+        value.unwrap_or(Value::Unit)
+    }
+}
+
+/// Frees the objects that are only kept alive by reference cycles,
+/// returning how many there were.
+fn collect(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    if vm.pop()? != Value::Unit {
+        return Err(RuntimeError::UnexpectedType);
+    }
+    Ok(Value::Integer(vm.collect_garbage() as i64))
+}
+
+/// Creates a weak reference to a table, array or closure, which a table can
+/// hold on to without being part of a cycle.
+fn weak(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let weak = match vm.pop()? {
+        Value::Table(table) => Weak::Table(Rc::downgrade(&table)),
+        Value::Array(array) => Weak::Array(Rc::downgrade(&array)),
+        Value::Closure(closure) => Weak::Closure(Rc::downgrade(&closure)),
+        _ => return Err(RuntimeError::UnexpectedType),
+    };
+    Ok(Value::UserData(Box::new(Rc::new(weak))))
+}
+
+/// Returns what a weak reference refers to, or `()` if it was freed.
+fn get(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let user_data = vm
+        .pop()?
+        .as_user_data()
+        .ok_or(RuntimeError::UnexpectedType)?;
+    let weak = user_data
+        .downcast_ref::<Weak>()
+        .ok_or(RuntimeError::UnexpectedType)?;
+    Ok(weak.upgrade())
+}
+
+pub fn module() -> Module {
+    NativeModuleBuilder::new("Gc")
+        .with_function("collect", collect)
+        .with_function("weak", weak)
+        .with_function("get", get)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{value::Value, vm::tests::run};
+
+    #[test]
+    fn weak_references() {
+        let result = run("let main () =
+    let t = { name: \"t\" }
+    let parent = { child: t }
+    t.parent = Gc.weak parent
+    let w = Gc.weak t
+    let child = Gc.get w
+    let parent = Gc.get t.parent
+    let closure = Gc.weak (fn () -> 1)
+    [child.name, parent.child.name, Gc.get closure]");
+        let result = result.as_array().unwrap();
+        let name = Value::String("t".to_string().into());
+        assert_eq!(*result.borrow(), [name.clone(), name, Value::Unit]);
+    }
+
+    #[test]
+    fn collect() {
+        let result = run("let cycle () =
+    let t = { name: \"t\" }
+    t.get = fn () -> t.name
+    Gc.weak t
+let main () =
+    let w = cycle ()
+    let freed = Gc.collect ()
+    [freed, Gc.get w]");
+        let result = result.as_array().unwrap();
+        assert_eq!(*result.borrow(), [Value::Integer(3), Value::Unit]);
+    }
+
+    #[test]
+    fn tuple_cycles() {
+        let result = run("let cycle () =
+    let t = {}
+    t.pair = (t, 1)
+    Gc.weak t
+let main () =
+    let w = cycle ()
+    let freed = Gc.collect ()
+    [freed, Gc.get w]");
+        let result = result.as_array().unwrap();
+        assert_eq!(*result.borrow(), [Value::Integer(2), Value::Unit]);
+    }
+}
@@ -1,10 +1,10 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
 use reqwest::blocking::{get, Response};
 
 use crate::{
     state::{Module, NativeModuleBuilder},
-    value::Value,
+    value::{Table, Value},
     vm::{RuntimeError, Vm},
 };
 
@@ -13,10 +13,10 @@ pub fn get_(vm: &mut Vm) -> Result<Value, RuntimeError> {
         return Err(RuntimeError::IncorrectNumberOfArguments);
     }
 
-    let value = vm.pop().as_string().ok_or(RuntimeError::UnexpectedType)?;
+    let value = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
     let response = get(&*value).map_err(|e| RuntimeError::Custom(e.to_string()))?;
 
-    let mut ret = HashMap::new();
+    let mut ret = Table::new();
     ret.insert(
         Value::String(Rc::new("is_ok".to_string())),
         Value::Bool(response.status().is_success()),
@@ -26,7 +26,7 @@ pub fn get_(vm: &mut Vm) -> Result<Value, RuntimeError> {
         Value::UserData(Box::new(Rc::new(RefCell::new(Some(response))))),
     );
 
-    Ok(Value::Table(Rc::new(RefCell::new(ret))))
+    Ok(Value::Table(vm.alloc_table(ret)))
 }
 
 pub fn json(vm: &mut Vm) -> Result<Value, RuntimeError> {
@@ -34,7 +34,7 @@ pub fn json(vm: &mut Vm) -> Result<Value, RuntimeError> {
         return Err(RuntimeError::IncorrectNumberOfArguments);
     }
 
-    let value = vm.pop().as_table().ok_or(RuntimeError::UnexpectedType)?;
+    let value = vm.pop()?.as_table().ok_or(RuntimeError::UnexpectedType)?;
     let data = value.borrow();
     let data = data
         .get(&Value::String(Rc::new("_data".to_string())))
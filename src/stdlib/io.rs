@@ -6,72 +6,55 @@ use crate::{
     vm::{RuntimeError, Vm},
 };
 
-use super::fmt::format_to_string;
+/// Sends a line of output to the host as a `"log"` event, printing it to
+/// the standard output when nobody is listening.
+fn log(vm: &mut Vm, string: String) {
+    if !vm.emit("log", Value::String(Rc::new(string.clone()))) {
+        println!("{}", string);
+    }
+}
 
 fn print(vm: &mut Vm) -> Result<Value, RuntimeError> {
     let num_args = vm.top();
     let mut string = String::new();
     for _ in 1..num_args {
-        let arg = vm.pop();
+        let arg = vm.pop()?;
         string.insert_str(0, &arg.to_string());
     }
-    #[cfg(target_arch = "wasm32")]
-    {
-        vm.event_target()
-            .dispatch_event(
-                &web_sys::CustomEvent::new_with_event_init_dict(
-                    "log",
-                    web_sys::CustomEventInit::new().detail(&string.clone().into()),
-                )
-                .unwrap(),
-            )
-            .unwrap();
-    }
-    #[cfg(not(target_arch = "wasm32"))]
-    {
-        println!("{}", string);
-    }
+    log(vm, string);
     Ok(Value::Unit)
 }
 
 fn printf(vm: &mut Vm) -> Result<Value, RuntimeError> {
     if vm.top() - 1 != 1 {
-        panic!("Invalid number of arguments");
+        return Err(RuntimeError::IncorrectNumberOfArguments);
     }
-    let arg = vm.pop();
+    let arg = vm.pop()?;
     match arg {
-        Value::Table(table) => {
-            let string = format_to_string(table);
-            #[cfg(target_arch = "wasm32")]
-            {
-                vm.event_target()
-                    .dispatch_event(
-                        &web_sys::CustomEvent::new_with_event_init_dict(
-                            "log",
-                            web_sys::CustomEventInit::new().detail(&string.clone().into()),
-                        )
-                        .unwrap(),
-                    )
-                    .unwrap();
-                web_sys::console::log_2(&"from rust: ".into(), &string.into());
-            }
-            #[cfg(not(target_arch = "wasm32"))]
-            {
-                println!("{}", string);
-            }
-        }
+        Value::String(string) => log(vm, string.to_string()),
         _ => return Err(RuntimeError::UnexpectedType),
     }
     Ok(Value::Unit)
 }
 
+/// Emits a custom event to the host with the given detail.
+fn emit(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let detail = vm.pop()?;
+    let type_ = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
+    vm.emit(&type_, detail);
+    Ok(Value::Unit)
+}
+
 fn open_file(vm: &mut Vm) -> Result<Value, RuntimeError> {
     if vm.top() - 1 != 2 {
-        panic!("Invalid number of arguments");
+        return Err(RuntimeError::IncorrectNumberOfArguments);
     }
 
-    let mode = vm.pop().as_string().unwrap();
-    let path = vm.pop().as_string().unwrap();
+    let mode = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
+    let path = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
 
     let append = mode.chars().any(|c| c == 'a');
     let create = mode.chars().any(|c| c == 'c');
@@ -86,25 +69,27 @@ fn open_file(vm: &mut Vm) -> Result<Value, RuntimeError> {
         .write(write)
         .read(read)
         .open(&*path)
-        .unwrap();
+        .map_err(|e| RuntimeError::Custom(e.to_string()))?;
 
     Ok(Value::UserData(Box::new(Rc::new(RefCell::new(file)))))
 }
 
 fn read_file(vm: &mut Vm) -> Result<Value, RuntimeError> {
     if vm.top() - 1 != 1 {
-        panic!("Invalid number of arguments");
+        return Err(RuntimeError::IncorrectNumberOfArguments);
     }
 
     let file = vm
-        .pop()
+        .pop()?
         .as_user_data()
-        .unwrap()
+        .ok_or(RuntimeError::UnexpectedType)?
         .downcast::<RefCell<std::fs::File>>()
-        .unwrap();
+        .map_err(|_| RuntimeError::UnexpectedType)?;
 
     let mut buf = String::new();
-    file.borrow_mut().read_to_string(&mut buf).unwrap();
+    file.borrow_mut()
+        .read_to_string(&mut buf)
+        .map_err(|e| RuntimeError::Custom(e.to_string()))?;
     Ok(Value::String(Rc::new(buf)))
 }
 
@@ -112,7 +97,50 @@ pub fn module() -> Module {
     NativeModuleBuilder::new("Io")
         .with_function("print", print)
         .with_function("printf", printf)
+        .with_function("emit", emit)
         .with_function("open_file", open_file)
         .with_function("read_file", read_file)
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{RuntimeError, Vm};
+
+    #[test]
+    fn invalid_arguments() {
+        let error = |source: &str| {
+            let mut vm = Vm::new_with_std();
+            let index = vm.load_from_source("main", source).unwrap();
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace)
+                .unwrap_err()
+        };
+        for source in [
+            "let main () = Io.printf 1 2",
+            "let main () = Io.open_file \"a\"",
+            "let main () = Io.read_file 5 6",
+        ] {
+            let error = error(source);
+            assert!(
+                matches!(error, RuntimeError::IncorrectNumberOfArguments),
+                "{source}: {error:?}"
+            );
+        }
+        for source in [
+            "let main () = Io.printf 1",
+            "let main () = Io.open_file 1 2",
+            "let main () = Io.open_file \"a\" 2",
+            "let main () = Io.read_file 5",
+            "let main () = Io.read_file (Gc.weak {})",
+        ] {
+            let error = error(source);
+            assert!(
+                matches!(error, RuntimeError::UnexpectedType),
+                "{source}: {error:?}"
+            );
+        }
+        let error = error("let main () = Io.open_file \"/missing/file\" \"r\"");
+        assert!(matches!(error, RuntimeError::Custom(_)), "{error:?}");
+    }
+}
@@ -1,16 +1,37 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cmp::Ordering, slice};
 
 use crate::{
     state::{Module, NativeModuleBuilder},
-    value::{Closure, NativeFunction, Value},
+    value::{ClosureRef, Value},
     vm::{RuntimeError, Vm},
 };
 
-fn iter_from_fn<T: FnMut(&mut Vm) -> Result<Value, RuntimeError> + 'static>(fun: T) -> Value {
-    Value::Iterator(Rc::new(Closure::from_native(Rc::new(NativeFunction {
-        ident: "_iter".to_string(),
-        function: Rc::new(RefCell::new(fun)),
-    }))))
+/// Iterates over the integers from `start` up to `end`, which is included
+/// only if `inclusive` is set.
+pub(crate) fn range(start: i64, end: i64, inclusive: bool) -> ClosureRef {
+    let mut next = Some(start);
+    let value = Value::iterator(move |_vm| {
+        let Some(current) = next.filter(|&n| n < end || (inclusive && n == end)) else {
+            next = None;
+            return Ok(Value::Unit);
+        };
+        next = current.checked_add(1);
+        Ok(Value::Integer(current))
+    });
+    value.as_iterator().unwrap()
+}
+
+fn call(vm: &mut Vm, function: &ClosureRef, args: &[Value]) -> Result<Value, RuntimeError> {
+    vm.push(Value::Closure(function.clone()));
+    for arg in args {
+        vm.push(arg.clone());
+    }
+    vm.call(function.clone(), args.len())?;
+    vm.pop()
+}
+
+fn next(vm: &mut Vm, iterator: &ClosureRef) -> Result<Value, RuntimeError> {
+    call(vm, iterator, &[])
 }
 
 fn new(vm: &mut Vm) -> Result<Value, RuntimeError> {
@@ -18,149 +39,237 @@ fn new(vm: &mut Vm) -> Result<Value, RuntimeError> {
         return Err(RuntimeError::IncorrectNumberOfArguments);
     }
 
-    let value = vm.pop();
-
-    let result = match value {
-        Value::Unit => todo!(),
-        Value::Bool(_) => todo!(),
-        Value::Integer(_) => todo!(),
-        Value::Number(_) => todo!(),
-        Value::String(str) => {
-            let mut i = 0;
-            iter_from_fn(move |_vm| {
-                if i < str.len() {
-                    let result = str.chars().nth(i).unwrap();
-                    i += 1;
-                    Ok(Value::Char(result))
-                } else {
-                    Ok(Value::Unit)
-                }
-            })
-        }
-        Value::Table(_) => todo!(),
-        Value::Closure(closure) => Value::Iterator(closure),
-        Value::Array(array) => {
-            let mut i = 0;
-            iter_from_fn(move |_vm| {
-                let array = array.borrow();
-                if i < array.len() {
-                    let result = array[i].clone();
-                    i += 1;
-                    Ok(result)
-                } else {
-                    Ok(Value::Unit)
-                }
-            })
-        }
-        Value::Module(_) => todo!(),
-        Value::UserData(_) => todo!(),
-        Value::Char(_) => todo!(),
-        Value::Iterator(iterator) => Value::Iterator(iterator),
-    };
-
-    Ok(result)
+    let value = vm.pop()?;
+    Ok(Value::Iterator(value.into_iterator()?))
 }
 
 fn map(vm: &mut Vm) -> Result<Value, RuntimeError> {
     if vm.top() - 1 != 2 {
         return Err(RuntimeError::IncorrectNumberOfArguments);
     }
-    let function = vm.pop().as_closure().unwrap();
-    let value = vm.pop();
-    vm.pop();
+    let function = vm.pop()?.as_closure().ok_or(RuntimeError::UnexpectedType)?;
+    let source = vm.pop()?.into_iterator()?;
     let mut results = Vec::new();
-    match value {
-        Value::Array(array) => {
-            let value = array.borrow();
-            for v in value.iter() {
-                vm.push(Value::Closure(function.clone()));
-                vm.push(v.clone());
-                vm.call(function.clone(), 1)?;
-                results.push(vm.pop());
-            }
-            return Ok(Value::Array(Rc::new(RefCell::new(results))));
-        }
-        Value::Iterator(iterator) => loop {
-            loop {
-                vm.push(Value::Closure(iterator.clone()));
-                vm.push(Value::Unit);
-                vm.call(iterator.clone(), 1)?;
-                let value = vm.pop();
-                match value {
-                    Value::Unit => break,
-                    value => {
-                        vm.push(Value::Closure(function.clone()));
-                        vm.push(value.clone());
-                        vm.call(function.clone(), 1)?;
-                        results.push(vm.pop());
-                    }
-                }
-            }
-            return Ok(Value::Array(Rc::new(RefCell::new(results))));
-        },
-        _ => return Err(RuntimeError::UnexpectedType),
+    while let Some(value) = vm.next(&source)? {
+        results.push(call(vm, &function, &[value])?);
     }
+    Ok(Value::Array(vm.alloc_array(results)))
 }
 
 fn filter(vm: &mut Vm) -> Result<Value, RuntimeError> {
     if vm.top() - 1 != 2 {
         return Err(RuntimeError::IncorrectNumberOfArguments);
     }
-    let function = vm.pop().as_closure().unwrap();
-    let value = &*vm.pop().as_array().unwrap();
-    let value = value.borrow();
-    vm.pop();
+    let function = vm.pop()?.as_closure().ok_or(RuntimeError::UnexpectedType)?;
+    let source = vm.pop()?.into_iterator()?;
     let mut results = Vec::new();
-    for v in value.iter() {
-        vm.push(Value::Closure(function.clone()));
-        vm.push(v.clone());
-        vm.call(function.clone(), 1)?;
-        let result = vm.pop();
+    while let Some(value) = vm.next(&source)? {
+        let result = call(vm, &function, &[value])?;
         if result != Value::Unit {
             results.push(result);
         }
     }
-    Ok(Value::Array(Rc::new(RefCell::new(results))))
+    Ok(Value::Array(vm.alloc_array(results)))
 }
 
 fn for_each(vm: &mut Vm) -> Result<Value, RuntimeError> {
     if vm.top() - 1 != 2 {
         return Err(RuntimeError::IncorrectNumberOfArguments);
     }
-    let function = vm.pop().as_closure().unwrap();
-    let value = vm.pop();
-    vm.pop();
-    match value {
-        Value::Array(array) => {
-            let value = array.borrow();
-            for v in value.iter() {
-                vm.push(Value::Closure(function.clone()));
-                vm.push(v.clone());
-                vm.call(function.clone(), 1)?;
-                vm.pop();
-            }
+    let function = vm.pop()?.as_closure().ok_or(RuntimeError::UnexpectedType)?;
+    let source = vm.pop()?.into_iterator()?;
+    while let Some(value) = vm.next(&source)? {
+        call(vm, &function, &[value])?;
+    }
+    Ok(Value::Unit)
+}
+
+fn scan(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 3 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let function = vm.pop()?.as_closure().ok_or(RuntimeError::UnexpectedType)?;
+    let mut accumulator = vm.pop()?;
+    let source = vm.pop()?.into_iterator()?;
+    Ok(Value::iterator(move |vm| match next(vm, &source)? {
+        Value::Unit => Ok(Value::Unit),
+        value => {
+            accumulator = call(vm, &function, &[accumulator.clone(), value])?;
+            Ok(accumulator.clone())
+        }
+    }))
+}
+
+fn collect(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let source = vm.pop()?;
+    let results = vm.collect(source)?;
+    Ok(Value::Array(vm.alloc_array(results)))
+}
+
+fn take(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let count = vm.pop()?.as_int().ok_or(RuntimeError::UnexpectedType)?;
+    let source = vm.pop()?.into_iterator()?;
+    let mut taken = 0;
+    Ok(Value::iterator(move |vm| {
+        if taken >= count {
             return Ok(Value::Unit);
         }
-        Value::Iterator(iterator) => loop {
-            loop {
-                vm.push(Value::Closure(iterator.clone()));
-                vm.push(Value::Unit);
-                vm.call(iterator.clone(), 1)?;
-                let value = vm.pop();
-                match value {
-                    Value::Unit => break,
-                    value => {
-                        vm.push(Value::Closure(function.clone()));
-                        vm.push(value.clone());
-                        vm.call(function.clone(), 1)?;
-                        vm.pop();
-                    }
+        taken += 1;
+        next(vm, &source)
+    }))
+}
+
+fn step_by(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let step = vm.pop()?.as_int().ok_or(RuntimeError::UnexpectedType)?;
+    if step <= 0 {
+        return Err(RuntimeError::Custom(
+            "step_by requires a positive step".to_string(),
+        ));
+    }
+    let source = vm.pop()?.into_iterator()?;
+    let mut first = true;
+    Ok(Value::iterator(move |vm| {
+        if !first {
+            for _ in 1..step {
+                if next(vm, &source)? == Value::Unit {
+                    return Ok(Value::Unit);
+                }
+            }
+        }
+        first = false;
+        next(vm, &source)
+    }))
+}
+
+fn cycle(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let source = vm.pop()?.into_iterator()?;
+    // Iterators can only be consumed once, so the first pass is buffered
+    // and replayed on every following pass.
+    let mut buffer = Vec::new();
+    let mut exhausted = false;
+    let mut i = 0;
+    Ok(Value::iterator(move |vm| {
+        if !exhausted {
+            match next(vm, &source)? {
+                Value::Unit => exhausted = true,
+                value => {
+                    buffer.push(value.clone());
+                    return Ok(value);
                 }
             }
+        }
+        if buffer.is_empty() {
             return Ok(Value::Unit);
-        },
-        _ => return Err(RuntimeError::UnexpectedType),
+        }
+        let value = buffer[i % buffer.len()].clone();
+        i += 1;
+        Ok(value)
+    }))
+}
+
+fn partition(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let predicate = vm.pop()?.as_closure().ok_or(RuntimeError::UnexpectedType)?;
+    let source = vm.pop()?.into_iterator()?;
+    let mut matched = Vec::new();
+    let mut unmatched = Vec::new();
+    loop {
+        match next(vm, &source)? {
+            Value::Unit => break,
+            value => {
+                if call(vm, &predicate, slice::from_ref(&value))?.is_false() {
+                    unmatched.push(value);
+                } else {
+                    matched.push(value);
+                }
+            }
+        }
+    }
+    let matched = Value::Array(vm.alloc_array(matched));
+    let unmatched = Value::Array(vm.alloc_array(unmatched));
+    Ok(Value::Array(vm.alloc_array(vec![matched, unmatched])))
+}
+
+fn extremum(
+    vm: &mut Vm,
+    source: Value,
+    key: Option<ClosureRef>,
+    wanted: Ordering,
+) -> Result<Value, RuntimeError> {
+    let source = source.into_iterator()?;
+    let mut best: Option<(Value, Value)> = None;
+    loop {
+        let value = match next(vm, &source)? {
+            Value::Unit => break,
+            value => value,
+        };
+        let value_key = match &key {
+            Some(key) => call(vm, key, slice::from_ref(&value))?,
+            None => value.clone(),
+        };
+        best = match best {
+            None => Some((value_key, value)),
+            Some((best_key, best_value)) => match value_key.partial_cmp(&best_key) {
+                Some(ordering) if ordering == wanted => Some((value_key, value)),
+                Some(_) => Some((best_key, best_value)),
+                None => {
+                    return Err(RuntimeError::InvalidOperandType {
+                        lhs: best_key.type_name().to_string(),
+                        rhs: value_key.type_name().to_string(),
+                    })
+                }
+            },
+        };
+    }
+    Ok(best.map(|(_, value)| value).unwrap_or(Value::Unit))
+}
+
+fn min(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let source = vm.pop()?;
+    extremum(vm, source, None, Ordering::Less)
+}
+
+fn max(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let source = vm.pop()?;
+    extremum(vm, source, None, Ordering::Greater)
+}
+
+fn min_by(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
     }
+    let key = vm.pop()?.as_closure().ok_or(RuntimeError::UnexpectedType)?;
+    let source = vm.pop()?;
+    extremum(vm, source, Some(key), Ordering::Less)
+}
+
+fn max_by(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let key = vm.pop()?.as_closure().ok_or(RuntimeError::UnexpectedType)?;
+    let source = vm.pop()?;
+    extremum(vm, source, Some(key), Ordering::Greater)
 }
 
 pub fn module() -> Module {
@@ -169,5 +278,108 @@ pub fn module() -> Module {
         .with_function("map", map)
         .with_function("filter", filter)
         .with_function("for_each", for_each)
+        .with_function("scan", scan)
+        .with_function("collect", collect)
+        .with_function("take", take)
+        .with_function("step_by", step_by)
+        .with_function("cycle", cycle)
+        .with_function("partition", partition)
+        .with_function("min", min)
+        .with_function("max", max)
+        .with_function("min_by", min_by)
+        .with_function("max_by", max_by)
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        value::Value,
+        vm::{tests::run, Vm},
+    };
+
+    fn array(values: Vec<Value>) -> Value {
+        Value::Array(Rc::new(RefCell::new(values)))
+    }
+
+    #[test]
+    fn scan() {
+        let result = run("let main () = Iter.scan [1, 2, 3] 0 fn acc x -> acc + x |> Iter.collect");
+        assert_eq!(
+            result,
+            array(vec![
+                Value::Integer(1),
+                Value::Integer(3),
+                Value::Integer(6)
+            ])
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let result = run("let main () = Iter.cycle [1, 2] |> Iter.take 5 |> Iter.collect");
+        assert_eq!(
+            result,
+            array(vec![
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(1)
+            ])
+        );
+    }
+
+    #[test]
+    fn step_by() {
+        let result = run("let main () = Iter.step_by [1, 2, 3, 4, 5] 2 |> Iter.collect");
+        assert_eq!(
+            result,
+            array(vec![
+                Value::Integer(1),
+                Value::Integer(3),
+                Value::Integer(5)
+            ])
+        );
+    }
+
+    #[test]
+    fn step_by_zero() {
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let main () = Iter.step_by [1, 2] 0")
+            .unwrap();
+        assert!(vm.execute_module(index, "main").is_err());
+    }
+
+    #[test]
+    fn partition() {
+        let result = run("let main () = Iter.partition [1, 2, 3, 4] fn x -> x % 2 == 0");
+        assert_eq!(
+            result,
+            array(vec![
+                array(vec![Value::Integer(2), Value::Integer(4)]),
+                array(vec![Value::Integer(1), Value::Integer(3)])
+            ])
+        );
+    }
+
+    #[test]
+    fn max_by() {
+        let result = run("let main () = Iter.max_by [{ n: 1 }, { n: 3 }, { n: 2 }] (fn x -> x.n)");
+        let table = result.as_table().unwrap();
+        assert_eq!(
+            table.borrow().get(&Value::String("n".to_string().into())),
+            Some(&Value::Integer(3))
+        );
+    }
+
+    #[test]
+    fn min_max() {
+        assert_eq!(run("let main () = Iter.min [3, 1, 2]"), Value::Integer(1));
+        assert_eq!(run("let main () = Iter.max [3, 1, 2]"), Value::Integer(3));
+        assert_eq!(run("let main () = Iter.max []"), Value::Unit);
+    }
+}
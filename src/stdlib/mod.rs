@@ -1,16 +1,110 @@
+use std::ops::{BitOr, BitOrAssign};
+
 use crate::state::Module;
 
+pub mod array;
+pub mod coroutine;
 pub mod fmt;
+pub mod gc;
 pub mod io;
 pub mod iter;
 pub mod parse;
+pub mod set;
 pub mod string;
+pub mod table;
+
+/// A selection of standard library modules to make available to a VM.
+///
+/// Modules are combined with `|`, e.g. `StdlibSet::ITER | StdlibSet::STRING`.
+/// Leaving out `IO` takes away the console and file functions, but scripts
+/// can still import module files, unless the module loader is told not to
+/// with [`crate::state::ModuleLoader::set_file_imports`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StdlibSet(u32);
+
+impl StdlibSet {
+    pub const NONE: Self = Self(0);
+    pub const IO: Self = Self(1 << 0);
+    pub const ITER: Self = Self(1 << 1);
+    pub const STRING: Self = Self(1 << 2);
+    pub const PARSE: Self = Self(1 << 3);
+    pub const ARRAY: Self = Self(1 << 4);
+    pub const TABLE: Self = Self(1 << 5);
+    pub const SET: Self = Self(1 << 6);
+    pub const FMT: Self = Self(1 << 7);
+    pub const COROUTINE: Self = Self(1 << 8);
+    pub const GC: Self = Self(1 << 9);
+    pub const ALL: Self = Self((1 << 10) - 1);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for StdlibSet {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl BitOr for StdlibSet {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for StdlibSet {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
 
 pub fn modules() -> Vec<Module> {
-    vec![
-        io::module(),
-        iter::module(),
-        string::module(),
-        parse::module(),
-    ]
+    modules_in(StdlibSet::ALL)
+}
+
+pub fn modules_in(set: StdlibSet) -> Vec<Module> {
+    let all: [(StdlibSet, fn() -> Module); 10] = [
+        (StdlibSet::IO, io::module),
+        (StdlibSet::ITER, iter::module),
+        (StdlibSet::STRING, string::module),
+        (StdlibSet::PARSE, parse::module),
+        (StdlibSet::ARRAY, array::module),
+        (StdlibSet::TABLE, table::module),
+        (StdlibSet::SET, set::module),
+        (StdlibSet::FMT, fmt::module),
+        (StdlibSet::COROUTINE, coroutine::module),
+        (StdlibSet::GC, gc::module),
+    ];
+    all.into_iter()
+        .filter(|(flag, _)| set.contains(*flag))
+        .map(|(_, module)| module())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::{CompilerError, CompilerErrorKind},
+        vm::Vm,
+    };
+
+    use super::StdlibSet;
+
+    #[test]
+    fn without_io() {
+        let mut vm = Vm::new_with_stdlib(StdlibSet::ITER | StdlibSet::STRING);
+        assert!(matches!(
+            vm.load_from_source("main", "let main () = Io.print \"hello\""),
+            Err(CompilerError {
+                kind: CompilerErrorKind::NameNotFound(name),
+                ..
+            }) if name == "Io"
+        ));
+        assert!(vm
+            .load_from_source("main", "let main () = String.repeat \"a\" 2")
+            .is_ok());
+    }
 }
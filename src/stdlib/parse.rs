@@ -11,12 +11,13 @@ pub fn to_int(vm: &mut Vm) -> Result<Value, RuntimeError> {
         return Err(RuntimeError::IncorrectNumberOfArguments);
     }
 
-    let value = vm.pop();
+    let value = vm.pop()?;
     match value {
         Value::Bool(bool) => Ok(Value::Integer(bool as i64)),
         Value::Integer(int) => Ok(Value::Integer(int)),
         Value::Number(number) => Ok(Value::Integer(number as i64)),
         Value::String(str) => str
+            .trim()
             .parse()
             .map(Value::Integer)
             .map_err(|_| RuntimeError::InvalidConversion),
@@ -24,18 +25,87 @@ pub fn to_int(vm: &mut Vm) -> Result<Value, RuntimeError> {
     }
 }
 
+pub fn to_number(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+
+    let value = vm.pop()?;
+    match value {
+        Value::Bool(bool) => Ok(Value::Number(bool as i64 as f64)),
+        Value::Integer(int) => Ok(Value::Number(int as f64)),
+        Value::Number(number) => Ok(Value::Number(number)),
+        Value::String(str) => str
+            .trim()
+            .parse()
+            .map(Value::Number)
+            .map_err(|_| RuntimeError::InvalidConversion),
+        _ => Err(RuntimeError::UnexpectedType),
+    }
+}
+
+/// Coerces any value to a boolean using the language's truthiness rule:
+/// `()`, `false` and `0` are false, everything else is true.
+pub fn to_bool(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+
+    let value = vm.pop()?;
+    Ok(Value::Bool(!value.is_false()))
+}
+
 pub fn to_string(vm: &mut Vm) -> Result<Value, RuntimeError> {
     if vm.top() != 2 {
         return Err(RuntimeError::IncorrectNumberOfArguments);
     }
 
-    let value = vm.pop();
+    let value = vm.pop()?;
     Ok(Value::String(Rc::new(value.to_string())))
 }
 
 pub fn module() -> Module {
     NativeModuleBuilder::new("Parse")
         .with_function("to_int", to_int)
+        .with_function("to_number", to_number)
+        .with_function("to_bool", to_bool)
         .with_function("to_string", to_string)
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        value::Value,
+        vm::{tests::run, Vm},
+    };
+
+    #[test]
+    fn surrounding_whitespace_and_sign() {
+        assert_eq!(
+            run("let main () = Parse.to_int \" +42 \""),
+            Value::Integer(42)
+        );
+        assert_eq!(
+            run("let main () = Parse.to_number \"\t-1.5 \""),
+            Value::Number(-1.5)
+        );
+    }
+
+    #[test]
+    fn to_bool() {
+        assert_eq!(run("let main () = Parse.to_bool 0"), Value::Bool(false));
+        assert_eq!(run("let main () = Parse.to_bool ()"), Value::Bool(false));
+        assert_eq!(run("let main () = Parse.to_bool [1]"), Value::Bool(true));
+        assert_eq!(run("let main () = Parse.to_bool \"\""), Value::Bool(true));
+    }
+
+    #[test]
+    fn invalid_input() {
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let main () = Parse.to_int \"4 2\"")
+            .unwrap();
+        assert!(vm.execute_module(index, "main").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+// Elements can't be mutated in place, since the ones that could are rejected
+// by `element`, so interior mutability can't change a key's hash.
+#![allow(clippy::mutable_key_type)]
+
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+use crate::{
+    state::{Module, NativeModuleBuilder},
+    value::Value,
+    vm::{RuntimeError, Vm},
+};
+
+type Set = RefCell<HashSet<Value>>;
+
+fn set_value(set: HashSet<Value>) -> Value {
+    Value::UserData(Box::new(Rc::new(RefCell::new(set))))
+}
+
+fn as_set(value: Value) -> Result<Rc<dyn std::any::Any>, RuntimeError> {
+    let user_data = value.as_user_data().ok_or(RuntimeError::UnexpectedType)?;
+    if user_data.is::<Set>() {
+        Ok(*user_data)
+    } else {
+        Err(RuntimeError::UnexpectedType)
+    }
+}
+
+fn borrow_set(set: &Rc<dyn std::any::Any>) -> &Set {
+    set.downcast_ref::<Set>().unwrap()
+}
+
+/// Checks that `value` can be an element. Tables, arrays, closures and
+/// iterators hash by pointer but compare by contents, so a set couldn't find
+/// them again.
+fn element(value: Value) -> Result<Value, RuntimeError> {
+    fn is_hashable(value: &Value) -> bool {
+        match value {
+            Value::Table(_) | Value::Array(_) | Value::Closure(_) | Value::Iterator(_) => false,
+            Value::Tuple(tuple) => tuple.iter().all(is_hashable),
+            _ => true,
+        }
+    }
+    if is_hashable(&value) {
+        Ok(value)
+    } else {
+        Err(RuntimeError::UnexpectedType)
+    }
+}
+
+/// Creates a set, empty when called with `()` or else filled with the
+/// elements of an array.
+fn new(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let set = match vm.pop()? {
+        Value::Unit => HashSet::new(),
+        Value::Array(array) => array
+            .borrow()
+            .iter()
+            .cloned()
+            .map(element)
+            .collect::<Result<_, _>>()?,
+        _ => return Err(RuntimeError::UnexpectedType),
+    };
+    Ok(set_value(set))
+}
+
+fn add(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let value = element(vm.pop()?)?;
+    let set = vm.pop()?;
+    borrow_set(&as_set(set.clone())?).borrow_mut().insert(value);
+    Ok(set)
+}
+
+fn remove(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let value = element(vm.pop()?)?;
+    let set = vm.pop()?;
+    borrow_set(&as_set(set.clone())?)
+        .borrow_mut()
+        .remove(&value);
+    Ok(set)
+}
+
+fn contains(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let value = element(vm.pop()?)?;
+    let set = as_set(vm.pop()?)?;
+    let contains = borrow_set(&set).borrow().contains(&value);
+    Ok(Value::Bool(contains))
+}
+
+fn len(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let set = as_set(vm.pop()?)?;
+    let len = borrow_set(&set).borrow().len();
+    Ok(Value::Integer(len as i64))
+}
+
+fn union(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let rhs = as_set(vm.pop()?)?;
+    let lhs = as_set(vm.pop()?)?;
+    let rhs = borrow_set(&rhs).borrow();
+    let lhs = borrow_set(&lhs).borrow();
+    Ok(set_value(lhs.union(&rhs).cloned().collect()))
+}
+
+fn intersect(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let rhs = as_set(vm.pop()?)?;
+    let lhs = as_set(vm.pop()?)?;
+    let rhs = borrow_set(&rhs).borrow();
+    let lhs = borrow_set(&lhs).borrow();
+    Ok(set_value(lhs.intersection(&rhs).cloned().collect()))
+}
+
+pub fn module() -> Module {
+    NativeModuleBuilder::new("Set")
+        .with_function("new", new)
+        .with_function("add", add)
+        .with_function("remove", remove)
+        .with_function("contains", contains)
+        .with_function("len", len)
+        .with_function("union", union)
+        .with_function("intersect", intersect)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        value::Value,
+        vm::{tests::run, RuntimeError, Vm},
+    };
+
+    #[test]
+    fn union() {
+        let result = run("let main () = Set.union (Set.new [1, 2]) (Set.new [2, 3]) |> Set.len");
+        assert_eq!(result, Value::Integer(3));
+    }
+
+    #[test]
+    fn contains() {
+        let result = run("let main () = Set.new () |> Set.add 4 |> Set.contains 4");
+        assert_eq!(result, Value::Bool(true));
+        let result = run("let main () = Set.new [4] |> Set.remove 4 |> Set.contains 4");
+        assert_eq!(result, Value::Bool(false));
+    }
+
+    #[test]
+    fn aggregate_elements() {
+        let mut vm = Vm::new_with_std();
+        for source in [
+            "let main () = Set.new () |> Set.add [1]",
+            "let main () = Set.new () |> Set.add {}",
+            "let main () = Set.new () |> Set.add (1, [1])",
+            "let main () = Set.new () |> Set.contains [1]",
+            "let main () = Set.new [{}]",
+        ] {
+            let index = vm.load_from_source("main", source).unwrap();
+            assert!(
+                matches!(
+                    vm.execute_module(index, "main")
+                        .map_err(RuntimeError::without_trace),
+                    Err(RuntimeError::UnexpectedType)
+                ),
+                "{source}"
+            );
+        }
+        let result = run("let main () = Set.new () |> Set.add (1, 2) |> Set.add (1, 2) |> Set.len");
+        assert_eq!(result, Value::Integer(1));
+    }
+}
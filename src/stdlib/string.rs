@@ -6,19 +6,222 @@ use crate::{
     vm::{RuntimeError, Vm},
 };
 
-use super::fmt::format_to_string;
+/// The most bytes that `repeat` and the padding functions add to a string,
+/// so that a script can't make the host run out of memory.
+const MAX_LEN: usize = 1 << 30;
 
+/// The length in bytes of `count` copies of something `len` bytes long, if
+/// that isn't over [`MAX_LEN`].
+fn repeated_len(len: usize, count: i64) -> Result<usize, RuntimeError> {
+    let count = usize::try_from(count.max(0)).unwrap_or(usize::MAX);
+    len.checked_mul(count)
+        .filter(|len| *len <= MAX_LEN)
+        .ok_or_else(|| {
+            RuntimeError::Custom(format!("strings can't grow by more than {MAX_LEN} bytes"))
+        })
+}
+
+/// Returns the string built by an interpolated string literal. Interpolated
+/// strings are formatted when evaluated, so this is the identity on strings.
 fn from_format(vm: &mut Vm) -> Result<Value, RuntimeError> {
-    if vm.top() != 1 {
-        panic!("Incorrect number of arguments");
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    match vm.pop()? {
+        Value::String(string) => Ok(Value::String(string)),
+        _ => Err(RuntimeError::UnexpectedType),
+    }
+}
+
+fn trim_start(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let string = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
+    Ok(Value::String(Rc::new(string.trim_start().to_string())))
+}
+
+fn trim_end(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let string = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
+    Ok(Value::String(Rc::new(string.trim_end().to_string())))
+}
+
+/// Pops the `width` and `ch` arguments of the padding functions along with
+/// the string, returning the string and the padding needed to reach `width`.
+fn padding(vm: &mut Vm) -> Result<(Rc<String>, String), RuntimeError> {
+    if vm.top() - 1 != 3 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
     }
-    let args = vm.pop().as_table().unwrap();
-    let format = format_to_string(args);
-    Ok(Value::String(Rc::new(format)))
+    let ch = match vm.pop()? {
+        Value::Char(ch) => ch,
+        _ => return Err(RuntimeError::UnexpectedType),
+    };
+    let width = vm.pop()?.as_int().ok_or(RuntimeError::UnexpectedType)?;
+    let string = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
+    let len = string.chars().count() as i64;
+    let count = width.saturating_sub(len);
+    repeated_len(ch.len_utf8(), count)?;
+    let padding = std::iter::repeat_n(ch, count.max(0) as usize).collect();
+    Ok((string, padding))
+}
+
+fn pad_left(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    let (string, padding) = padding(vm)?;
+    Ok(Value::String(Rc::new(padding + &string)))
+}
+
+fn pad_right(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    let (string, padding) = padding(vm)?;
+    Ok(Value::String(Rc::new(string.to_string() + &padding)))
+}
+
+fn repeat(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let count = vm.pop()?.as_int().ok_or(RuntimeError::UnexpectedType)?;
+    let string = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
+    repeated_len(string.len(), count)?;
+    Ok(Value::String(Rc::new(string.repeat(count.max(0) as usize))))
+}
+
+/// Pops a string and the pattern searched in it.
+fn string_and_pattern(vm: &mut Vm) -> Result<(Rc<String>, Rc<String>), RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let pattern = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
+    let string = vm.pop()?.as_string().ok_or(RuntimeError::UnexpectedType)?;
+    Ok((string, pattern))
+}
+
+fn contains(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    let (string, pattern) = string_and_pattern(vm)?;
+    Ok(Value::Bool(string.contains(pattern.as_str())))
+}
+
+fn starts_with(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    let (string, pattern) = string_and_pattern(vm)?;
+    Ok(Value::Bool(string.starts_with(pattern.as_str())))
+}
+
+fn ends_with(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    let (string, pattern) = string_and_pattern(vm)?;
+    Ok(Value::Bool(string.ends_with(pattern.as_str())))
+}
+
+/// Returns the character index of the first occurrence of the pattern, or
+/// unit if it is not found.
+fn index_of(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    let (string, pattern) = string_and_pattern(vm)?;
+    Ok(match string.find(pattern.as_str()) {
+        Some(offset) => Value::Integer(string[..offset].chars().count() as i64),
+        None => Value::Unit,
+    })
 }
 
 pub fn module() -> Module {
     NativeModuleBuilder::new("String")
         .with_function("from_format", from_format)
+        .with_function("trim_start", trim_start)
+        .with_function("trim_end", trim_end)
+        .with_function("pad_left", pad_left)
+        .with_function("pad_right", pad_right)
+        .with_function("repeat", repeat)
+        .with_function("contains", contains)
+        .with_function("starts_with", starts_with)
+        .with_function("ends_with", ends_with)
+        .with_function("index_of", index_of)
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        value::Value,
+        vm::{tests::run, RuntimeError, Vm},
+    };
+
+    fn string(str: &str) -> Value {
+        Value::String(Rc::new(str.to_string()))
+    }
+
+    #[test]
+    fn pad() {
+        assert_eq!(
+            run("let main () = String.pad_left \"7\" 3 '0'"),
+            string("007")
+        );
+        assert_eq!(
+            run("let main () = String.pad_right \"é\" 3 '.'"),
+            string("é..")
+        );
+    }
+
+    #[test]
+    fn trim_and_repeat() {
+        assert_eq!(
+            run("let main () = String.trim_start \"  a \""),
+            string("a ")
+        );
+        assert_eq!(run("let main () = String.trim_end \" a  \""), string(" a"));
+        assert_eq!(
+            run("let main () = String.repeat \"ab\" 3"),
+            string("ababab")
+        );
+    }
+
+    #[test]
+    fn too_long() {
+        for source in [
+            "let main () = String.repeat \"a\" 9223372036854775807",
+            "let main () = String.repeat \"ab\" 4611686018427387904",
+            "let main () = String.pad_left \"a\" 9223372036854775807 'x'",
+        ] {
+            let mut vm = Vm::new_with_std();
+            let index = vm.load_from_source("main", source).unwrap();
+            assert!(matches!(
+                vm.execute_module(index, "main")
+                    .map_err(RuntimeError::without_trace),
+                Err(RuntimeError::Custom(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn index_of() {
+        assert_eq!(
+            run("let main () = String.index_of \"hello\" \"ll\""),
+            Value::Integer(2)
+        );
+        assert_eq!(
+            run("let main () = String.index_of \"héllo\" \"l\""),
+            Value::Integer(2)
+        );
+        assert_eq!(
+            run("let main () = String.index_of \"hello\" \"x\""),
+            Value::Unit
+        );
+    }
+
+    #[test]
+    fn predicates() {
+        assert_eq!(
+            run("let main () = String.starts_with \"hello\" \"he\""),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            run("let main () = String.ends_with \"hello\" \"he\""),
+            Value::Bool(false)
+        );
+        assert_eq!(
+            run("let main () = String.contains \"hello\" \"ell\""),
+            Value::Bool(true)
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+
+use crate::{
+    state::{Module, NativeModuleBuilder},
+    value::Value,
+    vm::{RuntimeError, Vm},
+};
+
+/// Orders keys by their type and then by value, so that tables give the
+/// same order every run. Keys that can't be compared, like tables or
+/// functions, are left in the order the table has them.
+fn compare_keys(a: &Value, b: &Value) -> Ordering {
+    a.type_name().cmp(b.type_name()).then_with(|| match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        _ => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+    })
+}
+
+/// Turns a table into an array of `[key, value]` pairs.
+///
+/// Tables don't keep the order keys were inserted in, so the pairs are
+/// sorted by key, see [`compare_keys`].
+fn to_array(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 1 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let table = vm.pop()?.as_table().ok_or(RuntimeError::UnexpectedType)?;
+    let mut entries: Vec<(Value, Value)> = table
+        .borrow()
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    entries.sort_by(|(a, _), (b, _)| compare_keys(a, b));
+    let pairs = entries
+        .into_iter()
+        .map(|(key, value)| Value::Array(vm.alloc_array(vec![key, value])))
+        .collect();
+    Ok(Value::Array(vm.alloc_array(pairs)))
+}
+
+/// Returns whether a table has a key, even one set to `()`.
+fn contains(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 2 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let key = vm.pop()?;
+    let table = vm.pop()?.as_table().ok_or(RuntimeError::UnexpectedType)?;
+    let contains = table.borrow().contains_key(&key);
+    Ok(Value::Bool(contains))
+}
+
+/// Returns the value of a key, or `default` if the table doesn't have it.
+///
+/// Indexing a table with a missing key yields `()`, the same as a key set
+/// to `()`; unlike indexing, `get_or` only falls back for missing keys.
+fn get_or(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    if vm.top() - 1 != 3 {
+        return Err(RuntimeError::IncorrectNumberOfArguments);
+    }
+    let default = vm.pop()?;
+    let key = vm.pop()?;
+    let table = vm.pop()?.as_table().ok_or(RuntimeError::UnexpectedType)?;
+    let value = table.borrow().get(&key).cloned().unwrap_or(default);
+    Ok(value)
+}
+
+pub fn module() -> Module {
+    NativeModuleBuilder::new("Table")
+        .with_function("to_array", to_array)
+        .with_function("contains", contains)
+        .with_function("get_or", get_or)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{value::Value, vm::tests::run};
+
+    #[test]
+    fn get_or() {
+        let result = run("let t = { a: 1, b: () }
+let main () = [Table.get_or t \"a\" 0, Table.get_or t \"b\" 0, Table.get_or t \"c\" 0]");
+        let result = result.as_array().unwrap();
+        assert_eq!(
+            *result.borrow(),
+            [Value::Integer(1), Value::Unit, Value::Integer(0)]
+        );
+    }
+
+    #[test]
+    fn contains() {
+        let result = run("let t = { a: () }
+let main () = [Table.contains t \"a\", Table.contains t \"b\", t.b]");
+        let result = result.as_array().unwrap();
+        assert_eq!(
+            *result.borrow(),
+            [Value::Bool(true), Value::Bool(false), Value::Unit]
+        );
+    }
+
+    #[test]
+    fn to_array_is_sorted_by_key() {
+        let result = run("let main () =
+    let t = { c: 3, a: 1, b: 2 }
+    t[2] = 0
+    t[1] = 0
+    Table.to_array t");
+        assert_eq!(result.to_string(), "[[1,0,],[2,0,],[a,1,],[b,2,],[c,3,],]");
+    }
+
+    #[test]
+    fn round_trip() {
+        let result = run("let main () = Table.to_array { a: 1, b: 2 } |> Array.to_table");
+        let table = result.as_table().unwrap();
+        let table = table.borrow();
+        assert_eq!(table.len(), 2);
+        assert_eq!(
+            table.get(&Value::String("a".to_string().into())),
+            Some(&Value::Integer(1))
+        );
+        assert_eq!(
+            table.get(&Value::String("b".to_string().into())),
+            Some(&Value::Integer(2))
+        );
+    }
+}
@@ -74,10 +74,13 @@ token_types!(
     Comma ",",
     SingleQuote "'",
     DoubleQuote "\"",
+    RawString "<raw string>",
     Dot ".",
     Dots "..",
+    DotsEq "..=",
     Spread "...",
     Plus "+",
+    Concat "++",
     Minus "-",
     Div "/",
     IDiv "//",
@@ -98,6 +101,7 @@ token_types!(
     NotEqual "!=",
     ThinArrow "->",
     Pipe "|>",
+    Coalesce "??",
     LBracket "[",
     RBracket "]",
     LParen "(",
@@ -121,9 +125,23 @@ token_types!(
     If "if" reserved,
     Then "then" reserved,
     Else "else" reserved,
+    While "while" reserved,
+    Do "do" reserved,
+    For "for" reserved,
+    In "in" reserved,
+    Break "break" reserved,
+    Continue "continue" reserved,
     From "from" reserved,
     Import "import" reserved,
     As "as" reserved,
+    Pub "pub" reserved,
+    Type "type" reserved,
+    Try "try" reserved,
+    Catch "catch" reserved,
+    Assert "assert" reserved,
+    Yield "yield" reserved,
+    Await "await" reserved,
+    Const "const" reserved,
 );
 
 impl TokenType {
@@ -139,10 +157,15 @@ impl TokenType {
                 | TokenType::False
                 | TokenType::Unit
                 | TokenType::DoubleQuote
+                | TokenType::RawString
+                | TokenType::SingleQuote
                 | TokenType::LCurly
                 | TokenType::LBracket
                 | TokenType::Function
                 | TokenType::If
+                | TokenType::Match
+                | TokenType::While
+                | TokenType::For
         )
     }
 }
@@ -0,0 +1,1103 @@
+//! An optional pass between parsing and compilation that infers the types of
+//! expressions where it can and reports operations that would fail at
+//! runtime.
+//!
+//! The checker is conservative: anything it can't infer, such as the values
+//! of other modules or of locals that are reassigned, has the type `any` and
+//! is never reported.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fmt::Display,
+};
+
+use crate::ast::{
+    ArithmeticOperator, BitwiseOperator, BooleanOperator, ComparisonOperator, Expression,
+    ExpressionKind, Literal, Operation, PathPart, Pattern, Statement, TableEntry, Type,
+    UnaryOperation,
+};
+
+/// The inferred type of an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    /// A type that couldn't be inferred.
+    Any,
+    Unit,
+    Bool,
+    Char,
+    Integer,
+    Number,
+    String,
+    Array,
+    Tuple(Vec<Ty>),
+    /// A table with the fields known to be in it.
+    Table(BTreeMap<String, Ty>),
+    Function {
+        params: Vec<Ty>,
+        /// The number of parameters without a default value.
+        required: usize,
+        ret: Box<Ty>,
+    },
+    Iterator,
+}
+
+impl Ty {
+    /// Converts an annotation to the type it describes. Names that aren't
+    /// built-in types, such as records, are `any`.
+    pub fn from_annotation(annotation: &Type) -> Self {
+        match annotation {
+            Type::Unit => Ty::Unit,
+            Type::Named(name) => match name.as_str() {
+                "unit" => Ty::Unit,
+                "bool" => Ty::Bool,
+                "char" => Ty::Char,
+                "int" => Ty::Integer,
+                "number" => Ty::Number,
+                "string" => Ty::String,
+                "array" => Ty::Array,
+                "table" => Ty::Table(BTreeMap::new()),
+                "iterator" => Ty::Iterator,
+                _ => Ty::Any,
+            },
+            Type::Array(_) => Ty::Array,
+            Type::Tuple(types) => Ty::Tuple(types.iter().map(Ty::from_annotation).collect()),
+        }
+    }
+
+    /// Returns whether a value of type `found` can be used where `self` is
+    /// expected.
+    pub fn accepts(&self, found: &Ty) -> bool {
+        match (self, found) {
+            (Ty::Any, _) | (_, Ty::Any) => true,
+            (Ty::Number, Ty::Integer) => true,
+            (Ty::Tuple(expected), Ty::Tuple(found)) => {
+                expected.len() == found.len()
+                    && expected.iter().zip(found).all(|(e, f)| e.accepts(f))
+            }
+            (Ty::Table(_), Ty::Table(_)) => true,
+            (Ty::Function { .. }, Ty::Function { .. }) => true,
+            (expected, found) => expected == found,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Ty::Any | Ty::Integer | Ty::Number)
+    }
+
+    fn is_integer(&self) -> bool {
+        matches!(self, Ty::Any | Ty::Integer)
+    }
+
+    /// The type of a value that is either of `self` or `other`.
+    fn join(self, other: Ty) -> Ty {
+        if self == other {
+            self
+        } else {
+            Ty::Any
+        }
+    }
+}
+
+impl Display for Ty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ty::Any => write!(f, "any"),
+            Ty::Unit => write!(f, "unit"),
+            Ty::Bool => write!(f, "bool"),
+            Ty::Char => write!(f, "char"),
+            Ty::Integer => write!(f, "int"),
+            Ty::Number => write!(f, "number"),
+            Ty::String => write!(f, "string"),
+            Ty::Array => write!(f, "array"),
+            Ty::Tuple(types) => {
+                write!(f, "(")?;
+                for (i, ty) in types.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{ty}")?;
+                }
+                write!(f, ")")
+            }
+            Ty::Table(_) => write!(f, "table"),
+            Ty::Function { .. } => write!(f, "function"),
+            Ty::Iterator => write!(f, "iterator"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TypeErrorKind {
+    InvalidOperands {
+        operator: &'static str,
+        lhs: Ty,
+        rhs: Ty,
+    },
+    InvalidOperand {
+        operator: &'static str,
+        operand: Ty,
+    },
+    NotCallable(Ty),
+    WrongNumberOfArguments {
+        expected: usize,
+        found: usize,
+    },
+    Mismatch {
+        expected: Ty,
+        found: Ty,
+    },
+    InvalidIndex(Ty),
+    WrongNumberOfElements {
+        expected: usize,
+        found: usize,
+    },
+}
+
+/// A type error and the line of the statement it was found in.
+#[derive(Debug, PartialEq)]
+pub struct TypeError {
+    pub line_no: usize,
+    pub kind: TypeErrorKind,
+}
+
+impl Error for TypeError {}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.line_no)?;
+        match &self.kind {
+            TypeErrorKind::InvalidOperands { operator, lhs, rhs } => {
+                write!(f, "`{operator}` cannot be applied to {lhs} and {rhs}")
+            }
+            TypeErrorKind::InvalidOperand { operator, operand } => {
+                write!(f, "`{operator}` cannot be applied to {operand}")
+            }
+            TypeErrorKind::NotCallable(ty) => write!(f, "A value of type {ty} cannot be called"),
+            TypeErrorKind::WrongNumberOfArguments { expected, found } => {
+                write!(f, "Expected {expected} arguments, found {found}")
+            }
+            TypeErrorKind::Mismatch { expected, found } => {
+                write!(f, "Expected {expected}, found {found}")
+            }
+            TypeErrorKind::InvalidIndex(ty) => write!(f, "An array cannot be indexed with {ty}"),
+            TypeErrorKind::WrongNumberOfElements { expected, found } => {
+                write!(f, "Expected {expected} elements to unpack, found {found}")
+            }
+        }
+    }
+}
+
+/// Checks the statements of a module, returning every error found.
+pub fn check(statements: &[Statement]) -> Result<(), Vec<TypeError>> {
+    let mut checker = TypeChecker::default();
+    for statement in statements {
+        checker.collect_assigned_statement(statement);
+    }
+    checker.scopes.push(HashMap::new());
+    // Module locals can be used by the functions before them.
+    for statement in statements {
+        match statement {
+            Statement::Let { ident, .. } => checker.declare(ident, Ty::Any),
+            Statement::LetTuple { idents, .. } => {
+                for ident in idents {
+                    checker.declare(ident, Ty::Any);
+                }
+            }
+            _ => {}
+        }
+    }
+    checker.declare_functions(statements);
+    for statement in statements {
+        checker.statement(statement);
+    }
+    if checker.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(checker.errors)
+    }
+}
+
+#[derive(Default)]
+struct TypeChecker {
+    scopes: Vec<HashMap<String, Ty>>,
+    /// The idents that are assigned to anywhere in the module. Their type
+    /// can change, so they are `any`.
+    assigned: HashSet<String>,
+    errors: Vec<TypeError>,
+    line_no: usize,
+    /// Whether the function being checked yields.
+    yields: bool,
+}
+
+impl TypeChecker {
+    fn error(&mut self, kind: TypeErrorKind) {
+        self.errors.push(TypeError {
+            line_no: self.line_no,
+            kind,
+        });
+    }
+
+    fn declare(&mut self, ident: &str, ty: Ty) {
+        let ty = if self.assigned.contains(ident) {
+            Ty::Any
+        } else {
+            ty
+        };
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(ident.to_string(), ty);
+    }
+
+    fn lookup(&self, ident: &str) -> Ty {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(ident))
+            .cloned()
+            .unwrap_or(Ty::Any)
+    }
+
+    /// Declares the function statements so that they can call each other
+    /// before their bodies are checked.
+    fn declare_functions(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            if let Statement::Function {
+                ident,
+                arg_types,
+                arg_defaults,
+                ..
+            } = statement
+            {
+                let params = arg_types.iter().map(param_type).collect();
+                self.declare(
+                    ident,
+                    Ty::Function {
+                        params,
+                        required: num_required(arg_defaults),
+                        ret: Ty::Any.into(),
+                    },
+                );
+            }
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Ty {
+        match statement {
+            Statement::Let {
+                line_no,
+                ident,
+                ty,
+                value,
+                ..
+            } => {
+                self.line_no = *line_no;
+                let found = match value {
+                    Some(value) => self.expression(value),
+                    None => Ty::Unit,
+                };
+                let ty = match ty {
+                    Some(annotation) => {
+                        let expected = Ty::from_annotation(annotation);
+                        if !expected.accepts(&found) {
+                            self.error(TypeErrorKind::Mismatch {
+                                expected: expected.clone(),
+                                found,
+                            });
+                        }
+                        expected
+                    }
+                    None => found,
+                };
+                self.declare(ident, ty);
+                Ty::Unit
+            }
+            Statement::Function {
+                line_no,
+                ident,
+                args,
+                arg_types,
+                arg_defaults,
+                expr,
+                ..
+            } => {
+                self.line_no = *line_no;
+                let params: Vec<_> = arg_types.iter().map(param_type).collect();
+                let ret = self.function(args, &params, arg_defaults, expr);
+                self.declare(
+                    ident,
+                    Ty::Function {
+                        params,
+                        required: num_required(arg_defaults),
+                        ret: ret.into(),
+                    },
+                );
+                Ty::Unit
+            }
+            Statement::LetTuple {
+                line_no,
+                idents,
+                value,
+            } => {
+                self.line_no = *line_no;
+                let types = match self.expression(value) {
+                    Ty::Tuple(types) if types.len() == idents.len() => types,
+                    Ty::Tuple(types) => {
+                        self.error(TypeErrorKind::WrongNumberOfElements {
+                            expected: idents.len(),
+                            found: types.len(),
+                        });
+                        vec![Ty::Any; idents.len()]
+                    }
+                    Ty::Any | Ty::Array => vec![Ty::Any; idents.len()],
+                    found => {
+                        self.error(TypeErrorKind::Mismatch {
+                            expected: Ty::Tuple(vec![Ty::Any; idents.len()]),
+                            found,
+                        });
+                        vec![Ty::Any; idents.len()]
+                    }
+                };
+                for (ident, ty) in idents.iter().zip(types) {
+                    self.declare(ident, ty);
+                }
+                Ty::Unit
+            }
+            Statement::Const {
+                line_no,
+                ident,
+                value,
+                ..
+            } => {
+                self.line_no = *line_no;
+                let ty = self.expression(value);
+                self.declare(ident, ty);
+                Ty::Unit
+            }
+            Statement::Type { .. } | Statement::Import { .. } => Ty::Unit,
+            Statement::Expression {
+                line_no,
+                expression,
+            } => {
+                self.line_no = *line_no;
+                self.expression(expression)
+            }
+        }
+    }
+
+    /// Checks the body of a function and returns the type it returns.
+    fn function(
+        &mut self,
+        args: &[String],
+        params: &[Ty],
+        defaults: &[Option<Expression>],
+        expr: &Expression,
+    ) -> Ty {
+        let line_no = self.line_no;
+        let yields = std::mem::take(&mut self.yields);
+        self.scopes.push(HashMap::new());
+        for ((arg, param), default) in args.iter().zip(params).zip(defaults) {
+            if let Some(default) = default {
+                let found = self.expression(default);
+                if !param.accepts(&found) {
+                    self.error(TypeErrorKind::Mismatch {
+                        expected: param.clone(),
+                        found,
+                    });
+                }
+            }
+            self.declare(arg, param.clone());
+        }
+        let ret = self.expression(expr);
+        self.scopes.pop();
+        self.line_no = line_no;
+        // Calling a generator creates an iterator over what it yields.
+        if std::mem::replace(&mut self.yields, yields) {
+            Ty::Iterator
+        } else {
+            ret
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Ty {
+        match &expression.kind {
+            ExpressionKind::Literal(literal) => match literal {
+                Literal::Unit => Ty::Unit,
+                Literal::Bool(_) => Ty::Bool,
+                Literal::Char(_) => Ty::Char,
+                Literal::Integer(_) => Ty::Integer,
+                Literal::Number(_) => Ty::Number,
+                Literal::String(_) => Ty::String,
+            },
+            ExpressionKind::UnaryOperation { operand, operation } => {
+                let operand = self.expression(operand);
+                let (operator, valid, ty) = match operation {
+                    UnaryOperation::Not => ("not", true, Ty::Bool),
+                    UnaryOperation::Negate => ("-", operand.is_numeric(), operand.clone()),
+                    UnaryOperation::BitNot => ("~", operand.is_integer(), Ty::Integer),
+                };
+                if !valid {
+                    self.error(TypeErrorKind::InvalidOperand { operator, operand });
+                    return Ty::Any;
+                }
+                ty
+            }
+            ExpressionKind::Operation {
+                lhs,
+                operation: Operation::Assignment,
+                rhs,
+            } => {
+                self.assignment_target(lhs);
+                self.expression(rhs);
+                Ty::Any
+            }
+            ExpressionKind::Operation {
+                lhs,
+                operation,
+                rhs,
+            } => {
+                let lhs = self.expression(lhs);
+                let rhs = self.expression(rhs);
+                self.operation(operation, lhs, rhs)
+            }
+            ExpressionKind::Array(elements) => {
+                for element in elements {
+                    self.expression(element);
+                }
+                Ty::Array
+            }
+            ExpressionKind::Tuple(elements) => {
+                Ty::Tuple(elements.iter().map(|e| self.expression(e)).collect())
+            }
+            ExpressionKind::Table(entries) => {
+                let mut fields = BTreeMap::new();
+                for TableEntry { key, value } in entries {
+                    self.expression(key);
+                    let ty = self.expression(value);
+                    if let ExpressionKind::Literal(Literal::String(key)) = &key.kind {
+                        fields.insert(key.clone(), ty);
+                    }
+                }
+                Ty::Table(fields)
+            }
+            ExpressionKind::Block(statements) => {
+                self.scopes.push(HashMap::new());
+                self.declare_functions(statements);
+                let mut ty = Ty::Unit;
+                for statement in statements {
+                    ty = self.statement(statement);
+                }
+                if !statements.last().is_some_and(Statement::is_expression) {
+                    ty = Ty::Any;
+                }
+                self.scopes.pop();
+                ty
+            }
+            ExpressionKind::Path { ident, parts } => {
+                let mut ty = self.lookup(ident);
+                for part in parts {
+                    ty = self.access(ty, part);
+                }
+                ty
+            }
+            ExpressionKind::Call { callee, args } => {
+                let callee = self.expression(callee);
+                let arg_types: Vec<_> = args.iter().map(|arg| self.expression(arg)).collect();
+                match callee {
+                    Ty::Any => Ty::Any,
+                    Ty::Function {
+                        params,
+                        required,
+                        ret,
+                    } => {
+                        if args
+                            .iter()
+                            .any(|arg| matches!(arg.kind, ExpressionKind::Spread(_)))
+                        {
+                            return *ret;
+                        }
+                        // Fewer arguments than the required ones result in a
+                        // partial application.
+                        if args.len() > params.len() || args.is_empty() && required > 0 {
+                            self.error(TypeErrorKind::WrongNumberOfArguments {
+                                expected: params.len(),
+                                found: args.len(),
+                            });
+                            return *ret;
+                        }
+                        let rest = params[args.len()..].to_vec();
+                        for (expected, found) in params.into_iter().zip(arg_types) {
+                            if !expected.accepts(&found) {
+                                self.error(TypeErrorKind::Mismatch { expected, found });
+                            }
+                        }
+                        if args.len() >= required {
+                            *ret
+                        } else {
+                            Ty::Function {
+                                params: rest,
+                                required: required - args.len(),
+                                ret,
+                            }
+                        }
+                    }
+                    ty => {
+                        self.error(TypeErrorKind::NotCallable(ty));
+                        Ty::Any
+                    }
+                }
+            }
+            ExpressionKind::Function {
+                args,
+                arg_types,
+                arg_defaults,
+                expr,
+            } => {
+                let params: Vec<_> = arg_types.iter().map(param_type).collect();
+                let ret = self.function(args, &params, arg_defaults, expr);
+                Ty::Function {
+                    params,
+                    required: num_required(arg_defaults),
+                    ret: ret.into(),
+                }
+            }
+            ExpressionKind::If {
+                condition,
+                block,
+                r#else,
+            } => {
+                self.expression(condition);
+                let ty = self.expression(block);
+                match r#else {
+                    Some(r#else) => {
+                        let other = self.expression(r#else);
+                        ty.join(other)
+                    }
+                    None => Ty::Any,
+                }
+            }
+            ExpressionKind::InterpolatedString { arguments, .. } => {
+                for argument in arguments {
+                    self.expression(&argument.expression);
+                }
+                Ty::String
+            }
+            ExpressionKind::Match { value, arms } => {
+                let value = self.expression(value);
+                let mut ty = None;
+                for arm in arms {
+                    self.scopes.push(HashMap::new());
+                    match &arm.pattern {
+                        Pattern::Binding(ident) => self.declare(ident, value.clone()),
+                        pattern => self.declare_pattern(pattern),
+                    }
+                    if let Some(guard) = &arm.guard {
+                        self.expression(guard);
+                    }
+                    let arm = self.expression(&arm.expression);
+                    self.scopes.pop();
+                    ty = Some(match ty {
+                        Some(ty) => arm.join(ty),
+                        None => arm,
+                    });
+                }
+                // A value that matches no arm results in unit.
+                let exhaustive = arms
+                    .iter()
+                    .any(|arm| arm.guard.is_none() && arm.pattern.is_irrefutable());
+                match ty {
+                    Some(ty) if exhaustive => ty,
+                    _ => Ty::Any,
+                }
+            }
+            ExpressionKind::While { condition, block } => {
+                self.expression(condition);
+                self.expression(block);
+                Ty::Any
+            }
+            ExpressionKind::For {
+                ident,
+                iterable,
+                block,
+            } => {
+                self.expression(iterable);
+                self.scopes.push(HashMap::new());
+                self.declare(ident, Ty::Any);
+                self.expression(block);
+                self.scopes.pop();
+                Ty::Any
+            }
+            ExpressionKind::Try {
+                block,
+                ident,
+                catch,
+            } => {
+                let ty = self.expression(block);
+                self.scopes.push(HashMap::new());
+                self.declare(ident, Ty::Any);
+                let other = self.expression(catch);
+                self.scopes.pop();
+                ty.join(other)
+            }
+            ExpressionKind::Assert {
+                condition, message, ..
+            } => {
+                self.expression(condition);
+                if let Some(message) = message {
+                    self.expression(message);
+                }
+                Ty::Unit
+            }
+            ExpressionKind::Break | ExpressionKind::Continue => Ty::Any,
+            ExpressionKind::Spread(expression) => {
+                self.expression(expression);
+                Ty::Any
+            }
+            ExpressionKind::Yield(value) => {
+                self.expression(value);
+                self.yields = true;
+                Ty::Unit
+            }
+            // What a future results in isn't known.
+            ExpressionKind::Await(value) => {
+                self.expression(value);
+                Ty::Any
+            }
+            // The fields a method can be looked up in aren't known.
+            ExpressionKind::Method { receiver, .. } => {
+                self.expression(receiver);
+                Ty::Any
+            }
+        }
+    }
+
+    /// Declares the bindings in the parts of a structural pattern.
+    fn declare_pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Binding(ident) => self.declare(ident, Ty::Any),
+            Pattern::Table(fields) => {
+                for (_, pattern) in fields {
+                    self.declare_pattern(pattern);
+                }
+            }
+            Pattern::Array { elements, rest } => {
+                for pattern in elements {
+                    self.declare_pattern(pattern);
+                }
+                if let Some(Pattern::Binding(ident)) = rest.as_deref() {
+                    self.declare(ident, Ty::Array);
+                }
+            }
+            Pattern::Wildcard | Pattern::Literal(_) => {}
+        }
+    }
+
+    /// Checks the indices in the target of an assignment.
+    fn assignment_target(&mut self, target: &Expression) {
+        if let ExpressionKind::Path { parts, .. } = &target.kind {
+            for part in parts {
+                match part {
+                    PathPart::Index(index) => {
+                        self.expression(index);
+                    }
+                    PathPart::Slice { start, end, .. } => {
+                        for bound in [start, end].into_iter().flatten() {
+                            self.expression(bound);
+                        }
+                    }
+                    PathPart::Ident(_) => {}
+                }
+            }
+        } else {
+            self.expression(target);
+        }
+    }
+
+    /// The type of a field or an element of a value of type `ty`.
+    fn access(&mut self, ty: Ty, part: &PathPart) -> Ty {
+        let key = match part {
+            PathPart::Ident(field) => {
+                if let Ty::Table(fields) = &ty {
+                    return fields.get(field).cloned().unwrap_or(Ty::Any);
+                }
+                Ty::String
+            }
+            PathPart::Index(index) => self.expression(index),
+            PathPart::Slice { start, end, .. } => {
+                for bound in [start, end].into_iter().flatten() {
+                    let bound_ty = self.expression(bound);
+                    if !bound_ty.is_integer() {
+                        self.error(TypeErrorKind::InvalidIndex(bound_ty));
+                    }
+                }
+                return match ty {
+                    Ty::Array | Ty::String => ty,
+                    _ => Ty::Any,
+                };
+            }
+        };
+        if ty == Ty::String && matches!(part, PathPart::Index(_)) && key.is_integer() {
+            return Ty::Char;
+        }
+        if ty == Ty::Array && !key.is_integer() {
+            self.error(TypeErrorKind::InvalidIndex(key));
+        }
+        Ty::Any
+    }
+
+    fn operation(&mut self, operation: &Operation, lhs: Ty, rhs: Ty) -> Ty {
+        let operator = operator(operation);
+        let ty = match operation {
+            Operation::Assignment => Some(Ty::Any),
+            Operation::Arithmetic(operator) => match (&lhs, &rhs) {
+                _ if !lhs.is_numeric() || !rhs.is_numeric() => None,
+                (Ty::Any, _) | (_, Ty::Any) => Some(Ty::Any),
+                _ if *operator == ArithmeticOperator::IDivide => Some(Ty::Integer),
+                (Ty::Integer, Ty::Integer) => Some(Ty::Integer),
+                _ => Some(Ty::Number),
+            },
+            Operation::Comparison(_) | Operation::Boolean(_) => Some(Ty::Bool),
+            Operation::Bitwise(_) => (lhs.is_integer() && rhs.is_integer()).then_some(Ty::Integer),
+            Operation::Concat => match (&lhs, &rhs) {
+                (Ty::String, _) | (_, Ty::String) => Some(Ty::String),
+                (Ty::Array, Ty::Array) => Some(Ty::Array),
+                (Ty::Any, _) | (_, Ty::Any) => Some(Ty::Any),
+                _ => None,
+            },
+            Operation::Range { .. } => {
+                (lhs.is_integer() && rhs.is_integer()).then_some(Ty::Iterator)
+            }
+            Operation::Coalesce if lhs == Ty::Unit => Some(rhs.clone()),
+            Operation::Coalesce => Some(lhs.clone().join(rhs.clone())),
+        };
+        ty.unwrap_or_else(|| {
+            self.error(TypeErrorKind::InvalidOperands { operator, lhs, rhs });
+            Ty::Any
+        })
+    }
+
+    fn collect_assigned_statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Let {
+                value: Some(value), ..
+            } => self.collect_assigned(value),
+            Statement::Function { expr, .. } => self.collect_assigned(expr),
+            Statement::LetTuple { value, .. } | Statement::Const { value, .. } => {
+                self.collect_assigned(value)
+            }
+            Statement::Expression { expression, .. } => self.collect_assigned(expression),
+            Statement::Let { value: None, .. }
+            | Statement::Type { .. }
+            | Statement::Import { .. } => {}
+        }
+    }
+
+    fn collect_assigned(&mut self, expression: &Expression) {
+        match &expression.kind {
+            ExpressionKind::Operation {
+                lhs,
+                operation,
+                rhs,
+            } => {
+                if let (Operation::Assignment, ExpressionKind::Path { ident, .. }) =
+                    (operation, &lhs.kind)
+                {
+                    self.assigned.insert(ident.clone());
+                }
+                self.collect_assigned(lhs);
+                self.collect_assigned(rhs);
+            }
+            ExpressionKind::UnaryOperation { operand, .. } => self.collect_assigned(operand),
+            ExpressionKind::Array(elements) | ExpressionKind::Tuple(elements) => {
+                for element in elements {
+                    self.collect_assigned(element);
+                }
+            }
+            ExpressionKind::Table(entries) => {
+                for entry in entries {
+                    self.collect_assigned(&entry.key);
+                    self.collect_assigned(&entry.value);
+                }
+            }
+            ExpressionKind::Block(statements) => {
+                for statement in statements {
+                    self.collect_assigned_statement(statement);
+                }
+            }
+            ExpressionKind::Path { parts, .. } => {
+                for part in parts {
+                    match part {
+                        PathPart::Index(index) => self.collect_assigned(index),
+                        PathPart::Slice { start, end, .. } => {
+                            for bound in [start, end].into_iter().flatten() {
+                                self.collect_assigned(bound);
+                            }
+                        }
+                        PathPart::Ident(_) => {}
+                    }
+                }
+            }
+            ExpressionKind::Call { callee, args } => {
+                self.collect_assigned(callee);
+                for arg in args {
+                    self.collect_assigned(arg);
+                }
+            }
+            ExpressionKind::Function { expr, .. } => self.collect_assigned(expr),
+            ExpressionKind::If {
+                condition,
+                block,
+                r#else,
+            } => {
+                self.collect_assigned(condition);
+                self.collect_assigned(block);
+                if let Some(r#else) = r#else {
+                    self.collect_assigned(r#else);
+                }
+            }
+            ExpressionKind::InterpolatedString { arguments, .. } => {
+                for argument in arguments {
+                    self.collect_assigned(&argument.expression);
+                }
+            }
+            ExpressionKind::Match { value, arms } => {
+                self.collect_assigned(value);
+                for arm in arms {
+                    if let Some(guard) = &arm.guard {
+                        self.collect_assigned(guard);
+                    }
+                    self.collect_assigned(&arm.expression);
+                }
+            }
+            ExpressionKind::While { condition, block } => {
+                self.collect_assigned(condition);
+                self.collect_assigned(block);
+            }
+            ExpressionKind::For {
+                iterable, block, ..
+            } => {
+                self.collect_assigned(iterable);
+                self.collect_assigned(block);
+            }
+            ExpressionKind::Try { block, catch, .. } => {
+                self.collect_assigned(block);
+                self.collect_assigned(catch);
+            }
+            ExpressionKind::Assert {
+                condition, message, ..
+            } => {
+                self.collect_assigned(condition);
+                if let Some(message) = message {
+                    self.collect_assigned(message);
+                }
+            }
+            ExpressionKind::Spread(expression) => self.collect_assigned(expression),
+            ExpressionKind::Method { receiver, .. } => self.collect_assigned(receiver),
+            ExpressionKind::Yield(value) | ExpressionKind::Await(value) => {
+                self.collect_assigned(value)
+            }
+            ExpressionKind::Literal(_) | ExpressionKind::Break | ExpressionKind::Continue => {}
+        }
+    }
+}
+
+/// The number of arguments before the first with a default value.
+fn num_required(defaults: &[Option<Expression>]) -> usize {
+    defaults
+        .iter()
+        .take_while(|default| default.is_none())
+        .count()
+}
+
+fn param_type(annotation: &Option<Type>) -> Ty {
+    annotation
+        .as_ref()
+        .map(Ty::from_annotation)
+        .unwrap_or(Ty::Any)
+}
+
+fn operator(operation: &Operation) -> &'static str {
+    match operation {
+        Operation::Assignment => "=",
+        Operation::Arithmetic(operator) => match operator {
+            ArithmeticOperator::Add => "+",
+            ArithmeticOperator::Subtract => "-",
+            ArithmeticOperator::Divide => "/",
+            ArithmeticOperator::IDivide => "//",
+            ArithmeticOperator::Multiply => "*",
+            ArithmeticOperator::Modulus => "%",
+        },
+        Operation::Comparison(operator) => match operator {
+            ComparisonOperator::Less => "<",
+            ComparisonOperator::LessEqual => "<=",
+            ComparisonOperator::Equal => "==",
+            ComparisonOperator::NotEqual => "!=",
+            ComparisonOperator::GreaterEqual => ">=",
+            ComparisonOperator::Greater => ">",
+        },
+        Operation::Boolean(BooleanOperator::And) => "and",
+        Operation::Boolean(BooleanOperator::Or) => "or",
+        Operation::Bitwise(operator) => match operator {
+            BitwiseOperator::And => "&",
+            BitwiseOperator::Or => "|",
+            BitwiseOperator::Xor => "^",
+            BitwiseOperator::Shl => "<<",
+            BitwiseOperator::Shr => ">>",
+        },
+        Operation::Concat => "++",
+        Operation::Range { inclusive: false } => "..",
+        Operation::Range { inclusive: true } => "..=",
+        Operation::Coalesce => "??",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::{CompilerError, CompilerErrorKind},
+        parser::{Parser, ParserError},
+        state::ModuleLoader,
+    };
+
+    use super::{check, Ty, TypeError, TypeErrorKind};
+
+    fn errors(source: &str) -> Vec<TypeError> {
+        let mut parser = Parser::new(source);
+        let mut statements = Vec::new();
+        loop {
+            match parser.parse() {
+                Ok(statement) => statements.push(statement),
+                Err(ParserError::EndOfSource) => break,
+                Err(e) => panic!("{e}"),
+            }
+        }
+        check(&statements).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn well_typed() {
+        let source = "let a = 1
+let f (x: int) y = x + y * 2.5
+let g () =
+    let t = { name: \"a\", n: 2 }
+    let s = \"n: \" ++ t.n
+    let c: char = s[-1]
+    let w: string = s[1..]
+    let n = t.n + f a 2
+    let h = f 1
+    let m = n + h 2
+    let i = 0
+    i = i ++ \"x\"
+    let (p, q) = (1, \"b\")
+    p + (if n > 2 then 1 else 2)
+let main () = g ()";
+        assert_eq!(errors(source), []);
+        for path in [
+            "docs/examples/fibonacci.fl",
+            "docs/tutorials/03-functions.fl",
+            "docs/tutorials/04-blocks.fl",
+        ] {
+            let source = std::fs::read_to_string(path).unwrap();
+            assert_eq!(errors(&source), [], "{path}");
+        }
+    }
+
+    #[test]
+    fn type_errors() {
+        let source = "let f (x: int) = x
+let main () =
+    let a = 1 + \"b\"
+    let t = { n: \"c\" }
+    let b: int = t.n
+    f 1 2
+    f \"x\"
+    let (c, d) = (1, 2, 3)
+    let k = 2
+    k ()
+    let l = fn (x: int) y -> x
+    l \"y\"
+    let st = \"abc\"
+    let m: int = st[0]
+    m";
+        let kinds: Vec<_> = errors(source)
+            .into_iter()
+            .map(|e| (e.line_no, e.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (
+                    3,
+                    TypeErrorKind::InvalidOperands {
+                        operator: "+",
+                        lhs: Ty::Integer,
+                        rhs: Ty::String
+                    }
+                ),
+                (
+                    5,
+                    TypeErrorKind::Mismatch {
+                        expected: Ty::Integer,
+                        found: Ty::String
+                    }
+                ),
+                (
+                    6,
+                    TypeErrorKind::WrongNumberOfArguments {
+                        expected: 1,
+                        found: 2
+                    }
+                ),
+                (
+                    7,
+                    TypeErrorKind::Mismatch {
+                        expected: Ty::Integer,
+                        found: Ty::String
+                    }
+                ),
+                (
+                    8,
+                    TypeErrorKind::WrongNumberOfElements {
+                        expected: 2,
+                        found: 3
+                    }
+                ),
+                (10, TypeErrorKind::NotCallable(Ty::Integer)),
+                (
+                    12,
+                    TypeErrorKind::Mismatch {
+                        expected: Ty::Integer,
+                        found: Ty::String
+                    }
+                ),
+                (
+                    14,
+                    TypeErrorKind::Mismatch {
+                        expected: Ty::Integer,
+                        found: Ty::Char
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn opt_in() {
+        let source = "let main () = 1 + \"a\"";
+        let mut module_loader = ModuleLoader::new("");
+        assert!(module_loader.load_module_from_source("a", source).is_ok());
+        module_loader.set_typecheck(true);
+        let Err(CompilerError {
+            kind: CompilerErrorKind::TypeErrors(errors),
+            ..
+        }) = module_loader.load_module_from_source("b", source)
+        else {
+            panic!("expected type errors");
+        };
+        assert_eq!(
+            errors[0].to_string(),
+            "line 1: `+` cannot be applied to int and string"
+        );
+    }
+}
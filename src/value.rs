@@ -1,30 +1,135 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::HashMap,
     fmt::{Debug, Display},
+    future::Future,
     hash::Hash,
+    ops::Deref,
+    pin::Pin,
     process::Termination,
     rc::Rc,
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::{
     state::{Module, Prototype},
     vm::{RuntimeError, Vm},
 };
 
-pub type Table = HashMap<Value, Value>;
-
 pub type StringRef = Rc<String>;
 pub type TableRef = Rc<RefCell<Table>>;
 pub type PrototypeRef = Rc<Prototype>;
 pub type NativeFunctionRef = Rc<NativeFunction>;
 pub type UpvalueRef = Rc<RefCell<Upvalue>>;
 pub type ClosureRef = Rc<Closure>;
+pub type PartialRef = Rc<Partial>;
 pub type ArrayRef = Rc<RefCell<Vec<Value>>>;
+// Tuples and user data are boxed so that their pointers are thin and a
+// `Value` is two words.
+pub type TupleRef = Rc<Box<[Value]>>;
 pub type ModuleRef = Rc<Module>;
 pub type UserDataRef = Box<Rc<dyn std::any::Any>>;
+pub type NativeFn = dyn FnMut(&mut Vm) -> Result<Value, RuntimeError>;
+pub type BoxFuture = Pin<Box<dyn Future<Output = Result<Value, RuntimeError>>>>;
+/// The future inside a future value, or `None` once it has been awaited.
+pub type FutureRef = Rc<RefCell<Option<BoxFuture>>>;
+
+thread_local! {
+    static NEXT_VERSION: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A number that no other call returns, which tables and module caches are
+/// versioned with.
+pub fn next_version() -> u64 {
+    NEXT_VERSION.with(|next| {
+        let version = next.get();
+        next.set(version + 1);
+        version
+    })
+}
+
+/// The entries of a table value.
+///
+/// Each change to the entries gives the table a new version, which no other
+/// table has, so that a `GetField` cache that saw a version knows the
+/// entries are the ones it saw. A clone keeps the version, since it has the
+/// same entries until one of them changes.
+#[derive(Debug, Clone)]
+pub struct Table {
+    entries: HashMap<Value, Value>,
+    version: u64,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            version: next_version(),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn insert(&mut self, key: Value, value: Value) -> Option<Value> {
+        self.version = next_version();
+        self.entries.insert(key, value)
+    }
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reading the entries goes straight to the map, while changing them has to
+/// go through the table to update its version.
+impl Deref for Table {
+    type Target = HashMap<Value, Value>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl PartialEq for Table {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl FromIterator<(Value, Value)> for Table {
+    fn from_iter<T: IntoIterator<Item = (Value, Value)>>(iter: T) -> Self {
+        Self {
+            entries: HashMap::from_iter(iter),
+            version: next_version(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Table {
+    type Item = (&'a Value, &'a Value);
+    type IntoIter = std::collections::hash_map::Iter<'a, Value, Value>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+impl Serialize for Table {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.entries.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Table {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::<Value, Value>::deserialize(deserializer).map(Table::from_iter)
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum Upvalue {
@@ -34,12 +139,12 @@ pub enum Upvalue {
 
 pub struct NativeFunction {
     pub ident: String,
-    pub function: Rc<RefCell<dyn FnMut(&mut Vm) -> Result<Value, RuntimeError>>>,
+    pub function: Rc<RefCell<NativeFn>>,
 }
 
 impl PartialEq for NativeFunction {
     fn eq(&self, other: &Self) -> bool {
-        self.ident == other.ident && Rc::as_ptr(&self.function) == Rc::as_ptr(&other.function)
+        self.ident == other.ident && Rc::ptr_eq(&self.function, &other.function)
     }
 }
 
@@ -52,10 +157,19 @@ impl Debug for NativeFunction {
     }
 }
 
+/// A function called with fewer arguments than it takes, waiting for the
+/// rest of them.
+#[derive(Debug, PartialEq)]
+pub struct Partial {
+    pub closure: ClosureRef,
+    pub args: Vec<Value>,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Function {
     Prototype(PrototypeRef),
     Native(NativeFunctionRef),
+    Partial(PartialRef),
 }
 
 impl Function {
@@ -63,6 +177,7 @@ impl Function {
         match self {
             Function::Prototype(prototype) => &prototype.ident,
             Function::Native(native) => &native.ident,
+            Function::Partial(partial) => partial.closure.function.ident(),
         }
     }
 
@@ -94,7 +209,7 @@ impl Closure {
         Self {
             function: Function::Prototype(function),
             upvalues: Vec::with_capacity(num_upvalues),
-            num_upvalues: num_upvalues,
+            num_upvalues,
         }
     }
 
@@ -105,6 +220,14 @@ impl Closure {
             num_upvalues: 0,
         }
     }
+
+    pub fn from_partial(partial: Partial) -> Self {
+        Self {
+            function: Function::Partial(Rc::new(partial)),
+            upvalues: Vec::new(),
+            num_upvalues: 0,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -127,19 +250,99 @@ pub enum Value {
     #[serde(skip_deserializing)]
     UserData(UserDataRef),
     Char(char),
+    /// A closure called without arguments for each next value, which
+    /// returns unit once there are no more. For loops, spreads and the
+    /// `Iter` functions all consume iterators this way.
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     Iterator(ClosureRef),
+    #[serde(skip_deserializing)]
+    Tuple(TupleRef),
 }
 
 impl Value {
-    pub fn is_false(&self) -> bool {
+    /// Creates an iterator that calls `function` for each next value.
+    pub fn iterator<F: FnMut(&mut Vm) -> Result<Value, RuntimeError> + 'static>(
+        function: F,
+    ) -> Value {
+        Value::Iterator(Rc::new(Closure::from_native(Rc::new(NativeFunction {
+            ident: "_iter".to_string(),
+            function: Rc::new(RefCell::new(function)),
+        }))))
+    }
+
+    /// Creates a future for scripts to `await`, for natives that would
+    /// otherwise block. It only runs under `Vm::run_async`.
+    pub fn future(future: impl Future<Output = Result<Value, RuntimeError>> + 'static) -> Value {
+        let future: Rc<dyn std::any::Any> =
+            Rc::new(RefCell::new(Some(Box::pin(future) as BoxFuture)));
+        Value::UserData(Box::new(future))
+    }
+
+    /// Returns the future of a future value, or the value itself if it isn't
+    /// one.
+    pub fn into_future(self) -> Result<FutureRef, Value> {
         match self {
-            Value::Unit | Value::Bool(false) | Value::Integer(0) => true,
-            _ => false,
+            Value::UserData(user_data) => (*user_data)
+                .downcast::<RefCell<Option<BoxFuture>>>()
+                .map_err(|user_data| Value::UserData(Box::new(user_data))),
+            value => Err(value),
+        }
+    }
+
+    /// Turns strings, arrays and closures into iterators. Iterators are
+    /// returned as they are.
+    pub fn into_iterator(self) -> Result<ClosureRef, RuntimeError> {
+        let result = match self {
+            Value::String(str) => {
+                let mut i = 0;
+                Value::iterator(move |_vm| {
+                    let Some(result) = str[i..].chars().next() else {
+                        return Ok(Value::Unit);
+                    };
+                    i += result.len_utf8();
+                    Ok(Value::Char(result))
+                })
+            }
+            Value::Closure(closure) => Value::Iterator(closure),
+            Value::Array(array) => {
+                let mut i = 0;
+                Value::iterator(move |_vm| {
+                    let array = array.borrow();
+                    if i < array.len() {
+                        let result = array[i].clone();
+                        i += 1;
+                        Ok(result)
+                    } else {
+                        Ok(Value::Unit)
+                    }
+                })
+            }
+            Value::Iterator(iterator) => Value::Iterator(iterator),
+            _ => return Err(RuntimeError::UnexpectedType),
+        };
+
+        Ok(result.as_iterator().unwrap())
+    }
+
+    /// Equality as used by the `==` and `!=` operators: like `PartialEq`,
+    /// but integers and numbers compare by numeric value, so `1 == 1.0`.
+    ///
+    /// `PartialEq` itself keeps the variants distinct because it has to
+    /// agree with `Hash`, which table keys rely on.
+    pub fn numeric_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Integer(l), Value::Number(r)) | (Value::Number(r), Value::Integer(l)) => {
+                *l as f64 == *r
+            }
+            _ => self == other,
         }
     }
 
+    pub fn is_false(&self) -> bool {
+        matches!(self, Value::Unit | Value::Bool(false) | Value::Integer(0))
+    }
+
     pub fn as_user_data(self) -> Option<UserDataRef> {
         match self {
             Value::UserData(user_data) => Some(user_data),
@@ -161,6 +364,13 @@ impl Value {
         }
     }
 
+    pub fn as_tuple(self) -> Option<TupleRef> {
+        match self {
+            Value::Tuple(tuple) => Some(tuple),
+            _ => None,
+        }
+    }
+
     pub fn as_table(self) -> Option<TableRef> {
         match self {
             Value::Table(table) => Some(table),
@@ -203,6 +413,7 @@ impl Value {
             Value::Module(_) => "module",
             Value::UserData(_) => "user_data",
             Value::Iterator(_) => "iterator",
+            Value::Tuple(_) => "tuple",
         }
     }
 }
@@ -224,8 +435,9 @@ impl PartialEq for Value {
             (Self::Table(l0), Self::Table(r0)) => l0 == r0,
             (Self::Closure(l0), Self::Closure(r0)) => l0 == r0,
             (Self::Array(l0), Self::Array(r0)) => l0 == r0,
+            (Self::Tuple(l0), Self::Tuple(r0)) => l0 == r0,
             (Self::Module(l0), Self::Module(r0)) => l0 == r0,
-            (Self::UserData(l0), Self::UserData(r0)) => Rc::as_ptr(l0) == Rc::as_ptr(r0),
+            (Self::UserData(l0), Self::UserData(r0)) => Rc::ptr_eq(l0, r0),
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
@@ -240,11 +452,32 @@ impl PartialOrd for Value {
             (Value::Number(l), Value::Number(r)) => l.partial_cmp(r),
             (Value::Integer(l), Value::Number(r)) => (*l as f64).partial_cmp(r),
             (Value::Number(l), Value::Integer(r)) => l.partial_cmp(&(*r as f64)),
+            (Value::Char(l), Value::Char(r)) => l.partial_cmp(r),
+            (Value::String(l), Value::String(r)) => l.partial_cmp(r),
+            (Value::Array(l), Value::Array(r)) => {
+                if Rc::ptr_eq(l, r) {
+                    return Some(std::cmp::Ordering::Equal);
+                }
+                compare_elements(&l.borrow(), &r.borrow())
+            }
+            (Value::Tuple(l), Value::Tuple(r)) => compare_elements(l, r),
             _ => None,
         }
     }
 }
 
+/// Compares sequences element by element. A prefix of a sequence is less
+/// than the sequence.
+fn compare_elements(l: &[Value], r: &[Value]) -> Option<std::cmp::Ordering> {
+    for (l, r) in l.iter().zip(r.iter()) {
+        match l.partial_cmp(r)? {
+            std::cmp::Ordering::Equal => {}
+            ordering => return Some(ordering),
+        }
+    }
+    l.len().partial_cmp(&r.len())
+}
+
 impl Hash for Value {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         core::mem::discriminant(self).hash(state);
@@ -261,6 +494,8 @@ impl Hash for Value {
             Value::Module(module) => Rc::as_ptr(module).hash(state),
             Value::UserData(user_data) => Rc::as_ptr(user_data).hash(state),
             Value::Iterator(iterator) => Rc::as_ptr(iterator).hash(state),
+            // Tuples are immutable, so they can be hashed by value.
+            Value::Tuple(tuple) => tuple.hash(state),
         }
     }
 }
@@ -305,6 +540,16 @@ impl Display for Value {
             Value::Iterator(iterator) => {
                 write!(f, "iterator: {:x?}", Rc::as_ptr(iterator))
             }
+            Value::Tuple(tuple) => {
+                write!(f, "(")?;
+                for (i, value) in tuple.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, ")")
+            }
         }
     }
 }
@@ -1,19 +1,37 @@
-use core::panic;
-use std::{cell::RefCell, error::Error, fmt::Display, rc::Rc, usize};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Display,
+    rc::Rc,
+};
 
 use crate::{
     compiler::CompilerError,
-    op::OpCode,
-    state::{Module, ModuleLoader, ModuleValue, StackTrace, StackTraceInfo},
-    stdlib,
-    value::{Closure, ClosureRef, Function, Table, Upvalue, UpvalueRef, Value},
+    gc::Gc,
+    op::{OpCode, Operator},
+    plugin::{Plugin, PluginError},
+    state::{FieldCache, Module, ModuleLoader, ModuleValue, Prototype, StackTrace, StackTraceInfo},
+    stdlib::{self, fmt, StdlibSet},
+    value::{
+        next_version, ArrayRef, BoxFuture, Closure, ClosureRef, Function, Partial, Table, TableRef,
+        Upvalue, UpvalueRef, Value,
+    },
 };
 
 const NUM_FRAMES: usize = 64;
+/// The native module holding the callbacks registered by the host.
+pub const HOST_MODULE: &str = "Host";
 const STACK_SIZE: usize = u8::MAX as usize;
+/// The number of calls in the stack trace of an error that ends a script.
+const TRACE_DEPTH: usize = 16;
 
 struct CallFrame {
     closure: ClosureRef,
+    /// The code of the closure's prototype, kept here so that running it
+    /// doesn't go through the closure, or empty for natives.
+    code: Rc<[u8]>,
+    /// The offset of the next op code in `code`.
     ip: usize,
     slot_offset: usize,
 }
@@ -21,11 +39,90 @@ struct CallFrame {
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
 pub struct Vm {
     frames: Vec<CallFrame>,
-    stack: Vec<Value>,
+    stack: Vec<Slot>,
     open_upvalues: Vec<UpvalueRef>,
+    /// The active `try` blocks, innermost last.
+    handlers: Vec<Handler>,
+    /// The generator frame that the last `Yield` suspended, until the
+    /// generator takes it back.
+    suspended: Option<Suspended>,
+    /// The number of coroutines that are running, inside each other.
+    running_coroutines: usize,
+    /// The value a coroutine yielded, until its `resume` takes it.
+    yielded: Option<Value>,
+    /// Whether the VM is running under `run_async`, where scripts can await
+    /// futures.
+    is_async: bool,
+    /// The future the last `await` suspended the script for, until
+    /// `run_async` takes it.
+    awaiting: Option<BoxFuture>,
     module_loader: ModuleLoader,
+    /// The locals of the imported modules that have been initialized,
+    /// keyed by module.
+    module_values: HashMap<*const Module, Rc<[Value]>>,
+    /// Changes whenever `module_values` is cleared, so that `GetField`
+    /// caches don't give values from before.
+    module_values_version: u64,
+    /// Keeps track of the objects the VM allocates, to free the ones in
+    /// reference cycles.
+    gc: Gc,
+    /// The executed lines of each prototype, when coverage is enabled.
+    coverage: Option<HashSet<(*const Prototype, usize)>>,
+    trace: Option<Box<TraceFn>>,
     #[cfg(target_arch = "wasm32")]
     event_emitter: web_sys::EventTarget,
+    #[cfg(not(target_arch = "wasm32"))]
+    event_listeners: HashMap<String, Vec<Box<EventListener>>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub type EventListener = dyn FnMut(&Value);
+
+/// Called with each opcode, its offset in the function's code and the
+/// stack, right before the opcode executes.
+pub type TraceFn = dyn FnMut(&OpCode, usize, &[Slot]);
+
+/// A value on the stack, which is a [`Value`] unless the `nan-boxing`
+/// feature packs it in a [`NanBox`](crate::nanbox::NanBox) of 8 bytes.
+#[cfg(not(feature = "nan-boxing"))]
+pub type Slot = Value;
+#[cfg(feature = "nan-boxing")]
+pub type Slot = crate::nanbox::NanBox;
+
+#[cfg(not(feature = "nan-boxing"))]
+#[inline(always)]
+fn into_slot(value: Value) -> Slot {
+    value
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+#[inline(always)]
+fn from_slot(slot: Slot) -> Value {
+    slot
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+#[inline(always)]
+fn slot_value(slot: &Slot) -> &Value {
+    slot
+}
+
+#[cfg(feature = "nan-boxing")]
+#[inline(always)]
+fn into_slot(value: Value) -> Slot {
+    Slot::from(value)
+}
+
+#[cfg(feature = "nan-boxing")]
+#[inline(always)]
+fn from_slot(slot: Slot) -> Value {
+    Value::from(slot)
+}
+
+#[cfg(feature = "nan-boxing")]
+#[inline(always)]
+fn slot_value(slot: &Slot) -> crate::nanbox::ValueRef<'_> {
+    slot.value()
 }
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
@@ -43,6 +140,45 @@ impl Vm {
     pub fn event_target(&mut self) -> &mut web_sys::EventTarget {
         &mut self.event_emitter
     }
+
+    /// Dispatches an event with the value's string form as its detail.
+    pub fn emit(&mut self, type_: &str, detail: Value) -> bool {
+        self.event_emitter
+            .dispatch_event(
+                &web_sys::CustomEvent::new_with_event_init_dict(
+                    type_,
+                    web_sys::CustomEventInit::new().detail(&detail.to_string().into()),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        true
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Vm {
+    /// Subscribes to events of `type_`, such as the `"log"` events emitted
+    /// by `Io.print`.
+    pub fn add_event_listener(&mut self, type_: &str, listener: impl FnMut(&Value) + 'static) {
+        self.event_listeners
+            .entry(type_.to_string())
+            .or_default()
+            .push(Box::new(listener));
+    }
+
+    /// Calls the listeners of `type_` and returns whether there were any.
+    pub fn emit(&mut self, type_: &str, detail: Value) -> bool {
+        match self.event_listeners.get_mut(type_) {
+            Some(listeners) if !listeners.is_empty() => {
+                for listener in listeners {
+                    listener(&detail);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
@@ -52,64 +188,316 @@ impl Vm {
             frames: Vec::with_capacity(NUM_FRAMES),
             stack: Vec::with_capacity(STACK_SIZE * NUM_FRAMES),
             open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            suspended: None,
+            running_coroutines: 0,
+            yielded: None,
+            is_async: false,
+            awaiting: None,
             module_loader,
+            module_values: HashMap::new(),
+            module_values_version: next_version(),
+            gc: Gc::new(),
+            coverage: None,
+            trace: None,
             #[cfg(target_arch = "wasm32")]
             event_emitter: web_sys::EventTarget::new().unwrap(),
+            #[cfg(not(target_arch = "wasm32"))]
+            event_listeners: HashMap::new(),
         }
     }
 
     pub fn new_with_std() -> Self {
-        let mut module_loader = ModuleLoader::new("");
-        module_loader.add_modules(stdlib::modules());
-        Self {
-            frames: Vec::with_capacity(NUM_FRAMES),
-            stack: Vec::with_capacity(STACK_SIZE * NUM_FRAMES),
-            open_upvalues: Vec::new(),
-            module_loader,
-            #[cfg(target_arch = "wasm32")]
-            event_emitter: web_sys::EventTarget::new().unwrap(),
-        }
+        Self::new_with_stdlib(StdlibSet::ALL)
     }
 
     pub fn load_from_source(&mut self, ident: &str, source: &str) -> Result<usize, CompilerError> {
         self.module_loader.load_module_from_source(ident, source)
     }
 
+    /// Loads a compiled module, which runs like one loaded from source.
+    pub fn load_from_bytes(&mut self, bytes: &[u8]) -> Result<usize, CompilerError> {
+        self.module_loader.load_module_from_bytes(bytes)
+    }
+
     pub fn execute_module(&mut self, index: usize, ident: &str) -> Result<(), RuntimeError> {
         let module = self.module_loader.module_at(index).unwrap();
+        if self.module_loader.is_stale(index) {
+            return Err(RuntimeError::StaleModule(module.ident.clone()));
+        }
+        for array in self.module_loader.take_arrays() {
+            self.gc.alloc(array);
+        }
         let index = module.local(ident).unwrap();
-        self.load_module(module)?;
-        let closure = self.stack[index].clone().as_closure().unwrap();
-        self.push(Value::Closure(closure.clone()));
-        self.push(Value::Unit);
-        self.execute_prototype(closure, 1)?;
-        self.run()
+        self.init_imported_modules(&module)
+            .and_then(|_| self.load_module(module))
+            .and_then(|_| {
+                let closure = from_slot(self.stack[index].clone()).as_closure().unwrap();
+                self.push(Value::Closure(closure.clone()));
+                self.execute_prototype(closure, 0)
+            })
+            .and_then(|_| self.run())
+            .map_err(|error| self.traced(error))
     }
 
+    /// The innermost `depth` calls that are running, innermost first, each
+    /// with the line it's at. The frames of module code that has finished,
+    /// which stay to hold the module's locals, are left out.
     pub fn stack_trace(&self, depth: usize) -> StackTrace {
-        let mut info = Vec::new();
-        for i in 0..depth.min(self.frames.len() - 1) {
-            let frame = &self.frames[self.frames.len() - 1 - i];
-            let st_info = match &frame.closure.function {
-                Function::Prototype(prototype) => StackTraceInfo::Prototype {
-                    ident: prototype.ident.clone(),
-                    line: prototype.line(frame.ip),
-                },
-                Function::Native(native) => StackTraceInfo::NativeFunction {
+        let info = self
+            .frames
+            .iter()
+            .rev()
+            .filter_map(|frame| match &frame.closure.function {
+                // The op code being run is the one before `ip`, or for the
+                // callers the call to the frame above.
+                Function::Prototype(prototype) if frame.ip <= frame.code.len() => {
+                    Some(StackTraceInfo::Prototype {
+                        ident: prototype.ident.clone(),
+                        line: prototype.line(frame.ip.saturating_sub(1)),
+                    })
+                }
+                Function::Prototype(_) => None,
+                Function::Native(native) => Some(StackTraceInfo::NativeFunction {
                     ident: native.ident.clone(),
-                },
-            };
-            info.push(st_info);
-        }
+                }),
+                Function::Partial(_) => unreachable!("Partial applications don't run in a frame."),
+            })
+            .take(depth)
+            .collect();
         StackTrace::new(info)
     }
 }
 
 impl Vm {
+    /// Creates a VM with only the selected standard library modules.
+    pub fn new_with_stdlib(set: StdlibSet) -> Self {
+        let mut module_loader = ModuleLoader::new("");
+        module_loader.add_modules(stdlib::modules_in(set));
+        Self::new(module_loader)
+    }
+
+    /// Creates an error that raises `value` from the innermost script
+    /// function, for natives to report failures that scripts can catch.
+    pub fn error(&self, value: Value) -> RuntimeError {
+        let (function, line) = self
+            .frames
+            .iter()
+            .rev()
+            .find_map(|frame| {
+                let prototype = frame.closure.function.prototype()?;
+                Some((
+                    prototype.ident.clone(),
+                    prototype.line(frame.ip.saturating_sub(1)),
+                ))
+            })
+            .unwrap_or_default();
+        RuntimeError::Thrown {
+            value,
+            function,
+            line,
+        }
+    }
+
+    /// Executes a function of a module like `execute_module`, as a future.
+    /// Whenever the script awaits a future, such as one returned by a
+    /// native, the VM suspends the script and awaits that future itself, so
+    /// the host's executor can go on with other tasks in the meantime.
+    pub async fn run_async(&mut self, index: usize, ident: &str) -> Result<(), RuntimeError> {
+        let module = self.module_loader.module_at(index).unwrap();
+        let index = module.local(ident).unwrap();
+        if let Err(error) = self
+            .init_imported_modules(&module)
+            .and_then(|_| self.load_module(module))
+        {
+            return Err(self.traced(error));
+        }
+        let closure = from_slot(self.stack[index].clone()).as_closure().unwrap();
+        let frame_base = self.frames.len();
+        let stack_base = self.stack.len();
+        let handler_base = self.handlers.len();
+        self.is_async = true;
+        self.push(Value::Closure(closure.clone()));
+        let mut result = self.execute_prototype(closure, 0);
+        while let Err(RuntimeError::Await) = result {
+            let future = self.awaiting.take().unwrap();
+            let Some(segment) = self.suspend(frame_base, stack_base, handler_base) else {
+                result = Err(self.cannot_suspend(RuntimeError::Await));
+                break;
+            };
+            let value = future.await;
+            result = self.restore(segment, value);
+        }
+        self.is_async = false;
+        result.map_err(|error| self.traced(error))
+    }
+
+    /// Attaches the calls that were running to an error that ends a script.
+    fn traced(&self, error: RuntimeError) -> RuntimeError {
+        match error {
+            RuntimeError::Traced { .. } => error,
+            error => RuntimeError::Traced {
+                error: error.into(),
+                trace: self.stack_trace(TRACE_DEPTH),
+            },
+        }
+    }
+
+    /// Adds the modules of a plugin. Nothing is added if any of them
+    /// collides with a module that is already loaded.
+    pub fn register_plugin(&mut self, plugin: &impl Plugin) -> Result<(), PluginError> {
+        let modules = plugin.modules();
+        for (i, module) in modules.iter().enumerate() {
+            if self.module_loader.module(&module.ident).is_some()
+                || modules[..i].iter().any(|m| m.ident == module.ident)
+            {
+                return Err(PluginError::ModuleAlreadyExists {
+                    plugin: plugin.name().to_string(),
+                    module: module.ident.clone(),
+                });
+            }
+        }
+        self.module_loader.add_modules(modules);
+        Ok(())
+    }
+
+    /// Makes a Rust callback callable from scripts as `Host.<name>`.
+    ///
+    /// Callbacks must be registered before the scripts using them are
+    /// compiled, since module members are resolved at compile time.
+    pub fn register_callback(
+        &mut self,
+        name: &str,
+        mut callback: Box<dyn FnMut(Vec<Value>) -> Value>,
+    ) -> Result<(), RuntimeError> {
+        let function = move |vm: &mut Vm| {
+            let num_args = vm.top() - 1;
+            Ok(callback(vm.pop_values(num_args)))
+        };
+        self.module_loader
+            .add_native_function(HOST_MODULE, name, Rc::new(RefCell::new(function)))
+    }
+
+    /// Calls a closure with the given arguments and returns its result.
+    pub fn call_closure(
+        &mut self,
+        closure: &ClosureRef,
+        args: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        self.push(Value::Closure(closure.clone()));
+        for arg in args {
+            self.push(arg.clone());
+        }
+        match self.call(closure.clone(), args.len()) {
+            // The caller goes on in Rust, which can't be suspended.
+            Err(error @ (RuntimeError::Yield | RuntimeError::Await)) => {
+                return Err(self.cannot_suspend(error))
+            }
+            result => result?,
+        }
+        self.pop()
+    }
+
+    /// Returns the next value of an iterator, or `None` once it returns
+    /// unit.
+    pub fn next(&mut self, iterator: &ClosureRef) -> Result<Option<Value>, RuntimeError> {
+        match self.call_closure(iterator, &[])? {
+            Value::Unit => Ok(None),
+            value => Ok(Some(value)),
+        }
+    }
+
+    /// Collects the values of anything that can be iterated over.
+    pub fn collect(&mut self, value: Value) -> Result<Vec<Value>, RuntimeError> {
+        if let Value::Array(array) = value {
+            return Ok(array.borrow().clone());
+        }
+        let iterator = value.into_iterator()?;
+        let mut values = Vec::new();
+        while let Some(value) = self.next(&iterator)? {
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    /// Starts or stops recording the executed lines. Starting clears the
+    /// lines recorded so far.
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = enabled.then(HashSet::new);
+    }
+
+    /// Returns the `(module index, line)` pairs executed since coverage was
+    /// enabled. Lines of modules that were reloaded since are left out.
+    pub fn coverage(&self) -> HashSet<(usize, usize)> {
+        let Some(coverage) = &self.coverage else {
+            return HashSet::new();
+        };
+        let mut prototypes = HashMap::new();
+        for (index, module) in self.module_loader.modules().iter().enumerate() {
+            if let ModuleValue::Normal(prototype) = &module.value {
+                let mut pending = vec![prototype];
+                while let Some(prototype) = pending.pop() {
+                    prototypes.insert(Rc::as_ptr(prototype), index);
+                    pending.extend(&prototype.prototypes);
+                }
+            }
+        }
+        coverage
+            .iter()
+            .filter_map(|(prototype, line)| prototypes.get(prototype).map(|index| (*index, *line)))
+            .collect()
+    }
+
+    /// Sets a callback invoked before each opcode executes, replacing the
+    /// previous one. Passing `None` stops tracing.
+    pub fn set_trace(&mut self, trace: Option<Box<TraceFn>>) {
+        self.trace = trace;
+    }
+
     pub fn module_loader(&self) -> &ModuleLoader {
         &self.module_loader
     }
 
+    /// Returns the string shared with the constants of the loaded modules,
+    /// for natives that look up or return the same strings on every call.
+    pub fn intern(&mut self, string: &str) -> Rc<String> {
+        self.module_loader.intern(string)
+    }
+
+    /// Allocates a table that the garbage collector keeps track of, which
+    /// natives should use for the tables they return.
+    pub fn alloc_table(&mut self, table: Table) -> TableRef {
+        self.gc.alloc(Rc::new(RefCell::new(table)))
+    }
+
+    /// Allocates an array that the garbage collector keeps track of, like
+    /// [`Vm::alloc_table`].
+    pub fn alloc_array(&mut self, array: Vec<Value>) -> ArrayRef {
+        self.gc.alloc(Rc::new(RefCell::new(array)))
+    }
+
+    /// Frees the tables, arrays, tuples and closures that are only kept
+    /// alive by reference cycles, returning how many objects were freed. It
+    /// also happens on its own as objects are allocated.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.gc.collect()
+    }
+
+    /// Recompiles a loaded module from new source.
+    ///
+    /// The stack is cleared since it may hold closures and locals of the
+    /// previous version of the module.
+    pub fn reload_module(&mut self, index: usize, source: &str) -> Result<(), CompilerError> {
+        self.module_loader.reload(index, source)?;
+        self.frames.clear();
+        self.stack.clear();
+        self.open_upvalues.clear();
+        self.handlers.clear();
+        self.module_values.clear();
+        self.module_values_version = next_version();
+        Ok(())
+    }
+
     fn load_module(&mut self, module: Rc<Module>) -> Result<(), RuntimeError> {
         let module = Rc::new(module);
         match &module.value {
@@ -126,10 +514,82 @@ impl Vm {
         Ok(())
     }
 
-    pub fn stack(&self) -> &[Value] {
+    /// Initializes the modules imported by `module`, directly or not,
+    /// dependencies first. Each module then finds the members of its own
+    /// imports already initialized, so initializing a chain of imports
+    /// doesn't nest calls to `run`.
+    fn init_imported_modules(&mut self, module: &Rc<Module>) -> Result<(), RuntimeError> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![(module.clone(), false)];
+        while let Some((module, is_expanded)) = pending.pop() {
+            if is_expanded {
+                order.push(module);
+                continue;
+            }
+            if !visited.insert(Rc::as_ptr(&module)) {
+                continue;
+            }
+            if !matches!(module.value, ModuleValue::Normal(_)) {
+                continue;
+            }
+            pending.push((module.clone(), true));
+            for index in module.imports() {
+                if self.module_loader.is_stale(index) {
+                    return Err(RuntimeError::StaleModule(
+                        self.module_loader.module_at(index).unwrap().ident.clone(),
+                    ));
+                }
+                if let Some(import) = self.module_loader.module_at(index) {
+                    if !visited.contains(&Rc::as_ptr(&import)) {
+                        pending.push((import, false));
+                    }
+                }
+            }
+        }
+
+        for import in order {
+            if !Rc::ptr_eq(&import, module)
+                && !self.module_values.contains_key(&Rc::as_ptr(&import))
+            {
+                self.init_module(&import)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the main function of a normal module and keeps its locals, so
+    /// that accessing its members doesn't run it again.
+    fn init_module(&mut self, module: &Rc<Module>) -> Result<Rc<[Value]>, RuntimeError> {
+        let ModuleValue::Normal(prototype) = &module.value else {
+            return Err(RuntimeError::CannotLoadNativeModuleAtRuntime);
+        };
+        let closure = Rc::new(Closure::from_prototype(prototype.clone()));
+        self.push(Value::Closure(closure.clone()));
+        self.execute_prototype(closure, 0)?;
+        let slot_offset = self.frame().slot_offset;
+        let values: Rc<[Value]> = self.stack[slot_offset..]
+            .iter()
+            .map(|slot| from_slot(slot.clone()))
+            .collect();
+        self.close_upvalues(slot_offset);
+        self.frames.pop();
+        self.stack.truncate(slot_offset);
+        self.module_values
+            .insert(Rc::as_ptr(module), values.clone());
+        Ok(values)
+    }
+
+    pub fn stack(&self) -> &[Slot] {
         &self.stack
     }
 
+    /// The value on top of the stack, such as the result of the function
+    /// that [`Vm::execute_module`] ran.
+    pub fn peek(&self) -> Option<Value> {
+        self.stack.last().cloned().map(from_slot)
+    }
+
     fn frame(&mut self) -> &CallFrame {
         self.frames.last().unwrap()
     }
@@ -138,46 +598,230 @@ impl Vm {
         self.frames.last_mut().unwrap()
     }
 
+    fn load_constant(&mut self, index: usize) {
+        let value = self
+            .frame()
+            .closure
+            .function
+            .prototype()
+            .unwrap()
+            .constant(index)
+            .clone();
+        self.push(value);
+    }
+
+    /// Replaces the arguments on top with the string of the template in the
+    /// constant at `index`.
+    fn format(&mut self, index: usize, num_args: usize) -> Result<(), RuntimeError> {
+        let template = self
+            .frame()
+            .closure
+            .function
+            .prototype()
+            .unwrap()
+            .constant(index)
+            .clone()
+            .as_array()
+            .unwrap();
+        let template = template.borrow();
+        let args = self.pop_values(num_args);
+        let string = fmt::format(&template, &args)?;
+        self.push(Value::String(Rc::new(string)));
+        Ok(())
+    }
+
+    fn get_local(&mut self, slot: usize) {
+        let offset = self.frame().slot_offset;
+        let entry = self.stack[offset + slot].clone();
+        self.stack.push(entry);
+    }
+
+    fn set_local(&mut self, slot: usize) -> Result<(), RuntimeError> {
+        let offset = self.frame().slot_offset;
+        let value = self.pop_slot()?;
+        self.stack[offset + slot] = value;
+        Ok(())
+    }
+
+    /// Replaces the two values on top with the result of an operator on
+    /// them.
+    #[cfg_attr(not(feature = "nan-boxing"), allow(clippy::needless_borrow))]
+    fn operate(&mut self, operator: Operator) -> Result<(), RuntimeError> {
+        let rhs = self.pop_slot()?;
+        let lhs = self.pop_slot()?;
+        let value = operate(operator, &slot_value(&lhs), &slot_value(&rhs))?;
+        self.push(value);
+        Ok(())
+    }
+
+    /// The value of a key of a table, an element of an array or string, or
+    /// an export of a module.
+    fn get_table(&mut self, table: Value, key: Value) -> Result<Value, RuntimeError> {
+        match (table, key) {
+            (Value::Table(table), key) => {
+                Ok(table.borrow().get(&key).cloned().unwrap_or(Value::Unit))
+            }
+            (Value::Array(array), Value::Integer(index)) => {
+                let array = array.borrow();
+                let len = array.len();
+                let Some(index) = resolve_index(index, len) else {
+                    return Err(RuntimeError::IndexOutOfBounds { index, len });
+                };
+                Ok(array[index].clone())
+            }
+            (Value::String(string), Value::Integer(index)) => {
+                let len = string.chars().count();
+                let Some(char) =
+                    resolve_index(index, len).and_then(|index| string.chars().nth(index))
+                else {
+                    return Err(RuntimeError::IndexOutOfBounds { index, len });
+                };
+                Ok(Value::Char(char))
+            }
+            (Value::Module(module), Value::Integer(index)) => match &module.value {
+                ModuleValue::Native(native) => module_value(native, index),
+                ModuleValue::Normal(_) => {
+                    let values = match self.module_values.get(&Rc::as_ptr(&module)) {
+                        Some(values) => values.clone(),
+                        None => self.init_module(&module)?,
+                    };
+                    module_value(&values, index)
+                }
+            },
+            (table, key) => Err(RuntimeError::CannotIndex {
+                value: table.type_name().to_string(),
+                key: key.type_name().to_string(),
+            }),
+        }
+    }
+
+    /// Like [`Vm::get_table`], going through the `GetField` cache of the
+    /// current function with the index given.
+    fn get_field(&mut self, cache: usize, table: Value, key: Value) -> Result<Value, RuntimeError> {
+        let Function::Prototype(prototype) = &self.frames.last().unwrap().closure.function else {
+            unreachable!("Only prototypes have code.");
+        };
+        let cached =
+            prototype.field_caches[cache]
+                .borrow()
+                .get(&table, &key, self.module_values_version);
+        if let Some(value) = cached {
+            return Ok(value);
+        }
+        let prototype = prototype.clone();
+        let value = self.get_table(table.clone(), key.clone())?;
+        *prototype.field_caches[cache].borrow_mut() =
+            FieldCache::new(&table, key, value.clone(), self.module_values_version);
+        Ok(value)
+    }
+
+    fn get_upvalue(&mut self, index: usize) {
+        let upvalue = self.frame().closure.upvalues[index].clone();
+        let value = match &*upvalue.borrow() {
+            Upvalue::Open { slot } => from_slot(self.stack[*slot].clone()),
+            Upvalue::Closed { value } => value.clone(),
+        };
+        self.push(value);
+    }
+
+    fn set_upvalue(&mut self, index: usize) -> Result<(), RuntimeError> {
+        let value = self.pop()?;
+        let upvalue = self.frame().closure.upvalues[index].clone();
+        match *RefCell::borrow_mut(&upvalue) {
+            Upvalue::Open { slot } => {
+                self.stack[slot] = into_slot(value);
+            }
+            Upvalue::Closed { value: ref mut val } => {
+                *val = value;
+            }
+        }
+        Ok(())
+    }
+
+    /// The 16-bit argument of a wide op code or a jump, made of the low byte
+    /// in the op code and the high byte in the `ExtraArg` after it, which is
+    /// skipped.
+    fn wide_arg(&mut self, low: u8) -> usize {
+        let high = self.extra_arg();
+        u16::from_le_bytes([low, high]) as usize
+    }
+
     fn extra_arg(&mut self) -> u8 {
         let frame = self.frame_mut();
-        let code_len = &frame.closure.function.prototype().unwrap().code;
-        let code = &code_len[frame.ip];
-        frame.ip += 1;
-        match code {
-            OpCode::ExtraArg(arg) => *arg,
+        match OpCode::decode(&frame.code[frame.ip..]) {
+            Some((OpCode::ExtraArg(arg), size)) => {
+                frame.ip += size;
+                arg
+            }
             _ => unreachable!(),
         }
     }
 
+    /// Runs the current frame. An error raised while a `try` of the frame
+    /// is active, whether by the frame or by a function it called, resumes
+    /// the frame at the `catch` of the innermost one.
     fn run(&mut self) -> Result<(), RuntimeError> {
+        let frame = self.frames.len() - 1;
+        loop {
+            match self.dispatch() {
+                Err(error)
+                    if !matches!(error, RuntimeError::Yield | RuntimeError::Await)
+                        && self.handlers.last().is_some_and(|h| h.frame == frame) =>
+                {
+                    self.catch(error)
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Continues from the `catch` of the innermost `try` block, with the
+    /// error as its value.
+    fn catch(&mut self, error: RuntimeError) {
+        let handler = self.handlers.pop().unwrap();
+        self.frames.truncate(handler.frame + 1);
+        self.close_upvalues(handler.stack_len);
+        self.stack.truncate(handler.stack_len);
+        self.push(error.into_value());
+        self.frame_mut().ip = handler.ip;
+    }
+
+    // Slots are borrowed as values the same way whether or not they are
+    // values already.
+    #[cfg_attr(not(feature = "nan-boxing"), allow(clippy::needless_borrow))]
+    fn dispatch(&mut self) -> Result<(), RuntimeError> {
         loop {
-            self.frame_mut().ip += 1;
-            if self.frame_mut().ip
-                > self
-                    .frame()
+            let frame = self.frame_mut();
+            let ip = frame.ip;
+            let Some((code, size)) = frame.code.get(ip..).and_then(OpCode::decode) else {
+                // A frame that has finished is left past the end of its
+                // code.
+                frame.ip += 1;
+                break;
+            };
+            frame.ip += size;
+            if let Some(coverage) = &mut self.coverage {
+                let prototype = self
+                    .frames
+                    .last()
+                    .unwrap()
                     .closure
                     .function
                     .prototype()
-                    .unwrap()
-                    .code
-                    .len()
-            {
-                break;
+                    .unwrap();
+                coverage.insert((Rc::as_ptr(&prototype), prototype.line(ip)));
+            }
+            if let Some(trace) = &mut self.trace {
+                trace(&code, ip, &self.stack);
             }
-            let ip = self.frame_mut().ip - 1;
-            let code = self.frame_mut().closure.function.prototype().unwrap().code[ip];
 
             match code {
                 OpCode::LoadConst(index) => {
-                    let value = self
-                        .frame_mut()
-                        .closure
-                        .function
-                        .prototype()
-                        .unwrap()
-                        .constant(index as usize)
-                        .clone();
-                    self.push(value);
+                    self.load_constant(index as usize);
+                }
+                OpCode::LoadConstW(low) => {
+                    let index = self.wide_arg(low);
+                    self.load_constant(index);
                 }
                 OpCode::LoadUnit => {
                     self.push(Value::Unit);
@@ -192,21 +836,18 @@ impl Vm {
                     self.push(Value::Integer(integer as i64));
                 }
                 OpCode::GetLocal(slot) => {
-                    let offset = self.frames.last().unwrap().slot_offset;
-                    let entry = self.stack[offset + slot as usize].clone();
-                    self.push(entry);
+                    self.get_local(slot as usize);
+                }
+                OpCode::GetLocalW(low) => {
+                    let slot = self.wide_arg(low);
+                    self.get_local(slot);
                 }
                 OpCode::GetUpvalue(index) => {
-                    let upvalue = self.frame().closure.upvalues[index as usize].clone();
-                    match &*upvalue.borrow() {
-                        Upvalue::Open { slot } => {
-                            let value = self.stack[*slot].clone();
-                            self.push(value);
-                        }
-                        Upvalue::Closed { value } => {
-                            self.push(value.clone());
-                        }
-                    };
+                    self.get_upvalue(index as usize);
+                }
+                OpCode::GetUpvalueW(low) => {
+                    let index = self.wide_arg(low);
+                    self.get_upvalue(index);
                 }
                 OpCode::GetModule(index) => {
                     let module = &self.module_loader.module_at(index as usize).unwrap();
@@ -214,75 +855,35 @@ impl Vm {
                     self.push(Value::Module(value));
                 }
                 OpCode::GetTable => {
-                    let key = self.pop();
-                    let table = self.pop();
-                    match table {
-                        Value::Table(table) => {
-                            let table = RefCell::borrow_mut(table.as_ref());
-                            let value = table.get(&key).cloned().unwrap_or(Value::Unit);
-                            self.push(value);
-                        }
-                        Value::Array(array) => {
-                            if let Value::Integer(index) = key {
-                                let array = array.borrow();
-                                if index as usize >= array.len() {
-                                    panic!("Out of bounds");
-                                }
-                                self.push(array[index as usize].clone());
-                            } else {
-                                panic!("Non integer value cannot index array");
-                            }
-                        }
-                        Value::Module(module) => {
-                            if let Value::Integer(integer) = key {
-                                let value = match &module.value {
-                                    ModuleValue::Native(native) => native[integer as usize].clone(),
-                                    ModuleValue::Normal(prototype) => {
-                                        let closure =
-                                            Rc::new(Closure::from_prototype(prototype.clone()));
-                                        self.push(Value::Closure(closure.clone()));
-                                        self.execute_prototype(closure.clone(), 0)?;
-                                        let slot_offset = self.frame().slot_offset;
-                                        let value =
-                                            self.stack[slot_offset + integer as usize].clone();
-                                        self.close_upvalues(
-                                            self.frames.last().unwrap().slot_offset,
-                                        );
-                                        let frame = self.frames.pop().unwrap();
-                                        let frame_offset = frame.slot_offset;
-                                        self.stack.truncate(frame_offset);
-                                        value
-                                    }
-                                };
-                                self.push(value);
-                            } else {
-                                unreachable!()
-                            }
-                        }
-                        _ => panic!("Unable to index value {table:?}"),
-                    }
+                    let key = self.pop()?;
+                    let table = self.pop()?;
+                    let value = self.get_table(table, key)?;
+                    self.push(value);
+                }
+                OpCode::GetField(cache) => {
+                    let key = self.pop()?;
+                    let table = self.pop()?;
+                    let value = self.get_field(cache as usize, table, key)?;
+                    self.push(value);
                 }
                 OpCode::SetLocal(slot) => {
-                    let offset = self.frames.last().unwrap().slot_offset;
-                    let front = self.stack.last().unwrap().clone();
-                    self.stack[offset + slot as usize] = front;
+                    self.set_local(slot as usize)?;
+                }
+                OpCode::SetLocalW(low) => {
+                    let slot = self.wide_arg(low);
+                    self.set_local(slot)?;
                 }
                 OpCode::SetUpvalue(index) => {
-                    let value = self.pop();
-                    let upvalue = &self.frames.last_mut().unwrap().closure.upvalues[index as usize];
-                    match *RefCell::borrow_mut(upvalue) {
-                        Upvalue::Open { slot } => {
-                            self.stack[slot] = value;
-                        }
-                        Upvalue::Closed { value: ref mut val } => {
-                            *val = value;
-                        }
-                    }
+                    self.set_upvalue(index as usize)?;
+                }
+                OpCode::SetUpvalueW(low) => {
+                    let index = self.wide_arg(low);
+                    self.set_upvalue(index)?;
                 }
                 OpCode::SetTable => {
-                    let value = self.pop();
-                    let key = self.pop();
-                    let table = self.pop();
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    let table = self.pop()?;
                     match table {
                         Value::Table(table) => {
                             let mut table = RefCell::borrow_mut(table.as_ref());
@@ -291,35 +892,84 @@ impl Vm {
                         Value::Array(array) => {
                             if let Value::Integer(index) = key {
                                 let mut array = (*array).borrow_mut();
-                                if index as usize >= array.len() {
-                                    for _ in array.len()..=index as usize {
-                                        array.push(Value::Unit);
-                                    }
+                                let len = array.len();
+                                let slot = if index < 0 {
+                                    resolve_index(index, len)
+                                        .ok_or(RuntimeError::IndexOutOfBounds { index, len })?
+                                } else {
+                                    index as usize
+                                };
+                                if slot >= len {
+                                    array.resize(slot + 1, Value::Unit);
                                 }
-                                array[index as usize] = value;
+                                array[slot] = value;
                             } else {
-                                panic!("Non integer value cannot index array");
+                                return Err(RuntimeError::CannotIndex {
+                                    value: "array".to_string(),
+                                    key: key.type_name().to_string(),
+                                });
                             }
                         }
-                        _ => panic!("Unable to index value {table:?}"),
+                        table => {
+                            return Err(RuntimeError::CannotIndex {
+                                value: table.type_name().to_string(),
+                                key: key.type_name().to_string(),
+                            })
+                        }
                     }
                 }
                 OpCode::CreateList(size) => {
-                    let mut array = Vec::with_capacity(size as usize);
-                    for _ in 0..size {
-                        let value = self.pop();
-                        array.insert(0, value);
+                    self.create_list(size as usize)?;
+                }
+                OpCode::CreateListW(size) => {
+                    let arg = self.extra_arg();
+                    self.create_list((arg as usize) << 8 | size as usize)?;
+                }
+                OpCode::CreateTuple(len) => {
+                    let len = len as usize;
+                    if self.top() <= len {
+                        return Err(RuntimeError::StackUnderflow);
                     }
-                    self.push(Value::Array(Rc::new(RefCell::new(array))));
+                    let tuple = self.pop_values(len);
+                    let tuple = self.gc.alloc(Rc::new(tuple.into_boxed_slice()));
+                    self.push(Value::Tuple(tuple));
                 }
-                OpCode::CreateTable(size) => {
-                    let mut table = Table::new();
-                    for _ in 0..size {
-                        let value = self.pop();
-                        let key = self.pop();
-                        table.insert(key, value);
+                OpCode::Unpack(len) => {
+                    let value = self.pop()?;
+                    let elements = match &value {
+                        Value::Tuple(tuple) => tuple.to_vec(),
+                        Value::Array(array) => array.borrow().clone(),
+                        _ => return Err(RuntimeError::UnexpectedType),
+                    };
+                    if elements.len() != len as usize {
+                        return Err(RuntimeError::Custom(format!(
+                            "Cannot unpack {} values into {len} locals",
+                            elements.len()
+                        )));
                     }
-                    self.push(Value::Table(Rc::new(RefCell::new(table))));
+                    self.stack.extend(elements.into_iter().map(into_slot));
+                }
+                OpCode::Extend => {
+                    let value = self.pop()?;
+                    let elements = self.collect(value)?;
+                    let Some(Value::Array(array)) = self.peek() else {
+                        return Err(RuntimeError::UnexpectedType);
+                    };
+                    array.borrow_mut().extend(elements);
+                }
+                OpCode::Format(index, num_args) => {
+                    self.format(index as usize, num_args as usize)?;
+                }
+                OpCode::FormatW(low, num_args) => {
+                    let index = self.wide_arg(low);
+                    self.format(index, num_args as usize)?;
+                }
+                OpCode::CreateTable(size) => {
+                    self.create_table(size as usize)?;
+                }
+                OpCode::CreateTableW(size) => {
+                    let arg = self.extra_arg();
+                    self.create_table((arg as usize) << 8 | size as usize)?;
                 }
                 OpCode::Closure(index) => {
                     let prototype = self
@@ -338,32 +988,42 @@ impl Vm {
                         let index = prototype.upvalues[i].index;
                         if is_local {
                             let slot_offset = self.frames.last().unwrap().slot_offset;
-                            let upvalue = self.capture_upvalue(slot_offset + index as usize);
+                            let upvalue = self.capture_upvalue(slot_offset + index);
                             closure.upvalues.push(upvalue);
                         } else {
-                            let upvalue = self.frames.last().unwrap().closure.upvalues
-                                [index as usize]
-                                .clone();
+                            let upvalue =
+                                self.frames.last().unwrap().closure.upvalues[index].clone();
                             closure.upvalues.push(upvalue);
                         }
                     }
-                    self.push(Value::Closure(Rc::new(closure)));
+                    let closure = self.gc.alloc(Rc::new(closure));
+                    self.push(Value::Closure(closure));
                 }
-                OpCode::Add => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
+                OpCode::Add => self.operate(Operator::Add)?,
+                OpCode::Subtract => self.operate(Operator::Subtract)?,
+                OpCode::Divide => self.operate(Operator::Divide)?,
+                OpCode::IDivide => self.operate(Operator::IDivide)?,
+                OpCode::Multiply => self.operate(Operator::Multiply)?,
+                OpCode::Modulus => self.operate(Operator::Modulus)?,
+                OpCode::Concat => {
+                    let rhs = self.pop()?;
+                    let lhs = self.pop()?;
                     match (lhs, rhs) {
-                        (Value::Number(l), Value::Number(r)) => {
-                            self.push(Value::Number(l + r));
-                        }
-                        (Value::Integer(l), Value::Integer(r)) => {
-                            self.push(Value::Integer(l + r));
+                        (Value::Array(l), Value::Array(r)) => {
+                            let mut array = l.borrow().clone();
+                            array.extend_from_slice(r.borrow().as_slice());
+                            let array = self.alloc_array(array);
+                            self.push(Value::Array(array));
                         }
-                        (Value::Integer(l), Value::Number(r)) => {
-                            self.push(Value::Number(l as f64 + r));
+                        // A string concatenated with another value gets the
+                        // value's string form.
+                        (Value::String(l), rhs) => {
+                            let s = format!("{l}{rhs}");
+                            self.push(Value::String(Rc::new(s)));
                         }
-                        (Value::Number(l), Value::Integer(r)) => {
-                            self.push(Value::Number(l + r as f64));
+                        (lhs, Value::String(r)) => {
+                            let s = format!("{lhs}{r}");
+                            self.push(Value::String(Rc::new(s)));
                         }
                         (lhs, rhs) => {
                             return Err(RuntimeError::InvalidOperandType {
@@ -373,133 +1033,110 @@ impl Vm {
                         }
                     }
                 }
-                OpCode::Subtract => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match (lhs, rhs) {
-                        (Value::Number(l), Value::Number(r)) => {
-                            self.push(Value::Number(l - r));
-                        }
-                        (Value::Integer(l), Value::Integer(r)) => {
-                            self.push(Value::Integer(l - r));
-                        }
-                        (Value::Integer(l), Value::Number(r)) => {
-                            self.push(Value::Number(l as f64 - r));
-                        }
-                        (Value::Number(l), Value::Integer(r)) => {
-                            self.push(Value::Number(l - r as f64));
+                OpCode::Range => self.range(false)?,
+                OpCode::RangeInclusive => self.range(true)?,
+                OpCode::Slice => self.slice(false)?,
+                OpCode::SliceInclusive => self.slice(true)?,
+                OpCode::IsTable => {
+                    let value = self.pop()?;
+                    self.push(Value::Bool(matches!(value, Value::Table(_))));
+                }
+                OpCode::HasLen(len) => {
+                    let value = self.pop()?;
+                    let has_len = matches!(&value, Value::Array(array) if array.borrow().len() == len as usize);
+                    self.push(Value::Bool(has_len));
+                }
+                OpCode::HasMinLen(len) => {
+                    let value = self.pop()?;
+                    let has_len = matches!(&value, Value::Array(array) if array.borrow().len() >= len as usize);
+                    self.push(Value::Bool(has_len));
+                }
+                OpCode::Rest(len) => {
+                    let Value::Array(array) = self.pop()? else {
+                        return Err(RuntimeError::UnexpectedType);
+                    };
+                    let rest = array
+                        .borrow()
+                        .get(len as usize..)
+                        .ok_or(RuntimeError::UnexpectedType)?
+                        .to_vec();
+                    let rest = self.alloc_array(rest);
+                    self.push(Value::Array(rest));
+                }
+                OpCode::Yield => {
+                    let value = self.pop()?;
+                    let slot_offset = self.frame().slot_offset;
+                    self.close_upvalues(slot_offset);
+                    let mut stack = self.stack.split_off(slot_offset);
+                    // The `yield` itself results in unit once resumed.
+                    stack.push(into_slot(Value::Unit));
+                    let frame = self.frames.pop().unwrap();
+                    self.suspended = Some(Suspended {
+                        stack,
+                        ip: frame.ip,
+                    });
+                    self.push(value);
+                    return Ok(());
+                }
+                OpCode::Await => match self.pop()?.into_future() {
+                    Ok(future) => {
+                        if !self.is_async {
+                            return Err(RuntimeError::Custom(
+                                "Cannot await a future outside of Vm::run_async".to_string(),
+                            ));
                         }
-                        _ => todo!(),
+                        let future = future.borrow_mut().take().ok_or_else(|| {
+                            RuntimeError::Custom("The future has already been awaited".to_string())
+                        })?;
+                        self.awaiting = Some(future);
+                        return Err(RuntimeError::Await);
                     }
-                }
-                OpCode::Divide => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match (lhs, rhs) {
-                        (Value::Number(l), Value::Number(r)) => {
-                            self.push(Value::Number(l / r));
-                        }
-                        (Value::Integer(l), Value::Integer(r)) => {
-                            self.push(Value::Integer(l / r));
-                        }
-                        (Value::Integer(l), Value::Number(r)) => {
-                            self.push(Value::Number(l as f64 / r));
-                        }
-                        (Value::Number(l), Value::Integer(r)) => {
-                            self.push(Value::Number(l / r as f64));
-                        }
-                        _ => todo!(),
+                    Err(value) => {
+                        self.push(value);
                     }
+                },
+                OpCode::Throw => {
+                    let value = self.pop()?;
+                    return Err(self.error(value));
                 }
-                OpCode::IDivide => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match (lhs, rhs) {
-                        (Value::Number(l), Value::Number(r)) => {
-                            self.push(Value::Integer(l as i64 / r as i64));
-                        }
-                        (Value::Integer(l), Value::Integer(r)) => {
-                            self.push(Value::Integer(l / r));
-                        }
-                        (Value::Integer(l), Value::Number(r)) => {
-                            self.push(Value::Integer(l / r as i64));
-                        }
-                        (Value::Number(l), Value::Integer(r)) => {
-                            self.push(Value::Integer(l as i64 / r));
-                        }
-                        _ => todo!(),
-                    }
+                OpCode::Len => {
+                    let len = match self.pop()? {
+                        Value::String(string) => string.chars().count(),
+                        Value::Array(array) => array.borrow().len(),
+                        Value::Table(table) => table.borrow().len(),
+                        _ => return Err(RuntimeError::UnexpectedType),
+                    };
+                    self.push(Value::Integer(len as i64));
                 }
-                OpCode::Multiply => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match (lhs, rhs) {
-                        (Value::Number(l), Value::Number(r)) => {
-                            self.push(Value::Number(l * r));
-                        }
-                        (Value::Integer(l), Value::Integer(r)) => {
-                            self.push(Value::Integer(l * r));
-                        }
-                        (Value::Integer(l), Value::Number(r)) => {
-                            self.push(Value::Number(l as f64 * r));
-                        }
-                        (Value::Number(l), Value::Integer(r)) => {
-                            self.push(Value::Number(l * r as f64));
-                        }
-                        _ => todo!(),
-                    }
+                OpCode::BitAnd => self.bitwise(|l, r| Some(l & r))?,
+                OpCode::BitOr => self.bitwise(|l, r| Some(l | r))?,
+                OpCode::BitXor => self.bitwise(|l, r| Some(l ^ r))?,
+                OpCode::Shl => {
+                    self.bitwise(|l, r| u32::try_from(r).ok().and_then(|r| l.checked_shl(r)))?
                 }
-                OpCode::Modulus => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match (lhs, rhs) {
-                        (Value::Number(l), Value::Number(r)) => {
-                            self.push(Value::Number(l % r));
-                        }
-                        (Value::Integer(l), Value::Integer(r)) => {
-                            self.push(Value::Integer(l % r));
-                        }
-                        (Value::Integer(l), Value::Number(r)) => {
-                            self.push(Value::Number(l as f64 % r));
-                        }
-                        (Value::Number(l), Value::Integer(r)) => {
-                            self.push(Value::Number(l % r as f64));
-                        }
-                        _ => todo!(),
-                    }
+                OpCode::Shr => {
+                    self.bitwise(|l, r| u32::try_from(r).ok().and_then(|r| l.checked_shr(r)))?
                 }
-                OpCode::Concat => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    match (lhs, rhs) {
-                        (Value::Array(l), Value::Array(r)) => {
-                            l.borrow_mut().extend_from_slice(r.borrow().as_slice());
-                            self.push(Value::Array(l));
-                        }
-                        (Value::String(l), Value::String(r)) => {
-                            let mut s = l.as_str().to_string();
-                            s.push_str(r.as_str());
-                            self.push(Value::String(Rc::new(s)));
-                        }
-                        (lhs, rhs) => {
-                            return Err(RuntimeError::InvalidOperandType {
-                                lhs: lhs.type_name().to_string(),
-                                rhs: rhs.type_name().to_string(),
-                            })
-                        }
-                    }
+                OpCode::BitNot => {
+                    let value = match self.pop()? {
+                        Value::Integer(i) => Value::Integer(!i),
+                        _ => return Err(RuntimeError::UnexpectedType),
+                    };
+                    self.push(value);
                 }
                 OpCode::Negate => {
-                    let value = self.pop();
+                    let value = self.pop()?;
                     let result = match value {
-                        Value::Integer(i) => Value::Integer(-i),
+                        Value::Integer(i) => {
+                            Value::Integer(i.checked_neg().ok_or(RuntimeError::IntegerOverflow)?)
+                        }
                         Value::Number(n) => Value::Number(-n),
                         _ => return Err(RuntimeError::NegateOperatorOnNonNumericValue),
                     };
                     self.push(result);
                 }
                 OpCode::Not => {
-                    let value = self.pop();
+                    let value = self.pop()?;
                     let result = if value.is_false() {
                         Value::Bool(true)
                     } else {
@@ -508,74 +1145,49 @@ impl Vm {
                     self.push(result);
                 }
                 OpCode::Call(num_args) => {
-                    let value = self
-                        .stack
-                        .iter()
-                        .nth_back(num_args as usize)
-                        .unwrap()
-                        .clone();
-                    match value {
+                    let value = self.stack.iter().nth_back(num_args as usize).unwrap();
+                    match from_slot(value.clone()) {
                         Value::Closure(closure) => self.call(closure, num_args as usize)?,
                         _ => return Err(RuntimeError::CannotCallNonCallableValue),
                     }
                 }
-                OpCode::CmpEq => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    if lhs == rhs {
-                        self.push(Value::Bool(true));
-                    } else {
-                        self.push(Value::Bool(false));
-                    }
-                }
-                OpCode::CmpNEq => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    if lhs != rhs {
-                        self.push(Value::Bool(true));
-                    } else {
-                        self.push(Value::Bool(false));
-                    }
-                }
-                OpCode::CmpLEq => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    if lhs <= rhs {
-                        self.push(Value::Bool(true));
-                    } else {
-                        self.push(Value::Bool(false));
-                    }
-                }
-                OpCode::CmpGEq => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    if lhs >= rhs {
-                        self.push(Value::Bool(true));
-                    } else {
-                        self.push(Value::Bool(false));
-                    }
-                }
-                OpCode::CmpGreater => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    if lhs > rhs {
-                        self.push(Value::Bool(true));
-                    } else {
-                        self.push(Value::Bool(false));
+                OpCode::CallSpread => {
+                    let value = self.pop()?;
+                    let args = self.collect(value)?;
+                    let num_args = args.len();
+                    self.stack.extend(args.into_iter().map(into_slot));
+                    let value = self.stack.iter().nth_back(num_args).unwrap();
+                    match from_slot(value.clone()) {
+                        Value::Closure(closure) => self.call(closure, num_args)?,
+                        _ => return Err(RuntimeError::CannotCallNonCallableValue),
                     }
                 }
-                OpCode::CmpLess => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    if lhs < rhs {
-                        self.push(Value::Bool(true));
-                    } else {
-                        self.push(Value::Bool(false));
-                    }
+                OpCode::CallMethod(num_args) => {
+                    // The method takes the place of the receiver, which takes
+                    // the place of the method name.
+                    let num_args = num_args as usize;
+                    let receiver = self.stack.len() - num_args - 2;
+                    let Value::Table(table) = from_slot(self.stack[receiver].clone()) else {
+                        return Err(RuntimeError::UnexpectedType);
+                    };
+                    let key = slot_value(&self.stack[receiver + 1]);
+                    let method = table.borrow().get(&key).cloned();
+                    let Some(Value::Closure(method)) = method else {
+                        return Err(RuntimeError::CannotCallNonCallableValue);
+                    };
+                    self.stack[receiver + 1] = into_slot(Value::Table(table));
+                    self.stack[receiver] = into_slot(Value::Closure(method.clone()));
+                    self.call(method, num_args + 1)?;
                 }
+                OpCode::CmpEq => self.operate(Operator::Equal)?,
+                OpCode::CmpNEq => self.operate(Operator::NotEqual)?,
+                OpCode::CmpLEq => self.operate(Operator::LessEqual)?,
+                OpCode::CmpGEq => self.operate(Operator::GreaterEqual)?,
+                OpCode::CmpGreater => self.operate(Operator::Greater)?,
+                OpCode::CmpLess => self.operate(Operator::Less)?,
                 OpCode::CmpAnd => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
+                    let rhs = self.pop()?;
+                    let lhs = self.pop()?;
                     if lhs.is_false() && rhs.is_false() {
                         self.push(Value::Bool(false));
                     } else {
@@ -583,50 +1195,153 @@ impl Vm {
                     }
                 }
                 OpCode::CmpOr => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
+                    let rhs = self.pop()?;
+                    let lhs = self.pop()?;
                     if !lhs.is_false() || !rhs.is_false() {
                         self.push(Value::Bool(true));
                     } else {
                         self.push(Value::Bool(false));
                     }
                 }
+                OpCode::OpLocals(operator, lhs, rhs) => {
+                    let offset = self.frame().slot_offset;
+                    let value = operate(
+                        operator,
+                        &slot_value(&self.stack[offset + lhs as usize]),
+                        &slot_value(&self.stack[offset + rhs as usize]),
+                    )?;
+                    self.push(value);
+                }
+                OpCode::OpLocalInt(operator, lhs, rhs) => {
+                    let offset = self.frame().slot_offset;
+                    let value = operate(
+                        operator,
+                        &slot_value(&self.stack[offset + lhs as usize]),
+                        &Value::Integer(rhs as i64),
+                    )?;
+                    self.push(value);
+                }
+                OpCode::OpLocal(operator, rhs) => {
+                    let offset = self.frame().slot_offset;
+                    let lhs = self.pop()?;
+                    let value = operate(
+                        operator,
+                        &lhs,
+                        &slot_value(&self.stack[offset + rhs as usize]),
+                    )?;
+                    self.push(value);
+                }
+                OpCode::OpInt(operator, rhs) => {
+                    let lhs = self.pop()?;
+                    let value = operate(operator, &lhs, &Value::Integer(rhs as i64))?;
+                    self.push(value);
+                }
                 OpCode::JumpIfFalse(location) => {
-                    let value = self.pop();
-                    let arg = self.extra_arg();
-                    if value.is_false() {
-                        self.frames.last_mut().unwrap().ip +=
-                            ((arg as u16) << 8 | location as u16) as usize;
+                    let value = self.pop_slot()?;
+                    let offset = self.wide_arg(location);
+                    if slot_value(&value).is_false() {
+                        self.frames.last_mut().unwrap().ip += offset;
+                    }
+                }
+                OpCode::JumpIfNotUnit(location) => {
+                    let offset = self.wide_arg(location);
+                    if self
+                        .stack
+                        .last()
+                        .is_some_and(|slot| *slot_value(slot) == Value::Unit)
+                    {
+                        self.pop()?;
+                    } else {
+                        self.frames.last_mut().unwrap().ip += offset;
                     }
                 }
                 OpCode::Jump(location) => {
-                    let arg = self.extra_arg();
-                    self.frames.last_mut().unwrap().ip +=
-                        ((arg as u16) << 8 | location as u16) as usize;
+                    let offset = self.wide_arg(location);
+                    self.frames.last_mut().unwrap().ip += offset;
+                }
+                OpCode::Try(location) => {
+                    let offset = self.wide_arg(location);
+                    let ip = self.frame().ip + offset;
+                    self.handlers.push(Handler {
+                        frame: self.frames.len() - 1,
+                        stack_len: self.stack.len(),
+                        ip,
+                    });
+                }
+                OpCode::EndTry => {
+                    self.handlers.pop();
+                }
+                OpCode::IntoIter => {
+                    let value = self.pop()?;
+                    let iterator = value.into_iterator()?;
+                    self.push(Value::Iterator(iterator));
+                }
+                OpCode::ForIter(location) => {
+                    let offset = self.wide_arg(location);
+                    let iterator = self
+                        .peek()
+                        .and_then(Value::as_iterator)
+                        .ok_or(RuntimeError::UnexpectedType)?;
+                    match self.call_closure(&iterator, &[])? {
+                        Value::Unit => {
+                            self.frames.last_mut().unwrap().ip += offset;
+                        }
+                        value => {
+                            self.push(value);
+                        }
+                    }
+                }
+                OpCode::Loop(location) => {
+                    let offset = self.wide_arg(location);
+                    self.frames.last_mut().unwrap().ip -= offset;
                 }
                 OpCode::CloseUpvalue(index) => {
                     let offset = self.frame().slot_offset;
                     self.close_upvalues(offset + index as usize);
                 }
+                OpCode::CloseUpvalueW(low) => {
+                    let offset = self.frame().slot_offset + self.wide_arg(low);
+                    self.close_upvalues(offset);
+                }
                 OpCode::Pop => {
-                    self.pop();
+                    self.pop_slot()?;
                 }
-                OpCode::Return => {
-                    if self.frames.len() == 1 {
-                        return Ok(());
-                    } else {
-                        let result = self.pop();
-                        self.close_upvalues(self.frames.last().unwrap().slot_offset);
-                        let frame = self.frames.pop().unwrap();
-                        if self.frames.is_empty() {
-                            return Ok(());
-                        }
-
-                        let frame_offset = frame.slot_offset;
-                        self.stack.truncate(frame_offset);
-                        self.push(result);
+                OpCode::PopScope(num) => {
+                    let value = self.pop()?;
+                    let len = self.stack.len() - num as usize;
+                    self.stack.truncate(len);
+                    self.push(value);
+                }
+                OpCode::TailCall(num_args) => {
+                    let num_args = num_args as usize;
+                    let value = self.stack.iter().nth_back(num_args).unwrap();
+                    let Value::Closure(closure) = from_slot(value.clone()) else {
+                        return Err(RuntimeError::CannotCallNonCallableValue);
+                    };
+                    let prototype = closure.function.prototype();
+                    let Some(prototype) = prototype.filter(|prototype| {
+                        !prototype.is_generator
+                            && (prototype.num_required_args()..=prototype.num_args)
+                                .contains(&num_args)
+                    }) else {
+                        // Natives, partial applications and generators don't
+                        // run in a frame that can be reused.
+                        self.call(closure, num_args)?;
+                        self.return_from_frame()?;
                         return Ok(());
-                    }
+                    };
+                    let slot_offset = self.frame().slot_offset;
+                    self.close_upvalues(slot_offset);
+                    let callee = self.stack.len() - num_args - 1;
+                    self.stack.drain(slot_offset..callee);
+                    let frame = self.frame_mut();
+                    frame.ip = prototype.entry(num_args);
+                    frame.code = prototype.code.clone();
+                    frame.closure = closure;
+                }
+                OpCode::Return => {
+                    self.return_from_frame()?;
+                    return Ok(());
                 }
                 OpCode::ExtraArg(_) => unreachable!(),
             }
@@ -635,6 +1350,17 @@ impl Vm {
         Ok(())
     }
 
+    /// Pops the current frame, leaving the value on top of its stack in
+    /// place of its callee.
+    fn return_from_frame(&mut self) -> Result<(), RuntimeError> {
+        let result = self.pop()?;
+        self.close_upvalues(self.frames.last().unwrap().slot_offset);
+        let frame = self.frames.pop().unwrap();
+        self.stack.truncate(frame.slot_offset);
+        self.push(result);
+        Ok(())
+    }
+
     fn capture_upvalue(&mut self, index: usize) -> UpvalueRef {
         for open_upvalue in self.open_upvalues.iter().rev() {
             match *open_upvalue.borrow() {
@@ -649,12 +1375,109 @@ impl Vm {
             }
         }
 
-        let new_upvalue = Rc::new(RefCell::new(Upvalue::Open { slot: index }));
+        let new_upvalue = self
+            .gc
+            .alloc(Rc::new(RefCell::new(Upvalue::Open { slot: index })));
         self.open_upvalues.push(Rc::clone(&new_upvalue));
 
         new_upvalue
     }
 
+    /// Applies a bitwise operator to the two integers on top. Shifts by a
+    /// negative amount or by the integer's width or more are errors.
+    fn bitwise(&mut self, operator: fn(i64, i64) -> Option<i64>) -> Result<(), RuntimeError> {
+        let rhs = self.pop()?;
+        let lhs = self.pop()?;
+        let (Value::Integer(l), Value::Integer(r)) = (&lhs, &rhs) else {
+            return Err(RuntimeError::InvalidOperandType {
+                lhs: lhs.type_name().to_string(),
+                rhs: rhs.type_name().to_string(),
+            });
+        };
+        let result =
+            operator(*l, *r).ok_or_else(|| RuntimeError::Custom(format!("Cannot shift by {r}")))?;
+        self.push(Value::Integer(result));
+        Ok(())
+    }
+
+    /// Replaces the two integers on top with an iterator over the range
+    /// between them.
+    fn range(&mut self, inclusive: bool) -> Result<(), RuntimeError> {
+        let end = self.pop()?;
+        let start = self.pop()?;
+        let (Value::Integer(start), Value::Integer(end)) = (&start, &end) else {
+            return Err(RuntimeError::InvalidOperandType {
+                lhs: start.type_name().to_string(),
+                rhs: end.type_name().to_string(),
+            });
+        };
+        let iterator = stdlib::iter::range(*start, *end, inclusive);
+        self.push(Value::Iterator(iterator));
+        Ok(())
+    }
+
+    /// Slices an array or a string by its characters. Negative bounds count
+    /// back from the end, and bounds past either end stop at it.
+    fn slice(&mut self, inclusive: bool) -> Result<(), RuntimeError> {
+        let end = self.pop()?;
+        let start = self.pop()?;
+        let value = self.pop()?;
+        let len = match &value {
+            Value::Array(array) => array.borrow().len(),
+            Value::String(string) => string.chars().count(),
+            _ => return Err(RuntimeError::UnexpectedType),
+        };
+        let bound = |bound: &Value, default: usize| match *bound {
+            Value::Unit => Ok(default),
+            Value::Integer(index) if index < 0 => {
+                Ok(len.saturating_sub(index.unsigned_abs() as usize))
+            }
+            Value::Integer(index) => Ok((index as usize).min(len)),
+            _ => Err(RuntimeError::UnexpectedType),
+        };
+        let start = bound(&start, 0)?;
+        let end = match (&end, bound(&end, len)?) {
+            (Value::Integer(_), index) if inclusive => (index + 1).min(len),
+            (_, index) => index,
+        }
+        .max(start);
+        let slice = match value {
+            Value::Array(array) => {
+                let slice = array.borrow()[start..end].to_vec();
+                Value::Array(self.alloc_array(slice))
+            }
+            Value::String(string) => Value::String(Rc::new(
+                string.chars().skip(start).take(end - start).collect(),
+            )),
+            _ => unreachable!(),
+        };
+        self.push(slice);
+        Ok(())
+    }
+
+    fn create_list(&mut self, size: usize) -> Result<(), RuntimeError> {
+        if self.top() <= size {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        let array = self.pop_values(size);
+        let array = self.alloc_array(array);
+        self.push(Value::Array(array));
+        Ok(())
+    }
+
+    fn create_table(&mut self, size: usize) -> Result<(), RuntimeError> {
+        #[allow(clippy::mutable_key_type)]
+        let mut table = Table::new();
+        for _ in 0..size {
+            let value = self.pop()?;
+            let key = self.pop()?;
+            table.insert(key, value);
+        }
+        let table = self.alloc_table(table);
+        self.push(Value::Table(table));
+        Ok(())
+    }
+
     fn close_upvalues(&mut self, last: usize) {
         let mut i = self.open_upvalues.len();
         loop {
@@ -671,7 +1494,7 @@ impl Vm {
                 break;
             }
             upvalue.replace(Upvalue::Closed {
-                value: self.stack[location].clone(),
+                value: from_slot(self.stack[location].clone()),
             });
             i -= 1;
         }
@@ -679,19 +1502,58 @@ impl Vm {
         self.open_upvalues.truncate(i);
     }
 
-    pub fn call(&mut self, closure: ClosureRef, num_args: usize) -> Result<(), RuntimeError> {
-        match closure.function {
-            Function::Prototype(_) => self.execute_prototype(closure, num_args),
+    /// Calls a function with `num_args` arguments on the stack.
+    ///
+    /// A call without arguments, as in `f ()`, passes `()` as the only
+    /// argument, unless the function is declared without parameters, or
+    /// with defaults for all of them. Natives don't declare their
+    /// parameters, so they always get the `()`.
+    pub fn call(&mut self, closure: ClosureRef, mut num_args: usize) -> Result<(), RuntimeError> {
+        if num_args == 0 && needs_args(&closure.function) {
+            self.push(Value::Unit);
+            num_args = 1;
+        }
+        match &closure.function {
+            Function::Prototype(prototype) => {
+                if num_args > 0 && num_args < prototype.num_required_args() {
+                    self.partial(closure, num_args)
+                } else {
+                    self.execute_prototype(closure, num_args)
+                }
+            }
             Function::Native(_) => self.execute_native(closure, num_args),
+            Function::Partial(partial) => {
+                // The function goes in place of the partial application, with
+                // the arguments it already has before the new ones.
+                let callee = self.stack.len() - num_args - 1;
+                self.stack[callee] = into_slot(Value::Closure(partial.closure.clone()));
+                self.stack.splice(
+                    callee + 1..callee + 1,
+                    partial.args.iter().cloned().map(into_slot),
+                );
+                self.call(partial.closure.clone(), partial.args.len() + num_args)
+            }
         }
     }
 
+    /// Replaces the function and the arguments on top with a partial
+    /// application of the function to them.
+    fn partial(&mut self, closure: ClosureRef, num_args: usize) -> Result<(), RuntimeError> {
+        let args = self.pop_values(num_args);
+        self.pop()?;
+        let partial = Closure::from_partial(Partial { closure, args });
+        let partial = self.gc.alloc(Rc::new(partial));
+        self.push(Value::Closure(partial));
+        Ok(())
+    }
+
     fn execute_prototype(
         &mut self,
         closure: ClosureRef,
         num_args: usize,
     ) -> Result<(), RuntimeError> {
-        if closure.function.prototype().unwrap().num_args != num_args {
+        let prototype = closure.function.prototype().unwrap();
+        if !(prototype.num_required_args()..=prototype.num_args).contains(&num_args) {
             return Err(RuntimeError::IncorrectNumberOfArguments);
         }
 
@@ -699,15 +1561,259 @@ impl Vm {
             return Err(RuntimeError::StackOverflow);
         }
 
+        let slot_offset = self.stack.len() - num_args - 1;
+        if prototype.is_generator {
+            // The function only starts running once the first value is
+            // asked for.
+            let generator = RefCell::new(Generator {
+                closure,
+                suspended: Some(Suspended {
+                    stack: self.stack.split_off(slot_offset),
+                    ip: prototype.entry(num_args),
+                }),
+            });
+            self.push(Value::iterator(move |vm| vm.resume(&generator)));
+            return Ok(());
+        }
+
         let frame = CallFrame {
+            code: prototype.code.clone(),
             closure,
-            ip: 0,
-            slot_offset: (self.stack.len() - num_args - 1),
+            ip: prototype.entry(num_args),
+            slot_offset,
         };
         self.frames.push(frame);
         self.run()
     }
 
+    /// Runs a coroutine until it yields or returns, resulting in the yielded
+    /// or returned value. A suspended coroutine gets `value` as the result of
+    /// the `Coroutine.yield` that suspended it.
+    pub fn resume_coroutine(
+        &mut self,
+        coroutine: &RefCell<Coroutine>,
+        value: Value,
+    ) -> Result<Value, RuntimeError> {
+        match coroutine.borrow().status {
+            CoroutineStatus::Suspended => {}
+            status => {
+                return Err(RuntimeError::Custom(format!(
+                    "Cannot resume a {status} coroutine"
+                )))
+            }
+        }
+        let frame_base = self.frames.len();
+        let stack_base = self.stack.len();
+        let handler_base = self.handlers.len();
+        let segment = {
+            let mut coroutine = coroutine.borrow_mut();
+            coroutine.status = CoroutineStatus::Running;
+            coroutine.segment.take()
+        };
+        self.running_coroutines += 1;
+        let result = match segment {
+            None => {
+                let function = coroutine.borrow().function.clone();
+                self.push(Value::Closure(function.clone()));
+                let num_args = if value == Value::Unit {
+                    0
+                } else {
+                    self.push(value);
+                    1
+                };
+                self.call(function, num_args)
+            }
+            Some(segment) => self.restore(segment, Ok(value)),
+        };
+        self.running_coroutines -= 1;
+        match result {
+            Ok(()) => {
+                coroutine.borrow_mut().status = CoroutineStatus::Dead;
+                self.pop()
+            }
+            Err(RuntimeError::Yield) => {
+                // The frame on top is the one of `Coroutine.yield`.
+                let frame = self.frames.pop().unwrap();
+                self.stack.truncate(frame.slot_offset);
+                let Some(segment) = self.suspend(frame_base, stack_base, handler_base) else {
+                    coroutine.borrow_mut().status = CoroutineStatus::Dead;
+                    return Err(self.cannot_suspend(RuntimeError::Yield));
+                };
+                let mut coroutine = coroutine.borrow_mut();
+                coroutine.status = CoroutineStatus::Suspended;
+                coroutine.segment = Some(segment);
+                Ok(self.yielded.take().unwrap_or(Value::Unit))
+            }
+            Err(error) => {
+                coroutine.borrow_mut().status = CoroutineStatus::Dead;
+                self.unwind(frame_base, stack_base, handler_base);
+                Err(error)
+            }
+        }
+    }
+
+    /// Takes the frames, stack, `try` blocks and open upvalues above the given
+    /// lengths off the VM, for `restore` to put back later. Nothing is taken,
+    /// but all of it dropped, if a native function would have to be
+    /// suspended too.
+    fn suspend(
+        &mut self,
+        frame_base: usize,
+        stack_base: usize,
+        handler_base: usize,
+    ) -> Option<Segment> {
+        if self.frames[frame_base..]
+            .iter()
+            .any(|frame| frame.closure.function.prototype().is_none())
+        {
+            self.unwind(frame_base, stack_base, handler_base);
+            return None;
+        }
+        let upvalues = self
+            .open_upvalues
+            .iter()
+            .filter_map(|upvalue| match *upvalue.borrow() {
+                Upvalue::Open { slot } if slot >= stack_base => {
+                    Some((slot - stack_base, upvalue.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        self.close_upvalues(stack_base);
+        Some(Segment {
+            frames: self
+                .frames
+                .split_off(frame_base)
+                .into_iter()
+                .map(|frame| CallFrame {
+                    slot_offset: frame.slot_offset - stack_base,
+                    ..frame
+                })
+                .collect(),
+            stack: self.stack.split_off(stack_base),
+            handlers: self
+                .handlers
+                .split_off(handler_base)
+                .into_iter()
+                .map(|handler| Handler {
+                    frame: handler.frame - frame_base,
+                    stack_len: handler.stack_len - stack_base,
+                    ..handler
+                })
+                .collect(),
+            upvalues,
+        })
+    }
+
+    /// The error for a `Coroutine.yield` or `await` that would suspend a
+    /// native function, which also drops what it suspended with.
+    fn cannot_suspend(&mut self, error: RuntimeError) -> RuntimeError {
+        let message = match error {
+            RuntimeError::Yield => {
+                self.yielded = None;
+                "Cannot yield across a native function"
+            }
+            RuntimeError::Await => {
+                self.awaiting = None;
+                "Cannot await across a native function"
+            }
+            error => return error,
+        };
+        RuntimeError::Custom(message.to_string())
+    }
+
+    /// Puts the frames of a suspended coroutine or `run_async` back on top
+    /// and runs them, innermost first, with `value` as the result of the
+    /// `Coroutine.yield` or `await` that suspended them. An error is raised
+    /// from there instead.
+    fn restore(
+        &mut self,
+        segment: Segment,
+        value: Result<Value, RuntimeError>,
+    ) -> Result<(), RuntimeError> {
+        let frame_base = self.frames.len();
+        let stack_base = self.stack.len();
+        self.stack.extend(segment.stack);
+        for (slot, upvalue) in segment.upvalues {
+            let slot = stack_base + slot;
+            if let Upvalue::Closed { value } = upvalue.replace(Upvalue::Open { slot }) {
+                self.stack[slot] = into_slot(value);
+            }
+            self.open_upvalues.push(upvalue);
+        }
+        self.frames
+            .extend(segment.frames.into_iter().map(|frame| CallFrame {
+                slot_offset: frame.slot_offset + stack_base,
+                ..frame
+            }));
+        self.handlers
+            .extend(segment.handlers.into_iter().map(|handler| Handler {
+                frame: handler.frame + frame_base,
+                stack_len: handler.stack_len + stack_base,
+                ..handler
+            }));
+        match value {
+            Ok(value) => {
+                self.push(value);
+            }
+            Err(error) if self.handlers.last().is_some_and(|h| h.frame >= frame_base) => {
+                self.catch(error)
+            }
+            Err(error) => return Err(error),
+        }
+        while self.frames.len() > frame_base {
+            self.run()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the frames, stack and `try` blocks above the given lengths.
+    fn unwind(&mut self, num_frames: usize, stack_len: usize, num_handlers: usize) {
+        self.frames.truncate(num_frames);
+        self.close_upvalues(stack_len);
+        self.stack.truncate(stack_len);
+        self.handlers.truncate(num_handlers);
+    }
+
+    /// Suspends the running coroutine with `value`, returning the error that
+    /// natives return to unwind to its `resume`.
+    pub fn yield_coroutine(&mut self, value: Value) -> RuntimeError {
+        if self.running_coroutines == 0 {
+            return RuntimeError::Custom("Cannot yield outside of a coroutine".to_string());
+        }
+        self.yielded = Some(value);
+        RuntimeError::Yield
+    }
+
+    /// Runs a generator until it yields its next value, which is returned,
+    /// or returns, after which it only returns unit.
+    fn resume(&mut self, generator: &RefCell<Generator>) -> Result<Value, RuntimeError> {
+        let Some(Suspended { stack, ip }) = generator.borrow_mut().suspended.take() else {
+            return Ok(Value::Unit);
+        };
+        if self.frames.len() == usize::MAX {
+            return Err(RuntimeError::StackOverflow);
+        }
+        let closure = generator.borrow().closure.clone();
+        let frame = CallFrame {
+            code: closure.function.prototype().unwrap().code.clone(),
+            closure,
+            ip,
+            slot_offset: self.stack.len(),
+        };
+        self.stack.extend(stack);
+        self.frames.push(frame);
+        self.run()?;
+        let value = self.pop()?;
+        match self.suspended.take() {
+            Some(suspended) => {
+                generator.borrow_mut().suspended = Some(suspended);
+                Ok(value)
+            }
+            None => Ok(Value::Unit),
+        }
+    }
+
     fn execute_native(&mut self, closure: ClosureRef, num_args: usize) -> Result<(), RuntimeError> {
         if self.frames.len() == usize::MAX {
             return Err(RuntimeError::StackOverflow);
@@ -715,16 +1821,13 @@ impl Vm {
 
         let frame = CallFrame {
             closure: closure.clone(),
+            code: Rc::default(),
             ip: 0,
             slot_offset: (self.stack.len() - num_args - 1),
         };
         self.frames.push(frame);
         let result = (closure.function.native().unwrap().function.borrow_mut())(self)?;
         let frame = self.frames.pop().unwrap();
-        if self.frames.is_empty() {
-            return Ok(());
-        }
-
         let frame_offset = frame.slot_offset;
         self.stack.truncate(frame_offset);
         self.push(result);
@@ -733,61 +1836,368 @@ impl Vm {
     }
 
     pub fn top(&mut self) -> usize {
-        self.stack.len() - self.frame().slot_offset
+        self.stack.len() - self.frames.last().map_or(0, |frame| frame.slot_offset)
     }
 
     pub fn push(&mut self, value: Value) -> usize {
         let index = self.stack.len();
-        self.stack.push(value);
+        self.stack.push(into_slot(value));
         index
     }
 
-    pub fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
+    /// Pops a value off the stack. The slots below the current frame, and
+    /// the frame's own callee slot, cannot be popped.
+    pub fn pop(&mut self) -> Result<Value, RuntimeError> {
+        self.pop_slot().map(from_slot)
+    }
+
+    fn pop_slot(&mut self) -> Result<Slot, RuntimeError> {
+        let floor = self.frames.last().map_or(0, |frame| frame.slot_offset + 1);
+        if self.stack.len() <= floor {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        Ok(self.stack.pop().unwrap())
+    }
+
+    /// Takes the `num` values on top off the stack, in order.
+    fn pop_values(&mut self, num: usize) -> Vec<Value> {
+        let len = self.stack.len() - num;
+        self.stack.drain(len..).map(from_slot).collect()
     }
 }
 
-#[derive(Debug)]
-pub enum RuntimeError {
-    StackOverflow,
-    IncorrectNumberOfArguments,
-    NegateOperatorOnNonNumericValue,
-    CannotCallNonCallableValue,
-    CannotLoadNativeModuleAtRuntime,
-    UnexpectedType,
-    InvalidOperandType { lhs: String, rhs: String },
-    InvalidConversion,
-    Custom(String),
+/// The local of a module at an index that a module importing it was
+/// compiled with.
+fn module_value(values: &[Value], index: i64) -> Result<Value, RuntimeError> {
+    usize::try_from(index)
+        .ok()
+        .and_then(|i| values.get(i))
+        .cloned()
+        .ok_or(RuntimeError::IndexOutOfBounds {
+            index,
+            len: values.len(),
+        })
 }
 
-impl Error for RuntimeError {}
+/// A handle to a script closure that the host can call later, e.g. as an
+/// event handler.
+#[derive(Debug, Clone)]
+pub struct ScriptCallback {
+    closure: ClosureRef,
+}
 
-impl Display for RuntimeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            RuntimeError::StackOverflow => write!(f, "Stack Overflow"),
-            RuntimeError::IncorrectNumberOfArguments => {
-                write!(f, "Incorrect Number of Arguments")
-            }
-            RuntimeError::NegateOperatorOnNonNumericValue => {
-                write!(f, "Negate operator on non numeric value")
-            }
-            RuntimeError::CannotCallNonCallableValue => write!(f, "Cannot call non callable value"),
-            RuntimeError::CannotLoadNativeModuleAtRuntime => {
-                write!(f, "Cannot load native module at runtime")
-            }
-            RuntimeError::UnexpectedType => {
-                write!(f, "Unexpected type")
-            }
-            RuntimeError::InvalidOperandType { lhs, rhs } => {
-                write!(f, "Invalid type of operands. lhs: {lhs}, rhs: {rhs}")
-            }
-            RuntimeError::InvalidConversion => {
-                write!(f, "Invalid conversion.")
-            }
-            RuntimeError::Custom(message) => {
+impl ScriptCallback {
+    pub fn new(closure: ClosureRef) -> Self {
+        Self { closure }
+    }
+
+    pub fn from_value(value: Value) -> Option<Self> {
+        value.as_closure().map(Self::new)
+    }
+
+    pub fn call(&self, vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+        vm.call_closure(&self.closure, args)
+    }
+}
+
+fn needs_args(function: &Function) -> bool {
+    match function {
+        Function::Prototype(prototype) => prototype.num_required_args() > 0,
+        Function::Native(_) | Function::Partial(_) => true,
+    }
+}
+
+/// Turns an index into a position in a sequence of `len` elements, where a
+/// negative index counts back from the end, so `-1` is the last element.
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 {
+        len.checked_sub(index.unsigned_abs() as usize)?
+    } else {
+        index as usize
+    };
+    (index < len).then_some(index)
+}
+
+/// Applies an arithmetic or comparison operator, for both the op codes that
+/// pop their operands and those that read them in place.
+#[inline(always)]
+fn operate(operator: Operator, lhs: &Value, rhs: &Value) -> Result<Value, RuntimeError> {
+    let value = match (operator, lhs, rhs) {
+        (_, Value::Integer(l), Value::Integer(r)) => integers(operator, *l, *r)?,
+        (Operator::Less, _, _) => Value::Bool(lhs < rhs),
+        (Operator::LessEqual, _, _) => Value::Bool(lhs <= rhs),
+        (Operator::Greater, _, _) => Value::Bool(lhs > rhs),
+        (Operator::GreaterEqual, _, _) => Value::Bool(lhs >= rhs),
+        (Operator::Equal, _, _) => Value::Bool(lhs.numeric_eq(rhs)),
+        (Operator::NotEqual, _, _) => Value::Bool(!lhs.numeric_eq(rhs)),
+        (_, Value::Number(l), Value::Number(r)) => numbers(operator, *l, *r)?,
+        // Integer division truncates each operand on its own.
+        (Operator::IDivide, Value::Integer(l), Value::Number(r)) => {
+            Value::Integer(divide(*l, *r as i64)?)
+        }
+        (Operator::IDivide, Value::Number(l), Value::Integer(r)) => {
+            Value::Integer(divide(*l as i64, *r)?)
+        }
+        (_, Value::Integer(l), Value::Number(r)) => numbers(operator, *l as f64, *r)?,
+        (_, Value::Number(l), Value::Integer(r)) => numbers(operator, *l, *r as f64)?,
+        (_, lhs, rhs) => {
+            return Err(RuntimeError::InvalidOperandType {
+                lhs: lhs.type_name().to_string(),
+                rhs: rhs.type_name().to_string(),
+            })
+        }
+    };
+    Ok(value)
+}
+
+#[inline(always)]
+fn integers(operator: Operator, l: i64, r: i64) -> Result<Value, RuntimeError> {
+    let value = match operator {
+        Operator::Add => l.checked_add(r).ok_or(RuntimeError::IntegerOverflow)?,
+        Operator::Subtract => l.checked_sub(r).ok_or(RuntimeError::IntegerOverflow)?,
+        Operator::Multiply => l.checked_mul(r).ok_or(RuntimeError::IntegerOverflow)?,
+        Operator::Divide | Operator::IDivide => divide(l, r)?,
+        Operator::Modulus => remainder(l, r)?,
+        Operator::Less => return Ok(Value::Bool(l < r)),
+        Operator::LessEqual => return Ok(Value::Bool(l <= r)),
+        Operator::Greater => return Ok(Value::Bool(l > r)),
+        Operator::GreaterEqual => return Ok(Value::Bool(l >= r)),
+        Operator::Equal => return Ok(Value::Bool(l == r)),
+        Operator::NotEqual => return Ok(Value::Bool(l != r)),
+    };
+    Ok(Value::Integer(value))
+}
+
+#[inline(always)]
+fn numbers(operator: Operator, l: f64, r: f64) -> Result<Value, RuntimeError> {
+    let value = match operator {
+        Operator::Add => l + r,
+        Operator::Subtract => l - r,
+        Operator::Multiply => l * r,
+        Operator::Divide => l / r,
+        Operator::IDivide => return Ok(Value::Integer(divide(l as i64, r as i64)?)),
+        Operator::Modulus => l % r,
+        _ => unreachable!("{operator} is not arithmetic."),
+    };
+    Ok(Value::Number(value))
+}
+
+/// Divides integers, where dividing by zero or overflowing is an error rather
+/// than a panic.
+fn divide(l: i64, r: i64) -> Result<i64, RuntimeError> {
+    if r == 0 {
+        return Err(RuntimeError::DivisionByZero);
+    }
+    l.checked_div(r).ok_or(RuntimeError::IntegerOverflow)
+}
+
+/// The remainder of dividing integers, which is an error for zero like
+/// [`divide`].
+fn remainder(l: i64, r: i64) -> Result<i64, RuntimeError> {
+    if r == 0 {
+        return Err(RuntimeError::DivisionByZero);
+    }
+    Ok(l.wrapping_rem(r))
+}
+
+/// The stack and the next instruction of a suspended generator frame.
+struct Suspended {
+    stack: Vec<Slot>,
+    ip: usize,
+}
+
+/// A call to a generator function, which runs a little further each time
+/// the next value is asked for.
+struct Generator {
+    closure: ClosureRef,
+    /// The suspended frame, or `None` once the function has returned.
+    suspended: Option<Suspended>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineStatus {
+    Suspended,
+    Running,
+    Dead,
+}
+
+impl Display for CoroutineStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoroutineStatus::Suspended => write!(f, "suspended"),
+            CoroutineStatus::Running => write!(f, "running"),
+            CoroutineStatus::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+/// A function that can suspend itself with `Coroutine.yield` from any of the
+/// script functions it calls, and be resumed from there later.
+pub struct Coroutine {
+    function: ClosureRef,
+    status: CoroutineStatus,
+    /// The frames of the coroutine while it's suspended, or `None` before it
+    /// first runs.
+    segment: Option<Segment>,
+}
+
+impl Coroutine {
+    pub fn new(function: ClosureRef) -> Self {
+        Self {
+            function,
+            status: CoroutineStatus::Suspended,
+            segment: None,
+        }
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.status
+    }
+}
+
+/// The frames, stack, `try` blocks and open upvalues of a suspended
+/// coroutine, with their stack slots and frame indices relative to where the
+/// coroutine starts.
+struct Segment {
+    frames: Vec<CallFrame>,
+    stack: Vec<Slot>,
+    handlers: Vec<Handler>,
+    upvalues: Vec<(usize, UpvalueRef)>,
+}
+
+/// An active `try` block.
+struct Handler {
+    /// The index of the frame of the block.
+    frame: usize,
+    /// The length of the stack when the block started.
+    stack_len: usize,
+    /// Where the `catch` starts.
+    ip: usize,
+}
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    StackOverflow,
+    StackUnderflow,
+    IncorrectNumberOfArguments,
+    NegateOperatorOnNonNumericValue,
+    CannotCallNonCallableValue,
+    CannotLoadNativeModuleAtRuntime,
+    UnexpectedType,
+    InvalidOperandType {
+        lhs: String,
+        rhs: String,
+    },
+    InvalidConversion,
+    /// An index past either end of an array or string of `len` elements.
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    /// Indexing a value that can't be indexed, or with a key of a type it
+    /// can't be indexed with.
+    CannotIndex {
+        value: String,
+        key: String,
+    },
+    /// An integer division or remainder by zero.
+    DivisionByZero,
+    /// An integer operation whose result doesn't fit in an `int`.
+    IntegerOverflow,
+    /// Running a module that imports one that was reloaded with different
+    /// locals, without reloading it too.
+    StaleModule(String),
+    Custom(String),
+    /// A value raised with `throw`, or by a native with [`Vm::error`].
+    Thrown {
+        value: Value,
+        function: String,
+        line: usize,
+    },
+    /// Unwinds a coroutine that yields to its `resume`, from [`Vm::yield_coroutine`].
+    Yield,
+    /// Unwinds a script that awaits a future to [`Vm::run_async`].
+    Await,
+    /// An error that ended a script, with the calls that were running when
+    /// it was raised.
+    Traced {
+        error: Box<RuntimeError>,
+        trace: StackTrace,
+    },
+}
+
+impl RuntimeError {
+    /// The value that a `catch` binds the error to, which is the thrown
+    /// value itself or else the message of the error.
+    pub fn into_value(self) -> Value {
+        match self {
+            RuntimeError::Thrown { value, .. } => value,
+            RuntimeError::Traced { error, .. } => error.into_value(),
+            error => Value::String(Rc::new(error.to_string())),
+        }
+    }
+
+    /// The error without the stack trace that [`Vm::execute_module`] and
+    /// [`Vm::run_async`] attach to it.
+    pub fn without_trace(self) -> RuntimeError {
+        match self {
+            RuntimeError::Traced { error, .. } => *error,
+            error => error,
+        }
+    }
+}
+
+impl Error for RuntimeError {}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::StackOverflow => write!(f, "Stack Overflow"),
+            RuntimeError::StackUnderflow => write!(f, "Stack Underflow"),
+            RuntimeError::IncorrectNumberOfArguments => {
+                write!(f, "Incorrect Number of Arguments")
+            }
+            RuntimeError::NegateOperatorOnNonNumericValue => {
+                write!(f, "Negate operator on non numeric value")
+            }
+            RuntimeError::CannotCallNonCallableValue => write!(f, "Cannot call non callable value"),
+            RuntimeError::CannotLoadNativeModuleAtRuntime => {
+                write!(f, "Cannot load native module at runtime")
+            }
+            RuntimeError::UnexpectedType => {
+                write!(f, "Unexpected type")
+            }
+            RuntimeError::InvalidOperandType { lhs, rhs } => {
+                write!(f, "Invalid type of operands. lhs: {lhs}, rhs: {rhs}")
+            }
+            RuntimeError::InvalidConversion => {
+                write!(f, "Invalid conversion.")
+            }
+            RuntimeError::IndexOutOfBounds { index, len } => {
+                write!(f, "Index {index} is out of bounds for length {len}")
+            }
+            RuntimeError::CannotIndex { value, key } => {
+                write!(f, "Cannot index a value of type {value} with {key}")
+            }
+            RuntimeError::DivisionByZero => write!(f, "Division by zero"),
+            RuntimeError::IntegerOverflow => write!(f, "Integer overflow"),
+            RuntimeError::StaleModule(ident) => write!(
+                f,
+                "Module `{ident}` has to be reloaded since a module it imports changed"
+            ),
+            RuntimeError::Custom(message) => {
                 write!(f, "Error: {message}")
             }
+            RuntimeError::Thrown {
+                value,
+                function,
+                line,
+            } => {
+                write!(f, "{value} (thrown in `{function}` at line {line})")
+            }
+            RuntimeError::Yield => write!(f, "Yield outside of a coroutine"),
+            RuntimeError::Await => write!(f, "Await outside of Vm::run_async"),
+            RuntimeError::Traced { error, trace } => write!(f, "{error}\n{trace}"),
         }
     }
 }
@@ -798,3 +2208,1683 @@ impl Into<wasm_bindgen::JsValue> for RuntimeError {
         wasm_bindgen::JsValue::from_str(&self.to_string())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        cell::RefCell,
+        collections::HashSet,
+        future::Future,
+        rc::Rc,
+        task::{Context, Poll, Waker},
+    };
+
+    use crate::{
+        compiler::{CompilerError, CompilerErrorKind},
+        plugin::{Plugin, PluginError},
+        state::{
+            FieldCache, Module, ModuleError, ModuleLoader, ModuleValue, NativeModuleBuilder,
+            StackTraceInfo,
+        },
+        stdlib::{self, StdlibSet},
+        value::Value,
+    };
+
+    use super::{RuntimeError, ScriptCallback, Vm};
+
+    pub(crate) fn run(source: &str) -> Value {
+        let mut vm = Vm::new_with_std();
+        let index = vm.load_from_source("main", source).unwrap();
+        vm.execute_module(index, "main").unwrap();
+        vm.peek().unwrap()
+    }
+
+    #[test]
+    fn zero_arg_function() {
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let five () = 5\nlet main () = five ()")
+            .unwrap();
+        let module = vm.module_loader().module_at(index).unwrap();
+        let ModuleValue::Normal(main) = &module.value else {
+            unreachable!()
+        };
+        assert!(main.prototypes.iter().all(|p| p.num_args == 0));
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(5)));
+    }
+
+    #[test]
+    fn zero_arg_call_arity() {
+        // `()` is the argument of functions that take one.
+        assert_eq!(run("let id x = x\nlet main () = id ()"), Value::Unit);
+        assert_eq!(run("let id x = x\nlet main () = id (())"), Value::Unit);
+        assert_eq!(
+            run("let f x y = y\nlet main () =\n    let g = f ()\n    g 2"),
+            Value::Integer(2)
+        );
+        assert_eq!(
+            run("let f (x = 3) = x\nlet main () = f ()"),
+            Value::Integer(3)
+        );
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let f () = 1\nlet main () = f 2")
+            .unwrap();
+        assert!(matches!(
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace),
+            Err(RuntimeError::IncorrectNumberOfArguments)
+        ));
+    }
+
+    #[test]
+    fn zero_arg_lambda() {
+        assert_eq!(
+            run("let main () =\n    let f = fn () -> 3\n    f ()"),
+            Value::Integer(3)
+        );
+        assert_eq!(
+            run("let main () =\n    let f = fn -> 4\n    f ()"),
+            Value::Integer(4)
+        );
+    }
+
+    #[test]
+    fn host_callback() {
+        let mut vm = Vm::new_with_std();
+        vm.register_callback(
+            "add",
+            Box::new(|args| Value::Integer(args.iter().map(|a| a.clone().as_int().unwrap()).sum())),
+        )
+        .unwrap();
+        let index = vm
+            .load_from_source("main", "let main () = Host.add 1 2 3")
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(6)));
+    }
+
+    #[test]
+    fn script_callback() {
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let main () = fn x -> x * 2")
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+        let callback = ScriptCallback::from_value(vm.peek().unwrap()).unwrap();
+        let result = callback.call(&mut vm, &[Value::Integer(21)]).unwrap();
+        assert_eq!(result, Value::Integer(42));
+    }
+
+    #[test]
+    fn log_events() {
+        let mut vm = Vm::new_with_std();
+        let logs = Rc::new(RefCell::new(Vec::new()));
+        let listener_logs = logs.clone();
+        vm.add_event_listener("log", move |detail| {
+            listener_logs.borrow_mut().push(detail.to_string())
+        });
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let listener_events = events.clone();
+        vm.add_event_listener("done", move |detail| {
+            listener_events.borrow_mut().push(detail.clone())
+        });
+        let source =
+            "let main () =\n    Io.print \"a\" 1\n    Io.printf \"b{(1 + 1)}\"\n    Io.emit \"done\" 3";
+        let index = vm.load_from_source("main", source).unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(*logs.borrow(), vec!["a1".to_string(), "b2".to_string()]);
+        assert_eq!(*events.borrow(), vec![Value::Integer(3)]);
+    }
+
+    #[test]
+    fn mixed_numeric_equality() {
+        assert_eq!(run("let main () = 1 == 1.0"), Value::Bool(true));
+        assert_eq!(run("let main () = 1 != 1.5"), Value::Bool(true));
+        assert_eq!(run("let main () = 2.0 != 2"), Value::Bool(false));
+    }
+
+    #[test]
+    fn native_stack_underflow() {
+        let mut module_loader = ModuleLoader::new("");
+        let greedy = |vm: &mut Vm| {
+            vm.pop()?;
+            vm.pop()?;
+            vm.pop()
+        };
+        module_loader
+            .add_native_function("Test", "greedy", Rc::new(RefCell::new(greedy)))
+            .unwrap();
+        let mut vm = Vm::new(module_loader);
+        let index = vm
+            .load_from_source("main", "let main () = Test.greedy 1")
+            .unwrap();
+        assert!(matches!(
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace),
+            Err(RuntimeError::StackUnderflow)
+        ));
+    }
+
+    struct Greeter;
+
+    impl Plugin for Greeter {
+        fn name(&self) -> &str {
+            "greeter"
+        }
+
+        fn modules(&self) -> Vec<Module> {
+            fn greet(vm: &mut Vm) -> Result<Value, RuntimeError> {
+                let name = vm.pop()?;
+                Ok(Value::String(Rc::new(format!("hello {name}"))))
+            }
+            vec![NativeModuleBuilder::new("Greeter")
+                .with_function("greet", greet)
+                .build()]
+        }
+    }
+
+    #[test]
+    fn plugin() {
+        let mut vm = Vm::new_with_std();
+        vm.register_plugin(&Greeter).unwrap();
+        assert!(matches!(
+            vm.register_plugin(&Greeter),
+            Err(PluginError::ModuleAlreadyExists { .. })
+        ));
+        let index = vm
+            .load_from_source("main", "let main () = Greeter.greet \"you\"")
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(
+            vm.peek(),
+            Some(Value::String(Rc::new("hello you".to_string())))
+        );
+    }
+
+    #[test]
+    fn module_chain() {
+        let mut vm = Vm::new_with_std();
+        vm.load_from_source("m0", "let value = 0").unwrap();
+        for i in 1..100 {
+            let source = format!("let value = m{}.value + 1", i - 1);
+            vm.load_from_source(&format!("m{i}"), &source).unwrap();
+        }
+        let index = vm
+            .load_from_source("main", "let main () = m99.value")
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(99)));
+    }
+
+    /// Writes `files` as modules to a new directory in the system's temp
+    /// dir and returns its path.
+    fn module_dir(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = std::env::temp_dir().join(format!("focus-lang-{name}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, source) in files {
+            std::fs::write(dir.join(file), source).unwrap();
+        }
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn selective_import() {
+        let dir = module_dir(
+            "selective",
+            &[("utils.fl", "let a = 1\nlet b = 2\nlet c = 3")],
+        );
+        let mut vm = Vm::new(ModuleLoader::new(&dir));
+        let index = vm
+            .load_from_source(
+                "main",
+                "from \"utils\" import a, b as c\nlet main () = [a, c]",
+            )
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+        let Some(Value::Array(array)) = vm.peek() else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [Value::Integer(1), Value::Integer(2)]);
+        assert!(matches!(
+            vm.load_from_source("other", "from \"utils\" import d\nlet main () = 1"),
+            Err(CompilerError {
+                kind: CompilerErrorKind::NameNotFound(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn import_module_by_name() {
+        let source = "import Iter
+from Array import sort as sorted
+let main () = collect (take (sorted [3, 1, 2]) 2)";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [Value::Integer(1), Value::Integer(2)]);
+        let mut vm = Vm::new_with_std();
+        assert!(matches!(
+            vm.load_from_source("main", "import Missing"),
+            Err(CompilerError {
+                kind: CompilerErrorKind::NameNotFound(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn import_alias() {
+        let dir = module_dir("alias", &[("utils.fl", "let value = 1")]);
+        let mut module_loader = ModuleLoader::new(&dir);
+        module_loader.add_modules(crate::stdlib::modules());
+        let mut vm = Vm::new(module_loader);
+        let source = "import \"utils\" as U
+import Iter as I
+let main () = [U.value, I.collect (I.take [3, 4] 1)]";
+        let index = vm.load_from_source("main", source).unwrap();
+        vm.execute_module(index, "main").unwrap();
+        let Some(Value::Array(array)) = vm.peek() else {
+            panic!("expected an array");
+        };
+        let array = array.borrow();
+        assert_eq!(array[0], Value::Integer(1));
+        assert_eq!(
+            *array[1].clone().as_array().unwrap().borrow(),
+            [Value::Integer(3)]
+        );
+        assert!(matches!(
+            vm.load_from_source("other", "import \"utils\" as U\nlet main () = value"),
+            Err(CompilerError {
+                kind: CompilerErrorKind::NameNotFound(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn file_imports_disabled() {
+        let root = module_dir("no-file-imports", &[("secret.fl", "let value = 7")]);
+        let mut module_loader = ModuleLoader::new(&root);
+        module_loader.add_modules(stdlib::modules_in(StdlibSet::ITER));
+        module_loader.set_file_imports(false);
+        let mut vm = Vm::new(module_loader);
+        assert!(matches!(
+            vm.load_from_source("main", "import \"secret\"\nlet main () = value"),
+            Err(CompilerError {
+                kind: CompilerErrorKind::ModuleError(ModuleError::FileImportsDisabled(_)),
+                ..
+            })
+        ));
+        let index = vm
+            .load_from_source("main", "import Iter\nlet main () = 1")
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+    }
+
+    #[test]
+    fn module_search_paths() {
+        let root = module_dir("search-root", &[]);
+        let extra = module_dir("search-extra", &[("extra.fl", "let value = 7")]);
+        let mut module_loader = ModuleLoader::new(&root);
+        assert!(matches!(
+            module_loader.load_module("extra"),
+            Err(CompilerError {
+                kind: CompilerErrorKind::ModuleError(ModuleError::NotFound { .. }),
+                ..
+            })
+        ));
+        module_loader.add_search_path(&extra);
+        let mut vm = Vm::new(module_loader);
+        let index = vm
+            .load_from_source("main", "import \"extra\"\nlet main () = value")
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(7)));
+    }
+
+    #[test]
+    fn module_loaded_once() {
+        let dir = module_dir("dedup", &[("shared.fl", "let value = 1")]);
+        let mut module_loader = ModuleLoader::new(&dir);
+        let index = module_loader.load_module("shared").unwrap();
+        let path = std::path::Path::new(&dir).join("shared.fl");
+        assert_eq!(module_loader.load_module(path).unwrap(), index);
+        let mut vm = Vm::new(module_loader);
+        vm.load_from_source("a", "import \"shared\"").unwrap();
+        vm.load_from_source("b", "import \"./shared\"").unwrap();
+        let shared = vm.module_loader().modules().iter();
+        assert_eq!(shared.filter(|m| m.ident == "shared").count(), 1);
+    }
+
+    #[test]
+    fn circular_import() {
+        let dir = module_dir(
+            "circular",
+            &[
+                ("a.fl", "import \"b\"\nlet x = 1"),
+                ("b.fl", "import \"a\"\nlet y = 1"),
+                ("c.fl", "import \"c\""),
+            ],
+        );
+        let mut module_loader = ModuleLoader::new(&dir);
+        let Err(CompilerError {
+            kind: CompilerErrorKind::CircularImport(chain),
+            ..
+        }) = module_loader.load_module("a")
+        else {
+            panic!("expected a circular import");
+        };
+        let files: Vec<_> = chain
+            .iter()
+            .map(|p| std::path::Path::new(p).file_name().unwrap())
+            .collect();
+        assert_eq!(files, ["a.fl", "b.fl", "a.fl"]);
+        assert!(matches!(
+            module_loader.load_module("c"),
+            Err(CompilerError {
+                kind: CompilerErrorKind::CircularImport(chain),
+                ..
+            }) if chain.len() == 2
+        ));
+    }
+
+    #[test]
+    fn private_locals() {
+        let dir = module_dir(
+            "private",
+            &[(
+                "lib.fl",
+                "let helper x = x * 2\npub let double x = helper x",
+            )],
+        );
+        let mut vm = Vm::new(ModuleLoader::new(&dir));
+        let index = vm
+            .load_from_source("main", "import \"lib\"\nlet main () = double 2")
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(4)));
+        for source in [
+            "import \"lib\"\nlet main () = helper 2",
+            "from \"lib\" import helper",
+            "import \"lib\"\nlet main () = lib.helper 2",
+        ] {
+            assert!(
+                matches!(
+                    vm.load_from_source("other", source),
+                    Err(CompilerError {
+                        kind: CompilerErrorKind::NameNotFound(_),
+                        ..
+                    })
+                ),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn module_initialized_once() {
+        let mut vm = Vm::new_with_std();
+        let logs = Rc::new(RefCell::new(0));
+        let counter = logs.clone();
+        vm.add_event_listener("log", move |_| *counter.borrow_mut() += 1);
+        vm.load_from_source("config", "let value = Io.print \"init\"")
+            .unwrap();
+        let index = vm
+            .load_from_source("main", "let main () = [config.value, config.value]")
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(*logs.borrow(), 1);
+    }
+
+    #[test]
+    fn coverage() {
+        let mut vm = Vm::new_with_std();
+        let source = "let main () =\n    if 1 > 2 then\n        1\n    else\n        2";
+        let index = vm.load_from_source("main", source).unwrap();
+        vm.set_coverage(true);
+        vm.execute_module(index, "main").unwrap();
+        let lines: HashSet<usize> = vm
+            .coverage()
+            .into_iter()
+            .filter(|(module, _)| *module == index)
+            .map(|(_, line)| line)
+            .collect();
+        assert!(lines.contains(&2));
+        assert!(!lines.contains(&3));
+        assert!(lines.contains(&5));
+    }
+
+    #[test]
+    fn shadowing_in_block() {
+        let mut vm = Vm::new_with_std();
+        let source = "let main () =
+    let y = if true then
+        let x = 1
+        let x = x + 1
+        x
+    else 0
+    let z = 10
+    [y, z]";
+        let index = vm.load_from_source("main", source).unwrap();
+        vm.execute_module(index, "main").unwrap();
+        let Some(Value::Array(array)) = vm.peek() else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [Value::Integer(2), Value::Integer(10)]);
+        // The module's two locals and the result of `main`.
+        assert_eq!(vm.stack().len(), 3);
+    }
+
+    #[test]
+    fn block_statements_are_popped() {
+        let source = "let id v = v
+let main () =
+    let a = if true then
+        let b = 1
+        id b
+        b = b + 1
+        b
+    else 0
+    let c = 3
+    [a, c]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [Value::Integer(2), Value::Integer(3)]);
+    }
+
+    #[test]
+    fn large_array_literal() {
+        let elements: Vec<String> = (0..1000).map(|i| (i % 200).to_string()).collect();
+        let source = format!("let main () = [{}]", elements.join(", "));
+        let Value::Array(array) = run(&source) else {
+            panic!("expected an array");
+        };
+        let array = array.borrow();
+        assert_eq!(array.len(), 1000);
+        assert_eq!(array[999], Value::Integer(199));
+    }
+
+    #[test]
+    fn len() {
+        let source = "let xs = [1, 2, 3, 4]
+let main () = [len xs, len \"héllo\", len { a: xs, b: 2 }, len []]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [4, 5, 2, 0].map(Value::Integer));
+    }
+
+    #[test]
+    fn trace() {
+        let mut vm = Vm::new_with_std();
+        let index = vm.load_from_source("main", "let main () = 1 + 2").unwrap();
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let log = trace.clone();
+        vm.set_trace(Some(Box::new(move |op, ip, stack| {
+            log.borrow_mut().push((op.to_string(), ip, stack.len()))
+        })));
+        vm.execute_module(index, "main").unwrap();
+        let expected: &[_] = if cfg!(feature = "stack-vm") {
+            &[
+                ("Closure 0", 0, 1),
+                ("LoadInt 1", 0, 3),
+                ("LoadInt 2", 2, 4),
+                ("Add", 4, 5),
+                ("Return", 5, 4),
+            ]
+        } else {
+            &[
+                ("Closure 0", 0, 1),
+                ("LoadInt 1", 0, 3),
+                ("OpInt Add 2", 2, 4),
+                ("Return", 5, 4),
+            ]
+        };
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(op, ip, len)| (op.to_string(), ip, len))
+            .collect();
+        assert_eq!(*trace.borrow(), expected);
+    }
+
+    #[test]
+    fn match_expression() {
+        let source = "let describe x =
+    match x
+    | 0 -> \"zero\"
+    | () -> \"unit\"
+    | 'c' -> \"char\"
+    | n ->
+        let m = n * 2
+        \"other {m}\"
+
+let main () =
+    let a = describe 0
+    let b = describe ()
+    let c = describe 'c'
+    let d = describe 21
+    let e = match 3 | 1 -> 1 | _ -> 2
+    [a, b, c, d, e]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let values: Vec<String> = array.borrow().iter().map(|v| v.to_string()).collect();
+        assert_eq!(values, ["zero", "unit", "char", "other 42", "2"]);
+    }
+
+    #[test]
+    fn match_guards() {
+        let source = "let classify x =
+    match x
+    | 0 if false -> \"never\"
+    | 0 -> \"zero\"
+    | n if n > 10 ->
+        let f = fn () -> n
+        \"big {(f ())}\"
+    | n if n < 0 -> \"negative\"
+    | _ if x == 5 -> \"five\"
+    | n -> \"small {n}\"
+
+let main () =
+    let results = []
+    for x in [0, 11, -3, 5, 7] do
+        results = [...results, classify x]
+    results";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let values: Vec<String> = array.borrow().iter().map(|v| v.to_string()).collect();
+        assert_eq!(values, ["zero", "big 11", "negative", "five", "small 7"]);
+    }
+
+    #[test]
+    fn match_structural_patterns() {
+        let source = "let describe x =
+    match x
+    | {status: 200, body} -> \"ok {body}\"
+    | {status: 404} -> \"missing\"
+    | {status, error: {code}} -> \"{status}: {code}\"
+    | [] -> \"empty\"
+    | [a, b] if a == b -> \"pair of {a}\"
+    | [a, 2] -> \"{a} and two\"
+    | [head, ...rest] ->
+        let f = fn () -> head
+        \"{(f ())} then {rest}\"
+    | _ -> \"other\"
+
+let main () =
+    let results = []
+    for x in [{status: 200, body: \"hi\"}, {status: 404}, {status: 500, error: {code: 7}}, [], [3, 3], [1, 2], [1, 2, 3], 5, {status: 500}] do
+        results = [...results, describe x]
+    results";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let values: Vec<String> = array.borrow().iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            values,
+            [
+                "ok hi",
+                "missing",
+                "500: 7",
+                "empty",
+                "pair of 3",
+                "1 and two",
+                "1 then [2,3,]",
+                "other",
+                "other"
+            ]
+        );
+    }
+
+    #[test]
+    fn match_in_operands() {
+        let source = "let id x = x
+let add x = 1 + (match x | 5 -> 10 | n -> n)
+let call x = id (match x | {a, b} -> a + b)
+let guard x = 2 * (match x | 1 -> 0 | n if n > 2 -> n + 1 | _ -> 0)
+let pair x = (1, match x | \"s\" -> \"s!\" | s -> s)
+let main () = [add 5, call {a: 1, b: 2}, guard 3, pair \"s\"]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let values: Vec<String> = array.borrow().iter().map(|v| v.to_string()).collect();
+        assert_eq!(values, ["11", "3", "8", "(1, s!)"]);
+    }
+
+    #[test]
+    fn structural_patterns_in_operands() {
+        let source = "let main () =
+    let a = [9, match [1, 2] | [a, b] -> b | _ -> 0]
+    let b = 1 + (match {point: [3, 4]} | {point: [x, ...rest]} -> x + rest[0] | _ -> 0)
+    [a[1], b]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [Value::Integer(2), Value::Integer(8)]);
+    }
+
+    #[test]
+    fn match_without_matching_arm() {
+        assert_eq!(run("let main () = match 5 | 1 -> 1"), Value::Unit);
+    }
+
+    #[test]
+    fn while_loop() {
+        let source = "let main () =
+    let i = 0
+    let sum = 0
+    while i < 100000 do
+        i = i + 1
+        sum = sum + i
+    while sum > 10 do sum = sum // 10
+    [i, sum]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [Value::Integer(100000), Value::Integer(5)]);
+    }
+
+    #[test]
+    fn for_loop() {
+        let source = "let main () =
+    let sum = 0
+    for x in [1, 2, 3] do
+        sum = sum + x
+    let fs = []
+    let i = 0
+    for c in \"aé\" do
+        fs[i] = fn () -> c
+        i = i + 1
+    for x in Iter.take [4, 5, 6] 2 do sum = sum + x
+    [sum, fs[0] (), fs[1] ()]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(
+            *array.borrow(),
+            [Value::Integer(15), Value::Char('a'), Value::Char('é')]
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        let source = "let main () =
+    let n = 3
+    let sum = 0
+    for i in 0..n + 1 do sum = sum + i
+    let a = Iter.map (1..=n) fn x -> x * 2
+    let b = Iter.collect (n..1)
+    let c = Iter.collect (1..=1)
+    Iter.for_each (0..2) fn x -> sum = sum + x
+    [sum, a, b, c]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let array = array.borrow();
+        assert_eq!(array[0], Value::Integer(7));
+        let ranges: Vec<Vec<Value>> = array[1..]
+            .iter()
+            .map(|v| v.clone().as_array().unwrap().borrow().clone())
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                [2, 4, 6].map(Value::Integer).to_vec(),
+                vec![],
+                vec![Value::Integer(1)]
+            ]
+        );
+    }
+
+    #[test]
+    fn spread() {
+        let source = "let sum a b c = a + b + c
+let main () =
+    let a = [1, 2]
+    let b = [...a, 3, ...a]
+    let c = [...[]]
+    let d = sum ...a 10
+    let e = sum 100 ...[20, 3]
+    [b, c, [d, e, len a]]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let arrays: Vec<Vec<Value>> = array
+            .borrow()
+            .iter()
+            .map(|v| v.clone().as_array().unwrap().borrow().clone())
+            .collect();
+        assert_eq!(
+            arrays,
+            [
+                [1, 2, 3, 1, 2].map(Value::Integer).to_vec(),
+                vec![],
+                [13, 123, 2].map(Value::Integer).to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn spread_iterators() {
+        let source = "let sum a b c = a + b + c
+let main () =
+    let a = [...(0..3), ...\"ab\"]
+    let b = sum ...(Iter.take (1..100) 3)
+    let evens = Iter.filter (0..6) fn x -> if x % 2 == 0 then x else ()
+    [a, b, evens]";
+        assert_eq!(run(source).to_string(), "[[0,1,2,a,b,],6,[0,2,4,],]");
+    }
+
+    #[test]
+    fn generators() {
+        let source = "let naturals () =
+    let n = 0
+    while true do
+        yield n
+        n = n + 1
+
+let evens_below limit =
+    for n in naturals () do
+        if n >= limit then break
+        if n % 2 == 0 then yield n
+    \"ignored\"
+
+let main () =
+    let a = Iter.take (naturals ()) 3 |> Iter.collect
+    let b = Iter.map (evens_below 5) fn x -> x * 10
+    let g = evens_below 3
+    let c = Iter.collect g
+    let d = Iter.collect g
+    let pair = fn (start = 1) ->
+        yield start
+        yield start + 1
+    [a, b, c, d, [...(pair ()), ...(pair 10)]]";
+        assert_eq!(
+            run(source).to_string(),
+            "[[0,1,2,],[0,20,40,],[0,2,],[],[1,2,10,11,],]"
+        );
+        let mut vm = Vm::new_with_std();
+        assert!(matches!(
+            vm.load_from_source("main", "let main () = try yield 1 catch e -> e"),
+            Err(CompilerError {
+                kind: CompilerErrorKind::YieldInsideTry,
+                ..
+            })
+        ));
+    }
+
+    /// Polls a future until it's ready, returning its output and the number
+    /// of times it was polled.
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut polls = 1;
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return (output, polls),
+                Poll::Pending => polls += 1,
+            }
+        }
+    }
+
+    /// A future that is pending the first time it's polled.
+    fn later(result: Result<Value, RuntimeError>) -> Value {
+        let mut result = Some(result);
+        let mut pending = true;
+        Value::future(std::future::poll_fn(move |cx| {
+            if std::mem::take(&mut pending) {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            } else {
+                Poll::Ready(result.take().unwrap())
+            }
+        }))
+    }
+
+    #[test]
+    fn run_async() {
+        let source = "let double x = await Host.fetch x
+let main () =
+    let a = double 20
+    let b = try await Host.fail () catch e -> e
+    let n = 1
+    let add = fn x -> x + n
+    n = await Host.fetch 2
+    let c = await 7
+    [a, b, c, add 1]";
+        let mut vm = Vm::new_with_std();
+        vm.register_callback(
+            "fetch",
+            Box::new(|args| later(Ok(Value::Integer(args[0].clone().as_int().unwrap() * 2)))),
+        )
+        .unwrap();
+        vm.register_callback(
+            "fail",
+            Box::new(|_| later(Err(RuntimeError::Custom("offline".to_string())))),
+        )
+        .unwrap();
+        let index = vm.load_from_source("main", source).unwrap();
+        let (result, polls) = block_on(vm.run_async(index, "main"));
+        result.unwrap();
+        assert_eq!(polls, 4);
+        assert_eq!(vm.peek().unwrap().to_string(), "[40,Error: offline,7,5,]");
+    }
+
+    #[test]
+    fn await_errors() {
+        let mut vm = Vm::new_with_std();
+        vm.register_callback("fetch", Box::new(|args| later(Ok(args[0].clone()))))
+            .unwrap();
+        let source = "let main () = Iter.collect (Iter.map [1] fn x -> await Host.fetch x)";
+        let index = vm.load_from_source("main", source).unwrap();
+        assert!(matches!(
+            block_on(vm.run_async(index, "main")).0.map_err(RuntimeError::without_trace),
+            Err(RuntimeError::Custom(message)) if message == "Cannot await across a native function"
+        ));
+        let mut vm = Vm::new_with_std();
+        vm.register_callback("fetch", Box::new(|args| later(Ok(args[0].clone()))))
+            .unwrap();
+        let index = vm
+            .load_from_source("main", "let main () = await Host.fetch 1")
+            .unwrap();
+        assert!(matches!(
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace),
+            Err(RuntimeError::Custom(message)) if message == "Cannot await a future outside of Vm::run_async"
+        ));
+    }
+
+    #[test]
+    fn negative_indexing() {
+        let source = "let main () =
+    let a = [1, 2, 3]
+    a[-1] = 30
+    a[4] = 5
+    [a[-1], a[-5], a[-3], a]";
+        assert_eq!(run(source).to_string(), "[5,1,30,[1,2,30,(),5,],]");
+    }
+
+    #[test]
+    fn slicing() {
+        let source = "let main () =
+    let a = [1, 2, 3, 4, 5]
+    let s = \"héllo\"
+    let n = 1
+    [a[1..3], a[2..], a[..2], a[-2..], a[1..=-2], a[3..1], a[0..10], a[n..(n + 1)], s[1..3], s[-3..]]";
+        assert_eq!(
+            run(source).to_string(),
+            "[[2,3,],[3,4,5,],[1,2,],[4,5,],[2,3,4,],[],[1,2,3,4,5,],[2,],él,llo,]"
+        );
+        let mut vm = Vm::new_with_std();
+        assert!(matches!(
+            vm.load_from_source("main", "let main () =\n    let a = [1]\n    a[0..1] = [2]"),
+            Err(CompilerError {
+                kind: CompilerErrorKind::UnexpectedExpression,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn coalesce() {
+        let source = "let main () =
+    let config = { port: 80 }
+    let calls = 0
+    let f () =
+        calls = calls + 1
+        1
+    let a = config.port ?? f ()
+    let b = config.host ?? \"localhost\"
+    let c = () ?? () ?? 3
+    let d = config.x ?? 1 + 2
+    let e = false ?? true
+    (a, b, c, d, e, calls)";
+        assert_eq!(run(source).to_string(), "(80, localhost, 3, 3, false, 0)");
+    }
+
+    #[test]
+    fn consts() {
+        let source = "const width = 80
+const half = width / 2
+pub const title = \"w: \" ++ half
+let main () =
+    let f width = width
+    (width, half, title, f 1, -half + 0.5)";
+        assert_eq!(run(source).to_string(), "(80, 40, w: 40, 1, -39.5)");
+
+        let mut vm = Vm::new_with_std();
+        assert!(vm
+            .load_from_source("a", "const x = 1\nlet main () = x = 2")
+            .is_err());
+        assert!(vm.load_from_source("b", "let y = 1\nconst x = y").is_err());
+        assert!(vm.load_from_source("c", "const x = 1 / 0").is_err());
+    }
+
+    #[test]
+    fn string_indexing() {
+        let source = "let main () =
+    let s = \"héllo\"
+    (s[1], s[-1], s[1..3])";
+        assert_eq!(run(source).to_string(), "(é, o, él)");
+    }
+
+    #[test]
+    fn negative_index_out_of_bounds() {
+        let mut vm = Vm::new_with_std();
+        let source = "let main () =\n    let a = [1, 2]\n    a[-3]";
+        let index = vm.load_from_source("main", source).unwrap();
+        assert!(matches!(
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace),
+            Err(RuntimeError::IndexOutOfBounds { index: -3, len: 2 })
+        ));
+    }
+
+    #[test]
+    fn spread_non_array() {
+        let mut vm = Vm::new_with_std();
+        let index = vm.load_from_source("main", "let main () = [...1]").unwrap();
+        assert!(matches!(
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace),
+            Err(RuntimeError::UnexpectedType)
+        ));
+    }
+
+    #[test]
+    fn recursive_local_functions() {
+        let source = "let main () =
+    let fact n = if n < 2 then 1 else n * fact (n - 1)
+    let count = fn n -> if n == 0 then 0 else 1 + count (n - 1)
+    let go n =
+        let inner x =
+            if x == 0 then 0 else
+                let y = x - 1
+                go y
+        inner n
+    [fact 5, count 3, go 3]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [120, 3, 0].map(Value::Integer));
+    }
+
+    #[test]
+    fn mutually_recursive_local_functions() {
+        let source = "let main () =
+    let is_even n = if n == 0 then true else is_odd (n - 1)
+    let is_odd n = if n == 0 then false else is_even (n - 1)
+    let x = 1
+    [is_even 10, is_odd 7, is_even 3, x]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(
+            *array.borrow(),
+            [
+                Value::Bool(true),
+                Value::Bool(true),
+                Value::Bool(false),
+                Value::Integer(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn tail_calls() {
+        let source = "let count n acc =
+    if n == 0 then acc else
+        let m = n - 1
+        let f = fn () -> m
+        count m (acc + f ())
+let collect xs = Iter.collect xs
+let main () = [count 100000 0, collect [1]]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let array = array.borrow();
+        assert_eq!(array[0], Value::Integer(4999950000));
+        assert_eq!(
+            *array[1].clone().as_array().unwrap().borrow(),
+            [Value::Integer(1)]
+        );
+    }
+
+    #[test]
+    fn partial_application() {
+        let source = "let add3 a b c = a + b + c
+let apply f x = f x
+
+let main () =
+    let add1 = add3 1
+    let add2 = add1 2
+    let a = add2 3
+    let b = add1 2 3
+    let c = add1 ()
+    let d = Iter.max [5] |> add3 1 2
+    let e = apply (add3 1 1) 1
+    let f = Iter.map [10, 20] (add3 1 2) |> Iter.collect
+    [a, b, c, d, e, f]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let array = array.borrow();
+        assert_eq!(array[0], Value::Integer(6));
+        assert_eq!(array[1], Value::Integer(6));
+        assert!(matches!(array[2], Value::Closure(_)));
+        assert_eq!(array[3], Value::Integer(8));
+        assert_eq!(array[4], Value::Integer(3));
+        assert_eq!(array[5].to_string(), "[13,23,]");
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let f a b = a\nlet main () = f 1 2 3")
+            .unwrap();
+        assert!(matches!(
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace),
+            Err(RuntimeError::IncorrectNumberOfArguments)
+        ));
+    }
+
+    #[test]
+    fn default_arguments() {
+        let source = "let greet name (greeting = \"hello\") (punctuation = greeting ++ \"!\") =
+    \"{greeting} {name}{punctuation}\"
+let add3 a b (c = 10) = a + b + c
+let count (n = 3) = if n == 0 then 0 else 1 + count (n - 1)
+let tail n (acc: int = 0) = if n == 0 then acc else tail (n - 1) (acc + n)
+
+let main () =
+    let a = greet \"you\"
+    let b = greet \"you\" \"hi\"
+    let c = greet \"you\" \"hi\" \"?\"
+    let add1 = add3 1
+    let d = add1 2
+    let e = count ()
+    let f = fn x (y = x * 2) -> x + y
+    let g = Iter.map [1, 2] f |> Iter.collect
+    [a, b, c, d, e, g, tail 4]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let values: Vec<String> = array.borrow().iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            values,
+            [
+                "hello youhello!",
+                "hi youhi!",
+                "hi you?",
+                "13",
+                "3",
+                "[3,6,]",
+                "10"
+            ]
+        );
+    }
+
+    #[test]
+    fn method_calls() {
+        let source = "let counter = {
+    count: 0,
+    add: fn self n -> self.count = self.count + n,
+    get: fn self -> self.count,
+}
+
+let main () =
+    counter:add 2
+    let t = { inner: counter }
+    t.inner:add 3
+    let a = counter:get ()
+    let b = counter:get
+    [a, b]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [Value::Integer(5), Value::Integer(5)]);
+        for source in [
+            "let main () =\n    let x = 1\n    x:get ()",
+            "let main () =\n    let x = { a: 1 }\n    x:a ()",
+            "let main () =\n    let x = { a: 1 }\n    x:b ()",
+        ] {
+            let mut vm = Vm::new_with_std();
+            let index = vm.load_from_source("main", source).unwrap();
+            assert!(vm.execute_module(index, "main").is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn tuples() {
+        let source = "let divmod a b = (a // b, a % b)
+let (one, two) = (1, 2)
+let main () =
+    let (q, r) = divmod 7 2
+    let t = { }
+    t[(1, 2)] = \"pair\"
+    let same = divmod 1 1 == (1, 0)
+    [q, r, two, t[(one, two)], same, \"{(divmod 9 4)}\"]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let values: Vec<String> = array.borrow().iter().map(|v| v.to_string()).collect();
+        assert_eq!(values, ["3", "1", "2", "pair", "true", "(2, 1)"]);
+    }
+
+    #[test]
+    fn unpack_wrong_length() {
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let main () =\n    let (a, b) = (1, 2, 3)\n    a")
+            .unwrap();
+        assert!(matches!(
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace),
+            Err(RuntimeError::Custom(_))
+        ));
+    }
+
+    #[test]
+    fn records() {
+        let source = "type Point = { x, y }
+let origin = Point { x: 0, y: 0 }
+let main () =
+    let p = Point { y: 2, x: 1 }
+    p.x = p.x + origin.y
+    p.x * 10 + p.y";
+        assert_eq!(run(source), Value::Integer(12));
+        let mut vm = Vm::new_with_std();
+        for (source, expected) in [
+            (
+                "type P = { x, y }\nlet main () = P { x: 1, z: 2 }",
+                CompilerErrorKind::UnknownField {
+                    record: "P".to_string(),
+                    field: "z".to_string(),
+                },
+            ),
+            (
+                "type P = { x, y }\nlet main () = P { x: 1 }",
+                CompilerErrorKind::MissingField {
+                    record: "P".to_string(),
+                    field: "y".to_string(),
+                },
+            ),
+            (
+                "type P = { x, y }\nlet main () =\n    let p = P { x: 1, y: 2 }\n    let f () = p.z\n    f ()",
+                CompilerErrorKind::UnknownField {
+                    record: "P".to_string(),
+                    field: "z".to_string(),
+                },
+            ),
+        ] {
+            let error = vm.load_from_source("main", source).unwrap_err();
+            assert_eq!(error.kind.to_string(), expected.to_string(), "{source}");
+        }
+    }
+
+    #[test]
+    fn try_catch() {
+        let source = "let fail x = x + \"a\"
+let main () =
+    let a = try fail 1 catch e -> 10
+    let b = try
+        let y = 2
+        y * 3
+    catch e -> 0
+    let c = try try fail 1 catch e -> fail 2 catch e -> 100
+    let d = 0
+    for x in [1, 2, 3] do
+        try
+            if x == 2 then break
+            d = d + x
+        catch e -> ()
+    let f = try Iter.collect (Iter.map (1..3) fn x -> x + ()) catch e -> 1000
+    a + b + c + d + f";
+        assert_eq!(run(source), Value::Integer(1117));
+        let message = "let main () = try 1 + () catch e -> e";
+        assert_eq!(
+            run(message).to_string(),
+            RuntimeError::InvalidOperandType {
+                lhs: "int".to_string(),
+                rhs: "unit".to_string()
+            }
+            .to_string()
+        );
+        let mut vm = Vm::new_with_std();
+        let source = "let main () =
+    while true do
+        try break catch e -> ()
+    1 + ()";
+        let index = vm.load_from_source("main", source).unwrap();
+        assert!(matches!(
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace),
+            Err(RuntimeError::InvalidOperandType { .. })
+        ));
+    }
+
+    #[test]
+    fn try_catch_in_operands() {
+        let source = "let id x = x
+let main () =
+    let a = 1 + (try throw 5 catch e -> e)
+    let b = id (try throw \"boom\" catch e -> e)
+    let c = [1, try throw 2 catch e -> e]
+    let d = { key: try throw 3 catch e -> e + 1 }
+    let e = 1 + (if true then
+        let y = 2
+        y
+    else 0)
+    [a, b, c[1], d.key, e]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let values: Vec<String> = array.borrow().iter().map(|v| v.to_string()).collect();
+        assert_eq!(values, ["6", "boom", "2", "4", "3"]);
+    }
+
+    #[test]
+    fn operators() {
+        let result = |source: &str| {
+            let mut vm = Vm::new_with_std();
+            let index = vm.load_from_source("main", source).unwrap();
+            vm.execute_module(index, "main").unwrap();
+            vm.peek().unwrap().to_string()
+        };
+        let source = "let main () =
+    let a = 7
+    let b = 2.0
+    [(a / 2), (a // b), (a % 4), (a * b), (a - 10), (2 * a), (a == 7.0), (b < a), ((a + 1) <= 8)]";
+        assert_eq!(result(source), "[3,3,3,14,-3,14,true,true,true,]");
+        // A local on the left is read before the right side changes it.
+        let source = "let main () =
+    let x = 1
+    let set () =
+        x = 10
+        0
+    x + set ()";
+        assert_eq!(result(source), "1");
+    }
+
+    #[test]
+    fn recoverable_errors() {
+        let error = |source: &str| {
+            let mut vm = Vm::new_with_std();
+            let index = vm.load_from_source("main", source).unwrap();
+            vm.execute_module(index, "main")
+                .map_err(RuntimeError::without_trace)
+                .unwrap_err()
+        };
+        let out_of_bounds = RuntimeError::IndexOutOfBounds { index: 3, len: 2 };
+        assert_eq!(
+            error("let main () =\n    let a = [1, 2]\n    a[3]").to_string(),
+            out_of_bounds.to_string()
+        );
+        assert_eq!(
+            error("let main () =\n    let a = \"ab\"\n    a[3]").to_string(),
+            out_of_bounds.to_string()
+        );
+        assert!(matches!(
+            error("let main () =\n    let a = [1]\n    a[-2] = 1"),
+            RuntimeError::IndexOutOfBounds { index: -2, len: 1 }
+        ));
+        assert!(matches!(
+            error("let main () =\n    let a = [1]\n    a[\"a\"]"),
+            RuntimeError::CannotIndex { value, key } if value == "array" && key == "string"
+        ));
+        assert!(matches!(
+            error("let main () =\n    let a = 1\n    a.b"),
+            RuntimeError::CannotIndex { value, key } if value == "int" && key == "string"
+        ));
+        assert!(matches!(
+            error("let main () =\n    let a = 1\n    a.b = 2"),
+            RuntimeError::CannotIndex { value, key } if value == "int" && key == "string"
+        ));
+        for operator in ["-", "*", "/", "//", "%"] {
+            assert!(matches!(
+                error(&format!("let main () = 1 {operator} \"a\"")),
+                RuntimeError::InvalidOperandType { lhs, rhs } if lhs == "int" && rhs == "string"
+            ));
+        }
+        for operator in ["/", "//", "%"] {
+            let source = format!("let main () =\n    let zero = 0\n    1 {operator} zero");
+            assert!(matches!(error(&source), RuntimeError::DivisionByZero));
+        }
+        for expression in ["max + 1", "min - 1", "max * 2", "-min", "min // -1"] {
+            let source = format!(
+                "let main () =\n    let max = 9223372036854775807\n    let min = -max - 1\n    {expression}"
+            );
+            assert!(matches!(error(&source), RuntimeError::IntegerOverflow));
+        }
+        // Scripts can catch them like any other error.
+        let source = "let main () =\n    let a = [1]\n    try a[5] catch e -> e";
+        assert_eq!(
+            run(source).to_string(),
+            RuntimeError::IndexOutOfBounds { index: 5, len: 1 }.to_string()
+        );
+    }
+
+    #[test]
+    fn stack_trace() {
+        let mut vm = Vm::new_with_std();
+        // Tail calls replace the caller's frame, so the calls here aren't.
+        let source =
+            "let fail x =\n    x + \"a\"\nlet call f = f 1 + 1\nlet main () =\n    call fail + 1";
+        let index = vm.load_from_source("main", source).unwrap();
+        let Err(RuntimeError::Traced { error, trace }) = vm.execute_module(index, "main") else {
+            panic!("expected an error with a stack trace");
+        };
+        assert!(matches!(*error, RuntimeError::InvalidOperandType { .. }));
+        let frame = |ident: &str, line| StackTraceInfo::Prototype {
+            ident: ident.to_string(),
+            line,
+        };
+        assert_eq!(
+            trace.info(),
+            [frame("fail", 2), frame("call", 3), frame("main", 5)]
+        );
+        assert_eq!(
+            trace.to_string(),
+            "function fail at line 2\nfunction call at line 3\nfunction main at line 5\n"
+        );
+        // Errors in module code are traced to the module.
+        let mut vm = Vm::new_with_std();
+        let index = vm.load_from_source("other", "let a = 1 + ()").unwrap();
+        let Err(RuntimeError::Traced { trace, .. }) = vm.execute_module(index, "a") else {
+            panic!("expected an error with a stack trace");
+        };
+        assert_eq!(trace.info(), [frame("<main>", 1)]);
+    }
+
+    #[test]
+    fn throw() {
+        let source = "let check x = if x > 2 then throw { code: x } else x
+let main () =
+    let a = try check 5 catch e -> e.code
+    let b = try check 1 catch e -> 0
+    a * 10 + b";
+        assert_eq!(run(source), Value::Integer(51));
+        let mut module_loader = ModuleLoader::new("");
+        let fail = |vm: &mut Vm| Err(vm.error(Value::Integer(7)));
+        module_loader
+            .add_native_function("Test", "fail", Rc::new(RefCell::new(fail)))
+            .unwrap();
+        let mut vm = Vm::new(module_loader);
+        let index = vm
+            .load_from_source("main", "let main () = try Test.fail () catch e -> e + 1")
+            .unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(8)));
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let main () =\n    let x = 1\n    throw \"bad\"")
+            .unwrap();
+        let result = vm
+            .execute_module(index, "main")
+            .map_err(RuntimeError::without_trace);
+        let Err(RuntimeError::Thrown {
+            value,
+            function,
+            line,
+        }) = result
+        else {
+            panic!("expected a thrown value, found {result:?}");
+        };
+        assert_eq!(value, Value::String(Rc::new("bad".to_string())));
+        assert_eq!((function.as_str(), line), ("main", 3));
+    }
+
+    #[test]
+    fn assert() {
+        let source = "let main () =
+    let x = 2
+    assert x > 1
+    assert x == 2 \"x is {x}\"
+    try assert x  <  1 catch e -> e";
+        assert_eq!(
+            run(source),
+            Value::String(Rc::new("Assertion failed in `main`: x  <  1".to_string()))
+        );
+        let mut vm = Vm::new_with_std();
+        let source = "let main () =\n    let x = 2\n    assert x == 3 \"x is {x}\"";
+        let index = vm.load_from_source("test", source).unwrap();
+        let Err(error @ RuntimeError::Thrown { line: 3, .. }) = vm
+            .execute_module(index, "main")
+            .map_err(RuntimeError::without_trace)
+        else {
+            panic!("expected a failed assertion");
+        };
+        assert_eq!(
+            error.into_value(),
+            Value::String(Rc::new(
+                "Assertion failed in `test`: x == 3: x is 2".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let source = "let main () =
+    let i = 0
+    let odd = 0
+    while true do
+        i = i + 1
+        let j = i
+        if j > 9 then break
+        if j % 2 == 0 then continue
+        odd = odd + j
+    let first = ()
+    for x in [4, 7, 8, 9] do
+        let y = x * 2
+        if x % 2 == 1 then
+            first = y
+            break
+    [i, odd, first]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(*array.borrow(), [10, 25, 14].map(Value::Integer));
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        let mut vm = Vm::new_with_std();
+        let result = vm.load_from_source(
+            "main",
+            "let main () =\n    let f = fn -> break\n    while true do f ()",
+        );
+        assert!(matches!(
+            result,
+            Err(CompilerError {
+                kind: CompilerErrorKind::BreakOutsideLoop,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn concat() {
+        let source = "let main () =
+    let a = [1]
+    let b = a ++ [2]
+    let c = \"n: \" ++ 1 ++ ',' ++ true
+    let d = 2.5 ++ \"!\"
+    let e = \"x\" ++ \"y\"
+    [c, d, e, len a, len b]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        let values: Vec<String> = array.borrow().iter().map(|v| v.to_string()).collect();
+        assert_eq!(values, ["n: 1,true", "2.5!", "xy", "1", "2"]);
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let source = "let main () =
+    let a = 12 & 10
+    let b = 12 | 3
+    let c = 12 ^ 10
+    let d = 1 << 4 | 1
+    let e = -16 >> 2
+    let f = ~5
+    let g = match 2 | 2 -> (1 | 2) | _ -> 0
+    [a, b, c, d, e, f, g]";
+        let Value::Array(array) = run(source) else {
+            panic!("expected an array");
+        };
+        assert_eq!(
+            *array.borrow(),
+            [8, 15, 6, 17, -4, -6, 3].map(Value::Integer)
+        );
+    }
+
+    #[test]
+    fn long_jumps() {
+        let body = "        x = x + 1\n".repeat(100);
+        let source = format!(
+            "let main () =
+    let x = 0
+    let i = 0
+    if x == 0 then
+{body}    else x = -1
+    while i < 3 do
+        i = i + 1
+{body}    x"
+        );
+        assert_eq!(run(&source), Value::Integer(400));
+
+        let body = "        x = x + 1\n".repeat(20000);
+        let mut vm = Vm::new_with_std();
+        let result = vm.load_from_source(
+            "main",
+            &format!("let main () =\n    let x = 0\n    if true then\n{body}    x"),
+        );
+        assert!(matches!(
+            result,
+            Err(CompilerError {
+                kind: CompilerErrorKind::JumpTooLong,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn many_constants() {
+        let numbers: Vec<String> = (0..300).map(|i| format!("{i}.5")).collect();
+        let source = format!(
+            "let main () =\n    let a = [{}]\n    \"{{a[299]}} of {{len a}}\"",
+            numbers.join(", ")
+        );
+        assert_eq!(run(&source).to_string(), "299.5 of 300");
+    }
+
+    #[test]
+    fn many_locals() {
+        let mut source = String::from("let x0 = 0\n");
+        for i in 1..300 {
+            source.push_str(&format!("let x{i} = x{} + 1\n", i - 1));
+        }
+        source.push_str("let f () = x299\nlet main () =\n");
+        for i in 0..300 {
+            source.push_str(&format!("    let y{i} = {i}\n"));
+        }
+        source.push_str("    let g = fn -> y299\n    y299 = y299 + 1\n    (f (), g (), y298, x0)");
+        assert_eq!(run(&source).to_string(), "(299, 300, 298, 0)");
+    }
+
+    #[test]
+    fn shift_out_of_range() {
+        let mut vm = Vm::new_with_std();
+        let index = vm
+            .load_from_source("main", "let main () = 1 << 64")
+            .unwrap();
+        assert!(vm.execute_module(index, "main").is_err());
+    }
+
+    #[test]
+    fn field_caches() {
+        let mut vm = Vm::new_with_std();
+        let m = vm.load_from_source("m", "let value = 1").unwrap();
+        let source = "let main () =
+    let t = { a: 1 }
+    let sum = 0
+    for i in 0..3 do
+        sum = sum + t.a
+        t.a = t.a + 1
+    [sum, m.value]";
+        let index = vm.load_from_source("main", source).unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek().unwrap().to_string(), "[6,1,]");
+        let module = vm.module_loader().module_at(index).unwrap();
+        let ModuleValue::Normal(prototype) = &module.value else {
+            unreachable!()
+        };
+        let caches: Vec<FieldCache> = prototype.prototypes[0]
+            .field_caches
+            .iter()
+            .map(|cache| cache.borrow().clone())
+            .collect();
+        assert!(matches!(
+            caches.as_slice(),
+            [
+                FieldCache::Table {
+                    value: Value::Integer(3),
+                    ..
+                },
+                FieldCache::Table {
+                    value: Value::Integer(3),
+                    ..
+                },
+                FieldCache::Module {
+                    value: Value::Integer(1),
+                    ..
+                },
+            ]
+        ));
+
+        // The values of reloaded modules aren't taken from the caches.
+        vm.reload_module(m, "let value = 2").unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek().unwrap().to_string(), "[6,2,]");
+    }
+
+    #[test]
+    fn reload_module() {
+        let mut vm = Vm::new_with_std();
+        let index = vm.load_from_source("main", "let main () = 1").unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(1)));
+
+        vm.reload_module(index, "let main () = 2").unwrap();
+        vm.execute_module(index, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(2)));
+        assert!(vm.module_loader().module_names().any(|name| name == "main"));
+    }
+
+    #[test]
+    fn reload_imported_module() {
+        let mut vm = Vm::new_with_std();
+        let lib = vm.load_from_source("lib", "let a = 1\nlet b = 2").unwrap();
+        let source = "import lib as L\nlet main () = L.b";
+        let main = vm.load_from_source("main", source).unwrap();
+        vm.execute_module(main, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(2)));
+
+        // The same locals, so `main` keeps working.
+        vm.reload_module(lib, "let a = 10\nlet b = 20").unwrap();
+        vm.execute_module(main, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(20)));
+
+        vm.reload_module(lib, "let b = 30").unwrap();
+        assert!(matches!(
+            vm.execute_module(main, "main"),
+            Err(RuntimeError::StaleModule(ident)) if ident == "main"
+        ));
+        vm.reload_module(main, source).unwrap();
+        vm.execute_module(main, "main").unwrap();
+        assert_eq!(vm.peek(), Some(Value::Integer(30)));
+    }
+}
@@ -0,0 +1,21 @@
+use std::process::Command;
+
+#[test]
+fn imports_next_to_the_script() {
+    let dir = std::env::temp_dir().join("focus-lang-run");
+    let scripts = dir.join("scripts");
+    std::fs::create_dir_all(&scripts).unwrap();
+    std::fs::write(scripts.join("utils.fl"), "let value = 7").unwrap();
+    std::fs::write(
+        scripts.join("main.fl"),
+        "from \"utils\" import value\nlet main () = Io.print value",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_run"))
+        .arg("scripts/main.fl")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "7");
+}